@@ -1,126 +1,331 @@
 use cosmwasm_std::{
-    entry_point, to_binary, Binary, Deps, DepsMut, Env, MessageInfo, Reply, Response, StdError,
-    StdResult,
+    entry_point, to_binary, Binary, ContractResult, Deps, DepsMut, Env, MessageInfo, Reply,
+    Response, StdError, StdResult,
 };
 
+use cw2::set_contract_version;
+
 use fields_of_mars::martian_field::{CallbackMsg, ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg};
 
+use crate::error::ContractError;
 use crate::execute;
 use crate::execute_callbacks as callbacks;
 use crate::execute_replies as replies;
-use crate::helpers::unwrap_reply;
-use crate::legacy;
+use crate::helpers::{assert_unlocked, decode_reply_id, unwrap_reply};
 use crate::queries;
 
+const CONTRACT_NAME: &str = "crates.io:martian-field";
+const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
 #[entry_point]
-pub fn instantiate(deps: DepsMut, _env: Env, _info: MessageInfo, msg: InstantiateMsg) -> StdResult<Response> {
-    let config = msg.check(deps.api)?;
-    config.validate()?;
-    execute::init_storage(deps, config)
+pub fn instantiate(
+    deps: DepsMut,
+    _env: Env,
+    _info: MessageInfo,
+    msg: InstantiateMsg,
+) -> Result<Response, ContractError> {
+    let limits = msg.limits.unwrap_or_default();
+    limits.validate()?;
+    let mut config = msg.config.check(deps.api)?;
+    config.validate(&limits)?;
+    config.assert_pairs(&deps.querier)?;
+    config.cache_decimals(&deps.querier)?;
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+    Ok(execute::init_storage(deps, config, limits)?)
 }
 
 #[entry_point]
-pub fn execute(deps: DepsMut, env: Env, info: MessageInfo, msg: ExecuteMsg) -> StdResult<Response> {
+pub fn execute(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    msg: ExecuteMsg,
+) -> Result<Response, ContractError> {
     let api = deps.api;
-    match msg {
-        ExecuteMsg::UpdatePosition(actions) => execute::update_position(deps, env, info, actions),
+
+    // while a callback chain is in progress, only the contract's own callbacks may be executed
+    if !matches!(msg, ExecuteMsg::Callback(_)) {
+        assert_unlocked(deps.storage)?;
+    }
+
+    let response = match msg {
+        ExecuteMsg::UpdatePosition(actions) => {
+            let user_addr = info.sender.clone();
+            execute::update_position(deps, env, info, user_addr, actions)?
+        }
+        ExecuteMsg::UpdatePositionFor {
+            user,
+            actions,
+        } => execute::update_position(deps, env, info, api.addr_validate(&user)?, actions)?,
+        ExecuteMsg::AuthorizeKeeper {
+            keeper,
+            actions,
+        } => execute::authorize_keeper(deps, info, api.addr_validate(&keeper)?, actions)?,
+        ExecuteMsg::RevokeKeeper {
+            keeper,
+        } => execute::revoke_keeper(deps, info, api.addr_validate(&keeper)?)?,
         ExecuteMsg::Harvest {
             max_spread,
             slippage_tolerance,
-        } => execute::harvest(deps, env, info, max_spread, slippage_tolerance),
+            min_lp_out,
+        } => execute::harvest(deps, env, info, max_spread, slippage_tolerance, min_lp_out)?,
         ExecuteMsg::Liquidate {
             user,
-        } => execute::liquidate(deps, env, info, api.addr_validate(&user)?),
+            max_bond_units,
+            max_spread,
+            bonus_asset,
+        } => execute::liquidate(
+            deps,
+            env,
+            info,
+            api.addr_validate(&user)?,
+            max_bond_units,
+            max_spread,
+            bonus_asset.map(|info| info.check(api, None)).transpose()?,
+        )?,
         ExecuteMsg::UpdateConfig {
             new_config,
-        } => execute::update_config(deps, info, new_config.check(api)?),
-        ExecuteMsg::Callback(callback_msg) => execute_callback(deps, env, info, callback_msg),
-    }
+        } => execute::update_config(deps, env, info, new_config.check(api)?)?,
+        ExecuteMsg::AdminAdjustPosition {
+            user,
+            bond_units,
+            debt_units,
+        } => execute::admin_adjust_position(
+            deps,
+            env,
+            info,
+            api.addr_validate(&user)?,
+            bond_units,
+            debt_units,
+        )?,
+        ExecuteMsg::FundIncentiveReserve {
+            amount,
+        } => execute::fund_incentive_reserve(deps, env, info, amount)?,
+        ExecuteMsg::WithdrawAccumulatedAstro {
+            recipient,
+        } => execute::withdraw_accumulated_astro(deps, info, api.addr_validate(&recipient)?)?,
+        ExecuteMsg::Sweep {
+            asset,
+            recipient,
+        } => execute::sweep(deps, info, asset.check(api, None)?, api.addr_validate(&recipient)?)?,
+        ExecuteMsg::SetPaused {
+            paused,
+        } => execute::set_paused(deps, info, paused)?,
+        ExecuteMsg::ProposeNewGovernance {
+            new_governance,
+        } => execute::propose_new_governance(deps, info, api.addr_validate(&new_governance)?)?,
+        ExecuteMsg::AcceptGovernance {} => execute::accept_governance(deps, info)?,
+        ExecuteMsg::AddOperator {
+            operator,
+        } => execute::add_operator(deps, info, api.addr_validate(&operator)?)?,
+        ExecuteMsg::RemoveOperator {
+            operator,
+        } => execute::remove_operator(deps, info, api.addr_validate(&operator)?)?,
+        ExecuteMsg::Callback(callback_msg) => execute_callback(deps, env, info, callback_msg)?,
+    };
+    Ok(response)
 }
 
-fn execute_callback(deps: DepsMut, env: Env, info: MessageInfo, msg: CallbackMsg) -> StdResult<Response> {
+fn execute_callback(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    msg: CallbackMsg,
+) -> Result<Response, ContractError> {
     if info.sender != env.contract.address {
-        return Err(StdError::generic_err("callbacks cannot be invoked externally"));
+        return Err(ContractError::unauthorized("callbacks cannot be invoked externally"));
     }
-    match msg {
+    let response = match msg {
+        CallbackMsg::CreditDeposits {
+            user_addr,
+            balances_before,
+        } => callbacks::credit_deposits(deps, env, user_addr, balances_before)?,
         CallbackMsg::ProvideLiquidity {
             user_addr,
             slippage_tolerance,
-        } => callbacks::provide_liquidity(deps, user_addr, slippage_tolerance),
+            min_lp_out,
+        } => callbacks::provide_liquidity(deps, env, user_addr, slippage_tolerance, min_lp_out)?,
         CallbackMsg::WithdrawLiquidity {
             user_addr,
-        } => callbacks::withdraw_liquidity(deps, user_addr),
+        } => callbacks::withdraw_liquidity(deps, env, user_addr)?,
         CallbackMsg::Bond {
             user_addr,
-        } => callbacks::bond(deps, env, user_addr),
+        } => callbacks::bond(deps, env, user_addr)?,
         CallbackMsg::Unbond {
             user_addr,
             bond_units_to_reduce,
-        } => callbacks::unbond(deps, env, user_addr, bond_units_to_reduce),
+        } => callbacks::unbond(deps, env, user_addr, bond_units_to_reduce)?,
         CallbackMsg::Borrow {
             user_addr,
             borrow_amount,
-        } => callbacks::borrow(deps, env, user_addr, borrow_amount),
+        } => callbacks::borrow(deps, env, user_addr, borrow_amount)?,
         CallbackMsg::Repay {
             user_addr,
             repay_amount,
-        } => callbacks::repay(deps, env, user_addr, repay_amount),
+        } => callbacks::repay(deps, env, user_addr, repay_amount)?,
+        CallbackMsg::RepayFor {
+            user_addr,
+            debtor_addr,
+            repay_amount,
+        } => callbacks::repay_for(deps, env, user_addr, debtor_addr, repay_amount)?,
         CallbackMsg::Refund {
             user_addr,
             recipient_addr,
             percentage,
-        } => callbacks::refund(deps, user_addr, recipient_addr, percentage),
+        } => callbacks::refund(deps, user_addr, recipient_addr, percentage)?,
+        CallbackMsg::RefundAsset {
+            user_addr,
+            recipient_addr,
+            asset,
+        } => callbacks::refund_asset(deps, user_addr, recipient_addr, asset)?,
         CallbackMsg::Swap {
             user_addr,
             offer_asset_info,
             offer_amount,
             max_spread,
-        } => callbacks::swap(deps, user_addr, offer_asset_info, offer_amount, max_spread),
+            min_return,
+        } => callbacks::swap(
+            deps,
+            env,
+            user_addr,
+            offer_asset_info,
+            offer_amount,
+            max_spread,
+            min_return,
+        )?,
         CallbackMsg::Balance {
+            user_addr,
             max_spread,
-        } => callbacks::balance(deps, max_spread),
+        } => callbacks::balance(deps, env, user_addr, max_spread)?,
         CallbackMsg::Cover {
             user_addr,
-        } => callbacks::cover(deps, env, user_addr),
+            max_spread,
+        } => callbacks::cover(deps, env, user_addr, max_spread)?,
         CallbackMsg::AssertHealth {
             user_addr,
-        } => callbacks::assert_health(deps, env, user_addr),
+        } => callbacks::assert_health(deps, env, user_addr)?,
+        CallbackMsg::AssertDepositCap {} => callbacks::assert_deposit_cap(deps, env)?,
         CallbackMsg::ClearBadDebt {
             user_addr,
-        } => callbacks::clear_bad_debt(deps, env, user_addr),
+        } => callbacks::clear_bad_debt(deps, env, user_addr)?,
+        CallbackMsg::Snapshot {
+            user_addr,
+        } => callbacks::snapshot(deps, env, user_addr)?,
         CallbackMsg::PurgeStorage {
             user_addr,
-        } => callbacks::purge_storage(deps, user_addr),
-    }
+        } => callbacks::purge_storage(deps, user_addr)?,
+        CallbackMsg::ReleaseLock {} => callbacks::release_lock(deps)?,
+    };
+    Ok(response)
 }
 
 #[entry_point]
-pub fn reply(deps: DepsMut, _env: Env, reply: Reply) -> StdResult<Response> {
-    match reply.id {
-        0 => replies::after_provide_liquidity(deps, unwrap_reply(reply)?),
-        1 => replies::after_withdraw_liquidity(deps, unwrap_reply(reply)?),
-        2 => replies::after_swap(deps, unwrap_reply(reply)?),
-        id => Err(StdError::generic_err(format!("invalid reply id: {}", id))),
+pub fn reply(deps: DepsMut, env: Env, reply: Reply) -> Result<Response, ContractError> {
+    // the high bits of the reply id hold the kind of the operation; the low bits hold its nonce,
+    // under which the user address is cached, so that concurrent operations never mix up users
+    let (kind, nonce) = decode_reply_id(reply.id);
+
+    // harvest steps that may fail, i.e. swaps and liquidity provisions, reply even on failure
+    if let ContractResult::Err(error) = &reply.result {
+        if matches!(kind, 0 | 2 | 4) {
+            return Ok(replies::after_harvest_step_failed(deps, kind, nonce, error.clone())?);
+        }
     }
+
+    let response = match kind {
+        0 => replies::after_provide_liquidity(deps, env, nonce, unwrap_reply(reply)?)?,
+        1 => replies::after_withdraw_liquidity(deps, env, nonce, unwrap_reply(reply)?)?,
+        2 => replies::after_swap(deps, env, nonce, unwrap_reply(reply)?)?,
+        3 => replies::after_bond_or_unbond(deps, env)?,
+        4 => replies::after_route_hop(deps, env, nonce, unwrap_reply(reply)?)?,
+        _ => return Err(StdError::generic_err(format!("invalid reply id: {}", reply.id)).into()),
+    };
+    Ok(response)
 }
 
 #[entry_point]
 pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
     match msg {
         QueryMsg::Config {} => to_binary(&queries::query_config(deps)?),
+        QueryMsg::Limits {} => to_binary(&queries::query_limits(deps)?),
         QueryMsg::State {} => to_binary(&queries::query_state(deps, env)?),
+        QueryMsg::StateSnapshot {} => to_binary(&queries::query_state_snapshot(deps, env)?),
         QueryMsg::Positions {
             start_after,
             limit,
-        } => to_binary(&queries::query_positions(deps, env, start_after, limit)?),
+            order_by,
+        } => to_binary(&queries::query_positions(deps, env, start_after, limit, order_by)?),
         QueryMsg::Position {
             user,
         } => to_binary(&queries::query_position(deps, env, deps.api.addr_validate(&user)?)?),
+        QueryMsg::Health {
+            user,
+        } => to_binary(&queries::query_health(
+            deps,
+            env,
+            user.map(|user| deps.api.addr_validate(&user)).transpose()?,
+        )?),
+        QueryMsg::LiquidatablePositions {
+            start_after,
+            limit,
+        } => to_binary(&queries::query_liquidatable_positions(deps, env, start_after, limit)?),
+        QueryMsg::AdminStatus {} => to_binary(&queries::query_admin_status(deps)?),
+        QueryMsg::LiquidationProfit {
+            user,
+        } => to_binary(&queries::query_liquidation_profit(
+            deps,
+            env,
+            deps.api.addr_validate(&user)?,
+        )?),
+        QueryMsg::LiquidationParams {
+            user,
+        } => to_binary(&queries::query_liquidation_params(
+            deps,
+            env,
+            deps.api.addr_validate(&user)?,
+        )?),
+        QueryMsg::SimulateUpdatePosition {
+            user,
+            actions,
+        } => to_binary(&queries::query_simulate_update_position(
+            deps,
+            env,
+            user.map(|user| deps.api.addr_validate(&user)).transpose()?,
+            actions,
+        )?),
+        QueryMsg::PendingRewards {} => to_binary(&queries::query_pending_rewards(deps)?),
+        QueryMsg::Authorization {
+            user,
+            keeper,
+        } => to_binary(&queries::query_authorization(
+            deps,
+            deps.api.addr_validate(&user)?,
+            deps.api.addr_validate(&keeper)?,
+        )?),
+        QueryMsg::Snapshots {
+            user,
+            start_after,
+            limit,
+        } => to_binary(&queries::query_snapshots(
+            deps,
+            deps.api.addr_validate(&user)?,
+            start_after,
+            limit,
+        )?),
+        QueryMsg::ConfigHistory {
+            start_after,
+            limit,
+        } => to_binary(&queries::query_config_history(deps, start_after, limit)?),
+        QueryMsg::MaxBorrow {
+            user,
+        } => to_binary(&queries::query_max_borrow(deps, env, deps.api.addr_validate(&user)?)?),
+        QueryMsg::Reconcile {} => to_binary(&queries::query_reconcile(deps, env)?),
+        QueryMsg::FeeStats {} => to_binary(&queries::query_fee_stats(deps)?),
     }
 }
 
 #[entry_point]
-pub fn migrate(deps: DepsMut, _env: Env, _msg: MigrateMsg) -> StdResult<Response> {
-    legacy::delete_snapshots(deps)?;
-    Ok(Response::new())
+pub fn migrate(deps: DepsMut, _env: Env, msg: MigrateMsg) -> Result<Response, ContractError> {
+    let new_config = msg.new_config.map(|config| config.check(deps.api)).transpose()?;
+    Ok(execute::migrate(deps, CONTRACT_NAME, CONTRACT_VERSION, new_config)?)
 }
@@ -1,32 +1,67 @@
-use cosmwasm_std::testing::{
-    mock_dependencies, mock_env, mock_info, MockApi, MockQuerier, MockStorage,
+use std::cmp;
+use std::str::FromStr;
+
+use cosmwasm_std::testing::{mock_env, mock_info, MockApi, MockStorage, MOCK_CONTRACT_ADDR};
+use cosmwasm_std::{
+    attr, from_binary, to_binary, Addr, Coin, ContractResult, CosmosMsg, Decimal, Event, OwnedDeps,
+    QuerierWrapper, Reply, ReplyOn, Response, StdError, StdResult, Storage, SubMsg,
+    SubMsgExecutionResponse, Uint128, WasmMsg,
 };
-use cosmwasm_std::{Addr, Coin, Decimal, OwnedDeps, StdError};
 
-use cw_asset::{Asset, AssetInfo};
+use cw2::{get_contract_version, set_contract_version};
+use cw_asset::{Asset, AssetInfo, AssetInfoUnchecked, AssetList, AssetUnchecked};
+use cw_storage_plus::U64Key;
 
-use fields_of_mars::adapters::{Generator, Oracle, Pair, RedBank};
-use fields_of_mars::martian_field::{Action, ExecuteMsg};
+use fields_of_mars::adapters::{
+    Generator, Oracle, Pair, PairType, RedBank, RefundBatcher, RefundBatcherCw20HookMsg,
+    RefundBatcherExecuteMsg,
+};
+use fields_of_mars::asset::{
+    asset_info_from_label, asset_label, is_cw20, AstroportAsset, AstroportAssetInfo,
+};
+use fields_of_mars::martian_field::{
+    Action, ActionKind, AdminStatusResponse, AstroHandling, Authorization, CallbackMsg,
+    ConfigHistoryEntry, ExecuteMsg, FeeBenchmark, FeeMode, FeeStatsResponse, HealthResponse,
+    InstantiateMsg, Limits, LiquidatablePositionsResponseItem, LiquidationParamsResponse,
+    LiquidationProfitResponse, MigrateMsg, PendingRewardsResponseItem, PositionOrder,
+    PositionResponse, PositionsResponseItem, QueryMsg, ReconcileResponse, ReinvestMode,
+    SimulateResponse, Snapshot, StateSnapshotResponse,
+};
 use fields_of_mars::martian_field::Config;
+use fields_of_mars::testing::{self, mock_dependencies, CustomMockQuerier};
 
-use crate::contract::{execute, instantiate};
-
-/// Deploy the contract, returns the `deps` object
-fn setup_test() -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
-    let mut deps = mock_dependencies(&[]);
+use crate::contract::{execute, instantiate, migrate, query, reply};
+use crate::error::ContractError;
+use crate::execute_callbacks::{compute_balancing_swap, compute_stable_balancing_swap};
+use crate::health::compute_health;
+use crate::helpers::{
+    calc_limit, decode_reply_id, encode_reply_id, fee_transfer_msgs, seed_units, value_of,
+    AssetListExt,
+};
+use crate::state::{
+    CachedUserAddr, Position, State, CACHED_BALANCES, CACHED_MIN_LP_OUT, CACHED_MIN_RETURN,
+    CACHED_REWARDS, CACHED_ROUTE, CONFIG, NEXT_OP_NONCE, PENDING_OPS, POSITION, STATE,
+};
 
-    let config = Config {
+/// The config used in tests
+fn mock_config() -> Config {
+    Config {
         primary_asset_info: AssetInfo::native("uluna"),
         secondary_asset_info: AssetInfo::native("uusd"),
+        borrow_asset_info: None,
+        borrow_pair: None,
         astro_token_info: AssetInfo::cw20(Addr::unchecked("astro_token")),
         primary_pair: Pair {
             contract_addr: Addr::unchecked("uluna_uusd_pair"),
             liquidity_token: Addr::unchecked("uluna_uusd_lp_token"),
+            pair_type: PairType::Xyk,
         },
         astro_pair: Pair {
             contract_addr: Addr::unchecked("astro_uusd_pair"),
             liquidity_token: Addr::unchecked("astro_uusd_lp_token"),
+            pair_type: PairType::Xyk,
         },
+        astro_route: vec![],
         astro_generator: Generator {
             contract_addr: Addr::unchecked("astro_generator"),
         },
@@ -36,19 +71,219 @@ fn setup_test() -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
         oracle: Oracle {
             contract_addr: Addr::unchecked("oracle"),
         },
-        treasury: Addr::unchecked("treasury"),
+        secondary_oracle: None,
+        fee_recipients: vec![(Addr::unchecked("treasury"), Decimal::one())],
         governance: Addr::unchecked("governance"),
         operators: vec![Addr::unchecked("operator")],
         max_ltv: Decimal::from_ratio(65u128, 100u128),
         fee_rate: Decimal::from_ratio(5u128, 100u128),
+        fee_mode: FeeMode::Flat,
         bonus_rate: Decimal::from_ratio(1u128, 100u128),
+        bonus_schedule: vec![],
+        allow_one_sided_provide: false,
+        repay_tax_reserve: Uint128::zero(),
+        borrow_tax_rate: Decimal::zero(),
+        astro_handling: AstroHandling::Reinvest,
+        reinvest_mode: ReinvestMode::Compound,
+        liquidation_incentive: None,
+        refund_batcher: None,
+        reward_swap_thresholds: vec![],
+        max_price_age: 0,
+        default_slippage_tolerance: Decimal::zero(),
+        insurance_fund: None,
+        reward_pairs: vec![],
+        deposit_cap: None,
+        max_debt_per_user: None,
+        min_deposit_value: Uint128::zero(),
+        assert_liquidity: false,
+        max_snapshots: 30,
+        liquidation_max_spread: Decimal::percent(5),
+        bond_units_per_share: Uint128::new(1_000_000),
+        debt_units_per_asset: Uint128::new(1_000_000),
+        harvest_cooldown: 0,
+        max_allowed_spread: Decimal::percent(10),
+        primary_asset_decimals: 6,
+        secondary_asset_decimals: 6,
+        borrow_asset_decimals: 6,
+    }
+}
+
+/// Deploy the contract, returns the `deps` object
+fn setup_test() -> OwnedDeps<MockStorage, MockApi, CustomMockQuerier> {
+    let mut deps = mock_dependencies();
+    set_pair_infos(&mut deps, PairType::Xyk);
+
+    let config = mock_config();
+    let msg = InstantiateMsg {
+        config: config.into(),
+        limits: None,
     };
+    instantiate(deps.as_mut(), mock_env(), mock_info("deployer", &[]), msg).unwrap();
 
-    instantiate(deps.as_mut(), mock_env(), mock_info("deployer", &[]), config.into()).unwrap();
+    deps.querier.set_reward_info("uluna_uusd_lp_token", "astro_token", None);
+    deps.querier.set_red_bank_market(&AssetInfo::native("uusd"));
+
+    set_pool_and_prices(&mut deps);
 
     deps
 }
 
+/// Set the depths of the primary pair and the oracle prices of its assets, which callbacks that
+/// mutate a position need in order to compute its health
+fn set_pool_and_prices(deps: &mut OwnedDeps<MockStorage, MockApi, CustomMockQuerier>) {
+    deps.querier.set_pool(
+        "uluna_uusd_pair",
+        &[Asset::native("uluna", 1_000_000u128), Asset::native("uusd", 10_000_000u128)],
+        1_000_000,
+    );
+    deps.querier.set_oracle_price(&AssetInfo::native("uluna"), Decimal::percent(1000));
+    deps.querier.set_oracle_price(&AssetInfo::native("uusd"), Decimal::one());
+}
+
+/// Register the pairs of `mock_config` with the mock querier, the primary pair having the
+/// specified type
+fn set_pair_infos(
+    deps: &mut OwnedDeps<MockStorage, MockApi, CustomMockQuerier>,
+    primary_pair_type: PairType,
+) {
+    deps.querier.set_pair_info(
+        "uluna_uusd_pair",
+        &[AssetInfo::native("uluna"), AssetInfo::native("uusd")],
+        "uluna_uusd_lp_token",
+        primary_pair_type,
+    );
+    deps.querier.set_pair_info(
+        "astro_uusd_pair",
+        &[AssetInfo::cw20(Addr::unchecked("astro_token")), AssetInfo::native("uusd")],
+        "astro_uusd_lp_token",
+        PairType::Xyk,
+    );
+}
+
+/// Set up a position for alice, whose bonded liquidity tokens are worth 2,000,000 uusd (100,000
+/// uluna + 1,000,000 uusd), and who owes the specified amount of uusd
+fn setup_leveraged_position(
+    deps: &mut OwnedDeps<MockStorage, MockApi, CustomMockQuerier>,
+    debt_amount: u128,
+) {
+    set_pool_and_prices(deps);
+    deps.querier.set_bonded_amount("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 100_000);
+    deps.querier.set_user_debt(MOCK_CONTRACT_ADDR, &AssetInfo::native("uusd"), debt_amount);
+    STATE
+        .save(
+            deps.as_mut().storage,
+            &State {
+                total_bond_units: Uint128::new(100_000_000),
+                total_debt_units: Uint128::new(debt_amount * 1_000),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    POSITION
+        .save(
+            deps.as_mut().storage,
+            &Addr::unchecked("alice"),
+            &Position {
+                bond_units: Uint128::new(100_000_000),
+                debt_units: Uint128::new(debt_amount * 1_000),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+}
+
+/// Invoke a callback as if it is sent by the contract itself
+fn execute_callback(
+    deps: &mut OwnedDeps<MockStorage, MockApi, CustomMockQuerier>,
+    callback_msg: CallbackMsg,
+) -> Result<Response, ContractError> {
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::Callback(callback_msg),
+    )
+}
+
+/// Execute, in order, the callbacks an operation dispatches to the contract itself, returning their
+/// responses. Messages to other contracts, and replies, are not simulated
+fn execute_dispatched_callbacks(
+    deps: &mut OwnedDeps<MockStorage, MockApi, CustomMockQuerier>,
+    res: &Response,
+) -> Vec<Response> {
+    res.messages
+        .iter()
+        .filter_map(|submsg| match &submsg.msg {
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr,
+                msg,
+                ..
+            }) if contract_addr == MOCK_CONTRACT_ADDR => match from_binary(msg).unwrap() {
+                ExecuteMsg::Callback(callback_msg) => Some(callback_msg),
+                _ => None,
+            },
+            _ => None,
+        })
+        .map(|callback_msg| execute_callback(deps, callback_msg).unwrap())
+        .collect()
+}
+
+/// Release the lock acquired by a top-level operation. Unit tests don't execute the callbacks an
+/// operation dispatches, including the last one releasing the lock, so this must be done before
+/// another top-level operation can be executed
+fn release_lock(deps: &mut OwnedDeps<MockStorage, MockApi, CustomMockQuerier>) {
+    execute_callback(deps, CallbackMsg::ReleaseLock {}).unwrap();
+}
+
+/// Submessages dispatched by a harvest step, which reply even if they fail
+fn replying_always(submsgs: Vec<SubMsg>) -> Vec<SubMsg> {
+    submsgs
+        .into_iter()
+        .map(|mut submsg| {
+            if submsg.reply_on != ReplyOn::Never {
+                submsg.reply_on = ReplyOn::Always;
+            }
+            submsg
+        })
+        .collect()
+}
+
+/// Results of the contract's entry points, whose errors wrap `StdError`s, as well as of functions
+/// that return `StdResult` directly
+trait IntoStdResult<T> {
+    fn into_std_result(self) -> StdResult<T>;
+}
+
+impl<T> IntoStdResult<T> for StdResult<T> {
+    fn into_std_result(self) -> StdResult<T> {
+        self
+    }
+}
+
+impl<T: std::fmt::Debug> IntoStdResult<T> for Result<T, ContractError> {
+    fn into_std_result(self) -> StdResult<T> {
+        self.map_err(|err| match err {
+            ContractError::Std(err) => err,
+            err => panic!("Unexpected error: {:?}", err),
+        })
+    }
+}
+
+/// Assert StdError::GenericErr message with expected_msg, whether or not it is wrapped in a
+/// `ContractError`
+fn assert_generic_error_message<T>(result: impl IntoStdResult<T>, expected_msg: &str) {
+    testing::assert_generic_error_message(result.into_std_result(), expected_msg)
+}
+
+/// Find the value of an attribute with the specified key; panic if not found
+fn attr_value(res: &Response, key: &str) -> String {
+    res.attributes
+        .iter()
+        .find(|attr| attr.key == key)
+        .map(|attr| attr.value.clone())
+        .unwrap_or_else(|| panic!("attribute not found: {}", key))
+}
+
 #[test]
 fn handling_native_deposits() {
     let mut deps = setup_test();
@@ -60,9 +295,12 @@ fn handling_native_deposits() {
         Action::Deposit(Asset::native("uusd", 67890u128).into()),
     ]);
     let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &deposits), msg);
-    assert_eq!(res, Err(StdError::generic_err("sent fund mismatch! expected: native:uusd:67890, received 0")));
+    assert_generic_error_message(
+        res,
+        "insufficient funds for deposit: expected native:uusd:67890, remaining 0",
+    );
 
-    // fund amount mismatch
+    // fund amount mismatch; the undeclared remainder is listed
     let deposits = vec![
         Coin::new(12345, "uluna"), 
         Coin::new(69420, "uusd"), 
@@ -72,7 +310,10 @@ fn handling_native_deposits() {
         Action::Deposit(Asset::native("uusd", 67890u128).into()),
     ]);
     let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &deposits), msg);
-    assert_eq!(res, Err(StdError::generic_err("sent fund mismatch! expected: native:uusd:67890, received 69420")));
+    assert_generic_error_message(
+        res,
+        "extra funds received, not claimed by any deposit action: native:uusd:1530",
+    );
 
     // extra fund
     let deposits = vec![
@@ -85,5 +326,6445 @@ fn handling_native_deposits() {
         Action::Deposit(Asset::native("uusd", 69420u128).into()),
     ]);
     let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &deposits), msg);
-    assert_eq!(res, Err(StdError::generic_err("extra funds received: native:uatom:88888")));
+    assert_generic_error_message(
+        res,
+        "extra funds received, not claimed by any deposit action: native:uatom:88888",
+    );
+
+    // two denoms, each split across two deposit actions. the failed updates above are not reverted
+    // by the mock, so we use another user
+    let deposits = vec![Coin::new(12345, "uluna"), Coin::new(69420, "uusd")];
+    let msg = ExecuteMsg::UpdatePosition(vec![
+        Action::Deposit(Asset::native("uluna", 10000u128).into()),
+        Action::Deposit(Asset::native("uusd", 60000u128).into()),
+        Action::Deposit(Asset::native("uluna", 2345u128).into()),
+        Action::Deposit(Asset::native("uusd", 9420u128).into()),
+    ]);
+    execute(deps.as_mut(), mock_env(), mock_info("bob", &deposits), msg).unwrap();
+    release_lock(&mut deps);
+    let position = POSITION.load(deps.as_ref().storage, &Addr::unchecked("bob")).unwrap();
+    assert_eq!(
+        position.unlocked_assets,
+        AssetList::from(vec![Asset::native("uluna", 12345u128), Asset::native("uusd", 69420u128)])
+    );
+
+    // deposit actions claiming more than was sent of a denom
+    let msg = ExecuteMsg::UpdatePosition(vec![
+        Action::Deposit(Asset::native("uluna", 10000u128).into()),
+        Action::Deposit(Asset::native("uluna", 2346u128).into()),
+    ]);
+    let deposits = vec![Coin::new(12345, "uluna")];
+    let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &deposits), msg);
+    assert_generic_error_message(
+        res,
+        "insufficient funds for deposit: expected native:uluna:2346, remaining 2345",
+    );
+}
+
+#[test]
+fn depositing_multiple_native_denoms() {
+    let mut deps = setup_test();
+
+    // two denoms, each matched by exactly one deposit action
+    let deposits = vec![Coin::new(12345, "uluna"), Coin::new(69420, "uusd")];
+    let msg = ExecuteMsg::UpdatePosition(vec![
+        Action::Deposit(Asset::native("uusd", 69420u128).into()),
+        Action::Deposit(Asset::native("uluna", 12345u128).into()),
+    ]);
+    execute(deps.as_mut(), mock_env(), mock_info("alice", &deposits), msg).unwrap();
+    let position = POSITION.load(deps.as_ref().storage, &Addr::unchecked("alice")).unwrap();
+    assert_eq!(
+        position.unlocked_assets,
+        AssetList::from(vec![Asset::native("uusd", 69420u128), Asset::native("uluna", 12345u128)])
+    );
+    release_lock(&mut deps);
+
+    // the deposit actions leave part of both denoms unclaimed; every leftover is listed
+    let deposits = vec![Coin::new(12345, "uluna"), Coin::new(69420, "uusd")];
+    let msg = ExecuteMsg::UpdatePosition(vec![
+        Action::Deposit(Asset::native("uluna", 12000u128).into()),
+        Action::Deposit(Asset::native("uusd", 69000u128).into()),
+    ]);
+    let res = execute(deps.as_mut(), mock_env(), mock_info("bob", &deposits), msg);
+    assert_generic_error_message(
+        res,
+        "extra funds received, not claimed by any deposit action: native:uluna:345,native:uusd:420",
+    );
+}
+
+#[test]
+fn crediting_net_amount_of_cw20_deposits() {
+    let mut deps = setup_test();
+
+    // the token, e.g. ASTRO, takes a 1% fee on transfer. the contract already holds 1000 of it
+    deps.querier.set_cw20_balance("astro_token", MOCK_CONTRACT_ADDR, 1000);
+
+    // alice deposits the same token twice in one message. the balance before the transfers is
+    // only recorded once
+    let msg = ExecuteMsg::UpdatePosition(vec![
+        Action::Deposit(Asset::cw20(Addr::unchecked("astro_token"), 6000u128).into()),
+        Action::Deposit(Asset::cw20(Addr::unchecked("astro_token"), 4000u128).into()),
+    ]);
+    let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+    let credit_msg = CallbackMsg::CreditDeposits {
+        user_addr: Addr::unchecked("alice"),
+        balances_before: AssetList::from(vec![Asset::cw20(
+            Addr::unchecked("astro_token"),
+            1000u128,
+        )]),
+    };
+    let token = Addr::unchecked("astro_token");
+    assert_eq!(
+        res.messages[0].msg,
+        Asset::cw20(token.clone(), 6000u128).transfer_from_msg("alice", MOCK_CONTRACT_ADDR).unwrap()
+    );
+    assert_eq!(
+        res.messages[1].msg,
+        Asset::cw20(token.clone(), 4000u128).transfer_from_msg("alice", MOCK_CONTRACT_ADDR).unwrap()
+    );
+    assert_eq!(
+        res.messages[2].msg,
+        credit_msg.into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR)).unwrap()
+    );
+
+    // the deposit is not credited until the transfers have been executed
+    let position = POSITION.load(deps.as_ref().storage, &Addr::unchecked("alice")).unwrap();
+    assert_eq!(position.unlocked_assets, AssetList::new());
+
+    // the transfers deliver 99% of the specified amounts, which is what alice should be credited
+    deps.querier.set_cw20_balance("astro_token", MOCK_CONTRACT_ADDR, 10_900);
+    let res = execute_callback(&mut deps, credit_msg).unwrap();
+    assert_eq!(attr_value(&res, "deposit_received"), "cw20:astro_token:9900");
+    let position = POSITION.load(deps.as_ref().storage, &Addr::unchecked("alice")).unwrap();
+    assert_eq!(
+        position.unlocked_assets,
+        AssetList::from(vec![Asset::cw20(token.clone(), 9900u128)])
+    );
+    release_lock(&mut deps);
+
+    // a keeper's deposits are credited to the position being managed, not to the keeper
+    let msg = ExecuteMsg::AuthorizeKeeper {
+        keeper: "keeper".to_string(),
+        actions: vec![ActionKind::Deposit],
+    };
+    execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+    let msg = ExecuteMsg::UpdatePositionFor {
+        user: "alice".to_string(),
+        actions: vec![Action::Deposit(Asset::native("uusd", 100u128).into())],
+    };
+    let info = mock_info("keeper", &[Coin::new(100, "uusd")]);
+    execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+    let position = POSITION.load(deps.as_ref().storage, &Addr::unchecked("alice")).unwrap();
+    assert_eq!(
+        position.unlocked_assets,
+        AssetList::from(vec![Asset::cw20(token, 9900u128), Asset::native("uusd", 100u128)])
+    );
+    let keeper_position = POSITION.may_load(deps.as_ref().storage, &Addr::unchecked("keeper"));
+    assert_eq!(keeper_position, Ok(None));
+}
+
+#[test]
+fn rejecting_deposits_of_unknown_assets() {
+    let mut deps = setup_test();
+
+    // assets other than the primary, secondary and borrowed assets, the liquidity token and ASTRO
+    // can't be deposited
+    let msg = ExecuteMsg::UpdatePosition(vec![
+        Action::Deposit(Asset::native("uluna", 100u128).into()),
+        Action::Deposit(Asset::native("uusd", 100u128).into()),
+        Action::Deposit(Asset::cw20(Addr::unchecked("uluna_uusd_lp_token"), 100u128).into()),
+        Action::Deposit(Asset::cw20(Addr::unchecked("dust_token"), 100u128).into()),
+    ]);
+    let deposits = [Coin::new(100, "uluna"), Coin::new(100, "uusd")];
+    let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &deposits), msg);
+    assert_generic_error_message(
+        res,
+        "cannot deposit cw20:dust_token: not an asset of this strategy",
+    );
+
+    let msg = ExecuteMsg::UpdatePosition(vec![Action::Deposit(
+        Asset::native("uatom", 100u128).into(),
+    )]);
+    let res = execute(deps.as_mut(), mock_env(), mock_info("bob", &[Coin::new(100, "uatom")]), msg);
+    assert_generic_error_message(res, "cannot deposit native:uatom: not an asset of this strategy");
+
+    // a position that already holds the maximum number of asset types, e.g. tokens deposited
+    // before the restriction, can't be credited with another type
+    let dust = (1..=4)
+        .map(|i| Asset::cw20(Addr::unchecked(format!("dust_token_{}", i)), 1u128))
+        .chain([Asset::native("uusd", 1u128)])
+        .collect::<Vec<_>>();
+    POSITION
+        .save(
+            deps.as_mut().storage,
+            &Addr::unchecked("charlie"),
+            &Position {
+                unlocked_assets: AssetList::from(dust),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    let msg = ExecuteMsg::UpdatePosition(vec![Action::Deposit(
+        Asset::native("uluna", 100u128).into(),
+    )]);
+    let info = mock_info("charlie", &[Coin::new(100, "uluna")]);
+    let res = execute(deps.as_mut(), mock_env(), info, msg);
+    assert_generic_error_message(res, "too many unlocked asset types: 6, max 5");
+}
+
+#[test]
+fn emitting_total_bonded_after() {
+    let mut deps = setup_test();
+
+    // 100 liquidity tokens are already bonded; alice has 69 unlocked liquidity tokens
+    deps.querier.set_bonded_amount("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 100);
+    STATE
+        .save(
+            deps.as_mut().storage,
+            &State {
+                total_bond_units: Uint128::new(100_000_000),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    POSITION
+        .save(
+            deps.as_mut().storage,
+            &Addr::unchecked("alice"),
+            &Position {
+                unlocked_assets: AssetList::from(vec![Asset::cw20(
+                    Addr::unchecked("uluna_uusd_lp_token"),
+                    69u128,
+                )]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::Bond {
+            user_addr: Some(Addr::unchecked("alice")),
+        },
+    )
+    .unwrap();
+    assert_eq!(attr_value(&res, "shares_bonded"), "69");
+    assert_eq!(attr_value(&res, "total_bonded_after"), "169");
+
+    // once the bond message is executed, a total of 169 liquidity tokens are bonded
+    deps.querier.set_bonded_amount("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 169);
+
+    // alice unbonds 23 liquidity tokens worth of bond units
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::Unbond {
+            user_addr: Addr::unchecked("alice"),
+            bond_units_to_reduce: Uint128::new(23_000_000),
+        },
+    )
+    .unwrap();
+    assert_eq!(attr_value(&res, "shares_unbonded"), "23");
+    assert_eq!(attr_value(&res, "total_bonded_after"), "146");
+}
+
+#[test]
+fn seeding_units_for_a_large_first_bond() {
+    let mut deps = setup_test();
+    let alice = Addr::unchecked("alice");
+    let bond = |deps: &mut OwnedDeps<_, _, _>, shares: u128| {
+        let position = Position {
+            unlocked_assets: AssetList::from(vec![Asset::cw20(
+                Addr::unchecked("uluna_uusd_lp_token"),
+                shares,
+            )]),
+            ..Default::default()
+        };
+        POSITION.save(deps.as_mut().storage, &Addr::unchecked("alice"), &position).unwrap();
+        execute_callback(
+            deps,
+            CallbackMsg::Bond {
+                user_addr: Some(Addr::unchecked("alice")),
+            },
+        )
+    };
+
+    // a first bond of 10^33 liquidity tokens, e.g. of 18-decimal tokens, would mint 10^39 units
+    // at 1,000,000 units per share, which overflows. instead, one unit is minted per share
+    let shares = 10u128.pow(33);
+    bond(&mut deps, shares).unwrap();
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state.total_bond_units, Uint128::new(shares));
+    let position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    assert_eq!(position.bond_units, state.total_bond_units);
+
+    // later bonds are pro-rated from the seeded units as usual
+    deps.querier.set_bonded_amount("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, shares);
+    bond(&mut deps, shares / 2).unwrap();
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state.total_bond_units, Uint128::new(shares + shares / 2));
+
+    // the multiplier is scaled down by powers of ten only as far as needed; small first bonds and
+    // borrows get the configured number of units
+    let max_seed_units = Uint128::new(1 << 96);
+    let units = seed_units(Uint128::new(10u128.pow(24)), Uint128::new(1_000_000)).unwrap();
+    assert_eq!(units, Uint128::new(10u128.pow(28)));
+    let units = seed_units(Uint128::new(10u128.pow(25)), Uint128::new(1_000_000)).unwrap();
+    assert_eq!(units, Uint128::new(10u128.pow(28)));
+    assert!(units <= max_seed_units);
+    let units = seed_units(Uint128::new(69), Uint128::new(1_000_000)).unwrap();
+    assert_eq!(units, Uint128::new(69_000_000));
+    let units = seed_units(Uint128::new(u128::MAX), Uint128::new(1_000_000)).unwrap();
+    assert_eq!(units, Uint128::new(u128::MAX));
+}
+
+#[test]
+fn emitting_position_event_on_repay() {
+    let mut deps = setup_test();
+    setup_leveraged_position(&mut deps, 1_000_000);
+    let alice = Addr::unchecked("alice");
+    let fund_alice = |deps: &mut OwnedDeps<_, _, _>| {
+        let mut position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+        position.unlocked_assets = AssetList::from(vec![Asset::native("uusd", 400_000u128)]);
+        POSITION.save(deps.as_mut().storage, &alice, &position).unwrap();
+    };
+    let repay = |deps: &mut OwnedDeps<_, _, _>| {
+        let msg = ExecuteMsg::UpdatePosition(vec![Action::Repay {
+            amount: Uint128::new(200_000),
+            user: None,
+        }]);
+        let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        assert!(res.events.is_empty());
+
+        // a pure repay doesn't assert health. of the callbacks it dispatches, including the refund
+        // of the remaining assets, only the repay callback emits the event
+        let assert_health = CallbackMsg::AssertHealth {
+            user_addr: Addr::unchecked("alice"),
+        }
+        .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+        .unwrap();
+        assert!(res.messages.iter().all(|submsg| submsg.msg != assert_health));
+        let events: Vec<Event> = execute_dispatched_callbacks(deps, &res)
+            .into_iter()
+            .flat_map(|res| res.events)
+            .filter(|event| event.ty == "position_changed")
+            .collect();
+        assert_eq!(events.len(), 1);
+        events[0].clone()
+    };
+
+    // the event reflects the debt after repaying, although the repay message is yet to be executed
+    fund_alice(&mut deps);
+    let event = repay(&mut deps);
+    let env = mock_env();
+    let expected = Event::new("position_changed")
+        .add_attribute("timestamp", env.block.time.seconds().to_string())
+        .add_attribute("height", env.block.height.to_string())
+        .add_attribute("user", "alice")
+        .add_attribute("bond_units", "100000000")
+        .add_attribute("bond_amount", "100000")
+        .add_attribute("bond_value", "2000000")
+        .add_attribute("debt_units", "800000000")
+        .add_attribute("debt_amount", "800000")
+        .add_attribute("debt_value", "800000")
+        .add_attribute("ltv", "0.4");
+    assert_eq!(event, expected);
+
+    // repaying must not fail on stale prices. the event then leaves out the values, which would
+    // have to be computed from prices
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.max_price_age = 300;
+            Ok(config)
+        })
+        .unwrap();
+    let now = env.block.time.seconds();
+    deps.querier.set_oracle_price_updated_at(&AssetInfo::native("uluna"), now - 301);
+    deps.querier.set_user_debt(MOCK_CONTRACT_ADDR, &AssetInfo::native("uusd"), 800_000);
+    fund_alice(&mut deps);
+    let event = repay(&mut deps);
+    let expected = Event::new("position_changed")
+        .add_attribute("timestamp", env.block.time.seconds().to_string())
+        .add_attribute("height", env.block.height.to_string())
+        .add_attribute("user", "alice")
+        .add_attribute("bond_units", "100000000")
+        .add_attribute("bond_amount", "100000")
+        .add_attribute("debt_units", "600000000")
+        .add_attribute("debt_amount", "600000");
+    assert_eq!(event, expected);
+}
+
+#[test]
+fn repaying_debt_of_another_user() {
+    let mut deps = setup_test();
+    setup_leveraged_position(&mut deps, 1_000_000);
+    let alice = Addr::unchecked("alice");
+    let bob = Addr::unchecked("bob");
+
+    // bob deposits 300,000 uusd, and uses them to repay alice's debt
+    let msg = ExecuteMsg::UpdatePosition(vec![
+        Action::Deposit(Asset::native("uusd", 300_000u128).into()),
+        Action::Repay {
+            amount: Uint128::new(300_000),
+            user: Some("alice".to_string()),
+        },
+    ]);
+    let funds = [Coin::new(300_000, "uusd")];
+    let res = execute(deps.as_mut(), mock_env(), mock_info("bob", &funds), msg).unwrap();
+    let repay_for = CallbackMsg::RepayFor {
+        user_addr: bob.clone(),
+        debtor_addr: alice.clone(),
+        repay_amount: Uint128::new(300_000),
+    };
+    assert_eq!(
+        res.messages[0].msg,
+        repay_for.clone().into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR)).unwrap()
+    );
+    release_lock(&mut deps);
+
+    // the repayment is taken from bob's unlocked assets, and deducted from alice's debt units
+    let res = execute_callback(&mut deps, repay_for).unwrap();
+    let config = CONFIG.load(deps.as_ref().storage).unwrap();
+    assert_eq!(
+        res.messages[0].msg,
+        config.red_bank.repay_msg(&Asset::native("uusd", 300_000u128)).unwrap()
+    );
+    assert_eq!(attr_value(&res, "payer"), "bob");
+    assert_eq!(attr_value(&res, "debtor"), "alice");
+    assert_eq!(attr_value(&res, "debt_units_deducted"), "300000000");
+
+    let position = POSITION.load(deps.as_ref().storage, &bob).unwrap();
+    assert_eq!(position.unlocked_assets, AssetList::default());
+    assert_eq!(position.debt_units, Uint128::zero());
+    let position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    assert_eq!(position.debt_units, Uint128::new(700_000_000));
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state.total_debt_units, Uint128::new(700_000_000));
+
+    // the position event is attributed to alice, whose debt is repaid
+    let event = res.events.iter().find(|event| event.ty == "position_changed").unwrap();
+    assert!(event.attributes.contains(&attr("user", "alice")));
+    assert!(event.attributes.contains(&attr("debt_amount", "700000")));
+
+    // the debtor must have debt to repay
+    let mut position = POSITION.load(deps.as_ref().storage, &bob).unwrap();
+    position.unlocked_assets = AssetList::from(vec![Asset::native("uusd", 300_000u128)]);
+    POSITION.save(deps.as_mut().storage, &bob, &position).unwrap();
+    let repay_for = |debtor: &str| CallbackMsg::RepayFor {
+        user_addr: bob.clone(),
+        debtor_addr: Addr::unchecked(debtor),
+        repay_amount: Uint128::new(300_000),
+    };
+    let res = execute_callback(&mut deps, repay_for("charlie"));
+    assert_generic_error_message(res, "charlie has no position");
+    let res = execute_callback(&mut deps, repay_for("bob"));
+    assert_generic_error_message(res, "bob has no debt to repay");
+
+    // repaying another user's debt can't be simulated
+    let msg = QueryMsg::SimulateUpdatePosition {
+        user: Some("bob".to_string()),
+        actions: vec![Action::Repay {
+            amount: Uint128::new(300_000),
+            user: Some("alice".to_string()),
+        }],
+    };
+    assert_generic_error_message(
+        query(deps.as_ref(), mock_env(), msg),
+        "simulation not supported when repaying the debt of another user",
+    );
+}
+
+#[test]
+fn rejecting_stale_cached_user_addr() {
+    let mut deps = setup_test();
+
+    let reply_msg = Reply {
+        id: encode_reply_id(1, 0),
+        result: ContractResult::Ok(SubMsgExecutionResponse {
+            events: vec![Event::new("from_contract")
+                .add_attribute("action", "withdraw_liquidity")
+                .add_attribute("refund_assets", "100uluna, 420uusd")],
+            data: None,
+        }),
+    };
+
+    // user address was cached in a previous block; the reply should be rejected
+    let mut env = mock_env();
+    PENDING_OPS
+        .save(
+            deps.as_mut().storage,
+            U64Key::new(0),
+            &CachedUserAddr {
+                user_addr: Addr::unchecked("alice"),
+                height: env.block.height - 1,
+            },
+        )
+        .unwrap();
+    CACHED_BALANCES.save(deps.as_mut().storage, &AssetList::default()).unwrap();
+    deps.querier.set_base_balances(
+        MOCK_CONTRACT_ADDR,
+        &[Coin::new(100, "uluna"), Coin::new(420, "uusd")],
+    );
+
+    let res = reply(deps.as_mut(), env.clone(), reply_msg.clone());
+    assert_eq!(
+        res,
+        Err(StdError::generic_err(format!(
+            "stale cached user address: cached at height {}, current height {}",
+            env.block.height - 1,
+            env.block.height
+        ))
+        .into())
+    );
+
+    // user address was cached in the current block; the reply should be processed
+    env.block.height -= 1;
+    reply(deps.as_mut(), env, reply_msg).unwrap();
+
+    let position = POSITION.load(deps.as_ref().storage, &Addr::unchecked("alice")).unwrap();
+    assert_eq!(
+        position.unlocked_assets,
+        AssetList::from(vec![Asset::native("uluna", 100u128), Asset::native("uusd", 420u128)])
+    );
+    assert_eq!(PENDING_OPS.may_load(deps.as_ref().storage, U64Key::new(0)).unwrap(), None);
+}
+
+#[test]
+fn attributing_replies_by_op_nonce() {
+    let mut deps = setup_test();
+    set_pool_and_prices(&mut deps);
+    let alice = Addr::unchecked("alice");
+    let bob = Addr::unchecked("bob");
+
+    deps.querier.set_bonded_amount("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 100);
+    let mut state = STATE.load(deps.as_ref().storage).unwrap();
+    state.total_bond_units = Uint128::new(100_000_000);
+    STATE.save(deps.as_mut().storage, &state).unwrap();
+    for (user, amount) in [(&alice, 1000u128), (&bob, 2000u128)] {
+        let position = Position {
+            unlocked_assets: AssetList::from(vec![Asset::native("uusd", amount)]),
+            ..Default::default()
+        };
+        POSITION.save(deps.as_mut().storage, user, &position).unwrap();
+    }
+
+    let swap = |user: &Addr, offer_amount: u128| CallbackMsg::Swap {
+        user_addr: Some(user.clone()),
+        offer_asset_info: AssetInfo::native("uusd"),
+        offer_amount: Some(Uint128::new(offer_amount)),
+        max_spread: None,
+        min_return: None,
+    };
+    let swap_reply = |id: u64, return_amount: &str| Reply {
+        id,
+        result: ContractResult::Ok(SubMsgExecutionResponse {
+            events: vec![Event::new("from_contract")
+                .add_attribute("action", "swap")
+                .add_attribute("ask_asset", "uluna")
+                .add_attribute("return_amount", return_amount)
+                .add_attribute("tax_amount", "0")],
+            data: None,
+        }),
+    };
+
+    // alice swaps half of her uusd; before the reply is handled, bob starts a swap of his own
+    let res = execute_callback(&mut deps, swap(&alice, 500)).unwrap();
+    let alice_swap_id = res.messages[0].id;
+    assert_eq!(alice_swap_id, encode_reply_id(2, 0));
+    let res = execute_callback(&mut deps, swap(&bob, 2000)).unwrap();
+    let bob_swap_id = res.messages[0].id;
+    assert_eq!(bob_swap_id, encode_reply_id(2, 1));
+
+    // each reply is credited to the user of its own operation, regardless of the order
+    reply(deps.as_mut(), mock_env(), swap_reply(bob_swap_id, "200")).unwrap();
+    reply(deps.as_mut(), mock_env(), swap_reply(alice_swap_id, "50")).unwrap();
+    let position = POSITION.load(deps.as_ref().storage, &bob).unwrap();
+    assert_eq!(position.unlocked_assets, AssetList::from(vec![Asset::native("uluna", 200u128)]));
+    let position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    assert_eq!(
+        position.unlocked_assets,
+        AssetList::from(vec![Asset::native("uusd", 500u128), Asset::native("uluna", 50u128)])
+    );
+
+    // alice then provides the assets and bonds the liquidity tokens in the same transaction
+    let callback = CallbackMsg::ProvideLiquidity {
+        user_addr: Some(alice.clone()),
+        slippage_tolerance: None,
+        min_lp_out: None,
+    };
+    let res = execute_callback(&mut deps, callback).unwrap();
+    let provide_id = res.messages.last().unwrap().id;
+    assert_eq!(provide_id, encode_reply_id(0, 2));
+    let reply_msg = Reply {
+        id: provide_id,
+        result: ContractResult::Ok(SubMsgExecutionResponse {
+            events: vec![Event::new("from_contract")
+                .add_attribute("action", "provide_liquidity")
+                .add_attribute("share", "69")],
+            data: None,
+        }),
+    };
+    reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+
+    let callback = CallbackMsg::Bond {
+        user_addr: Some(alice.clone()),
+    };
+    let res = execute_callback(&mut deps, callback).unwrap();
+    assert_eq!(res.messages[0].id, encode_reply_id(3, 3));
+    assert_eq!(attr_value(&res, "shares_bonded"), "69");
+
+    let position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    assert_eq!(position.bond_units, Uint128::new(69_000_000));
+    assert_eq!(position.unlocked_assets, AssetList::default());
+    assert_eq!(NEXT_OP_NONCE.load(deps.as_ref().storage).unwrap(), 4);
+    for nonce in 0..4 {
+        assert_eq!(PENDING_OPS.may_load(deps.as_ref().storage, U64Key::new(nonce)).unwrap(), None);
+    }
+}
+
+#[test]
+fn reconciling_withdrawn_amounts_with_balances() {
+    let mut deps = setup_test();
+
+    // the contract holds some assets belonging to other users; alice has 50 unlocked liquidity
+    // tokens to be burned
+    deps.querier.set_base_balances(
+        MOCK_CONTRACT_ADDR,
+        &[Coin::new(1000, "uluna"), Coin::new(2000, "uusd")],
+    );
+    POSITION
+        .save(
+            deps.as_mut().storage,
+            &Addr::unchecked("alice"),
+            &Position {
+                unlocked_assets: AssetList::from(vec![Asset::cw20(
+                    Addr::unchecked("uluna_uusd_lp_token"),
+                    50u128,
+                )]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::WithdrawLiquidity {
+            user_addr: Addr::unchecked("alice"),
+        },
+    )
+    .unwrap();
+
+    // the pool transfers 100 uluna and 421 uusd, but its event log reports 420 uusd
+    deps.querier.set_base_balances(
+        MOCK_CONTRACT_ADDR,
+        &[Coin::new(1100, "uluna"), Coin::new(2421, "uusd")],
+    );
+    let reply_msg = Reply {
+        id: res.messages[0].id,
+        result: ContractResult::Ok(SubMsgExecutionResponse {
+            events: vec![Event::new("from_contract")
+                .add_attribute("action", "withdraw_liquidity")
+                .add_attribute("refund_assets", "100uluna, 420uusd")],
+            data: None,
+        }),
+    };
+    let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+
+    // the position should be credited the amounts actually received
+    let position = POSITION.load(deps.as_ref().storage, &Addr::unchecked("alice")).unwrap();
+    assert_eq!(
+        position.unlocked_assets,
+        AssetList::from(vec![Asset::native("uluna", 100u128), Asset::native("uusd", 421u128)])
+    );
+    assert_eq!(CACHED_BALANCES.may_load(deps.as_ref().storage).unwrap(), None);
+
+    // the discrepancy should be reported
+    assert_eq!(
+        res.events,
+        vec![Event::new("withdraw_parse_mismatch")
+            .add_attribute("user", "alice")
+            .add_attribute("primary_received", "100")
+            .add_attribute("secondary_received", "421")
+            .add_attribute("primary_parsed", "100")
+            .add_attribute("secondary_parsed", "420")]
+    );
+}
+
+#[test]
+fn charging_fee_above_high_water_mark() {
+    let setup_with_high_water_mark = |high_water_mark: Decimal| {
+        let mut deps = setup_test();
+
+        CONFIG
+            .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+                config.fee_mode = FeeMode::HighWaterMark {
+                    benchmark: FeeBenchmark::Secondary,
+                };
+                Ok(config)
+            })
+            .unwrap();
+
+        // 10% of the pool's liquidity tokens are bonded, worth 2,000,000 uusd; 10,000 uASTRO worth
+        // 10,000 uusd are to be claimed. value per bond unit is therefore 0.0201 uusd
+        deps.querier.set_pool(
+            "uluna_uusd_pair",
+            &[Asset::native("uluna", 1_000_000u128), Asset::native("uusd", 10_000_000u128)],
+            1_000_000,
+        );
+        deps.querier.set_bonded_amount("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 100_000);
+        deps.querier.set_pending_tokens("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 10_000, None);
+        let astro_token_info = AssetInfo::cw20(Addr::unchecked("astro_token"));
+        deps.querier.set_oracle_price(&AssetInfo::native("uluna"), Decimal::percent(1000));
+        deps.querier.set_oracle_price(&AssetInfo::native("uusd"), Decimal::one());
+        deps.querier.set_oracle_price(&astro_token_info, Decimal::one());
+        STATE
+            .save(
+                deps.as_mut().storage,
+                &State {
+                    total_bond_units: Uint128::new(100_000_000),
+                    high_water_mark,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        deps
+    };
+    let harvest = |deps: &mut OwnedDeps<_, _, _>| {
+        let msg = ExecuteMsg::Harvest {
+            max_spread: None,
+            slippage_tolerance: None,
+            min_lp_out: None,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("operator", &[]), msg)
+    };
+    let harvest_with_high_water_mark = |high_water_mark: Decimal| {
+        let mut deps = setup_with_high_water_mark(high_water_mark);
+        let res = harvest(&mut deps).unwrap();
+        let fees = res.events[0].attributes.iter().find(|attr| attr.key == "fees").unwrap();
+        let state = STATE.load(deps.as_ref().storage).unwrap();
+        (fees.value.clone(), state.high_water_mark)
+    };
+
+    // value is below the high-water mark; no fee should be charged
+    let (fees, high_water_mark) = harvest_with_high_water_mark(Decimal::from_str("0.03").unwrap());
+    assert_eq!(fees, "");
+    assert_eq!(high_water_mark, Decimal::from_str("0.03").unwrap());
+
+    // appreciation above the high-water mark is worth half of the rewards; half of the flat fee
+    // should be charged
+    let (fees, high_water_mark) =
+        harvest_with_high_water_mark(Decimal::from_str("0.02005").unwrap());
+    assert_eq!(fees, "cw20:astro_token:250");
+    assert_eq!(high_water_mark, Decimal::from_str("0.0200975").unwrap());
+
+    // appreciation above the high-water mark exceeds the rewards; the full flat fee should be
+    // charged
+    let (fees, high_water_mark) = harvest_with_high_water_mark(Decimal::zero());
+    assert_eq!(fees, "cw20:astro_token:500");
+    assert_eq!(high_water_mark, Decimal::from_str("0.020095").unwrap());
+
+    // rewards are valued at fresh prices, same as the bond, so a stale reward price fails the
+    // harvest rather than mis-charging the fee
+    let mut deps = setup_with_high_water_mark(Decimal::zero());
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.max_price_age = 300;
+            Ok(config)
+        })
+        .unwrap();
+    let now = mock_env().block.time.seconds();
+    let astro_token_info = AssetInfo::cw20(Addr::unchecked("astro_token"));
+    deps.querier.set_oracle_price_updated_at(&AssetInfo::native("uluna"), now - 10);
+    deps.querier.set_oracle_price_updated_at(&AssetInfo::native("uusd"), now - 10);
+    deps.querier.set_oracle_price_updated_at(&astro_token_info, now - 301);
+    assert_generic_error_message(
+        harvest(&mut deps),
+        "stale price for cw20:astro_token: last updated 301 seconds ago; max age is 300 seconds",
+    );
+
+    // if a secondary oracle is configured, rewards are valued at the lower of the two prices. at
+    // half the price, the rewards are worth 5,000 uusd and the value per bond unit is 0.02005,
+    // so appreciation is worth half the rewards, and half the flat fee is charged
+    let mut deps = setup_with_high_water_mark(Decimal::from_str("0.020025").unwrap());
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.secondary_oracle = Some(Oracle {
+                contract_addr: Addr::unchecked("secondary_oracle"),
+            });
+            Ok(config)
+        })
+        .unwrap();
+    for (info, price) in [
+        (AssetInfo::native("uluna"), Decimal::percent(1000)),
+        (AssetInfo::native("uusd"), Decimal::one()),
+        (astro_token_info, Decimal::percent(50)),
+    ] {
+        deps.querier.set_oracle_price_at("secondary_oracle", &info, price);
+    }
+    let res = harvest(&mut deps).unwrap();
+    let fees = res.events[0].attributes.iter().find(|attr| attr.key == "fees").unwrap();
+    assert_eq!(fees.value, "cw20:astro_token:250");
+}
+
+#[test]
+fn splitting_fees_among_recipients() {
+    let mut deps = setup_test();
+
+    // weights must sum to one
+    let mut config = mock_config();
+    config.fee_recipients = vec![
+        (Addr::unchecked("treasury"), Decimal::percent(50)),
+        (Addr::unchecked("buyback"), Decimal::percent(30)),
+    ];
+    assert_generic_error_message(
+        config.validate(&Limits::default()),
+        "invalid fee recipients: weights sum to 0.8; must be 1",
+    );
+    config.fee_recipients = vec![];
+    assert_generic_error_message(
+        config.validate(&Limits::default()),
+        "invalid fee recipients: weights sum to 0; must be 1",
+    );
+
+    // a third each; weights can't be represented exactly, so the last one is rounded up
+    let third = Decimal::from_str("0.333333333333333333").unwrap();
+    let fee_recipients = vec![
+        (Addr::unchecked("treasury"), third),
+        (Addr::unchecked("buyback"), third),
+        (Addr::unchecked("safety_module"), Decimal::from_str("0.333333333333333334").unwrap()),
+    ];
+    config.fee_recipients = fee_recipients.clone();
+    config.validate(&Limits::default()).unwrap();
+    CONFIG.save(deps.as_mut().storage, &config).unwrap();
+
+    // 10,000 uASTRO are claimed, of which 500 are charged as fees
+    deps.querier.set_pending_tokens("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 10_000, None);
+    let msg = ExecuteMsg::Harvest {
+        max_spread: None,
+        slippage_tolerance: None,
+        min_lp_out: None,
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("operator", &[]), msg).unwrap();
+    let fees = res.events[0].attributes.iter().find(|attr| attr.key == "fees").unwrap();
+    assert_eq!(fees.value, "cw20:astro_token:500");
+
+    // each recipient gets its share rounded down, except for the last one, which gets what remains
+    // so that all 500 uASTRO are transferred
+    let astro_token = Addr::unchecked("astro_token");
+    let expected_msgs: Vec<CosmosMsg> =
+        [("treasury", 166u128), ("buyback", 166), ("safety_module", 168)]
+            .iter()
+            .map(|(recipient, amount)| {
+                Asset::cw20(astro_token.clone(), *amount).transfer_msg(*recipient).unwrap()
+            })
+            .collect();
+    let msgs: Vec<CosmosMsg> = res.messages.iter().map(|submsg| submsg.msg.clone()).collect();
+    assert_eq!(msgs[1..4], expected_msgs[..]);
+
+    // a fee too small to be split goes to the last recipient entirely, instead of being left in the
+    // contract as dust
+    let fees = AssetList::from(vec![Asset::cw20(astro_token.clone(), 2u128)]);
+    let msgs = fee_transfer_msgs(&fee_recipients, &fees).unwrap();
+    assert_eq!(msgs, vec![Asset::cw20(astro_token, 2u128).transfer_msg("safety_module").unwrap()]);
+}
+
+#[test]
+fn splitting_fees_conserves_amounts() {
+    // a linear congruential generator, so that the cases are arbitrary but reproducible
+    let mut seed = 0x2545_f491_4f6c_dd1du64;
+    let mut next = move || {
+        seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+        seed >> 16
+    };
+
+    let denoms = ["uluna", "uusd", "umars", "uatom"];
+    for _ in 0..500 {
+        let len = (next() % 5) as usize;
+        let assets = denoms[..len]
+            .iter()
+            .map(|denom| {
+                // amounts range from zero to well beyond 64 bits
+                let amount = match next() % 3 {
+                    0 => 0,
+                    1 => (next() % 1_000) as u128,
+                    _ => next() as u128 * next() as u128,
+                };
+                Asset::native(*denom, amount)
+            })
+            .collect::<Vec<_>>();
+        let list = AssetList::from(assets);
+
+        // rates range from zero to above 100%
+        let rate = Decimal::from_ratio(next() % 1_200_001, 1_000_000u128);
+        let (fees, remainder) = list.split_fee(rate);
+
+        let mut total = fees.clone();
+        total.add_many(&remainder).unwrap();
+        let mut expected = list.clone();
+        expected.purge();
+        assert_eq!(total.len(), expected.len());
+        for asset in expected.to_vec() {
+            assert_eq!(total.find(&asset.info), Some(&asset));
+        }
+
+        if rate <= Decimal::one() {
+            assert_eq!(fees, list.scaled(rate));
+        } else {
+            assert_eq!(fees, expected);
+            assert_eq!(remainder.len(), 0);
+        }
+    }
+}
+
+#[test]
+fn mutating_asset_lists_in_place() {
+    let list = AssetList::from(vec![
+        Asset::native("uluna", 12345u128),
+        Asset::native("uusd", 67890u128),
+        Asset::cw20(Addr::unchecked("astro_token"), 1u128),
+    ]);
+    let infos = [
+        AssetInfo::native("uluna"),
+        AssetInfo::native("uusd"),
+        AssetInfo::cw20(Addr::unchecked("astro_token")),
+    ];
+
+    // mutating in place gives exactly the same list as cloning the asset, deducting it, and adding
+    // the mutated one back, including the removal of assets left with zero amount
+    for info in &infos {
+        for amount in [0u128, 1, 12345, 67890] {
+            let mut expected = list.clone();
+            let asset = expected.find(info).cloned().unwrap();
+            let mut mutated = asset.clone();
+            mutated.amount = cmp::min(mutated.amount, Uint128::new(amount));
+            expected.deduct(&asset).unwrap();
+            if !mutated.amount.is_zero() {
+                expected.add(&mutated).unwrap();
+            }
+
+            let mut actual = list.clone();
+            let returned = actual.find_mut(info, |asset| {
+                asset.amount = cmp::min(asset.amount, Uint128::new(amount));
+                asset.amount
+            });
+            assert_eq!(returned, Some(mutated.amount));
+            assert_eq!(actual.len(), expected.len());
+            for asset in expected.to_vec() {
+                assert_eq!(actual.find(&asset.info), Some(&asset));
+            }
+        }
+    }
+
+    // taking an asset is the same as finding it, then deducting all of it
+    for info in &infos {
+        let mut expected = list.clone();
+        let asset = expected.find(info).cloned().unwrap();
+        expected.deduct(&asset).unwrap();
+
+        let mut actual = list.clone();
+        assert_eq!(actual.take(info), Some(asset.clone()));
+        assert_eq!(actual, expected);
+        assert_eq!(list.amount_of(info), asset.amount);
+        assert_eq!(actual.amount_of(info), Uint128::zero());
+    }
+
+    // an asset not held is neither mutated nor taken, and the list is left as it is
+    let mut actual = list.clone();
+    let ukrw = AssetInfo::native("ukrw");
+    assert_eq!(actual.find_mut(&ukrw, |asset| asset.amount = Uint128::zero()), None);
+    assert_eq!(actual.take(&ukrw), None);
+    assert_eq!(actual, list);
+}
+
+#[test]
+fn valuing_asset_lists() {
+    let mut deps = setup_test();
+    set_pool_and_prices(&mut deps);
+    let config = CONFIG.load(deps.as_ref().storage).unwrap();
+    let querier = QuerierWrapper::new(&deps.querier);
+
+    // 1,000 uluna at 10 uusd each plus 500 uusd
+    let assets = AssetList::from(vec![
+        Asset::native("uluna", 1_000u128),
+        Asset::native("uusd", 500u128),
+    ]);
+    let value = value_of(&querier, &mock_env(), &config, &assets).unwrap();
+    assert_eq!(value, Uint128::new(10_500));
+
+    // an asset the oracle has no price for can't be valued, even if its amount is zero
+    let assets = AssetList::from(vec![
+        Asset::native("uluna", 1_000u128),
+        Asset::cw20(Addr::unchecked("astro_token"), 0u128),
+    ]);
+    let err = value_of(&querier, &mock_env(), &config, &assets).unwrap_err();
+    assert!(err.to_string().contains("price not set"));
+}
+
+#[test]
+fn normalizing_values_across_decimals() {
+    let mut deps = setup_test();
+
+    // the primary asset is a CW20 token with 8 decimals. the oracle prices each of its smallest
+    // units normalized to 6 decimals, i.e. 1 XYZ = 100,000,000 units is worth 10 UST
+    deps.querier.set_cw20_decimals("xyz_token", 8);
+    let xyz_info = AssetInfo::cw20(Addr::unchecked("xyz_token"));
+    deps.querier.set_oracle_price(&xyz_info, Decimal::percent(1000));
+    let mut config = CONFIG.load(deps.as_ref().storage).unwrap();
+    config.primary_asset_info = xyz_info;
+    config.cache_decimals(&QuerierWrapper::new(&deps.querier)).unwrap();
+    assert_eq!(config.primary_asset_decimals, 8);
+    assert_eq!(config.secondary_asset_decimals, 6);
+    CONFIG.save(deps.as_mut().storage, &config).unwrap();
+
+    // 1 XYZ and 10 UST are worth the same
+    let querier = QuerierWrapper::new(&deps.querier);
+    let primary = AssetList::from(vec![Asset::cw20(Addr::unchecked("xyz_token"), 100_000_000u128)]);
+    let secondary = AssetList::from(vec![Asset::native("uusd", 10_000_000u128)]);
+    let primary_value = value_of(&querier, &mock_env(), &config, &primary).unwrap();
+    let secondary_value = value_of(&querier, &mock_env(), &config, &secondary).unwrap();
+    assert_eq!(primary_value, Uint128::new(10_000_000));
+    assert_eq!(primary_value, secondary_value);
+
+    // a pool of 1,000 XYZ and 10,000 UST is worth 20,000 UST, all of which is bonded by alice
+    deps.querier.set_pool(
+        "uluna_uusd_pair",
+        &[
+            Asset::cw20(Addr::unchecked("xyz_token"), 100_000_000_000u128),
+            Asset::native("uusd", 10_000_000_000u128),
+        ],
+        1_000_000,
+    );
+    deps.querier.set_bonded_amount("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 1_000_000);
+    let state = State {
+        total_bond_units: Uint128::new(100),
+        ..Default::default()
+    };
+    let position = Position {
+        bond_units: Uint128::new(100),
+        ..Default::default()
+    };
+    let querier = QuerierWrapper::new(&deps.querier);
+    let health = compute_health(&querier, &mock_env(), &config, &state, &position).unwrap();
+    assert_eq!(health.bond_value, Uint128::new(20_000_000_000));
+}
+
+#[test]
+fn accumulating_fee_stats() {
+    let mut deps = setup_test();
+    let harvest = |deps: &mut OwnedDeps<MockStorage, MockApi, CustomMockQuerier>| {
+        let msg = ExecuteMsg::Harvest {
+            max_spread: None,
+            slippage_tolerance: None,
+            min_lp_out: None,
+        };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("operator", &[]), msg).unwrap();
+        release_lock(deps);
+        let fees = res.events[0].attributes.iter().find(|attr| attr.key == "fees").unwrap();
+        fees.value.clone()
+    };
+    let query_fee_stats = |deps: &OwnedDeps<MockStorage, MockApi, CustomMockQuerier>| {
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::FeeStats {}).unwrap();
+        from_binary::<FeeStatsResponse>(&res).unwrap()
+    };
+
+    let stats = query_fee_stats(&deps);
+    assert_eq!(stats.total_fees_collected, AssetList::default().into());
+    assert_eq!(stats.last_harvest_time, 0);
+
+    deps.querier.set_pending_tokens("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 10_000, None);
+    assert_eq!(harvest(&mut deps), "cw20:astro_token:500");
+    deps.querier.set_pending_tokens("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 4_000, None);
+    assert_eq!(harvest(&mut deps), "cw20:astro_token:200");
+
+    let stats = query_fee_stats(&deps);
+    let expected = AssetList::from(vec![Asset::cw20(Addr::unchecked("astro_token"), 700u128)]);
+    assert_eq!(stats.total_fees_collected, expected.into());
+    assert_eq!(stats.last_harvest_time, mock_env().block.time.seconds());
+}
+
+#[test]
+fn charging_withdrawal_fee_above_entry_value() {
+    let mut deps = setup_test();
+    let alice = Addr::unchecked("alice");
+    let lp_token = Addr::unchecked("uluna_uusd_lp_token");
+
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.fee_mode = FeeMode::Withdrawal;
+            Ok(config)
+        })
+        .unwrap();
+
+    let set_uluna_price = |deps: &mut OwnedDeps<MockStorage, MockApi, CustomMockQuerier>,
+                           price: &str| {
+        let price = Decimal::from_str(price).unwrap();
+        deps.querier.set_oracle_price(&AssetInfo::native("uluna"), price);
+    };
+    let harvest = |deps: &mut OwnedDeps<MockStorage, MockApi, CustomMockQuerier>| {
+        let msg = ExecuteMsg::Harvest {
+            max_spread: None,
+            slippage_tolerance: None,
+            min_lp_out: None,
+        };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("operator", &[]), msg).unwrap();
+        release_lock(deps);
+        let fees = res.events[0].attributes.iter().find(|attr| attr.key == "fees").unwrap();
+        fees.value.clone()
+    };
+
+    // alice bonds 10% of the pool's liquidity tokens, worth 2,000,000 uusd, at 0.00002 uusd per
+    // bond unit
+    deps.querier.set_pool(
+        "uluna_uusd_pair",
+        &[Asset::native("uluna", 1_000_000u128), Asset::native("uusd", 10_000_000u128)],
+        1_000_000,
+    );
+    deps.querier.set_pending_tokens("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 10_000, None);
+    deps.querier.set_oracle_price(&AssetInfo::cw20(Addr::unchecked("astro_token")), Decimal::one());
+    deps.querier.set_oracle_price(&AssetInfo::native("uusd"), Decimal::one());
+    set_uluna_price(&mut deps, "10");
+    POSITION
+        .save(
+            deps.as_mut().storage,
+            &alice,
+            &Position {
+                unlocked_assets: AssetList::from(vec![Asset::cw20(lp_token.clone(), 100_000u128)]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    execute_callback(
+        &mut deps,
+        CallbackMsg::Bond {
+            user_addr: Some(alice.clone()),
+        },
+    )
+    .unwrap();
+    deps.querier.set_bonded_amount("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 100_000);
+
+    let position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    assert_eq!(position.entry_bond_value_per_unit, Decimal::from_str("0.00002").unwrap());
+
+    // the price of uluna rises by 44%, so the value per bond unit rises by 20%. harvests charge no
+    // fee, but record the new value as the high-water mark
+    set_uluna_price(&mut deps, "14.4");
+    assert_eq!(harvest(&mut deps), "");
+    assert_eq!(harvest(&mut deps), "");
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state.hwm_bond_value_per_unit, Decimal::from_str("0.000024").unwrap());
+
+    // the price rises further, to 0.000028 uusd per bond unit, but no harvest has observed it yet.
+    // alice unbonds half of her units; the fee is charged on the appreciation of 200,000 uusd up to
+    // the high-water mark, i.e. 10,000 uusd worth of liquidity tokens at 28 uusd each
+    set_uluna_price(&mut deps, "19.6");
+    let unbond = CallbackMsg::Unbond {
+        user_addr: alice.clone(),
+        bond_units_to_reduce: Uint128::new(50_000_000_000),
+    };
+    let res = execute_callback(&mut deps, unbond.clone()).unwrap();
+    assert_eq!(attr_value(&res, "shares_unbonded"), "50000");
+    assert_eq!(attr_value(&res, "withdrawal_fee"), "357");
+    assert_eq!(
+        res.messages[1].msg,
+        Asset::cw20(lp_token.clone(), 357u128).transfer_msg("treasury").unwrap()
+    );
+    let position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    assert_eq!(
+        position.unlocked_assets,
+        AssetList::from(vec![Asset::cw20(lp_token.clone(), 49_643u128)])
+    );
+    deps.querier.set_bonded_amount("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 50_000);
+
+    // once a harvest observes the new value, the remaining units are charged on their appreciation
+    // of 400,000 uusd; the units already withdrawn are not charged again
+    assert_eq!(harvest(&mut deps), "");
+    let res = execute_callback(&mut deps, unbond).unwrap();
+    assert_eq!(attr_value(&res, "shares_unbonded"), "50000");
+    assert_eq!(attr_value(&res, "withdrawal_fee"), "714");
+
+    // withdrawal fees count towards the fees collected
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(
+        state.total_fees_collected,
+        AssetList::from(vec![Asset::cw20(lp_token, 1071u128)])
+    );
+}
+
+#[test]
+fn providing_one_sided_liquidity() {
+    let mut deps = setup_test();
+
+    // alice only has the secondary asset unlocked
+    POSITION
+        .save(
+            deps.as_mut().storage,
+            &Addr::unchecked("alice"),
+            &Position {
+                unlocked_assets: AssetList::from(vec![Asset::native("uusd", 1000u128)]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+    let callback_msg = CallbackMsg::ProvideLiquidity {
+        user_addr: Some(Addr::unchecked("alice")),
+        slippage_tolerance: None,
+        min_lp_out: None,
+    };
+
+    // one-sided provide is not allowed by default
+    let res = execute_callback(&mut deps, callback_msg.clone());
+    assert_eq!(res, Err(StdError::generic_err("no primary asset available").into()));
+
+    // once allowed, half of the secondary asset should be swapped, then the callback invoked again
+    let config = CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.allow_one_sided_provide = true;
+            Ok(config)
+        })
+        .unwrap();
+
+    let res = execute_callback(&mut deps, callback_msg.clone()).unwrap();
+    assert_eq!(res.messages.len(), 2);
+    assert_eq!(
+        res.messages[0],
+        config
+            .primary_pair
+            .swap_submsg(encode_reply_id(2, 0), &Asset::native("uusd", 500u128), None, None)
+            .unwrap()
+    );
+    assert_eq!(
+        res.messages[1].msg,
+        callback_msg.into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR)).unwrap()
+    );
+    assert_eq!(attr_value(&res, "one_sided_asset_swapped"), "native:uusd:500");
+
+    let position = POSITION.load(deps.as_ref().storage, &Addr::unchecked("alice")).unwrap();
+    assert_eq!(position.unlocked_assets, AssetList::from(vec![Asset::native("uusd", 500u128)]));
+
+    let cached = PENDING_OPS.load(deps.as_ref().storage, U64Key::new(0)).unwrap();
+    assert_eq!(cached.user_addr, Addr::unchecked("alice"));
+}
+
+#[test]
+fn querying_admin_status() {
+    let mut deps = setup_test();
+
+    let query_status = |deps: &OwnedDeps<MockStorage, MockApi, CustomMockQuerier>| {
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::AdminStatus {}).unwrap();
+        from_binary::<AdminStatusResponse>(&res).unwrap()
+    };
+
+    let expected = AdminStatusResponse {
+        governance: "governance".to_string(),
+        operators: vec!["operator".to_string()],
+        fee_recipients: vec![("treasury".to_string(), Decimal::one())],
+        paused: false,
+        deposits_enabled: true,
+        deposit_cap: None,
+        pending_governance: None,
+    };
+    assert_eq!(query_status(&deps), expected);
+
+    // a pending governance transfer
+    let msg = ExecuteMsg::ProposeNewGovernance {
+        new_governance: "new_governance".to_string(),
+    };
+    execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), msg).unwrap();
+    let expected = AdminStatusResponse {
+        pending_governance: Some("new_governance".to_string()),
+        ..expected
+    };
+    assert_eq!(query_status(&deps), expected);
+
+    // paused, which disables deposits
+    let msg = ExecuteMsg::SetPaused {
+        paused: true,
+    };
+    execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), msg).unwrap();
+    let expected = AdminStatusResponse {
+        paused: true,
+        deposits_enabled: false,
+        ..expected
+    };
+    assert_eq!(query_status(&deps), expected);
+
+    // a deposit cap
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.deposit_cap = Some(Uint128::new(1_000_000));
+            Ok(config)
+        })
+        .unwrap();
+    let expected = AdminStatusResponse {
+        deposit_cap: Some(Uint128::new(1_000_000)),
+        ..expected
+    };
+    assert_eq!(query_status(&deps), expected);
+
+    // the transfer is accepted and the strategy unpaused
+    let msg = ExecuteMsg::AcceptGovernance {};
+    execute(deps.as_mut(), mock_env(), mock_info("new_governance", &[]), msg).unwrap();
+    let msg = ExecuteMsg::SetPaused {
+        paused: false,
+    };
+    execute(deps.as_mut(), mock_env(), mock_info("new_governance", &[]), msg).unwrap();
+    let expected = AdminStatusResponse {
+        governance: "new_governance".to_string(),
+        paused: false,
+        deposits_enabled: true,
+        pending_governance: None,
+        ..expected
+    };
+    assert_eq!(query_status(&deps), expected);
+}
+
+#[test]
+fn unbonding_and_bonding_in_one_message() {
+    let mut deps = setup_test();
+
+    // alice owns all 100 bonded liquidity tokens
+    deps.querier.set_bonded_amount("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 100);
+    STATE
+        .save(
+            deps.as_mut().storage,
+            &State {
+                total_bond_units: Uint128::new(100_000_000),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    POSITION
+        .save(
+            deps.as_mut().storage,
+            &Addr::unchecked("alice"),
+            &Position {
+                bond_units: Uint128::new(100_000_000),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+    // callbacks should be executed in the order the actions are specified. each callback is a
+    // separate message, so the generator's bonded amount is already updated by the time the
+    // subsequent callback queries it
+    let msg = ExecuteMsg::UpdatePosition(vec![
+        Action::Unbond {
+            bond_units_to_reduce: Uint128::new(40_000_000),
+            swap_to: None,
+        },
+        Action::Bond {
+            slippage_tolerance: None,
+        },
+    ]);
+    let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+    let alice = Addr::unchecked("alice");
+    let expected_callbacks = [
+        CallbackMsg::Unbond {
+            user_addr: alice.clone(),
+            bond_units_to_reduce: Uint128::new(40_000_000),
+        },
+        CallbackMsg::WithdrawLiquidity {
+            user_addr: alice.clone(),
+        },
+        CallbackMsg::ProvideLiquidity {
+            user_addr: Some(alice.clone()),
+            slippage_tolerance: None,
+            min_lp_out: None,
+        },
+        CallbackMsg::Bond {
+            user_addr: Some(alice.clone()),
+        },
+        CallbackMsg::Refund {
+            user_addr: alice.clone(),
+            recipient_addr: alice.clone(),
+            percentage: Decimal::one(),
+        },
+        CallbackMsg::AssertHealth {
+            user_addr: alice.clone(),
+        },
+        CallbackMsg::Snapshot {
+            user_addr: alice.clone(),
+        },
+        CallbackMsg::PurgeStorage {
+            user_addr: alice.clone(),
+        },
+        CallbackMsg::ReleaseLock {},
+    ];
+    let expected_msgs = expected_callbacks
+        .iter()
+        .map(|callback| callback.into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR)).unwrap())
+        .collect::<Vec<_>>();
+    let msgs = res.messages.iter().map(|submsg| submsg.msg.clone()).collect::<Vec<_>>();
+    assert_eq!(msgs, expected_msgs);
+
+    // unbond: 40 liquidity tokens are unbonded
+    let res = execute_callback(&mut deps, expected_callbacks[0].clone()).unwrap();
+    assert_eq!(attr_value(&res, "shares_unbonded"), "40");
+    deps.querier.set_bonded_amount("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 60);
+
+    // withdraw and provide: the 40 liquidity tokens are burned, and the returned assets are
+    // provided again, minting 38 liquidity tokens
+    let mut position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    position.unlocked_assets = AssetList::from(vec![Asset::cw20(
+        Addr::unchecked("uluna_uusd_lp_token"),
+        38u128,
+    )]);
+    POSITION.save(deps.as_mut().storage, &alice, &position).unwrap();
+
+    // bond: bond units are credited against the updated bonded amount
+    let res = execute_callback(&mut deps, expected_callbacks[3].clone()).unwrap();
+    assert_eq!(attr_value(&res, "bond_units_added"), "38000000");
+
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    let position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    assert_eq!(state.total_bond_units, Uint128::new(98_000_000));
+    assert_eq!(position.bond_units, Uint128::new(98_000_000));
+    assert_eq!(position.unlocked_assets, AssetList::default());
+}
+
+#[test]
+fn covering_debt_with_tax_reserve() {
+    let mut deps = setup_test();
+
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.repay_tax_reserve = Uint128::new(10);
+            Ok(config)
+        })
+        .unwrap();
+
+    // alice owes 1000 uusd, and has 200 uusd and 1000 uluna unlocked
+    deps.querier.set_pool(
+        "uluna_uusd_pair",
+        &[Asset::native("uluna", 1_000_000u128), Asset::native("uusd", 10_000_000u128)],
+        1_000_000,
+    );
+    deps.querier.set_user_debt(MOCK_CONTRACT_ADDR, &AssetInfo::native("uusd"), 1000);
+    STATE
+        .save(
+            deps.as_mut().storage,
+            &State {
+                total_debt_units: Uint128::new(1_000_000_000),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    POSITION
+        .save(
+            deps.as_mut().storage,
+            &Addr::unchecked("alice"),
+            &Position {
+                debt_units: Uint128::new(1_000_000_000),
+                unlocked_assets: AssetList::from(vec![
+                    Asset::native("uluna", 1000u128),
+                    Asset::native("uusd", 200u128),
+                ]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+    // cover should target the debt amount plus the tax reserve
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::Cover {
+            user_addr: Addr::unchecked("alice"),
+            max_spread: Decimal::percent(5),
+        },
+    )
+    .unwrap();
+    assert_eq!(attr_value(&res, "secondary_needed"), "810");
+
+    // once the swap returns the needed amount, repay should pay off the debt in full, leaving the
+    // reserve for tax
+    let mut position = POSITION.load(deps.as_ref().storage, &Addr::unchecked("alice")).unwrap();
+    position.unlocked_assets.add(&Asset::native("uusd", 810u128)).unwrap();
+    POSITION.save(deps.as_mut().storage, &Addr::unchecked("alice"), &position).unwrap();
+
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::Repay {
+            user_addr: Some(Addr::unchecked("alice")),
+            repay_amount: None,
+        },
+    )
+    .unwrap();
+    assert_eq!(attr_value(&res, "secondary_repaid"), "1000");
+
+    let position = POSITION.load(deps.as_ref().storage, &Addr::unchecked("alice")).unwrap();
+    assert_eq!(position.debt_units, Uint128::zero());
+    assert_eq!(
+        position.unlocked_assets.find(&AssetInfo::native("uusd")),
+        Some(&Asset::native("uusd", 10u128))
+    );
+}
+
+#[test]
+fn adjusting_position_as_admin() {
+    let mut deps = setup_test();
+
+    STATE
+        .save(
+            deps.as_mut().storage,
+            &State {
+                total_bond_units: Uint128::new(300),
+                total_debt_units: Uint128::new(200),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    for (user, bond_units, debt_units) in [("alice", 100u128, 150u128), ("bob", 200, 50)] {
+        POSITION
+            .save(
+                deps.as_mut().storage,
+                &Addr::unchecked(user),
+                &Position {
+                    bond_units: Uint128::new(bond_units),
+                    debt_units: Uint128::new(debt_units),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+    }
+
+    let msg = ExecuteMsg::AdminAdjustPosition {
+        user: "alice".to_string(),
+        bond_units: Uint128::new(120),
+        debt_units: Uint128::new(90),
+    };
+
+    // only governance can adjust positions
+    let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg.clone());
+    assert_eq!(res, Err(ContractError::unauthorized("only governance can adjust positions")));
+
+    let res = execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), msg).unwrap();
+    assert_eq!(res.events[0].ty, "admin_adjustment");
+
+    // the sum of all positions' units should equal the total units
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    let alice = POSITION.load(deps.as_ref().storage, &Addr::unchecked("alice")).unwrap();
+    let bob = POSITION.load(deps.as_ref().storage, &Addr::unchecked("bob")).unwrap();
+    assert_eq!(alice.bond_units, Uint128::new(120));
+    assert_eq!(alice.debt_units, Uint128::new(90));
+    assert_eq!(state.total_bond_units, alice.bond_units + bob.bond_units);
+    assert_eq!(state.total_debt_units, alice.debt_units + bob.debt_units);
+}
+
+#[test]
+fn accumulating_astro_rewards() {
+    let harvest_with_astro_handling = |astro_handling: AstroHandling| {
+        let mut deps = setup_test();
+
+        CONFIG
+            .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+                config.astro_handling = astro_handling;
+                Ok(config)
+            })
+            .unwrap();
+        deps.querier.set_pending_tokens("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 10_000, None);
+
+        let msg = ExecuteMsg::Harvest {
+            max_spread: None,
+            slippage_tolerance: None,
+            min_lp_out: None,
+        };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("operator", &[]), msg).unwrap();
+        (deps, res)
+    };
+
+    // reinvest mode: ASTRO should be swapped to the secondary asset
+    let (deps, res) = harvest_with_astro_handling(AstroHandling::Reinvest);
+    let swap_msg = CallbackMsg::Swap {
+        user_addr: None,
+        offer_asset_info: AssetInfo::cw20(Addr::unchecked("astro_token")),
+        offer_amount: Some(Uint128::new(9500)),
+        max_spread: None,
+        min_return: None,
+    }
+    .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+    .unwrap();
+    assert!(res.messages.iter().any(|submsg| submsg.msg == swap_msg));
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state.accumulated_astro, Uint128::zero());
+
+    // accumulate mode: ASTRO should be retained, and nothing reinvested
+    let (mut deps, res) = harvest_with_astro_handling(AstroHandling::Accumulate);
+    assert_eq!(res.messages.len(), 3); // claim rewards + transfer fee + release lock
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state.accumulated_astro, Uint128::new(9500));
+    assert_eq!(state.pending_rewards, AssetList::default());
+    release_lock(&mut deps);
+
+    // only governance can withdraw accumulated ASTRO
+    let msg = ExecuteMsg::WithdrawAccumulatedAstro {
+        recipient: "dao".to_string(),
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("operator", &[]), msg.clone());
+    assert_eq!(
+        res,
+        Err(ContractError::unauthorized("only governance can withdraw accumulated astro"))
+    );
+
+    let res = execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), msg).unwrap();
+    assert_eq!(
+        res.messages[0].msg,
+        Asset::cw20(Addr::unchecked("astro_token"), 9500u128)
+            .transfer_msg("dao")
+            .unwrap()
+    );
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state.accumulated_astro, Uint128::zero());
+}
+
+#[test]
+fn sweeping_stray_assets() {
+    let mut deps = setup_test();
+
+    let sweep = |deps: &mut OwnedDeps<_, _, _>, sender: &str, asset: Asset| {
+        let msg = ExecuteMsg::Sweep {
+            asset: asset.into(),
+            recipient: "dao".to_string(),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(sender, &[]), msg)
+    };
+    let stray_token = Asset::cw20(Addr::unchecked("stray_token"), 12345u128);
+
+    // only governance can sweep
+    let res = sweep(&mut deps, "operator", stray_token.clone());
+    assert_eq!(res, Err(ContractError::unauthorized("only governance can sweep")));
+
+    // a token that isn't an asset of the strategy is transferred to the recipient
+    let res = sweep(&mut deps, "governance", stray_token.clone()).unwrap();
+    assert_eq!(res.messages.len(), 1);
+    assert_eq!(res.messages[0].msg, stray_token.transfer_msg("dao").unwrap());
+    let res = sweep(&mut deps, "governance", Asset::native("ukrw", 100u128)).unwrap();
+    assert_eq!(res.messages[0].msg, Asset::native("ukrw", 100u128).transfer_msg("dao").unwrap());
+
+    // assets held by the strategy can't be swept
+    let protected = [
+        Asset::cw20(Addr::unchecked("uluna_uusd_lp_token"), 100u128),
+        Asset::native("uluna", 100u128),
+        Asset::native("uusd", 100u128),
+        Asset::cw20(Addr::unchecked("astro_token"), 100u128),
+    ];
+    for asset in protected {
+        let res = sweep(&mut deps, "governance", asset.clone());
+        assert_generic_error_message(
+            res,
+            &format!("cannot sweep {}: asset is held by the strategy", asset.info),
+        );
+    }
+}
+
+#[test]
+fn repaying_debt_with_rewards() {
+    let mut deps = setup_test();
+    setup_leveraged_position(&mut deps, 10_000);
+
+    let mut config = CONFIG.load(deps.as_ref().storage).unwrap();
+    config.reinvest_mode = ReinvestMode::RepayDebt;
+    CONFIG.save(deps.as_mut().storage, &config).unwrap();
+
+    // 5,000 uusd of rewards, e.g. proceeds of ASTRO sold in a previous harvest, are pending
+    let mut state = STATE.load(deps.as_ref().storage).unwrap();
+    state.pending_rewards = AssetList::from(vec![Asset::native("uusd", 5_000u128)]);
+    STATE.save(deps.as_mut().storage, &state).unwrap();
+
+    let harvest = |deps: &mut OwnedDeps<_, _, _>| {
+        let msg = ExecuteMsg::Harvest {
+            max_spread: None,
+            slippage_tolerance: None,
+            min_lp_out: None,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("operator", &[]), msg).unwrap()
+    };
+
+    // rewards should be used to repay debt instead of being balanced, provided, and bonded
+    let res = harvest(&mut deps);
+    let expected_callbacks = [
+        CallbackMsg::Repay {
+            user_addr: None,
+            repay_amount: None,
+        },
+        CallbackMsg::ReleaseLock {},
+    ];
+    let expected_msgs = expected_callbacks
+        .iter()
+        .map(|callback| callback.into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR)).unwrap())
+        .collect::<Vec<_>>();
+    let msgs = res.messages.iter().map(|submsg| submsg.msg.clone()).collect::<Vec<_>>();
+    assert_eq!(msgs, expected_msgs);
+
+    // the aggregate debt is repaid without deducting debt units, so that the debt per unit of
+    // every user decreases
+    let res = execute_callback(&mut deps, expected_callbacks[0].clone()).unwrap();
+    assert_eq!(
+        res.messages[0].msg,
+        config.red_bank.repay_msg(&Asset::native("uusd", 5_000u128)).unwrap()
+    );
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state.total_debt_units, Uint128::new(10_000_000));
+    assert_eq!(state.pending_rewards, AssetList::default());
+    release_lock(&mut deps);
+
+    // no liquidity is bonded, and alice's debt is halved
+    deps.querier.set_user_debt(MOCK_CONTRACT_ADDR, &AssetInfo::native("uusd"), 5_000);
+    let msg = QueryMsg::Position {
+        user: "alice".to_string(),
+    };
+    let res: PositionResponse =
+        from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+    assert_eq!(res.bond_amount, Uint128::new(100_000));
+    assert_eq!(res.debt_units, Uint128::new(10_000_000));
+    assert_eq!(res.debt_amount, Uint128::new(5_000));
+
+    // rewards in excess of the debt are kept pending
+    let mut state = STATE.load(deps.as_ref().storage).unwrap();
+    state.pending_rewards = AssetList::from(vec![Asset::native("uusd", 8_000u128)]);
+    STATE.save(deps.as_mut().storage, &state).unwrap();
+    harvest(&mut deps);
+    let res = execute_callback(&mut deps, expected_callbacks[0].clone()).unwrap();
+    assert_eq!(
+        res.messages[0].msg,
+        config.red_bank.repay_msg(&Asset::native("uusd", 5_000u128)).unwrap()
+    );
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state.pending_rewards, AssetList::from(vec![Asset::native("uusd", 3_000u128)]));
+
+    // repaying debt requires the secondary asset to be the borrowed asset
+    config.borrow_asset_info = Some(AssetInfo::native("uatom"));
+    config.borrow_pair = Some(config.primary_pair.clone());
+    assert_generic_error_message(
+        config.validate(&Limits::default()),
+        "invalid reinvest mode: repaying debt requires borrowing the secondary asset",
+    );
+}
+
+#[test]
+fn crediting_reinvested_rewards_to_earlier_stakers() {
+    let mut deps = setup_test();
+    setup_leveraged_position(&mut deps, 0);
+
+    let lp_token = |amount: u128| Asset::cw20(Addr::unchecked("uluna_uusd_lp_token"), amount);
+    let query_position = |deps: &OwnedDeps<_, _, _>, user: &str| -> PositionResponse {
+        let msg = QueryMsg::Position {
+            user: user.to_string(),
+        };
+        from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap()
+    };
+    let reinvest = |deps: &mut OwnedDeps<_, _, _>, amount: u128| {
+        let mut state = STATE.load(deps.as_ref().storage).unwrap();
+        state.pending_rewards = AssetList::from(vec![lp_token(amount)]);
+        STATE.save(deps.as_mut().storage, &state).unwrap();
+        execute_callback(
+            deps,
+            CallbackMsg::Bond {
+                user_addr: None,
+            },
+        )
+        .unwrap()
+    };
+
+    // bob bonds as many liquidity tokens as alice right before a harvest
+    POSITION
+        .save(
+            deps.as_mut().storage,
+            &Addr::unchecked("bob"),
+            &Position {
+                unlocked_assets: AssetList::from(vec![lp_token(100_000)]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::Bond {
+            user_addr: Some(Addr::unchecked("bob")),
+        },
+    )
+    .unwrap();
+    assert_eq!(attr_value(&res, "bond_units_added"), "100000000");
+    deps.querier.set_bonded_amount("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 200_000);
+
+    // the harvest reinvests 20,000 liquidity tokens. 20,000,000 bond units are minted, all of which
+    // are credited to alice, the only staker prior to the harvest
+    let res = reinvest(&mut deps, 20_000);
+    assert_eq!(attr_value(&res, "bond_units_added"), "20000000");
+    deps.querier.set_bonded_amount("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 220_000);
+
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state.total_bond_units, Uint128::new(220_000_000));
+    assert_eq!(state.reward_index, Decimal::percent(120));
+    assert_eq!(state.reward_epoch, 1);
+    assert_eq!(state.entering_bond_units, Uint128::zero());
+
+    let alice = query_position(&deps, "alice");
+    assert_eq!(alice.bond_units, Uint128::new(120_000_000));
+    assert_eq!(alice.bond_amount, Uint128::new(120_000));
+    let bob = query_position(&deps, "bob");
+    assert_eq!(bob.bond_units, Uint128::new(100_000_000));
+    assert_eq!(bob.bond_amount, Uint128::new(100_000));
+
+    // positions are settled lazily; the stored position is only credited once it is updated
+    let stored = POSITION.load(deps.as_ref().storage, &Addr::unchecked("alice")).unwrap();
+    assert_eq!(stored.bond_units, Uint128::new(100_000_000));
+
+    // bob's bond units are eligible for the next harvest, which reinvests 10% of the bonded
+    // liquidity tokens, so both positions grow by 10%
+    reinvest(&mut deps, 22_000);
+    deps.querier.set_bonded_amount("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 242_000);
+
+    let alice = query_position(&deps, "alice");
+    assert_eq!(alice.bond_units, Uint128::new(132_000_000));
+    assert_eq!(alice.bond_amount, Uint128::new(132_000));
+    let bob = query_position(&deps, "bob");
+    assert_eq!(bob.bond_units, Uint128::new(110_000_000));
+    assert_eq!(bob.bond_amount, Uint128::new(110_000));
+
+    // unbonding settles the position first, so that all of the credited units can be unbonded
+    execute_callback(
+        &mut deps,
+        CallbackMsg::Unbond {
+            user_addr: Addr::unchecked("bob"),
+            bond_units_to_reduce: Uint128::new(110_000_000),
+        },
+    )
+    .unwrap();
+    let stored = POSITION.load(deps.as_ref().storage, &Addr::unchecked("bob")).unwrap();
+    assert_eq!(stored.bond_units, Uint128::zero());
+    assert_eq!(stored.reward_index, Decimal::from_ratio(132u128, 100u128));
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state.total_bond_units, Uint128::new(132_000_000));
+}
+
+#[test]
+fn calculating_pagination_limit() {
+    assert_eq!(calc_limit(None), 10);
+    assert_eq!(calc_limit(Some(5)), 5);
+    assert_eq!(calc_limit(Some(30)), 30);
+    assert_eq!(calc_limit(Some(u32::MAX)), 30);
+}
+
+#[test]
+fn paying_liquidation_incentive() {
+    let mut deps = setup_test();
+
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.liquidation_incentive = Some(Asset::native("umars", 100u128));
+            Ok(config)
+        })
+        .unwrap();
+
+    // alice's LTV is 90% and liquidatable
+    setup_leveraged_position(&mut deps, 1_800_000);
+
+    let msg = ExecuteMsg::Liquidate {
+        user: "alice".to_string(),
+        max_bond_units: None,
+        max_spread: None,
+        bonus_asset: None,
+    };
+
+    // the reserve is empty; the incentive should be skipped without failing the liquidation
+    let res = execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), msg.clone()).unwrap();
+    assert_eq!(attr_value(&res, "incentive_skipped"), "insufficient reserve");
+    assert_eq!(res.messages.len(), 9);
+    release_lock(&mut deps);
+
+    // only governance can fund the reserve
+    let fund_msg = ExecuteMsg::FundIncentiveReserve {
+        amount: Uint128::new(150),
+    };
+    let funds = [Coin::new(150, "umars")];
+    let res = execute(deps.as_mut(), mock_env(), mock_info("bob", &funds), fund_msg.clone());
+    assert_eq!(res, Err(ContractError::unauthorized("only governance can fund incentive reserve")));
+
+    execute(deps.as_mut(), mock_env(), mock_info("governance", &funds), fund_msg).unwrap();
+
+    // the reserve is funded; the liquidator should be paid the incentive
+    let res = execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), msg.clone()).unwrap();
+    assert_eq!(attr_value(&res, "incentive_paid"), "native:umars:100");
+    assert_eq!(res.messages.len(), 10);
+    assert_eq!(
+        res.messages[8].msg,
+        Asset::native("umars", 100u128).transfer_msg("bob").unwrap()
+    );
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state.incentive_reserve, AssetList::from(vec![Asset::native("umars", 50u128)]));
+    release_lock(&mut deps);
+
+    // a partial liquidation is paid the incentive pro-rated by the portion liquidated, so that
+    // splitting a liquidation doesn't multiply the incentive
+    let partial_msg = |max_bond_units: u128| ExecuteMsg::Liquidate {
+        user: "alice".to_string(),
+        max_bond_units: Some(Uint128::new(max_bond_units)),
+        max_spread: None,
+        bonus_asset: None,
+    };
+    let res =
+        execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), partial_msg(10_000_000)).unwrap();
+    assert_eq!(attr_value(&res, "incentive_paid"), "native:umars:10");
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state.incentive_reserve, AssetList::from(vec![Asset::native("umars", 40u128)]));
+    release_lock(&mut deps);
+
+    // liquidating zero bond units is rejected, rather than paid the incentive for nothing
+    let res = execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), partial_msg(0));
+    assert_generic_error_message(res, "max bond units must be greater than zero");
+
+    // the remaining reserve is insufficient for another incentive
+    let res = execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), msg).unwrap();
+    assert_eq!(attr_value(&res, "incentive_skipped"), "insufficient reserve");
+}
+
+#[test]
+fn validating_config() {
+    let limits = Limits::default();
+    assert_eq!(mock_config().validate(&limits), Ok(()));
+
+    let mut config = mock_config();
+    config.secondary_asset_info = config.primary_asset_info.clone();
+    assert_generic_error_message(
+        config.validate(&limits),
+        "invalid assets: primary and secondary assets must be different",
+    );
+
+    let mut config = mock_config();
+    config.max_ltv = Decimal::zero();
+    assert_generic_error_message(
+        config.validate(&limits),
+        "invalid max ltv: must be greater than zero",
+    );
+
+    let mut config = mock_config();
+    config.max_ltv = Decimal::percent(80);
+    assert_generic_error_message(
+        config.validate(&limits),
+        "invalid max ltv: 0.8; must be in [0.55, 0.75]",
+    );
+
+    let mut config = mock_config();
+    config.fee_rate = Decimal::percent(11);
+    assert_generic_error_message(
+        config.validate(&limits),
+        "invalid fee rate: 0.11; must be <= 0.1",
+    );
+
+    let mut config = mock_config();
+    config.bonus_rate = Decimal::percent(11);
+    assert_generic_error_message(
+        config.validate(&limits),
+        "invalid bonus rate: 0.11; must be <= 0.1",
+    );
+
+    let mut config = mock_config();
+    config.default_slippage_tolerance = Decimal::percent(6);
+    assert_generic_error_message(
+        config.validate(&limits),
+        "invalid default slippage tolerance: 0.06; must be <= 0.05",
+    );
+
+    let mut config = mock_config();
+    config.liquidation_incentive = Some(Asset::native("umars", 0u128));
+    assert_generic_error_message(
+        config.validate(&limits),
+        "invalid liquidation incentive: amount must be greater than zero",
+    );
+
+    let mut config = mock_config();
+    config.bond_units_per_share = Uint128::zero();
+    assert_generic_error_message(
+        config.validate(&limits),
+        "invalid bond units per share: must be greater than zero",
+    );
+
+    let mut config = mock_config();
+    config.debt_units_per_asset = Uint128::zero();
+    assert_generic_error_message(
+        config.validate(&limits),
+        "invalid debt units per asset: must be greater than zero",
+    );
+
+    let mut config = mock_config();
+    config.liquidation_max_spread = Decimal::zero();
+    assert_generic_error_message(
+        config.validate(&limits),
+        "invalid liquidation max spread: must be greater than zero",
+    );
+
+    let mut config = mock_config();
+    config.liquidation_max_spread = Decimal::percent(51);
+    assert_generic_error_message(
+        config.validate(&limits),
+        "invalid liquidation max spread: 0.51; must be <= 0.5",
+    );
+
+    let mut config = mock_config();
+    config.max_allowed_spread = Decimal::percent(11);
+    assert_generic_error_message(
+        config.validate(&limits),
+        "invalid max allowed spread: 0.11; must be <= 0.1",
+    );
+
+    // ASTRO and the primary asset have dedicated pairs, and can't be given a reward pair
+    let mut config = mock_config();
+    config.reward_pairs = vec![(AssetInfo::native("uluna"), config.primary_pair.clone())];
+    assert_generic_error_message(
+        config.validate(&limits),
+        "invalid reward pairs: native:uluna is swapped using a dedicated pair",
+    );
+
+    let mut config = mock_config();
+    config.bonus_schedule = vec![
+        (Decimal::percent(80), Decimal::percent(5)),
+        (Decimal::percent(70), Decimal::percent(2)),
+    ];
+    assert_generic_error_message(
+        config.validate(&limits),
+        "invalid bonus schedule: breakpoints must be in ascending order",
+    );
+
+    let mut config = mock_config();
+    config.bonus_schedule = vec![(Decimal::percent(80), Decimal::percent(11))];
+    assert_generic_error_message(
+        config.validate(&limits),
+        "invalid bonus schedule: bonus rate 0.11 at ltv 0.8; must be <= 0.1",
+    );
+
+    // max LTV is a collateral ratio rather than a fee, and is not summed with the bonus rate
+    let limits_99 = Limits {
+        max_max_ltv: Decimal::percent(99),
+        ..limits.clone()
+    };
+    let mut config = mock_config();
+    config.max_ltv = Decimal::percent(90);
+    config.bonus_rate = Decimal::percent(10);
+    assert_eq!(config.validate(&limits_99), Ok(()));
+
+    // percentages taken from the collateral during liquidation must sum to less than 100%
+    let limits_high = Limits {
+        max_fee_rate: Decimal::one(),
+        max_bonus_rate: Decimal::percent(60),
+        ..limits.clone()
+    };
+    let mut config = mock_config();
+    config.bonus_rate = Decimal::percent(50);
+    config.liquidation_max_spread = Decimal::percent(50);
+    assert_generic_error_message(
+        config.validate(&limits_high),
+        "invalid liquidation rates: bonus rate + liquidation max spread = 1; must be < 1",
+    );
+
+    // the highest bonus rate in the schedule is counted, not just the base rate
+    let mut config = mock_config();
+    config.bonus_schedule = vec![(Decimal::percent(70), Decimal::percent(55))];
+    config.liquidation_max_spread = Decimal::percent(50);
+    assert_generic_error_message(
+        config.validate(&limits_high),
+        "invalid liquidation rates: bonus rate + liquidation max spread = 1.05; must be < 1",
+    );
+
+    // percentages deducted before assets are refunded must sum to less than 100%
+    let mut config = mock_config();
+    config.fee_mode = FeeMode::Withdrawal;
+    config.fee_rate = Decimal::percent(95);
+    config.bonus_rate = Decimal::percent(5);
+    assert_generic_error_message(
+        config.validate(&limits_high),
+        "invalid refund rates: withdrawal fee rate + bonus rate = 1; must be < 1",
+    );
+
+    let mut config = mock_config();
+    config.fee_mode = FeeMode::Withdrawal;
+    config.fee_rate = Decimal::percent(50);
+    config.bonus_schedule = vec![(Decimal::percent(70), Decimal::percent(60))];
+    assert_generic_error_message(
+        config.validate(&limits_high),
+        "invalid refund rates: withdrawal fee rate + bonus rate = 1.1; must be < 1",
+    );
+
+    // the fee rate is only charged upon withdrawal in the withdrawal fee mode
+    let mut config = mock_config();
+    config.fee_rate = Decimal::percent(95);
+    config.bonus_rate = Decimal::percent(5);
+    assert_eq!(config.validate(&limits_high), Ok(()));
+
+    // zero fee and bonus rates are allowed
+    let mut config = mock_config();
+    config.fee_rate = Decimal::zero();
+    config.bonus_rate = Decimal::zero();
+    assert_eq!(config.validate(&limits), Ok(()));
+
+    // an invalid new config should be rejected by `UpdateConfig`
+    let mut deps = setup_test();
+    let mut new_config = mock_config();
+    new_config.max_ltv = Decimal::zero();
+    let msg = ExecuteMsg::UpdateConfig {
+        new_config: new_config.into(),
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), msg);
+    assert_generic_error_message(res, "invalid max ltv: must be greater than zero");
+}
+
+#[test]
+fn instantiating_with_custom_limits() {
+    let limits = Limits {
+        min_max_ltv: Decimal::percent(50),
+        max_max_ltv: Decimal::percent(70),
+        max_fee_rate: Decimal::percent(5),
+        max_bonus_rate: Decimal::percent(2),
+    };
+
+    // limits that are inconsistent with each other should be rejected
+    let mut deps = mock_dependencies();
+    set_pair_infos(&mut deps, PairType::Xyk);
+    let msg = InstantiateMsg {
+        config: mock_config().into(),
+        limits: Some(Limits {
+            min_max_ltv: Decimal::percent(80),
+            ..limits.clone()
+        }),
+    };
+    let res = instantiate(deps.as_mut(), mock_env(), mock_info("deployer", &[]), msg);
+    assert_generic_error_message(res, "invalid limits: min max ltv 0.8; must be in (0, 0.7]");
+
+    // a config that violates the limits should be rejected
+    let mut config = mock_config();
+    config.fee_rate = Decimal::percent(6);
+    let msg = InstantiateMsg {
+        config: config.into(),
+        limits: Some(limits.clone()),
+    };
+    let res = instantiate(deps.as_mut(), mock_env(), mock_info("deployer", &[]), msg);
+    assert_generic_error_message(res, "invalid fee rate: 0.06; must be <= 0.05");
+
+    let msg = InstantiateMsg {
+        config: mock_config().into(),
+        limits: Some(limits.clone()),
+    };
+    instantiate(deps.as_mut(), mock_env(), mock_info("deployer", &[]), msg).unwrap();
+
+    let res: Limits =
+        from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::Limits {}).unwrap()).unwrap();
+    assert_eq!(res, limits);
+
+    // new configs are validated against the stored limits rather than the default ones
+    let update_config = |deps: &mut OwnedDeps<_, _, _>, max_ltv: Decimal| {
+        let mut new_config = mock_config();
+        new_config.max_ltv = max_ltv;
+        let msg = ExecuteMsg::UpdateConfig {
+            new_config: new_config.into(),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), msg)
+    };
+    let res = update_config(&mut deps, Decimal::percent(72));
+    assert_generic_error_message(res, "invalid max ltv: 0.72; must be in [0.5, 0.7]");
+    update_config(&mut deps, Decimal::percent(52)).unwrap();
+}
+
+#[test]
+fn refunding_dust_position() {
+    let mut deps = setup_test();
+    let alice = Addr::unchecked("alice");
+
+    // alice's position holds 1 unit each of uluna and uusd
+    let position = Position {
+        unlocked_assets: AssetList::from(vec![
+            Asset::native("uluna", 1u128),
+            Asset::native("uusd", 1u128),
+        ]),
+        ..Default::default()
+    };
+    POSITION.save(deps.as_mut().storage, &alice, &position).unwrap();
+
+    let refund = |deps: &mut OwnedDeps<_, _, _>, percentage: Decimal| {
+        let callback = CallbackMsg::Refund {
+            user_addr: alice.clone(),
+            recipient_addr: alice.clone(),
+            percentage,
+        };
+        execute_callback(deps, callback)
+            .map(|res| res.messages.into_iter().map(|submsg| submsg.msg).collect::<Vec<_>>())
+    };
+
+    // a partial refund rounds down to zero, in which case no transfer is sent
+    assert_eq!(refund(&mut deps, Decimal::percent(50)).unwrap(), vec![]);
+    let position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    assert_eq!(position.unlocked_assets.len(), 2);
+
+    // a percentage above 100% would refund more than is available, and is rejected
+    assert_generic_error_message(
+        refund(&mut deps, Decimal::percent(150)),
+        "invalid refund percentage: 1.5; must be <= 1",
+    );
+    let position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    assert_eq!(position.unlocked_assets.len(), 2);
+
+    let expected_msgs = vec![
+        Asset::native("uluna", 1u128).transfer_msg("alice").unwrap(),
+        Asset::native("uusd", 1u128).transfer_msg("alice").unwrap(),
+    ];
+    assert_eq!(refund(&mut deps, Decimal::one()).unwrap(), expected_msgs);
+    let position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    assert!(position.is_empty());
+
+    // refunding an empty position sends nothing
+    assert_eq!(refund(&mut deps, Decimal::one()).unwrap(), vec![]);
+}
+
+#[test]
+fn routing_refunds_through_batcher() {
+    let refund = |refund_batcher: Option<RefundBatcher>| {
+        let mut deps = setup_test();
+
+        CONFIG
+            .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+                config.refund_batcher = refund_batcher;
+                Ok(config)
+            })
+            .unwrap();
+        POSITION
+            .save(
+                deps.as_mut().storage,
+                &Addr::unchecked("alice"),
+                &Position {
+                    unlocked_assets: AssetList::from(vec![
+                        Asset::native("uluna", 100u128),
+                        Asset::native("uusd", 200u128),
+                        Asset::cw20(Addr::unchecked("astro_token"), 300u128),
+                    ]),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let res = execute_callback(
+            &mut deps,
+            CallbackMsg::Refund {
+                user_addr: Addr::unchecked("alice"),
+                recipient_addr: Addr::unchecked("alice"),
+                percentage: Decimal::one(),
+            },
+        )
+        .unwrap();
+        res.messages.into_iter().map(|submsg| submsg.msg).collect::<Vec<_>>()
+    };
+
+    // no batcher configured; refunds should be transferred directly
+    let msgs = refund(None);
+    assert_eq!(
+        msgs,
+        vec![
+            Asset::native("uluna", 100u128).transfer_msg("alice").unwrap(),
+            Asset::native("uusd", 200u128).transfer_msg("alice").unwrap(),
+            Asset::cw20(Addr::unchecked("astro_token"), 300u128).transfer_msg("alice").unwrap(),
+        ]
+    );
+
+    // batcher configured; refunds should be sent to the batcher, native coins in one message
+    let msgs = refund(Some(RefundBatcher {
+        contract_addr: Addr::unchecked("refund_batcher"),
+    }));
+    let credit = RefundBatcherCw20HookMsg::Credit {
+        recipient: "alice".to_string(),
+    };
+    assert_eq!(
+        msgs,
+        vec![
+            Asset::cw20(Addr::unchecked("astro_token"), 300u128)
+                .send_msg("refund_batcher", to_binary(&credit).unwrap())
+                .unwrap(),
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: "refund_batcher".to_string(),
+                msg: to_binary(&RefundBatcherExecuteMsg::Credit {
+                    recipient: "alice".to_string(),
+                })
+                .unwrap(),
+                funds: vec![Coin::new(100, "uluna"), Coin::new(200, "uusd")],
+            }),
+        ]
+    );
+}
+
+#[test]
+fn handling_native_primary_asset() {
+    let mut deps = setup_test();
+
+    // a native primary asset whose denom comes after the secondary asset's alphabetically
+    let config = CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.primary_asset_info = AssetInfo::native("uxyz");
+            Ok(config)
+        })
+        .unwrap();
+    deps.querier.set_pool(
+        "uluna_uusd_pair",
+        &[Asset::native("uxyz", 1_000_000u128), Asset::native("uusd", 2_000_000u128)],
+        1_000_000,
+    );
+
+    // deposit both assets and bond
+    let deposits = [Coin::new(100, "uxyz"), Coin::new(200, "uusd")];
+    let msg = ExecuteMsg::UpdatePosition(vec![
+        Action::Deposit(Asset::native("uxyz", 100u128).into()),
+        Action::Deposit(Asset::native("uusd", 200u128).into()),
+        Action::Bond {
+            slippage_tolerance: None,
+        },
+    ]);
+    execute(deps.as_mut(), mock_env(), mock_info("alice", &deposits), msg).unwrap();
+
+    let position = POSITION.load(deps.as_ref().storage, &Addr::unchecked("alice")).unwrap();
+    assert_eq!(
+        position.unlocked_assets,
+        AssetList::from(vec![Asset::native("uxyz", 100u128), Asset::native("uusd", 200u128)])
+    );
+
+    // provide liquidity: coins should be sent sorted by denom
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::ProvideLiquidity {
+            user_addr: Some(Addr::unchecked("alice")),
+            slippage_tolerance: None,
+            min_lp_out: None,
+        },
+    )
+    .unwrap();
+    assert_eq!(res.messages.len(), 1);
+    match &res.messages[0].msg {
+        CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr,
+            funds,
+            ..
+        }) => {
+            assert_eq!(contract_addr, "uluna_uusd_pair");
+            assert_eq!(funds, &vec![Coin::new(200, "uusd"), Coin::new(100, "uxyz")]);
+        }
+        msg => panic!("unexpected message: {:?}", msg),
+    }
+
+    let reply_msg = Reply {
+        id: res.messages[0].id,
+        result: ContractResult::Ok(SubMsgExecutionResponse {
+            events: vec![Event::new("from_contract")
+                .add_attribute("action", "provide_liquidity")
+                .add_attribute("share", "69")],
+            data: None,
+        }),
+    };
+    reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+
+    let position = POSITION.load(deps.as_ref().storage, &Addr::unchecked("alice")).unwrap();
+    assert_eq!(
+        position.unlocked_assets,
+        AssetList::from(vec![Asset::cw20(config.primary_pair.liquidity_token, 69u128)])
+    );
+
+    // withdraw liquidity: the primary asset's amount should be parsed from the event log exactly,
+    // so no mismatch is reported
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::WithdrawLiquidity {
+            user_addr: Addr::unchecked("alice"),
+        },
+    )
+    .unwrap();
+    deps.querier.set_base_balances(
+        MOCK_CONTRACT_ADDR,
+        &[Coin::new(99, "uxyz"), Coin::new(199, "uusd")],
+    );
+    let reply_msg = Reply {
+        id: res.messages[0].id,
+        result: ContractResult::Ok(SubMsgExecutionResponse {
+            events: vec![Event::new("from_contract")
+                .add_attribute("action", "withdraw_liquidity")
+                .add_attribute("refund_assets", "99uxyz, 199uusd")],
+            data: None,
+        }),
+    };
+    let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+    assert!(res.events.is_empty());
+
+    let position = POSITION.load(deps.as_ref().storage, &Addr::unchecked("alice")).unwrap();
+    assert_eq!(
+        position.unlocked_assets,
+        AssetList::from(vec![Asset::native("uxyz", 99u128), Asset::native("uusd", 199u128)])
+    );
+}
+
+#[test]
+fn grossing_up_borrow_for_tax() {
+    let mut deps = setup_test();
+
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.borrow_tax_rate = Decimal::permille(5);
+            Ok(config)
+        })
+        .unwrap();
+
+    // alice needs 995 uusd; 1000 uusd should be borrowed so that 995 uusd is received after tax
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::Borrow {
+            user_addr: Addr::unchecked("alice"),
+            borrow_amount: Uint128::new(995),
+        },
+    )
+    .unwrap();
+    assert_eq!(attr_value(&res, "secondary_borrowed"), "1000");
+    assert_eq!(attr_value(&res, "secondary_received"), "995");
+    assert_eq!(attr_value(&res, "debt_units_added"), "1000000000");
+
+    let position = POSITION.load(deps.as_ref().storage, &Addr::unchecked("alice")).unwrap();
+    assert_eq!(position.unlocked_assets, AssetList::from(vec![Asset::native("uusd", 995u128)]));
+
+    // amounts that do not divide evenly are rounded such that the net amount is never short
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::Borrow {
+            user_addr: Addr::unchecked("bob"),
+            borrow_amount: Uint128::new(1000),
+        },
+    )
+    .unwrap();
+    assert_eq!(attr_value(&res, "secondary_borrowed"), "1005");
+    assert_eq!(attr_value(&res, "secondary_received"), "1000");
+}
+
+#[test]
+fn seeding_units_with_configured_values() {
+    let mut deps = mock_dependencies();
+    set_pair_infos(&mut deps, PairType::Xyk);
+
+    let mut config = mock_config();
+    config.bond_units_per_share = Uint128::new(1_000);
+    config.debt_units_per_asset = Uint128::new(10_000_000_000);
+    let msg = InstantiateMsg {
+        config: config.into(),
+        limits: None,
+    };
+    instantiate(deps.as_mut(), mock_env(), mock_info("deployer", &[]), msg).unwrap();
+    deps.querier.set_reward_info("uluna_uusd_lp_token", "astro_token", None);
+    deps.querier.set_red_bank_market(&AssetInfo::native("uusd"));
+    set_pool_and_prices(&mut deps);
+
+    // nothing is bonded or borrowed yet, so the first bond and borrow mint units at the seeds
+    POSITION
+        .save(
+            deps.as_mut().storage,
+            &Addr::unchecked("alice"),
+            &Position {
+                unlocked_assets: AssetList::from(vec![Asset::cw20(
+                    Addr::unchecked("uluna_uusd_lp_token"),
+                    69u128,
+                )]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::Bond {
+            user_addr: Some(Addr::unchecked("alice")),
+        },
+    )
+    .unwrap();
+    assert_eq!(attr_value(&res, "bond_units_added"), "69000");
+
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::Borrow {
+            user_addr: Addr::unchecked("alice"),
+            borrow_amount: Uint128::new(420),
+        },
+    )
+    .unwrap();
+    assert_eq!(attr_value(&res, "debt_units_added"), "4200000000000");
+
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state.total_bond_units, Uint128::new(69_000));
+    assert_eq!(state.total_debt_units, Uint128::new(4_200_000_000_000));
+}
+
+#[test]
+fn asserting_red_bank_liquidity() {
+    let mut deps = setup_test();
+
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.assert_liquidity = true;
+            Ok(config)
+        })
+        .unwrap();
+
+    // Red Bank holds 1000 uusd
+    deps.querier.set_base_balances("red_bank", &[Coin::new(1000, "uusd")]);
+    let borrow = |amount: u128| CallbackMsg::Borrow {
+        user_addr: Addr::unchecked("alice"),
+        borrow_amount: Uint128::new(amount),
+    };
+
+    let res = execute_callback(&mut deps, borrow(1001));
+    assert_generic_error_message(
+        res,
+        "insufficient red bank liquidity: requested 1001, available 1000",
+    );
+
+    let res = execute_callback(&mut deps, borrow(1000)).unwrap();
+    assert_eq!(attr_value(&res, "secondary_borrowed"), "1000");
+}
+
+#[test]
+fn capping_debt_per_user() {
+    let mut deps = setup_test();
+
+    // alice owes 500,000 uusd, and may owe at most 501,000 uusd
+    setup_leveraged_position(&mut deps, 500_000);
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.max_debt_per_user = Some(Uint128::new(501_000));
+            Ok(config)
+        })
+        .unwrap();
+
+    // an update borrowing more than the cap allows should fail as a whole; the simulation runs the
+    // same check
+    let msg = QueryMsg::SimulateUpdatePosition {
+        user: Some("alice".to_string()),
+        actions: vec![Action::Borrow {
+            amount: Uint128::new(1001),
+        }],
+    };
+    let err = query(deps.as_ref(), mock_env(), msg);
+    assert_generic_error_message(err, "max debt per user exceeded: 501001; must be <= 501000");
+
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::Borrow {
+            user_addr: Addr::unchecked("alice"),
+            borrow_amount: Uint128::new(1001),
+        },
+    );
+    assert_generic_error_message(res, "max debt per user exceeded: 501001; must be <= 501000");
+
+    // borrowing up to exactly the cap is allowed
+    execute_callback(
+        &mut deps,
+        CallbackMsg::Borrow {
+            user_addr: Addr::unchecked("alice"),
+            borrow_amount: Uint128::new(1000),
+        },
+    )
+    .unwrap();
+    deps.querier.set_user_debt(MOCK_CONTRACT_ADDR, &AssetInfo::native("uusd"), 501_000);
+
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::Borrow {
+            user_addr: Addr::unchecked("alice"),
+            borrow_amount: Uint128::new(1),
+        },
+    );
+    assert_generic_error_message(res, "max debt per user exceeded: 501001; must be <= 501000");
+
+    // the cap applies to each user separately
+    execute_callback(
+        &mut deps,
+        CallbackMsg::Borrow {
+            user_addr: Addr::unchecked("bob"),
+            borrow_amount: Uint128::new(1000),
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn reconciling_debt_with_red_bank() {
+    let mut deps = setup_test();
+
+    let query_reconcile = |deps: &OwnedDeps<_, _, _>| -> ReconcileResponse {
+        from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::Reconcile {}).unwrap()).unwrap()
+    };
+
+    // alice holds all of the debt units, so her debt accounts for all that Red Bank reports
+    setup_leveraged_position(&mut deps, 1_200_000);
+    assert_eq!(
+        query_reconcile(&deps),
+        ReconcileResponse {
+            red_bank_debt: Uint128::new(1_200_000),
+            internal_debt: Uint128::new(1_200_000),
+            delta: Uint128::zero(),
+            collateral_enabled: false,
+        }
+    );
+
+    // the total debt units exceed those held by positions, so Red Bank reports more debt than the
+    // positions owe: 1,200,000 * 1,200,000,000 / 1,500,000,000 = 960,000
+    let mut state = STATE.load(deps.as_ref().storage).unwrap();
+    state.total_debt_units = Uint128::new(1_500_000_000);
+    STATE.save(deps.as_mut().storage, &state).unwrap();
+    deps.querier.set_user_collateral(MOCK_CONTRACT_ADDR, &AssetInfo::native("uusd"));
+    assert_eq!(
+        query_reconcile(&deps),
+        ReconcileResponse {
+            red_bank_debt: Uint128::new(1_200_000),
+            internal_debt: Uint128::new(960_000),
+            delta: Uint128::new(240_000),
+            collateral_enabled: true,
+        }
+    );
+}
+
+#[test]
+fn querying_max_borrow() {
+    let query_max_borrow = |deps: &OwnedDeps<_, _, _>| -> Uint128 {
+        let msg = QueryMsg::MaxBorrow {
+            user: "alice".to_string(),
+        };
+        from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap()
+    };
+
+    // alice's bond is worth 2,000,000 uusd, so at 65% max LTV she can owe up to 1,300,000 uusd
+    for (debt_amount, expected) in [
+        (0, 1_300_000),
+        (1_000_000, 300_000),
+        (1_299_999, 1),
+        (1_300_000, 0),
+        (1_500_000, 0),
+    ] {
+        let mut deps = setup_test();
+        setup_leveraged_position(&mut deps, debt_amount);
+        assert_eq!(query_max_borrow(&deps), Uint128::new(expected));
+    }
+
+    // with a borrow tax, the room of 300,000 uusd in debt is net of tax
+    let mut deps = setup_test();
+    setup_leveraged_position(&mut deps, 1_000_000);
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.borrow_tax_rate = Decimal::percent(10);
+            Ok(config)
+        })
+        .unwrap();
+    assert_eq!(query_max_borrow(&deps), Uint128::new(270_000));
+
+    // the per-user debt ceiling also limits the amount
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.borrow_tax_rate = Decimal::zero();
+            config.max_debt_per_user = Some(Uint128::new(1_100_000));
+            Ok(config)
+        })
+        .unwrap();
+    assert_eq!(query_max_borrow(&deps), Uint128::new(100_000));
+
+    // a user without a position can't borrow
+    let msg = QueryMsg::MaxBorrow {
+        user: "bob".to_string(),
+    };
+    let res: Uint128 = from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+    assert_eq!(res, Uint128::zero());
+}
+
+#[test]
+fn querying_health() {
+    let query_health = |deps: &OwnedDeps<_, _, _>, user: Option<&str>| -> HealthResponse {
+        let msg = QueryMsg::Health {
+            user: user.map(String::from),
+        };
+        from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap()
+    };
+
+    // neither a user without a position nor an empty strategy has health to compute. no price is
+    // set in the oracle yet, so this also verifies that no price is queried
+    let mut deps = setup_test();
+    assert_eq!(query_health(&deps, Some("alice")), HealthResponse::default());
+    assert_eq!(query_health(&deps, None), HealthResponse::default());
+
+    // alice's bond is worth 2,000,000 uusd and she owes 1,000,000 uusd
+    setup_leveraged_position(&mut deps, 1_000_000);
+    let expected = HealthResponse {
+        bond_amount: Uint128::new(100_000),
+        bond_value: Uint128::new(2_000_000),
+        debt_amount: Uint128::new(1_000_000),
+        debt_value: Uint128::new(1_000_000),
+        ltv: Some(Decimal::percent(50)),
+    };
+    assert_eq!(query_health(&deps, Some("alice")), expected);
+    assert_eq!(query_health(&deps, Some("bob")), HealthResponse::default());
+
+    // alice holds all of the bond and debt units, so the strategy's health is the same as hers
+    assert_eq!(query_health(&deps, None), expected);
+}
+
+#[test]
+fn querying_liquidation_profit() {
+    let query_profit = |debt_amount: u128| -> LiquidationProfitResponse {
+        let mut deps = setup_test();
+        setup_leveraged_position(&mut deps, debt_amount);
+        let msg = QueryMsg::LiquidationProfit {
+            user: "alice".to_string(),
+        };
+        from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap()
+    };
+
+    // LTV = 50%; not liquidatable
+    let res = query_profit(1_000_000);
+    assert!(!res.liquidatable);
+    assert_eq!(res.profit_value, Uint128::zero());
+
+    // LTV = 90%; selling ~87,000 uluna covers the debt, leaving ~13,000 uluna of which 1% is paid
+    // to the liquidator
+    let res = query_profit(1_800_000);
+    assert!(res.liquidatable);
+    assert!(res.swap_cost_value > Uint128::zero());
+    assert!(res.bonus_value > Uint128::zero());
+    assert_eq!(res.incentive_value, Uint128::zero());
+    assert_eq!(res.profit_value, res.bonus_value);
+
+    // LTV = 99.5%; selling all primary asset is not enough to cover the debt, so nothing remains
+    let res = query_profit(1_990_000);
+    assert!(res.liquidatable);
+    assert_eq!(res.bonus_value, Uint128::zero());
+    assert_eq!(res.profit_value, Uint128::zero());
+}
+
+#[test]
+fn migrating_from_old_storage() {
+    let mut deps = mock_dependencies();
+    set_pair_infos(&mut deps, PairType::Xyk);
+
+    // write config and state in the layout of v1.0.0, which lacks the fields added since then,
+    // and no contract version info
+    let old_config = r#"{
+        "primary_asset_info":{"native":"uluna"},
+        "secondary_asset_info":{"native":"uusd"},
+        "astro_token_info":{"cw20":"astro_token"},
+        "primary_pair":{"contract_addr":"uluna_uusd_pair","liquidity_token":"uluna_uusd_lp_token"},
+        "astro_pair":{"contract_addr":"astro_uusd_pair","liquidity_token":"astro_uusd_lp_token"},
+        "astro_generator":{"contract_addr":"astro_generator"},
+        "red_bank":{"contract_addr":"red_bank"},
+        "oracle":{"contract_addr":"oracle"},
+        "treasury":"treasury",
+        "governance":"governance",
+        "operators":["operator"],
+        "max_ltv":"0.65",
+        "fee_rate":"0.05",
+        "bonus_rate":"0.01"
+    }"#;
+    let old_state = r#"{"total_bond_units":"100","total_debt_units":"50","pending_rewards":[]}"#;
+    let old_position = r#"{"bond_units":"100","debt_units":"50","unlocked_assets":[]}"#;
+    deps.storage.set(b"config", old_config.as_bytes());
+    deps.storage.set(b"state", old_state.as_bytes());
+    deps.storage.set(&POSITION.key(&Addr::unchecked("alice")), old_position.as_bytes());
+
+    let msg = MigrateMsg {
+        new_config: None,
+    };
+    let res = migrate(deps.as_mut(), mock_env(), msg.clone()).unwrap();
+    assert_eq!(attr_value(&res, "from_version"), "0.0.0");
+
+    let config = CONFIG.load(deps.as_ref().storage).unwrap();
+    assert_eq!(config, mock_config());
+
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state.total_bond_units, Uint128::new(100));
+    assert_eq!(state.total_debt_units, Uint128::new(50));
+    assert_eq!(state.accumulated_astro, Uint128::zero());
+    assert_eq!(state.reward_index, Decimal::one());
+    assert_eq!(state.reward_epoch, 0);
+
+    // positions written by the old version start from the same reward index as the state
+    let position = POSITION.load(deps.as_ref().storage, &Addr::unchecked("alice")).unwrap();
+    assert_eq!(position.bond_units, Uint128::new(100));
+    assert_eq!(position.reward_index, state.reward_index);
+    assert_eq!(position.entering_bond_units, Uint128::zero());
+
+    let version = get_contract_version(deps.as_ref().storage).unwrap();
+    assert_eq!(version.contract, "crates.io:martian-field");
+    assert_eq!(version.version, env!("CARGO_PKG_VERSION"));
+
+    // migrating again to the same version is a no-op, apart from the optional config update
+    let mut new_config = mock_config();
+    new_config.max_ltv = Decimal::from_ratio(70u128, 100u128);
+    let msg_with_config = MigrateMsg {
+        new_config: Some(new_config.clone().into()),
+    };
+    migrate(deps.as_mut(), mock_env(), msg_with_config).unwrap();
+    assert_eq!(CONFIG.load(deps.as_ref().storage).unwrap(), new_config);
+    assert_eq!(STATE.load(deps.as_ref().storage).unwrap(), state);
+
+    // downgrading should fail
+    set_contract_version(deps.as_mut().storage, "crates.io:martian-field", "99.0.0").unwrap();
+    let res = migrate(deps.as_mut(), mock_env(), msg.clone());
+    assert_generic_error_message(
+        res,
+        &format!(
+            "cannot migrate from version 99.0.0 to older version {}",
+            env!("CARGO_PKG_VERSION")
+        ),
+    );
+
+    // migrating from a different contract should fail
+    set_contract_version(deps.as_mut().storage, "crates.io:other-contract", "1.0.0").unwrap();
+    let res = migrate(deps.as_mut(), mock_env(), msg);
+    assert_generic_error_message(
+        res,
+        "cannot migrate from a different contract: crates.io:other-contract",
+    );
+}
+
+#[test]
+fn querying_pending_rewards() {
+    let mut deps = setup_test();
+    let query_pending_rewards = |deps: &OwnedDeps<_, _, _>| -> Vec<PendingRewardsResponseItem> {
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::PendingRewards {}).unwrap();
+        from_binary(&res).unwrap()
+    };
+
+    // no rewards pending
+    assert_eq!(query_pending_rewards(&deps), vec![]);
+
+    // the oracle has a price for ASTRO but not for the proxy reward token
+    deps.querier
+        .set_oracle_price(&AssetInfo::cw20(Addr::unchecked("astro_token")), Decimal::percent(150));
+    let mut state = STATE.load(deps.as_ref().storage).unwrap();
+    state.pending_rewards = AssetList::from(vec![
+        Asset::cw20(Addr::unchecked("astro_token"), 1000u128),
+        Asset::cw20(Addr::unchecked("proxy_token"), 500u128),
+    ]);
+    STATE.save(deps.as_mut().storage, &state).unwrap();
+
+    assert_eq!(
+        query_pending_rewards(&deps),
+        vec![
+            PendingRewardsResponseItem {
+                asset: Asset::cw20(Addr::unchecked("astro_token"), 1000u128).into(),
+                value: Some(Uint128::new(1500)),
+            },
+            PendingRewardsResponseItem {
+                asset: Asset::cw20(Addr::unchecked("proxy_token"), 500u128).into(),
+                value: None,
+            },
+        ]
+    );
+}
+
+#[test]
+fn retaining_rewards_below_swap_threshold() {
+    let mut deps = setup_test();
+
+    // configure a threshold for ASTRO above the amount to be claimed on each harvest
+    deps.querier.set_pending_tokens("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 10_000, None);
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.reward_swap_thresholds =
+                vec![(AssetInfo::cw20(Addr::unchecked("astro_token")), Uint128::new(15_000))];
+            Ok(config)
+        })
+        .unwrap();
+
+    let msg = ExecuteMsg::Harvest {
+        max_spread: None,
+        slippage_tolerance: None,
+        min_lp_out: None,
+    };
+
+    // ASTRO is below the threshold, so it should be retained in pending rewards without being
+    // swapped, and nothing is reinvested
+    let res = execute(deps.as_mut(), mock_env(), mock_info("operator", &[]), msg.clone()).unwrap();
+    assert_eq!(res.messages.len(), 3); // claim rewards + transfer fee + release lock
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(
+        state.pending_rewards,
+        AssetList::from(vec![Asset::cw20(Addr::unchecked("astro_token"), 9500u128)])
+    );
+    release_lock(&mut deps);
+
+    // on the next harvest, the accumulated ASTRO exceeds the threshold and should be swapped. fees
+    // should only be charged on the newly claimed amount
+    let res = execute(deps.as_mut(), mock_env(), mock_info("operator", &[]), msg).unwrap();
+    let fees = res.events[0].attributes.iter().find(|attr| attr.key == "fees").unwrap();
+    assert_eq!(fees.value, "cw20:astro_token:500");
+    let swap_msg = CallbackMsg::Swap {
+        user_addr: None,
+        offer_asset_info: AssetInfo::cw20(Addr::unchecked("astro_token")),
+        offer_amount: Some(Uint128::new(19_000)),
+        max_spread: None,
+        min_return: None,
+    }
+    .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+    .unwrap();
+    assert!(res.messages.iter().any(|submsg| submsg.msg == swap_msg));
+}
+
+#[test]
+fn charging_fees_on_rewards_withdrawn_during_bond() {
+    let mut deps = setup_test();
+
+    // the generator automatically withdraws 10,000 uASTRO of pending rewards when alice bonds
+    deps.querier.set_pending_tokens("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 10_000, None);
+    let alice = Addr::unchecked("alice");
+    let position = Position {
+        unlocked_assets: AssetList::from(vec![Asset::cw20(
+            Addr::unchecked("uluna_uusd_lp_token"),
+            69u128,
+        )]),
+        ..Default::default()
+    };
+    POSITION.save(deps.as_mut().storage, &alice, &position).unwrap();
+    let callback = CallbackMsg::Bond {
+        user_addr: Some(alice),
+    };
+    execute_callback(&mut deps, callback).unwrap();
+
+    let astro_rewards =
+        AssetList::from(vec![Asset::cw20(Addr::unchecked("astro_token"), 10_000u128)]);
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state.pending_rewards, astro_rewards);
+    assert_eq!(state.uncharged_rewards, astro_rewards);
+
+    // nothing is left to claim at the next harvest, but the withdrawn rewards are charged the
+    // fee all the same, and only once
+    deps.querier.set_pending_tokens("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 0, None);
+    let msg = ExecuteMsg::Harvest {
+        max_spread: None,
+        slippage_tolerance: None,
+        min_lp_out: None,
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("operator", &[]), msg).unwrap();
+    let fees = res.events[0].attributes.iter().find(|attr| attr.key == "fees").unwrap();
+    assert_eq!(fees.value, "cw20:astro_token:500");
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state.uncharged_rewards, AssetList::default());
+    assert_eq!(
+        state.pending_rewards,
+        AssetList::from(vec![Asset::cw20(Addr::unchecked("astro_token"), 9_500u128)])
+    );
+}
+
+#[test]
+fn swapping_astro_along_route() {
+    let mut deps = setup_test();
+
+    // ASTRO is swapped to uusd through ukrw
+    let route = vec![
+        Pair {
+            contract_addr: Addr::unchecked("astro_ukrw_pair"),
+            liquidity_token: Addr::unchecked("astro_ukrw_lp_token"),
+            pair_type: PairType::Xyk,
+        },
+        Pair {
+            contract_addr: Addr::unchecked("ukrw_uusd_pair"),
+            liquidity_token: Addr::unchecked("ukrw_uusd_lp_token"),
+            pair_type: PairType::Stable,
+        },
+    ];
+    let mut config = CONFIG.load(deps.as_ref().storage).unwrap();
+    config.astro_route = route.clone();
+    CONFIG.save(deps.as_mut().storage, &config).unwrap();
+
+    let mut state = STATE.load(deps.as_ref().storage).unwrap();
+    state.pending_rewards = AssetList::from(vec![
+        Asset::cw20(Addr::unchecked("astro_token"), 10_000u128),
+        Asset::native("ukrw", 123u128),
+    ]);
+    STATE.save(deps.as_mut().storage, &state).unwrap();
+
+    let swap_reply = |ask_asset: &str, return_amount: &str, id: u64| Reply {
+        id,
+        result: ContractResult::Ok(SubMsgExecutionResponse {
+            events: vec![Event::new("from_contract")
+                .add_attribute("action", "swap")
+                .add_attribute("ask_asset", ask_asset)
+                .add_attribute("return_amount", return_amount)
+                .add_attribute("tax_amount", "0")],
+            data: None,
+        }),
+    };
+
+    // the first hop is sent by the swap callback; its reply sends the next hop
+    let callback = CallbackMsg::Swap {
+        user_addr: None,
+        offer_asset_info: AssetInfo::cw20(Addr::unchecked("astro_token")),
+        offer_amount: Some(Uint128::new(10_000)),
+        max_spread: Some(Decimal::percent(2)),
+        min_return: None,
+    };
+    let res = execute_callback(&mut deps, callback).unwrap();
+    assert_eq!(
+        res.messages,
+        replying_always(vec![route[0]
+            .swap_submsg(
+                encode_reply_id(4, 0),
+                &Asset::cw20(Addr::unchecked("astro_token"), 10_000u128),
+                None,
+                Some(Decimal::percent(2))
+            )
+            .unwrap()])
+    );
+
+    // all of the ukrw returned is offered to the last hop, but not the ukrw already pending
+    let id = encode_reply_id(4, 0);
+    let res = reply(deps.as_mut(), mock_env(), swap_reply("ukrw", "5000", id)).unwrap();
+    let offer_asset = Asset::native("ukrw", 5000u128);
+    assert_eq!(
+        res.messages,
+        replying_always(vec![route[1]
+            .swap_submsg(encode_reply_id(2, 1), &offer_asset, None, Some(Decimal::percent(2)))
+            .unwrap()])
+    );
+
+    // the last hop's return is credited to the pending rewards
+    let id = encode_reply_id(2, 1);
+    reply(deps.as_mut(), mock_env(), swap_reply("uusd", "4990", id)).unwrap();
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(
+        state.pending_rewards,
+        AssetList::from(vec![Asset::native("ukrw", 123u128), Asset::native("uusd", 4990u128)])
+    );
+    assert_eq!(CACHED_ROUTE.may_load(deps.as_ref().storage).unwrap(), None);
+}
+
+#[test]
+fn sorting_positions() {
+    let mut deps = setup_test();
+
+    // total bond value is 2,000,000 uusd and total debt is 1,200,000 uusd, and each user has 1/4
+    // of the bond. charlie owes 600,000 uusd (LTV = 120%), alice and dave 300,000 uusd each (LTV =
+    // 60%), and bob has no debt
+    setup_leveraged_position(&mut deps, 1_200_000);
+    STATE
+        .save(
+            deps.as_mut().storage,
+            &State {
+                total_bond_units: Uint128::new(400_000_000),
+                total_debt_units: Uint128::new(1_200_000_000),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    let positions = [
+        ("alice", 300_000_000u128),
+        ("bob", 0),
+        ("charlie", 600_000_000),
+        ("dave", 300_000_000),
+    ];
+    for (user, debt_units) in positions {
+        let position = Position {
+            bond_units: Uint128::new(100_000_000),
+            debt_units: Uint128::new(debt_units),
+            ..Default::default()
+        };
+        POSITION.save(deps.as_mut().storage, &Addr::unchecked(user), &position).unwrap();
+    }
+
+    let query_users = |start_after: Option<&str>, limit: Option<u32>| -> Vec<String> {
+        let msg = QueryMsg::Positions {
+            start_after: start_after.map(String::from),
+            limit,
+            order_by: Some(PositionOrder::LtvDesc),
+        };
+        let items: Vec<PositionsResponseItem> =
+            from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        items.into_iter().map(|item| item.user).collect()
+    };
+
+    // highest LTV first; alice and dave tie and remain in address order, and bob, who has no debt,
+    // comes last
+    assert_eq!(query_users(None, None), vec!["charlie", "alice", "dave", "bob"]);
+
+    // only the positions within the page are sorted
+    assert_eq!(query_users(None, Some(2)), vec!["alice", "bob"]);
+    assert_eq!(query_users(Some("alice"), Some(2)), vec!["charlie", "bob"]);
+}
+
+#[test]
+fn querying_liquidatable_positions() {
+    let mut deps = setup_test();
+
+    // total bond value is 2,000,000 uusd and total debt is 1,200,000 uusd. alice and charlie each
+    // have 1/3 of the bond and 1/2 of the debt (LTV = 90%), bob has no debt, and dave's position is
+    // closed
+    setup_leveraged_position(&mut deps, 1_200_000);
+    STATE
+        .save(
+            deps.as_mut().storage,
+            &State {
+                total_bond_units: Uint128::new(300_000_000),
+                total_debt_units: Uint128::new(1_200_000_000),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    let positions = [
+        ("alice", 100_000_000u128, 600_000_000u128),
+        ("bob", 100_000_000, 0),
+        ("charlie", 100_000_000, 600_000_000),
+        ("dave", 0, 0),
+    ];
+    for (user, bond_units, debt_units) in positions {
+        let position = Position {
+            bond_units: Uint128::new(bond_units),
+            debt_units: Uint128::new(debt_units),
+            ..Default::default()
+        };
+        POSITION.save(deps.as_mut().storage, &Addr::unchecked(user), &position).unwrap();
+    }
+
+    let query_liquidatable =
+        |start_after: Option<&str>, limit: Option<u32>| -> Vec<LiquidatablePositionsResponseItem> {
+            let msg = QueryMsg::LiquidatablePositions {
+                start_after: start_after.map(String::from),
+                limit,
+            };
+            from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap()
+        };
+    let expected_item = |user: &str| LiquidatablePositionsResponseItem {
+        user: user.to_string(),
+        ltv: Decimal::from_ratio(600_000u128, 666_666u128),
+        bond_value: Uint128::new(666_666),
+        debt_value: Uint128::new(600_000),
+    };
+
+    assert_eq!(
+        query_liquidatable(None, None),
+        vec![expected_item("alice"), expected_item("charlie")]
+    );
+    assert_eq!(query_liquidatable(None, Some(1)), vec![expected_item("alice")]);
+    assert_eq!(query_liquidatable(Some("alice"), None), vec![expected_item("charlie")]);
+    assert_eq!(query_liquidatable(Some("charlie"), None), vec![]);
+}
+
+#[test]
+fn querying_state_snapshot() {
+    let mut deps = setup_test();
+    setup_leveraged_position(&mut deps, 1_000_000);
+
+    let mut state = STATE.load(deps.as_ref().storage).unwrap();
+    state.pending_rewards =
+        AssetList::from(vec![Asset::cw20(Addr::unchecked("astro_token"), 1000u128)]);
+    STATE.save(deps.as_mut().storage, &state).unwrap();
+
+    let query_snapshot = |deps: &OwnedDeps<_, _, _>| -> StateSnapshotResponse {
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::StateSnapshot {}).unwrap();
+        from_binary(&res).unwrap()
+    };
+
+    let snapshot = query_snapshot(&deps);
+    assert_eq!(snapshot.snapshot_version, 2);
+    assert_eq!(snapshot.height, mock_env().block.height);
+    assert_eq!(snapshot.time, mock_env().block.time.seconds());
+    assert_eq!(snapshot.config, mock_config().into());
+    assert_eq!(snapshot.total_bond_units, Uint128::new(100_000_000));
+    assert_eq!(snapshot.total_debt_units, Uint128::new(1_000_000_000));
+    assert_eq!(snapshot.pending_rewards, state.pending_rewards.clone().into());
+    assert_eq!(snapshot.total_bad_debt, Uint128::zero());
+
+    // aggregate health should be the same as returned by the `State` query
+    let res = query(deps.as_ref(), mock_env(), QueryMsg::State {}).unwrap();
+    let state_health: PositionResponse = from_binary(&res).unwrap();
+    assert_eq!(snapshot.health, state_health);
+    assert_eq!(snapshot.health.bond_value, Uint128::new(2_000_000));
+    assert_eq!(snapshot.health.debt_value, Uint128::new(1_000_000));
+    assert_eq!(snapshot.health.ltv, Some(Decimal::percent(50)));
+
+    // waiving the position's debt should be reflected in the bad debt total. debt is only waived
+    // once the position has no liquidity tokens left bonded
+    POSITION
+        .update(deps.as_mut().storage, &Addr::unchecked("alice"), |position| -> StdResult<_> {
+            Ok(Position {
+                bond_units: Uint128::zero(),
+                ..position.unwrap()
+            })
+        })
+        .unwrap();
+    execute_callback(
+        &mut deps,
+        CallbackMsg::ClearBadDebt {
+            user_addr: Addr::unchecked("alice"),
+        },
+    )
+    .unwrap();
+    let snapshot = query_snapshot(&deps);
+    assert_eq!(snapshot.total_debt_units, Uint128::zero());
+    assert_eq!(snapshot.total_bad_debt, Uint128::new(1_000_000));
+}
+
+#[test]
+fn rejecting_stale_oracle_prices() {
+    let mut deps = setup_test();
+    setup_leveraged_position(&mut deps, 1_000_000);
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.max_price_age = 300;
+            Ok(config)
+        })
+        .unwrap();
+
+    let now = mock_env().block.time.seconds();
+    let query_position = |deps: &OwnedDeps<_, _, _>| {
+        let msg = QueryMsg::Position {
+            user: "alice".to_string(),
+        };
+        query(deps.as_ref(), mock_env(), msg)
+    };
+
+    // prices updated within the max age are accepted
+    deps.querier.set_oracle_price_updated_at(&AssetInfo::native("uluna"), now - 300);
+    deps.querier.set_oracle_price_updated_at(&AssetInfo::native("uusd"), now - 10);
+    assert!(query_position(&deps).is_ok());
+
+    // a stale price should cause health computation, and therefore liquidation, to fail
+    deps.querier.set_oracle_price_updated_at(&AssetInfo::native("uluna"), now - 301);
+    assert_generic_error_message(
+        query_position(&deps),
+        "stale price for native:uluna: last updated 301 seconds ago; max age is 300 seconds",
+    );
+
+    let msg = ExecuteMsg::Liquidate {
+        user: "alice".to_string(),
+        max_bond_units: None,
+        max_spread: None,
+        bonus_asset: None,
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("liquidator", &[]), msg);
+    assert_generic_error_message(
+        res,
+        "stale price for native:uluna: last updated 301 seconds ago; max age is 300 seconds",
+    );
+
+    // balancing pending rewards during harvest should also fail
+    let mut state = STATE.load(deps.as_ref().storage).unwrap();
+    state.pending_rewards = AssetList::from(vec![Asset::native("uluna", 1000u128)]);
+    STATE.save(deps.as_mut().storage, &state).unwrap();
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::Balance {
+            user_addr: None,
+            max_spread: None,
+        },
+    );
+    assert_generic_error_message(
+        res,
+        "stale price for native:uluna: last updated 301 seconds ago; max age is 300 seconds",
+    );
+}
+
+#[test]
+fn enforcing_min_secondary_from_swap() {
+    let mut deps = setup_test();
+    let position = Position {
+        unlocked_assets: AssetList::from(vec![Asset::native("uluna", 1000u128)]),
+        ..Default::default()
+    };
+    POSITION.save(deps.as_mut().storage, &Addr::unchecked("alice"), &position).unwrap();
+
+    // the minimum should be passed on to the swap callback
+    let msg = ExecuteMsg::UpdatePosition(vec![Action::Swap {
+        offer_amount: Uint128::new(1000),
+        max_spread: None,
+        min_secondary_from_swap: Some(Uint128::new(9000)),
+    }]);
+    let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+    let swap_msg = CallbackMsg::Swap {
+        user_addr: Some(Addr::unchecked("alice")),
+        offer_asset_info: AssetInfo::native("uluna"),
+        offer_amount: Some(Uint128::new(1000)),
+        max_spread: None,
+        min_return: Some(Uint128::new(9000)),
+    };
+    assert_eq!(
+        res.messages[0].msg,
+        swap_msg.clone().into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR)).unwrap()
+    );
+
+    let res = execute_callback(&mut deps, swap_msg).unwrap();
+    assert_eq!(CACHED_MIN_RETURN.load(deps.as_ref().storage).unwrap(), Uint128::new(9000));
+
+    let id = res.messages[0].id;
+    let swap_reply = |return_amount: &str| Reply {
+        id,
+        result: ContractResult::Ok(SubMsgExecutionResponse {
+            events: vec![Event::new("from_contract")
+                .add_attribute("action", "swap")
+                .add_attribute("ask_asset", "uusd")
+                .add_attribute("return_amount", return_amount)
+                .add_attribute("tax_amount", "0")],
+            data: None,
+        }),
+    };
+
+    // a front-runner skews the pool so that the swap returns less than the minimum. the reply
+    // should fail, reverting the whole transaction
+    let res = reply(deps.as_mut(), mock_env(), swap_reply("8500"));
+    assert_generic_error_message(res, "insufficient swap return: 8500; minimum 9000");
+
+    // a swap returning at least the minimum should be accepted
+    reply(deps.as_mut(), mock_env(), swap_reply("9000")).unwrap();
+    assert_eq!(CACHED_MIN_RETURN.may_load(deps.as_ref().storage).unwrap(), None);
+    let position = POSITION.load(deps.as_ref().storage, &Addr::unchecked("alice")).unwrap();
+    assert_eq!(
+        position.unlocked_assets.find(&AssetInfo::native("uusd")),
+        Some(&Asset::native("uusd", 9000u128))
+    );
+}
+
+#[test]
+fn applying_default_slippage_tolerance() {
+    let mut deps = setup_test();
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.default_slippage_tolerance = Decimal::percent(2);
+            Ok(config)
+        })
+        .unwrap();
+
+    // the pool is skewed away from the oracle price, which a sandwich attack could exploit
+    deps.querier.set_pool(
+        "uluna_uusd_pair",
+        &[Asset::native("uluna", 1_000_000u128), Asset::native("uusd", 12_000_000u128)],
+        1_000_000,
+    );
+
+    let provide = |deps: &mut OwnedDeps<_, _, _>, slippage_tolerance: Option<Decimal>| {
+        let mut state = STATE.load(deps.as_ref().storage).unwrap();
+        state.pending_rewards = AssetList::from(vec![
+            Asset::native("uluna", 1000u128),
+            Asset::native("uusd", 10_000u128),
+        ]);
+        STATE.save(deps.as_mut().storage, &state).unwrap();
+
+        let callback_msg = CallbackMsg::ProvideLiquidity {
+            user_addr: None,
+            slippage_tolerance,
+            min_lp_out: None,
+        };
+        execute_callback(deps, callback_msg).unwrap().messages
+    };
+    let expected_submsgs = |nonce: u64, slippage_tolerance: Decimal| {
+        replying_always(
+            mock_config()
+                .primary_pair
+                .provide_submsgs(
+                    encode_reply_id(0, nonce),
+                    &[Asset::native("uluna", 1000u128), Asset::native("uusd", 10_000u128)],
+                    Some(slippage_tolerance),
+                )
+                .unwrap(),
+        )
+    };
+
+    // when harvesting, the operator does not specify a slippage tolerance; the default should be
+    // used when providing liquidity
+    assert_eq!(provide(&mut deps, None), expected_submsgs(0, Decimal::percent(2)));
+
+    // a slippage tolerance specified by the caller takes precedence
+    assert_eq!(
+        provide(&mut deps, Some(Decimal::percent(1))),
+        expected_submsgs(1, Decimal::percent(1))
+    );
+}
+
+#[test]
+fn capping_caller_supplied_spreads() {
+    let mut deps = setup_test();
+    deps.querier.set_pending_tokens("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 10_000, None);
+
+    let mut config = CONFIG.load(deps.as_ref().storage).unwrap();
+    config.max_allowed_spread = Decimal::percent(2);
+    CONFIG.save(deps.as_mut().storage, &config).unwrap();
+
+    // a spread within the cap is passed through to the callback
+    let msg = ExecuteMsg::UpdatePosition(vec![Action::Swap {
+        offer_amount: Uint128::new(1000),
+        max_spread: Some(Decimal::percent(2)),
+        min_secondary_from_swap: None,
+    }]);
+    let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+    let callback = CallbackMsg::Swap {
+        user_addr: Some(Addr::unchecked("alice")),
+        offer_asset_info: AssetInfo::native("uluna"),
+        offer_amount: Some(Uint128::new(1000)),
+        max_spread: Some(Decimal::percent(2)),
+        min_return: None,
+    };
+    assert_eq!(
+        res.messages[0].msg,
+        callback.into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR)).unwrap()
+    );
+    release_lock(&mut deps);
+
+    // spreads and slippage tolerances above the cap are rejected
+    let msg = ExecuteMsg::UpdatePosition(vec![Action::Swap {
+        offer_amount: Uint128::new(1000),
+        max_spread: Some(Decimal::percent(3)),
+        min_secondary_from_swap: None,
+    }]);
+    let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg);
+    assert_generic_error_message(res, "max spread too large: 0.03; must be <= 0.02");
+
+    let msg = ExecuteMsg::UpdatePosition(vec![Action::Bond {
+        slippage_tolerance: Some(Decimal::one()),
+    }]);
+    let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg);
+    assert_generic_error_message(res, "slippage tolerance too large: 1; must be <= 0.02");
+
+    let harvest_msg = |max_spread: u64, slippage_tolerance: u64| ExecuteMsg::Harvest {
+        max_spread: Some(Decimal::percent(max_spread)),
+        slippage_tolerance: Some(Decimal::percent(slippage_tolerance)),
+        min_lp_out: None,
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("operator", &[]), harvest_msg(5, 1));
+    assert_generic_error_message(res, "max spread too large: 0.05; must be <= 0.02");
+    let res = execute(deps.as_mut(), mock_env(), mock_info("operator", &[]), harvest_msg(1, 5));
+    assert_generic_error_message(res, "slippage tolerance too large: 0.05; must be <= 0.02");
+    execute(deps.as_mut(), mock_env(), mock_info("operator", &[]), harvest_msg(2, 2)).unwrap();
+}
+
+#[test]
+fn enforcing_harvest_cooldown() {
+    let mut deps = setup_test();
+    deps.querier.set_pending_tokens("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 10_000, None);
+
+    let mut config = CONFIG.load(deps.as_ref().storage).unwrap();
+    config.harvest_cooldown = 3600;
+    CONFIG.save(deps.as_mut().storage, &config).unwrap();
+
+    let msg = ExecuteMsg::Harvest {
+        max_spread: None,
+        slippage_tolerance: None,
+        min_lp_out: None,
+    };
+    let harvest = |deps: &mut OwnedDeps<_, _, _>, seconds_later: u64| {
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(seconds_later);
+        let res = execute(deps.as_mut(), env, mock_info("operator", &[]), msg.clone());
+        release_lock(deps);
+        res
+    };
+
+    // the first harvest starts the cooldown
+    harvest(&mut deps, 0).unwrap();
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state.last_harvest_time, mock_env().block.time.seconds());
+
+    // a harvest within the cooldown reverts
+    let res = harvest(&mut deps, 3599);
+    assert_generic_error_message(res, "harvest is on cooldown: 1 seconds remaining");
+
+    // once the cooldown has passed, harvesting succeeds and restarts the cooldown
+    harvest(&mut deps, 3600).unwrap();
+    let res = harvest(&mut deps, 3700);
+    assert_generic_error_message(res, "harvest is on cooldown: 3500 seconds remaining");
+
+    // shortening the cooldown via a config update takes effect immediately
+    config.harvest_cooldown = 60;
+    let msg = ExecuteMsg::UpdateConfig {
+        new_config: config.into(),
+    };
+    execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), msg).unwrap();
+    harvest(&mut deps, 3700).unwrap();
+}
+
+#[test]
+fn recovering_from_failed_harvest_step() {
+    let mut deps = setup_test();
+    setup_leveraged_position(&mut deps, 0);
+
+    let mut state = STATE.load(deps.as_ref().storage).unwrap();
+    state.pending_rewards = AssetList::from(vec![Asset::native("uusd", 10_000u128)]);
+    STATE.save(deps.as_mut().storage, &state).unwrap();
+
+    // the balancing swap replies even if it fails
+    let balance = CallbackMsg::Balance {
+        user_addr: None,
+        max_spread: None,
+    };
+    let res = execute_callback(&mut deps, balance.clone()).unwrap();
+    assert_eq!(res.messages.len(), 1);
+    assert_eq!(res.messages[0].reply_on, ReplyOn::Always);
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    let remaining = state.pending_rewards.find(&AssetInfo::native("uusd")).unwrap().amount;
+    let offered = 10_000 - remaining.u128();
+
+    // the swap fails. the offered asset is retained in the pending rewards instead of the harvest
+    // being reverted
+    let failed_reply = Reply {
+        id: res.messages[0].id,
+        result: ContractResult::Err("Operation exceeds max spread limit".to_string()),
+    };
+    let res = reply(deps.as_mut(), mock_env(), failed_reply).unwrap();
+    assert_eq!(
+        res.events,
+        vec![Event::new("harvest_step_failed")
+            .add_attribute("step", "swap")
+            .add_attribute("assets_retained", format!("native:uusd:{}", offered))
+            .add_attribute("error", "Operation exceeds max spread limit")]
+    );
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state.pending_rewards, AssetList::from(vec![Asset::native("uusd", 10_000u128)]));
+
+    // the remaining steps of the harvest are skipped, so the harvest completes
+    let provide = CallbackMsg::ProvideLiquidity {
+        user_addr: None,
+        slippage_tolerance: None,
+        min_lp_out: None,
+    };
+    let bond = CallbackMsg::Bond {
+        user_addr: None,
+    };
+    for callback in [provide, bond] {
+        let res = execute_callback(&mut deps, callback).unwrap();
+        assert_eq!(res.messages, vec![]);
+        assert_eq!(attr_value(&res, "skipped"), "harvest_aborted");
+    }
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state.pending_rewards, AssetList::from(vec![Asset::native("uusd", 10_000u128)]));
+
+    // the next harvest reinvests the retained rewards
+    release_lock(&mut deps);
+    let res = execute_callback(&mut deps, balance).unwrap();
+    assert_eq!(res.messages.len(), 1);
+
+    // submessages of users' operations only reply on success, so any other failure is propagated
+    let failed_reply = Reply {
+        id: encode_reply_id(2, 99),
+        result: ContractResult::Err("unexpected failure".to_string()),
+    };
+    let res = reply(deps.as_mut(), mock_env(), failed_reply);
+    assert_generic_error_message(res, "unexpected failure");
+}
+
+#[test]
+fn enforcing_min_lp_out_on_harvest() {
+    let mut deps = setup_test();
+    let mut state = STATE.load(deps.as_ref().storage).unwrap();
+    state.pending_rewards =
+        AssetList::from(vec![Asset::native("uluna", 1000u128), Asset::native("uusd", 10_000u128)]);
+    STATE.save(deps.as_mut().storage, &state).unwrap();
+
+    // the minimum is passed on to the callback providing liquidity
+    let msg = ExecuteMsg::Harvest {
+        max_spread: None,
+        slippage_tolerance: None,
+        min_lp_out: Some(Uint128::new(1000)),
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("operator", &[]), msg).unwrap();
+    let provide_msg = CallbackMsg::ProvideLiquidity {
+        user_addr: None,
+        slippage_tolerance: None,
+        min_lp_out: Some(Uint128::new(1000)),
+    };
+    let provide_cosmos_msg =
+        provide_msg.clone().into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR)).unwrap();
+    assert!(res.messages.iter().any(|submsg| submsg.msg == provide_cosmos_msg));
+
+    execute_callback(&mut deps, provide_msg).unwrap();
+    assert_eq!(CACHED_MIN_LP_OUT.load(deps.as_ref().storage).unwrap(), Uint128::new(1000));
+
+    let provide_reply = |share: &str| Reply {
+        id: 0,
+        result: ContractResult::Ok(SubMsgExecutionResponse {
+            events: vec![Event::new("from_contract")
+                .add_attribute("action", "provide_liquidity")
+                .add_attribute("share", share)],
+            data: None,
+        }),
+    };
+
+    // a sandwicher manipulates the pool so that the provision mints too few liquidity tokens. the
+    // reply should fail, reverting the whole harvest
+    let res = reply(deps.as_mut(), mock_env(), provide_reply("900"));
+    assert_generic_error_message(res, "insufficient liquidity tokens minted: 900; minimum 1000");
+
+    // a provision minting at least the minimum should be accepted
+    reply(deps.as_mut(), mock_env(), provide_reply("1000")).unwrap();
+    assert_eq!(CACHED_MIN_LP_OUT.may_load(deps.as_ref().storage).unwrap(), None);
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(
+        state.pending_rewards,
+        AssetList::from(vec![Asset::cw20(Addr::unchecked("uluna_uusd_lp_token"), 1000u128)])
+    );
+}
+
+#[test]
+fn preventing_liquidation_in_opening_block() {
+    let mut deps = setup_test();
+    let alice = Addr::unchecked("alice");
+    let env = mock_env();
+
+    // opening a position records the block height
+    let msg = ExecuteMsg::UpdatePosition(vec![Action::Deposit(
+        Asset::native("uusd", 100u128).into(),
+    )]);
+    let deposits = vec![Coin::new(100, "uusd")];
+    execute(deps.as_mut(), env.clone(), mock_info("alice", &deposits), msg).unwrap();
+    let position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    assert_eq!(position.opened_at_height, env.block.height);
+    release_lock(&mut deps);
+
+    // alice's LTV is 90% and the position is liquidatable, but not in the block it is opened in
+    setup_leveraged_position(&mut deps, 1_800_000);
+    let mut position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    position.opened_at_height = env.block.height;
+    POSITION.save(deps.as_mut().storage, &alice, &position).unwrap();
+
+    let msg = ExecuteMsg::Liquidate {
+        user: "alice".to_string(),
+        max_bond_units: None,
+        max_spread: None,
+        bonus_asset: None,
+    };
+    let res = execute(deps.as_mut(), env.clone(), mock_info("liquidator", &[]), msg.clone());
+    assert_generic_error_message(res, "position was opened in the current block");
+
+    // one block later, the position can be liquidated
+    let mut env = env;
+    env.block.height += 1;
+    execute(deps.as_mut(), env, mock_info("liquidator", &[]), msg).unwrap();
+}
+
+#[test]
+fn electing_primary_liquidation_bonus() {
+    let mut deps = setup_test();
+    let alice = Addr::unchecked("alice");
+    let contract = Addr::unchecked(MOCK_CONTRACT_ADDR);
+
+    // alice's LTV is 90% and liquidatable
+    setup_leveraged_position(&mut deps, 1_800_000);
+
+    let liquidate = |deps: &mut OwnedDeps<_, _, _>, bonus_asset: Option<AssetInfo>| {
+        let msg = ExecuteMsg::Liquidate {
+            user: "alice".to_string(),
+            max_bond_units: None,
+            max_spread: None,
+            bonus_asset: bonus_asset.map(Into::into),
+        };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("liquidator", &[]), msg);
+        release_lock(deps);
+        res
+    };
+    let bonus_msg = CallbackMsg::Refund {
+        user_addr: alice.clone(),
+        recipient_addr: Addr::unchecked("liquidator"),
+        percentage: Decimal::percent(1),
+    }
+    .into_cosmos_msg(&contract)
+    .unwrap();
+    let cover_msg = CallbackMsg::Cover {
+        user_addr: alice.clone(),
+        max_spread: Decimal::percent(5),
+    }
+    .into_cosmos_msg(&contract)
+    .unwrap();
+
+    // by default, the bonus is paid out of what remains after the primary asset is sold and the
+    // debt is repaid
+    let res = liquidate(&mut deps, None).unwrap();
+    assert_eq!(res.messages[2].msg, cover_msg);
+    assert_eq!(res.messages[4].msg, bonus_msg);
+    let res = liquidate(&mut deps, Some(AssetInfo::native("uusd"))).unwrap();
+    assert_eq!(res.messages[4].msg, bonus_msg);
+
+    // electing the primary asset, the bonus is refunded before the primary asset is sold. it
+    // amounts to 1% of the equity, i.e. 1% * (2,000,000 - 1,800,000) = 2,000 uusd, which at the
+    // price of 10 uusd per uluna is 200 uluna
+    let res = liquidate(&mut deps, Some(AssetInfo::native("uluna"))).unwrap();
+    let primary_bonus = Asset::native("uluna", 200u128);
+    let primary_bonus_msg = CallbackMsg::RefundAsset {
+        user_addr: alice.clone(),
+        recipient_addr: Addr::unchecked("liquidator"),
+        asset: primary_bonus.clone(),
+    }
+    .into_cosmos_msg(&contract)
+    .unwrap();
+    assert_eq!(res.messages.len(), 9);
+    assert_eq!(res.messages[2].msg, primary_bonus_msg);
+    assert_eq!(res.messages[3].msg, cover_msg);
+
+    // once the liquidity is withdrawn, the liquidator is paid the bonus amount of the primary
+    // asset, and nothing of the secondary asset
+    let mut position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    position.unlocked_assets = AssetList::from(vec![
+        Asset::native("uluna", 100_000u128),
+        Asset::native("uusd", 1_000_000u128),
+    ]);
+    POSITION.save(deps.as_mut().storage, &alice, &position).unwrap();
+    let callback = CallbackMsg::RefundAsset {
+        user_addr: alice.clone(),
+        recipient_addr: Addr::unchecked("liquidator"),
+        asset: primary_bonus.clone(),
+    };
+    let res = execute_callback(&mut deps, callback).unwrap();
+    let msgs = res.messages.into_iter().map(|submsg| submsg.msg).collect::<Vec<_>>();
+    assert_eq!(msgs, vec![primary_bonus.transfer_msg("liquidator").unwrap()]);
+    let position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    assert_eq!(
+        position.unlocked_assets.to_vec(),
+        vec![Asset::native("uluna", 99_800u128), Asset::native("uusd", 1_000_000u128)]
+    );
+
+    // the default path pays 1% of what remains once the debt is repaid. here, the 2,000,000 uusd of
+    // withdrawn value less 1,800,000 uusd of repayment leaves 200,000 uusd, so the bonus is worth
+    // 2,000 uusd, the same as the primary bonus
+    let mut position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    position.unlocked_assets = AssetList::from(vec![Asset::native("uusd", 200_000u128)]);
+    POSITION.save(deps.as_mut().storage, &alice, &position).unwrap();
+    let callback = CallbackMsg::Refund {
+        user_addr: alice.clone(),
+        recipient_addr: Addr::unchecked("liquidator"),
+        percentage: Decimal::percent(1),
+    };
+    let res = execute_callback(&mut deps, callback).unwrap();
+    let msgs = res.messages.into_iter().map(|submsg| submsg.msg).collect::<Vec<_>>();
+    let secondary_bonus = Asset::native("uusd", 2_000u128);
+    assert_eq!(msgs, vec![secondary_bonus.transfer_msg("liquidator").unwrap()]);
+    assert_eq!(primary_bonus.amount * Uint128::new(10), secondary_bonus.amount);
+
+    // the primary bonus is capped at what the user holds
+    let mut position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    position.unlocked_assets = AssetList::from(vec![Asset::native("uluna", 150u128)]);
+    POSITION.save(deps.as_mut().storage, &alice, &position).unwrap();
+    let callback = CallbackMsg::RefundAsset {
+        user_addr: alice.clone(),
+        recipient_addr: Addr::unchecked("liquidator"),
+        asset: primary_bonus,
+    };
+    let res = execute_callback(&mut deps, callback).unwrap();
+    let msgs = res.messages.into_iter().map(|submsg| submsg.msg).collect::<Vec<_>>();
+    assert_eq!(msgs, vec![Asset::native("uluna", 150u128).transfer_msg("liquidator").unwrap()]);
+
+    // in a partial liquidation, the primary bonus is worth the bonus rate of the liquidated
+    // portion. liquidating 10% of alice's bond units, worth 200,000 uusd, it amounts to 2,000 uusd,
+    // i.e. 200 uluna, refunded in place of the bonus rate of the withdrawn assets
+    setup_leveraged_position(&mut deps, 1_800_000);
+    let liquidate_partially = |deps: &mut OwnedDeps<_, _, _>, bonus_asset: Option<AssetInfo>| {
+        let msg = ExecuteMsg::Liquidate {
+            user: "alice".to_string(),
+            max_bond_units: Some(Uint128::new(10_000_000)),
+            max_spread: None,
+            bonus_asset: bonus_asset.map(Into::into),
+        };
+        let res =
+            execute(deps.as_mut(), mock_env(), mock_info("liquidator", &[]), msg).unwrap();
+        release_lock(deps);
+        res
+    };
+    let bonus_in_primary = |res: &Response| {
+        let event = res.events.iter().find(|event| event.ty == "liquidated").unwrap();
+        event.attributes.iter().find(|attr| attr.key == "bonus_in_primary").unwrap().value.clone()
+    };
+    let res = liquidate_partially(&mut deps, None);
+    assert_eq!(res.messages[2].msg, bonus_msg);
+    assert_eq!(bonus_in_primary(&res), "false");
+    let res = liquidate_partially(&mut deps, Some(AssetInfo::native("uluna")));
+    let partial_bonus_msg = CallbackMsg::RefundAsset {
+        user_addr: alice.clone(),
+        recipient_addr: Addr::unchecked("liquidator"),
+        asset: Asset::native("uluna", 200u128),
+    }
+    .into_cosmos_msg(&contract)
+    .unwrap();
+    assert_eq!(res.messages[2].msg, partial_bonus_msg);
+    assert!(res.messages.iter().all(|submsg| submsg.msg != bonus_msg));
+    assert_eq!(bonus_in_primary(&res), "true");
+
+    // the bonus can't be paid in any other asset
+    let res = liquidate(&mut deps, Some(AssetInfo::native("uatom")));
+    assert_generic_error_message(
+        res,
+        "invalid bonus asset: native:uatom; must be the primary or secondary asset",
+    );
+}
+
+#[test]
+fn liquidating_partially() {
+    let mut deps = setup_test();
+
+    // bond value is 2,000,000 uusd and debt is 1,600,000 uusd; LTV = 80%
+    setup_leveraged_position(&mut deps, 1_600_000);
+
+    let liquidate = |deps: &mut OwnedDeps<_, _, _>, max_bond_units: u128| {
+        let msg = ExecuteMsg::Liquidate {
+            user: "alice".to_string(),
+            max_bond_units: Some(Uint128::new(max_bond_units)),
+            max_spread: None,
+            bonus_asset: None,
+        };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("liquidator", &[]), msg).unwrap();
+        release_lock(deps);
+        res
+    };
+    let unbond_msg = |bond_units: u128| {
+        CallbackMsg::Unbond {
+            user_addr: Addr::unchecked("alice"),
+            bond_units_to_reduce: Uint128::new(bond_units),
+        }
+        .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+        .unwrap()
+    };
+
+    // f = (1,600,000 - 0.65 * 2,000,000) / (2,000,000 * (1 - 0.01 - 0.65)) = 0.44117647...
+    let res = liquidate(&mut deps, 100_000_000);
+    assert_eq!(res.messages[0].msg, unbond_msg(44_117_648));
+    assert_eq!(res.messages.len(), 7);
+
+    // after liquidating, 99% of the liquidated portion's value repays the debt, which should bring
+    // the LTV back to `max_ltv`
+    let bond_value_liquidated =
+        Uint128::new(2_000_000).multiply_ratio(44_117_648u128, 100_000_000u128);
+    let bond_value_after = Uint128::new(2_000_000) - bond_value_liquidated;
+    let debt_value_after = Uint128::new(1_600_000) - bond_value_liquidated * Decimal::percent(99);
+    let ltv_after = Decimal::from_ratio(debt_value_after, bond_value_after);
+    assert!(ltv_after > Decimal::from_str("0.649999").unwrap()); // allow for rounding errors
+    assert!(ltv_after < Decimal::from_str("0.650001").unwrap());
+
+    // the position should remain open, so its storage should not be purged
+    assert!(!res.messages.iter().any(|submsg| {
+        submsg.msg
+            == CallbackMsg::PurgeStorage {
+                user_addr: Addr::unchecked("alice"),
+            }
+            .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+            .unwrap()
+    }));
+
+    // the amount liquidated is capped at the requested amount
+    let res = liquidate(&mut deps, 10_000_000);
+    assert_eq!(res.messages[0].msg, unbond_msg(10_000_000));
+
+    // if the minimum amount covers the whole position, a full liquidation is done instead
+    setup_leveraged_position(&mut deps, 1_990_000);
+    let res = liquidate(&mut deps, 100_000_000);
+    assert_eq!(res.messages[0].msg, unbond_msg(100_000_000));
+    assert_eq!(res.messages.len(), 9);
+}
+
+#[test]
+fn preserving_partially_liquidated_position() {
+    let mut deps = setup_test();
+    set_pool_and_prices(&mut deps);
+
+    // after a partial liquidation, alice has 60,000 LP bonded, worth 1,200,000 uusd, and 780,000
+    // uusd of debt; LTV = 65%
+    deps.querier.set_bonded_amount("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 60_000);
+    deps.querier.set_user_debt(MOCK_CONTRACT_ADDR, &AssetInfo::native("uusd"), 780_000);
+    let state = State {
+        total_bond_units: Uint128::new(60_000_000),
+        total_debt_units: Uint128::new(780_000_000),
+        ..Default::default()
+    };
+    STATE.save(deps.as_mut().storage, &state).unwrap();
+    let position = Position {
+        bond_units: Uint128::new(60_000_000),
+        debt_units: Uint128::new(780_000_000),
+        ..Default::default()
+    };
+    POSITION.save(deps.as_mut().storage, &Addr::unchecked("alice"), &position).unwrap();
+
+    // the terminal callbacks of a liquidation must neither waive the debt of, nor purge, a
+    // position that is still open
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::ClearBadDebt {
+            user_addr: Addr::unchecked("alice"),
+        },
+    )
+    .unwrap();
+    assert_eq!(attr_value(&res, "skipped"), "position_open");
+    execute_callback(
+        &mut deps,
+        CallbackMsg::PurgeStorage {
+            user_addr: Addr::unchecked("alice"),
+        },
+    )
+    .unwrap();
+
+    let position_after = POSITION.load(deps.as_ref().storage, &Addr::unchecked("alice")).unwrap();
+    assert_eq!(position_after, position);
+    let state_after = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state_after.total_debt_units, Uint128::new(780_000_000));
+    assert_eq!(state_after.total_bad_debt, Uint128::zero());
+
+    let liquidate = |deps: &mut OwnedDeps<_, _, _>| {
+        let msg = ExecuteMsg::Liquidate {
+            user: "alice".to_string(),
+            max_bond_units: Some(Uint128::new(60_000_000)),
+            max_spread: None,
+            bonus_asset: None,
+        };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("liquidator", &[]), msg);
+        release_lock(deps);
+        res
+    };
+
+    // the position is healthy at `max_ltv`
+    assert_eq!(liquidate(&mut deps), Err(ContractError::PositionHealthy));
+
+    // once the debt grows, the position can be liquidated again
+    deps.querier.set_user_debt(MOCK_CONTRACT_ADDR, &AssetInfo::native("uusd"), 900_000);
+    let res = liquidate(&mut deps).unwrap();
+    let event = res.events.iter().find(|event| event.ty == "liquidated").unwrap();
+    let bond_units_liquidated = event
+        .attributes
+        .iter()
+        .find(|attr| attr.key == "bond_units_liquidated")
+        .map(|attr| attr.value.parse::<u128>().unwrap())
+        .unwrap();
+    assert!(bond_units_liquidated < 60_000_000);
+}
+
+#[test]
+fn bounding_liquidation_max_spread() {
+    let mut deps = setup_test();
+
+    let mut config = CONFIG.load(deps.as_ref().storage).unwrap();
+    config.liquidation_max_spread = Decimal::percent(30);
+    CONFIG.save(deps.as_mut().storage, &config).unwrap();
+
+    // alice's LTV is 90% and liquidatable
+    setup_leveraged_position(&mut deps, 1_800_000);
+
+    let liquidate = |deps: &mut OwnedDeps<_, _, _>, max_spread: Option<Decimal>| {
+        let msg = ExecuteMsg::Liquidate {
+            user: "alice".to_string(),
+            max_bond_units: None,
+            max_spread,
+            bonus_asset: None,
+        };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("liquidator", &[]), msg);
+        release_lock(deps);
+        res
+    };
+    let cover_msg = |max_spread: Decimal| {
+        CallbackMsg::Cover {
+            user_addr: Addr::unchecked("alice"),
+            max_spread,
+        }
+        .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+        .unwrap()
+    };
+
+    // if the liquidator doesn't specify a spread, the config's spread is used
+    let res = liquidate(&mut deps, None).unwrap();
+    assert_eq!(res.messages[2].msg, cover_msg(Decimal::percent(30)));
+
+    // a large spread is accepted as long as it is within the config's spread
+    let res = liquidate(&mut deps, Some(Decimal::percent(30))).unwrap();
+    assert_eq!(res.messages[2].msg, cover_msg(Decimal::percent(30)));
+
+    let res = liquidate(&mut deps, Some(Decimal::percent(10))).unwrap();
+    assert_eq!(res.messages[2].msg, cover_msg(Decimal::percent(10)));
+
+    // a spread just over the config's spread is rejected
+    let res = liquidate(&mut deps, Some(Decimal::from_str("0.300001").unwrap()));
+    assert_generic_error_message(res, "max spread 0.300001 exceeds liquidation max spread 0.3");
+
+    // `Cover` should pass the spread on to the swap
+    let mut position = POSITION.load(deps.as_ref().storage, &Addr::unchecked("alice")).unwrap();
+    position.unlocked_assets = AssetList::from(vec![Asset::native("uluna", 100_000u128)]);
+    POSITION.save(deps.as_mut().storage, &Addr::unchecked("alice"), &position).unwrap();
+
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::Cover {
+            user_addr: Addr::unchecked("alice"),
+            max_spread: Decimal::percent(30),
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        res.messages[0],
+        config
+            .primary_pair
+            .swap_submsg(
+                encode_reply_id(2, 0),
+                &Asset::native("uluna", 100_000u128),
+                None,
+                Some(Decimal::percent(30))
+            )
+            .unwrap()
+    );
+}
+
+#[test]
+fn applying_tiered_liquidation_bonus() {
+    let mut deps = setup_test();
+
+    let mut config = CONFIG.load(deps.as_ref().storage).unwrap();
+    config.bonus_schedule = vec![
+        (Decimal::percent(70), Decimal::percent(2)),
+        (Decimal::percent(80), Decimal::percent(5)),
+    ];
+    CONFIG.save(deps.as_mut().storage, &config).unwrap();
+
+    let liquidate = |deps: &mut OwnedDeps<_, _, _>| {
+        let msg = ExecuteMsg::Liquidate {
+            user: "alice".to_string(),
+            max_bond_units: Some(Uint128::new(100_000_000)),
+            max_spread: None,
+            bonus_asset: None,
+        };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("liquidator", &[]), msg).unwrap();
+        release_lock(deps);
+        res
+    };
+    let bonus_rate = |res: &Response| {
+        let event = res.events.iter().find(|event| event.ty == "liquidated").unwrap();
+        event.attributes.iter().find(|attr| attr.key == "bonus_rate").unwrap().value.clone()
+    };
+
+    // below the first breakpoint, `bonus_rate` applies
+    setup_leveraged_position(&mut deps, 1_350_000);
+    let res = liquidate(&mut deps);
+    assert_eq!(bonus_rate(&res), "0.01");
+
+    // at or above a breakpoint, the rate of the highest breakpoint not above the LTV applies
+    setup_leveraged_position(&mut deps, 1_400_000);
+    let res = liquidate(&mut deps);
+    assert_eq!(bonus_rate(&res), "0.02");
+
+    setup_leveraged_position(&mut deps, 1_500_000);
+    let res = liquidate(&mut deps);
+    assert_eq!(bonus_rate(&res), "0.02");
+
+    // the higher bonus also increases the minimum amount to liquidate:
+    // f = (1,700,000 - 0.65 * 2,000,000) / (2,000,000 * (1 - 0.05 - 0.65)) = 0.66666666...
+    setup_leveraged_position(&mut deps, 1_700_000);
+    let res = liquidate(&mut deps);
+    assert_eq!(bonus_rate(&res), "0.05");
+    assert_eq!(
+        res.messages[0].msg,
+        CallbackMsg::Unbond {
+            user_addr: Addr::unchecked("alice"),
+            bond_units_to_reduce: Uint128::new(66_666_667),
+        }
+        .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+        .unwrap()
+    );
+    assert_eq!(
+        res.messages[2].msg,
+        CallbackMsg::Refund {
+            user_addr: Addr::unchecked("alice"),
+            recipient_addr: Addr::unchecked("liquidator"),
+            percentage: Decimal::percent(5),
+        }
+        .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+        .unwrap()
+    );
+
+    // if the bonus and max LTV add up to 100% or more, no partial liquidation can bring the LTV
+    // back to max LTV, so the position is liquidated in full
+    let mut config = CONFIG.load(deps.as_ref().storage).unwrap();
+    config.max_ltv = Decimal::percent(92);
+    config.bonus_schedule = vec![(Decimal::percent(95), Decimal::percent(10))];
+    CONFIG.save(deps.as_mut().storage, &config).unwrap();
+    setup_leveraged_position(&mut deps, 1_920_000);
+    let res = liquidate(&mut deps);
+    assert_eq!(bonus_rate(&res), "0.1");
+    assert_eq!(
+        res.messages[0].msg,
+        CallbackMsg::Unbond {
+            user_addr: Addr::unchecked("alice"),
+            bond_units_to_reduce: Uint128::new(100_000_000),
+        }
+        .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+        .unwrap()
+    );
+}
+
+#[test]
+fn querying_liquidation_params() {
+    let mut deps = setup_test();
+
+    let query_params = |deps: &OwnedDeps<_, _, _>| -> LiquidationParamsResponse {
+        let msg = QueryMsg::LiquidationParams {
+            user: "alice".to_string(),
+        };
+        from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap()
+    };
+    let liquidate = |deps: &mut OwnedDeps<_, _, _>, params: &LiquidationParamsResponse| {
+        let msg = ExecuteMsg::Liquidate {
+            user: "alice".to_string(),
+            max_bond_units: Some(params.max_bond_units),
+            max_spread: None,
+            bonus_asset: None,
+        };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("liquidator", &[]), msg).unwrap();
+        release_lock(deps);
+        res
+    };
+    let unbond_msg = |bond_units: u128| {
+        CallbackMsg::Unbond {
+            user_addr: Addr::unchecked("alice"),
+            bond_units_to_reduce: Uint128::new(bond_units),
+        }
+        .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+        .unwrap()
+    };
+
+    // LTV = 60%; the position is healthy
+    setup_leveraged_position(&mut deps, 1_200_000);
+    let params = query_params(&deps);
+    assert!(!params.liquidatable);
+    assert_eq!(params.max_bond_units, Uint128::zero());
+
+    // LTV = 80%; the minimum amount to liquidate is the same as in `liquidating_partially`. the
+    // liquidator is awarded 1% of the liquidated portion's value, 2,000,000 * 0.44117648 * 0.01
+    setup_leveraged_position(&mut deps, 1_600_000);
+    let params = query_params(&deps);
+    assert_eq!(
+        params,
+        LiquidationParamsResponse {
+            liquidatable: true,
+            max_bond_units: Uint128::new(44_117_648),
+            full_liquidation: false,
+            bonus_value: Uint128::new(8823),
+        }
+    );
+    let res = liquidate(&mut deps, &params);
+    assert_eq!(res.messages[0].msg, unbond_msg(44_117_648));
+    assert_eq!(res.messages.len(), 7);
+
+    // LTV = 99.5%; the whole position must be liquidated. the bonus is paid out of what remains
+    // after repaying all debt, same as estimated by the `LiquidationProfit` query
+    setup_leveraged_position(&mut deps, 1_990_000);
+    let params = query_params(&deps);
+    let profit: LiquidationProfitResponse = from_binary(
+        &query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::LiquidationProfit {
+                user: "alice".to_string(),
+            },
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert!(params.full_liquidation);
+    assert_eq!(params.max_bond_units, Uint128::new(100_000_000));
+    assert_eq!(params.bonus_value, profit.bonus_value);
+    let res = liquidate(&mut deps, &params);
+    assert_eq!(res.messages[0].msg, unbond_msg(100_000_000));
+    assert_eq!(res.messages.len(), 9);
+}
+
+#[test]
+fn repaying_bad_debt_from_insurance_fund() {
+    let mut deps = setup_test();
+
+    let mut config = CONFIG.load(deps.as_ref().storage).unwrap();
+    config.secondary_asset_info = AssetInfo::cw20(Addr::unchecked("uusd_token"));
+    config.insurance_fund = Some(Addr::unchecked("insurance"));
+    CONFIG.save(deps.as_mut().storage, &config).unwrap();
+
+    deps.querier.set_user_debt(MOCK_CONTRACT_ADDR, &config.secondary_asset_info, 1000);
+    deps.querier.set_cw20_balance("uusd_token", "insurance", 5000);
+    deps.querier.set_pool(
+        "uluna_uusd_pair",
+        &[
+            Asset::native("uluna", 1_000_000u128),
+            Asset::cw20(Addr::unchecked("uusd_token"), 10_000_000u128),
+        ],
+        1_000_000,
+    );
+    deps.querier.set_oracle_price(&config.secondary_asset_info, Decimal::one());
+
+    // alice has 1,000 uusd_token of bad debt
+    let clear_bad_debt = |deps: &mut OwnedDeps<_, _, _>| {
+        let state = State {
+            total_debt_units: Uint128::new(1_000_000),
+            ..Default::default()
+        };
+        STATE.save(deps.as_mut().storage, &state).unwrap();
+        let position = Position {
+            debt_units: Uint128::new(1_000_000),
+            ..Default::default()
+        };
+        POSITION.save(deps.as_mut().storage, &Addr::unchecked("alice"), &position).unwrap();
+
+        let msg = CallbackMsg::ClearBadDebt {
+            user_addr: Addr::unchecked("alice"),
+        };
+        execute_callback(deps, msg).unwrap()
+    };
+    let find_event = |res: &Response, ty: &str| -> Event {
+        res.events
+            .iter()
+            .find(|event| event.ty == ty)
+            .cloned()
+            .unwrap_or_else(|| panic!("event not found: {}", ty))
+    };
+
+    // the insurance fund has not approved enough tokens, so the debt is only waived
+    deps.querier.set_cw20_allowance("uusd_token", "insurance", MOCK_CONTRACT_ADDR, 999);
+    let res = clear_bad_debt(&mut deps);
+    assert_eq!(res.messages.len(), 0);
+    assert_eq!(
+        find_event(&res, "bad_debt_unrecovered"),
+        Event::new("bad_debt_unrecovered")
+            .add_attribute("user", "alice")
+            .add_attribute("bad_debt", "cw20:uusd_token:1000")
+            .add_attribute("reason", "insufficient insurance fund")
+    );
+    let state = STATE.load(deps.as_ref().storage).unwrap();
+    assert_eq!(state.total_debt_units, Uint128::zero());
+
+    // with sufficient allowance, the bad debt is drawn from the fund and repaid to Red Bank
+    deps.querier.set_cw20_allowance("uusd_token", "insurance", MOCK_CONTRACT_ADDR, 1000);
+    let res = clear_bad_debt(&mut deps);
+    let bad_debt = Asset::cw20(Addr::unchecked("uusd_token"), 1000u128);
+    assert_eq!(res.messages.len(), 2);
+    assert_eq!(
+        res.messages[0].msg,
+        bad_debt.transfer_from_msg("insurance", MOCK_CONTRACT_ADDR).unwrap()
+    );
+    assert_eq!(res.messages[1].msg, config.red_bank.repay_msg(&bad_debt).unwrap());
+    assert_eq!(
+        find_event(&res, "bad_debt_recovered"),
+        Event::new("bad_debt_recovered")
+            .add_attribute("user", "alice")
+            .add_attribute("insurance_fund", "insurance")
+            .add_attribute("amount", "cw20:uusd_token:1000")
+    );
+
+    // without an insurance fund, the debt is only waived
+    config.insurance_fund = None;
+    CONFIG.save(deps.as_mut().storage, &config).unwrap();
+    let res = clear_bad_debt(&mut deps);
+    assert_eq!(res.messages.len(), 0);
+    let event = find_event(&res, "bad_debt_unrecovered");
+    assert!(event
+        .attributes
+        .iter()
+        .any(|attr| attr.key == "reason" && attr.value == "no insurance fund configured"));
+}
+
+#[test]
+fn reconciling_rewards_withdrawn_on_unbond() {
+    let mut deps = setup_test();
+    deps.querier.set_reward_info("uluna_uusd_lp_token", "astro_token", Some("uluna_token"));
+    deps.querier.set_bonded_amount("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 100);
+    deps.querier.set_cw20_balance("astro_token", MOCK_CONTRACT_ADDR, 1000);
+    deps.querier.set_cw20_balance("uluna_token", MOCK_CONTRACT_ADDR, 500);
+
+    let unbond_and_reply = |deps: &mut OwnedDeps<MockStorage, MockApi, CustomMockQuerier>,
+                            astro_paid: u128,
+                            proxy_paid: u128| {
+        STATE
+            .save(
+                deps.as_mut().storage,
+                &State {
+                    total_bond_units: Uint128::new(100_000_000),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        POSITION
+            .save(
+                deps.as_mut().storage,
+                &Addr::unchecked("alice"),
+                &Position {
+                    bond_units: Uint128::new(100_000_000),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        // the generator reports 100 ASTRO and 50 proxy reward tokens claimable
+        deps.querier.set_pending_tokens("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 100, Some(50));
+        let msg = CallbackMsg::Unbond {
+            user_addr: Addr::unchecked("alice"),
+            bond_units_to_reduce: Uint128::new(10_000_000),
+        };
+        let res = execute_callback(deps, msg).unwrap();
+        let id = res.messages[0].id;
+        assert_eq!(decode_reply_id(id).0, 3);
+
+        // the generator pays the specified amounts when executing the unbond message
+        deps.querier.set_cw20_balance("astro_token", MOCK_CONTRACT_ADDR, 1000 + astro_paid);
+        deps.querier.set_cw20_balance("uluna_token", MOCK_CONTRACT_ADDR, 500 + proxy_paid);
+        let reply_msg = Reply {
+            id,
+            result: ContractResult::Ok(SubMsgExecutionResponse {
+                events: vec![],
+                data: None,
+            }),
+        };
+        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+
+        // reset balances for the next run
+        deps.querier.set_cw20_balance("astro_token", MOCK_CONTRACT_ADDR, 1000);
+        deps.querier.set_cw20_balance("uluna_token", MOCK_CONTRACT_ADDR, 500);
+        res
+    };
+    let pending_rewards = |deps: &OwnedDeps<_, _, _>| {
+        STATE.load(deps.as_ref().storage).unwrap().pending_rewards
+    };
+
+    // the generator pays exactly as predicted; no diff should be reported
+    let res = unbond_and_reply(&mut deps, 100, 50);
+    assert!(!res.attributes.iter().any(|attr| attr.key == "reward_prediction_diff"));
+    assert_eq!(
+        pending_rewards(&deps),
+        AssetList::from(vec![
+            Asset::cw20(Addr::unchecked("astro_token"), 100u128),
+            Asset::cw20(Addr::unchecked("uluna_token"), 50u128),
+        ])
+    );
+    assert_eq!(CACHED_REWARDS.may_load(deps.as_ref().storage).unwrap(), None);
+
+    // the generator pays more ASTRO and fewer proxy reward tokens than predicted; the pending
+    // rewards should match the amounts actually received
+    let res = unbond_and_reply(&mut deps, 120, 45);
+    assert_eq!(
+        attr_value(&res, "reward_prediction_diff"),
+        "cw20:astro_token: predicted 100, received 120; \
+         cw20:uluna_token: predicted 50, received 45"
+    );
+    assert_eq!(
+        pending_rewards(&deps),
+        AssetList::from(vec![
+            Asset::cw20(Addr::unchecked("astro_token"), 120u128),
+            Asset::cw20(Addr::unchecked("uluna_token"), 45u128),
+        ])
+    );
+}
+
+#[test]
+fn resolving_red_bank_asset_labels() {
+    let mut deps = setup_test();
+    let red_bank = mock_config().red_bank;
+
+    // Red Bank lists CW20 markets by lower case addresses
+    deps.querier.set_user_debt("alice", &AssetInfo::native("uusd"), 1000);
+    deps.querier.set_user_debt("alice", &AssetInfo::cw20(Addr::unchecked("terra1token")), 500);
+
+    let querier = QuerierWrapper::new(&deps.querier);
+    let resolve = |info: &AssetInfo| -> String {
+        let asset = red_bank.resolve_asset(&querier, info).unwrap();
+        String::from_utf8(to_binary(&asset).unwrap().to_vec()).unwrap()
+    };
+
+    assert_eq!(resolve(&AssetInfo::native("uusd")), r#"{"native":{"denom":"uusd"}}"#);
+    assert_eq!(
+        resolve(&AssetInfo::cw20(Addr::unchecked("TERRA1TOKEN"))),
+        r#"{"cw20":{"contract_addr":"terra1token"}}"#
+    );
+
+    // debts should be found under the resolved labels
+    let alice = Addr::unchecked("alice");
+    let debt = red_bank.query_user_debt(&querier, &alice, &AssetInfo::native("uusd")).unwrap();
+    assert_eq!(debt, Uint128::new(1000));
+    let token = AssetInfo::cw20(Addr::unchecked("TERRA1TOKEN"));
+    let debt = red_bank.query_user_debt(&querier, &alice, &token).unwrap();
+    assert_eq!(debt, Uint128::new(500));
+
+    // native denoms are case-sensitive, and an asset without a market is rejected instead of
+    // reporting zero debt
+    let err = red_bank.query_user_debt(&querier, &alice, &AssetInfo::native("UUSD"));
+    assert_generic_error_message(err, "no red bank market found for native:UUSD");
+}
+
+#[test]
+fn reinvesting_third_token_rewards() {
+    let mut deps = setup_test();
+
+    let anc_pair = Pair::new(&Addr::unchecked("anc_uusd_pair"), &Addr::unchecked("anc_uusd_lp"));
+    let mir_pair = Pair::new(&Addr::unchecked("mir_uusd_pair"), &Addr::unchecked("mir_uusd_lp"));
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.reward_pairs = vec![
+                (AssetInfo::cw20(Addr::unchecked("anc_token")), anc_pair.clone()),
+                (AssetInfo::cw20(Addr::unchecked("mir_token")), mir_pair.clone()),
+            ];
+            Ok(config)
+        })
+        .unwrap();
+
+    // the generator emits ASTRO and ANC as proxy reward. MIR rewards emitted by a previous proxy
+    // were retained from an earlier harvest, and have already been charged fees
+    deps.querier.set_reward_info("uluna_uusd_lp_token", "astro_token", Some("anc_token"));
+    deps.querier.set_pending_tokens("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 10_000, Some(2_000));
+    STATE
+        .save(
+            deps.as_mut().storage,
+            &State {
+                pending_rewards: AssetList::from(vec![Asset::cw20(
+                    Addr::unchecked("mir_token"),
+                    1_000u128,
+                )]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+    let msg = ExecuteMsg::Harvest {
+        max_spread: None,
+        slippage_tolerance: None,
+        min_lp_out: None,
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("operator", &[]), msg).unwrap();
+
+    // fees are charged on each newly claimed reward token
+    let fees = res.events[0].attributes.iter().find(|attr| attr.key == "fees").unwrap();
+    assert_eq!(fees.value, "cw20:astro_token:500,cw20:anc_token:100");
+
+    // every reward token is swapped to the secondary asset before reinvesting
+    let swap_msg = |token: &str, amount: u128| {
+        CallbackMsg::Swap {
+            user_addr: None,
+            offer_asset_info: AssetInfo::cw20(Addr::unchecked(token)),
+            offer_amount: Some(Uint128::new(amount)),
+            max_spread: None,
+            min_return: None,
+        }
+        .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+        .unwrap()
+    };
+    let msgs = res.messages.iter().map(|submsg| submsg.msg.clone()).collect::<Vec<_>>();
+    assert!(msgs.contains(&swap_msg("astro_token", 9_500)));
+    assert!(msgs.contains(&swap_msg("anc_token", 1_900)));
+    assert!(msgs.contains(&swap_msg("mir_token", 1_000)));
+
+    // proxy reward tokens are swapped using their configured pairs
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::Swap {
+            user_addr: None,
+            offer_asset_info: AssetInfo::cw20(Addr::unchecked("anc_token")),
+            offer_amount: Some(Uint128::new(1_900)),
+            max_spread: None,
+            min_return: None,
+        },
+    )
+    .unwrap();
+    let offer_asset = Asset::cw20(Addr::unchecked("anc_token"), 1_900u128);
+    assert_eq!(
+        res.messages,
+        replying_always(vec![anc_pair
+            .swap_submsg(encode_reply_id(2, 0), &offer_asset, None, None)
+            .unwrap()])
+    );
+
+    // a reward token without a configured pair can't be swapped
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::Swap {
+            user_addr: None,
+            offer_asset_info: AssetInfo::cw20(Addr::unchecked("mine_token")),
+            offer_amount: Some(Uint128::new(1)),
+            max_spread: None,
+            min_return: None,
+        },
+    );
+    assert_generic_error_message(res, "invalid offer asset: cw20:mine_token");
+}
+
+#[test]
+fn using_conservative_prices_from_two_oracles() {
+    let mut deps = setup_test();
+    setup_leveraged_position(&mut deps, 1_000_000);
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.secondary_oracle = Some(Oracle {
+                contract_addr: Addr::unchecked("secondary_oracle"),
+            });
+            Ok(config)
+        })
+        .unwrap();
+
+    let query_alice = |deps: &OwnedDeps<_, _, _>| -> PositionResponse {
+        let msg = QueryMsg::Position {
+            user: "alice".to_string(),
+        };
+        from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap()
+    };
+
+    // if the secondary oracle agrees with the primary one, health is unchanged
+    let position = query_alice(&deps);
+    assert_eq!(position.bond_value, Uint128::new(2_000_000));
+    assert_eq!(position.debt_value, Uint128::new(1_000_000));
+
+    // the secondary oracle reports luna at 9 uusd and uusd at 1.1 uusd. collateral is valued at the
+    // lower luna and uusd prices, whereas debt is valued at the higher uusd price:
+    // pool value = 2 * sqrt(1,000,000 * 9 * 10,000,000 * 1) = 18,973,664
+    let luna = AssetInfo::native("uluna");
+    let uusd = AssetInfo::native("uusd");
+    deps.querier.set_oracle_price_at("secondary_oracle", &luna, Decimal::percent(900));
+    deps.querier.set_oracle_price_at("secondary_oracle", &uusd, Decimal::percent(110));
+    let position = query_alice(&deps);
+    assert_eq!(position.bond_value, Uint128::new(1_897_366));
+    assert_eq!(position.debt_value, Uint128::new(1_100_000));
+    assert_eq!(position.ltv, Some(Decimal::from_ratio(1_100_000u128, 1_897_366u128)));
+
+    // a reading that diverges in the favorable direction is ignored
+    deps.querier.set_oracle_price_at("secondary_oracle", &luna, Decimal::percent(1100));
+    deps.querier.set_oracle_price_at("secondary_oracle", &uusd, Decimal::one());
+    let position = query_alice(&deps);
+    assert_eq!(position.bond_value, Uint128::new(2_000_000));
+    assert_eq!(position.debt_value, Uint128::new(1_000_000));
+    assert_eq!(position.ltv, Some(Decimal::percent(50)));
+
+    // as uusd is on both sides, a lower uusd price lowers the collateral value, but not the debt's
+    deps.querier.set_oracle_price_at("secondary_oracle", &uusd, Decimal::percent(90));
+    let position = query_alice(&deps);
+    assert_eq!(position.bond_value, Uint128::new(1_897_366));
+    assert_eq!(position.debt_value, Uint128::new(1_000_000));
+}
+
+#[test]
+fn batching_oracle_price_queries() {
+    let mut deps = setup_test();
+    setup_leveraged_position(&mut deps, 1_000_000);
+
+    let oracle = Oracle {
+        contract_addr: Addr::unchecked("oracle"),
+    };
+    let asset_infos = [AssetInfo::native("uluna"), AssetInfo::native("uusd")];
+    let query_alice = |deps: &OwnedDeps<_, _, _>| -> PositionResponse {
+        let msg = QueryMsg::Position {
+            user: "alice".to_string(),
+        };
+        from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap()
+    };
+
+    // the mock oracle doesn't support batch queries by default, so prices are queried one by one
+    let querier = QuerierWrapper::new(&deps.querier);
+    let fallback_prices = oracle.query_prices(&querier, &asset_infos).unwrap();
+    assert_eq!(fallback_prices, vec![Decimal::percent(1000), Decimal::one()]);
+    for (asset_info, price) in asset_infos.iter().zip(&fallback_prices) {
+        assert_eq!(oracle.query_price(&querier, asset_info).unwrap(), *price);
+    }
+    let fallback_position = query_alice(&deps);
+
+    // once the oracle supports batch queries, the same prices are returned
+    deps.querier.enable_oracle_batch_queries("oracle");
+    let querier = QuerierWrapper::new(&deps.querier);
+    let batch_prices = oracle.query_prices(&querier, &asset_infos).unwrap();
+    assert_eq!(batch_prices, fallback_prices);
+    assert_eq!(query_alice(&deps), fallback_position);
+}
+
+#[test]
+fn delegating_position_management_to_keeper() {
+    let mut deps = setup_test();
+    setup_leveraged_position(&mut deps, 100);
+
+    let execute_as = |deps: &mut OwnedDeps<_, _, _>, sender: &str, msg: ExecuteMsg| {
+        execute(deps.as_mut(), mock_env(), mock_info(sender, &[]), msg)
+    };
+    let update_for_alice = |actions: Vec<Action>| ExecuteMsg::UpdatePositionFor {
+        user: "alice".to_string(),
+        actions,
+    };
+    let deleverage = vec![
+        Action::Unbond {
+            bond_units_to_reduce: Uint128::new(1000),
+            swap_to: None,
+        },
+        Action::Repay {
+            amount: Uint128::new(100),
+            user: None,
+        },
+    ];
+
+    // alice authorizes the keeper to deleverage her position only
+    let msg = ExecuteMsg::AuthorizeKeeper {
+        keeper: "keeper".to_string(),
+        actions: vec![ActionKind::Unbond, ActionKind::Repay],
+    };
+    execute_as(&mut deps, "alice", msg).unwrap();
+
+    let msg = QueryMsg::Authorization {
+        user: "alice".to_string(),
+        keeper: "keeper".to_string(),
+    };
+    let authorization: Authorization =
+        from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+    assert_eq!(authorization.actions, vec![ActionKind::Unbond, ActionKind::Repay]);
+
+    // the keeper can perform authorized actions on alice's position. assets are refunded to alice
+    let res = execute_as(&mut deps, "keeper", update_for_alice(deleverage.clone())).unwrap();
+    let alice = Addr::unchecked("alice");
+    let contract = Addr::unchecked(MOCK_CONTRACT_ADDR);
+    let expected = [
+        CallbackMsg::Unbond {
+            user_addr: alice.clone(),
+            bond_units_to_reduce: Uint128::new(1000),
+        },
+        CallbackMsg::WithdrawLiquidity {
+            user_addr: alice.clone(),
+        },
+        CallbackMsg::Repay {
+            user_addr: Some(alice.clone()),
+            repay_amount: Some(Uint128::new(100)),
+        },
+        CallbackMsg::Refund {
+            user_addr: alice.clone(),
+            recipient_addr: alice.clone(),
+            percentage: Decimal::one(),
+        },
+        CallbackMsg::AssertHealth {
+            user_addr: alice.clone(),
+        },
+        CallbackMsg::Snapshot {
+            user_addr: alice.clone(),
+        },
+        CallbackMsg::PurgeStorage {
+            user_addr: alice,
+        },
+        CallbackMsg::ReleaseLock {},
+    ];
+    let msgs = res.messages.iter().map(|submsg| submsg.msg.clone()).collect::<Vec<_>>();
+    let expected_msgs = expected
+        .iter()
+        .map(|callback| callback.into_cosmos_msg(&contract).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(msgs, expected_msgs);
+    release_lock(&mut deps);
+
+    // the keeper can't perform actions alice hasn't authorized
+    let msg = update_for_alice(vec![Action::Borrow {
+        amount: Uint128::new(100),
+    }]);
+    let res = execute_as(&mut deps, "keeper", msg);
+    assert_eq!(
+        res,
+        Err(ContractError::unauthorized("keeper is not authorized to borrow on behalf of alice"))
+    );
+
+    // others can't manage alice's position at all
+    let res = execute_as(&mut deps, "bob", update_for_alice(deleverage.clone()));
+    assert_eq!(
+        res,
+        Err(ContractError::unauthorized("bob is not authorized to unbond on behalf of alice"))
+    );
+
+    // a keeper authorized to repay can only repay alice's own debt. paying down another debtor,
+    // including the keeper itself, would spend alice's assets on someone else's debt
+    let msg = ExecuteMsg::AuthorizeKeeper {
+        keeper: "repayer".to_string(),
+        actions: vec![ActionKind::Repay],
+    };
+    execute_as(&mut deps, "alice", msg).unwrap();
+    let repay_for = |debtor: &str| {
+        update_for_alice(vec![Action::Repay {
+            amount: Uint128::new(100),
+            user: Some(debtor.to_string()),
+        }])
+    };
+    for debtor in ["repayer", "bob"] {
+        let res = execute_as(&mut deps, "repayer", repay_for(debtor));
+        let msg = format!(
+            "repayer is not authorized to repay the debt of {} on behalf of alice",
+            debtor
+        );
+        assert_eq!(res, Err(ContractError::unauthorized(msg)));
+    }
+    let res = execute_as(&mut deps, "repayer", repay_for("alice")).unwrap();
+    assert_eq!(
+        res.messages[0].msg,
+        CallbackMsg::Repay {
+            user_addr: Some(Addr::unchecked("alice")),
+            repay_amount: Some(Uint128::new(100)),
+        }
+        .into_cosmos_msg(&contract)
+        .unwrap()
+    );
+    release_lock(&mut deps);
+
+    // neither keepers nor others can submit an empty list of actions on another user's behalf,
+    // which would otherwise open a position for them and record a snapshot evicting older ones
+    for (sender, user) in [("keeper", "alice"), ("bob", "alice"), ("bob", "carol")] {
+        let msg = ExecuteMsg::UpdatePositionFor {
+            user: user.to_string(),
+            actions: vec![],
+        };
+        let res = execute_as(&mut deps, sender, msg);
+        let msg = format!(
+            "{} is not authorized to submit an empty list of actions on behalf of {}",
+            sender, user
+        );
+        assert_eq!(res, Err(ContractError::unauthorized(msg)));
+    }
+    assert!(!POSITION.has(deps.as_ref().storage, &Addr::unchecked("carol")));
+
+    // once revoked, the keeper can no longer manage alice's position
+    let msg = ExecuteMsg::RevokeKeeper {
+        keeper: "keeper".to_string(),
+    };
+    execute_as(&mut deps, "alice", msg).unwrap();
+    let res = execute_as(&mut deps, "keeper", update_for_alice(deleverage));
+    assert_eq!(
+        res,
+        Err(ContractError::unauthorized("keeper is not authorized to unbond on behalf of alice"))
+    );
+
+    // a user can't authorize themselves
+    let msg = ExecuteMsg::AuthorizeKeeper {
+        keeper: "alice".to_string(),
+        actions: vec![ActionKind::Borrow],
+    };
+    let res = execute_as(&mut deps, "alice", msg);
+    assert_generic_error_message(res, "cannot authorize self as keeper");
+}
+
+#[test]
+fn pausing_exposure_increasing_actions() {
+    let mut deps = setup_test();
+
+    let set_paused = |deps: &mut OwnedDeps<_, _, _>, sender: &str, paused: bool| {
+        let msg = ExecuteMsg::SetPaused {
+            paused,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(sender, &[]), msg)
+    };
+    let deposit = |deps: &mut OwnedDeps<_, _, _>| {
+        let msg = ExecuteMsg::UpdatePosition(vec![Action::Deposit(
+            Asset::native("uusd", 100u128).into(),
+        )]);
+        execute(deps.as_mut(), mock_env(), mock_info("alice", &[Coin::new(100, "uusd")]), msg)
+    };
+
+    // only governance can pause
+    let res = set_paused(&mut deps, "alice", true);
+    assert_eq!(res, Err(ContractError::unauthorized("only governance can set paused")));
+
+    set_paused(&mut deps, "governance", true).unwrap();
+    let res: AdminStatusResponse =
+        from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::AdminStatus {}).unwrap()).unwrap();
+    assert!(res.paused);
+
+    // while paused, deposits are rejected, but debts can still be repaid
+    assert_eq!(
+        deposit(&mut deps),
+        Err(ContractError::Paused {
+            kind: ActionKind::Deposit,
+        })
+    );
+    let msg = ExecuteMsg::UpdatePosition(vec![Action::Repay {
+        amount: Uint128::new(100),
+        user: None,
+    }]);
+    execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+    release_lock(&mut deps);
+
+    // liquidations remain enabled
+    setup_leveraged_position(&mut deps, 1_990_000);
+    let msg = ExecuteMsg::Liquidate {
+        user: "alice".to_string(),
+        max_bond_units: None,
+        max_spread: None,
+        bonus_asset: None,
+    };
+    execute(deps.as_mut(), mock_env(), mock_info("liquidator", &[]), msg).unwrap();
+    release_lock(&mut deps);
+
+    // once unpaused, deposits are accepted again
+    set_paused(&mut deps, "governance", false).unwrap();
+    deposit(&mut deps).unwrap();
+}
+
+#[test]
+fn enforcing_deposit_cap() {
+    let mut deps = setup_test();
+    setup_leveraged_position(&mut deps, 0);
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.deposit_cap = Some(Uint128::new(2_000_000));
+            Ok(config)
+        })
+        .unwrap();
+
+    // the cap is asserted after all other callbacks, except for releasing the lock, so that the
+    // bonded amount reflects the deposits made in the same message
+    let msg = ExecuteMsg::UpdatePosition(vec![
+        Action::Deposit(Asset::native("uusd", 100u128).into()),
+        Action::Bond {
+            slippage_tolerance: None,
+        },
+    ]);
+    let info = mock_info("alice", &[Coin::new(100, "uusd")]);
+    let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+    assert_eq!(
+        res.messages[res.messages.len() - 2].msg,
+        CallbackMsg::AssertDepositCap {}
+            .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+            .unwrap()
+    );
+
+    // bonded liquidity tokens are worth 2,000,000 uusd; deposits up to the cap are accepted
+    let res = execute_callback(&mut deps, CallbackMsg::AssertDepositCap {}).unwrap();
+    assert_eq!(attr_value(&res, "total_bond_value"), "2000000");
+
+    // the next deposit takes the total bond value above the cap, and is rejected
+    deps.querier.set_bonded_amount("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 100_001);
+    let res = execute_callback(&mut deps, CallbackMsg::AssertDepositCap {});
+    assert_eq!(
+        res,
+        Err(ContractError::DepositCapExceeded {
+            deposit_cap: Uint128::new(2_000_000),
+        })
+    );
+}
+
+#[test]
+fn enforcing_min_deposit_value() {
+    let mut deps = setup_test();
+    set_pool_and_prices(&mut deps);
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.min_deposit_value = Uint128::new(1000);
+            Ok(config)
+        })
+        .unwrap();
+
+    let deposit = |deps: &mut OwnedDeps<_, _, _>, user: &str, assets: Vec<Asset>| {
+        let coins = assets
+            .iter()
+            .filter_map(|asset| match &asset.info {
+                AssetInfo::Native(denom) => Some(Coin::new(asset.amount.u128(), denom)),
+                AssetInfo::Cw20(_) => None,
+            })
+            .collect::<Vec<_>>();
+        let actions = assets.into_iter().map(|asset| Action::Deposit(asset.into())).collect();
+        let msg = ExecuteMsg::UpdatePosition(actions);
+        let res = execute(deps.as_mut(), mock_env(), mock_info(user, &coins), msg);
+        release_lock(deps);
+        res
+    };
+
+    // a first deposit worth less than 1,000 uusd is rejected
+    let res = deposit(&mut deps, "alice", vec![Asset::native("uusd", 999u128)]);
+    assert_eq!(
+        res,
+        Err(ContractError::DepositTooSmall {
+            deposit_value: Uint128::new(999),
+            min_deposit_value: Uint128::new(1000),
+        })
+    );
+    // the mock storage keeps writes made before an error, so each attempt is made by a new user
+    let res = deposit(
+        &mut deps,
+        "dave",
+        vec![Asset::native("uluna", 50u128), Asset::native("uusd", 499u128)],
+    );
+    assert!(res.is_err());
+
+    // deposits are valued together; liquidity tokens by the assets they are redeemable for, so 50
+    // shares, each redeemable for 1 uluna + 10 uusd, are worth 1,000 uusd
+    let res = deposit(
+        &mut deps,
+        "erin",
+        vec![Asset::native("uluna", 50u128), Asset::native("uusd", 500u128)],
+    );
+    assert!(res.is_ok());
+    let lp_token = Addr::unchecked("uluna_uusd_lp_token");
+    let res = deposit(&mut deps, "charlie", vec![Asset::cw20(lp_token, 50u128)]);
+    assert!(res.is_ok());
+
+    // an existing position may be topped up by less than the minimum
+    let position = Position {
+        bond_units: Uint128::new(100_000_000),
+        ..Default::default()
+    };
+    POSITION.save(deps.as_mut().storage, &Addr::unchecked("bob"), &position).unwrap();
+    let res = deposit(&mut deps, "bob", vec![Asset::native("uusd", 999u128)]);
+    assert!(res.is_ok());
+}
+
+#[test]
+fn skipping_health_check_for_risk_reducing_actions() {
+    let mut deps = setup_test();
+    setup_leveraged_position(&mut deps, 100_000);
+
+    let asserts_health = |deps: &mut OwnedDeps<MockStorage, MockApi, CustomMockQuerier>,
+                          actions: Vec<Action>| {
+        let msg = ExecuteMsg::UpdatePosition(actions);
+        let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+        release_lock(deps);
+        let assert_health = CallbackMsg::AssertHealth {
+            user_addr: Addr::unchecked("alice"),
+        }
+        .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+        .unwrap();
+        res.messages.iter().any(|submsg| submsg.msg == assert_health)
+    };
+    let repay = Action::Repay {
+        amount: Uint128::new(100),
+        user: None,
+    };
+    let unbond = Action::Unbond {
+        bond_units_to_reduce: Uint128::new(1000),
+        swap_to: None,
+    };
+    let borrow = Action::Borrow {
+        amount: Uint128::new(100),
+    };
+
+    // repaying can only make the position healthier, so health is not checked
+    assert!(!asserts_health(&mut deps, vec![repay.clone()]));
+
+    // unbonding returns liquidity to the user without repaying the debt, so while the position has
+    // debt, health is still checked
+    assert!(asserts_health(&mut deps, vec![unbond.clone(), repay.clone()]));
+
+    // if any action may increase exposure, health is checked
+    assert!(asserts_health(&mut deps, vec![repay, borrow.clone()]));
+
+    // once the position has no debt, unbonding can't make it unhealthy
+    let alice = Addr::unchecked("alice");
+    let mut position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    position.debt_units = Uint128::zero();
+    POSITION.save(deps.as_mut().storage, &alice, &position).unwrap();
+    assert!(!asserts_health(&mut deps, vec![unbond.clone()]));
+    assert!(asserts_health(&mut deps, vec![unbond, borrow]));
+}
+
+#[test]
+fn recording_config_history() {
+    let mut deps = setup_test();
+
+    let update_config = |deps: &mut OwnedDeps<_, _, _>, height: u64, fee_rate: Decimal| {
+        let mut env = mock_env();
+        env.block.height = height;
+        let mut new_config = mock_config();
+        new_config.fee_rate = fee_rate;
+        let msg = ExecuteMsg::UpdateConfig {
+            new_config: new_config.into(),
+        };
+        execute(deps.as_mut(), env, mock_info("governance", &[]), msg).unwrap();
+    };
+    let query_history = |deps: &OwnedDeps<_, _, _>, start_after: Option<u64>, limit: u32| {
+        let msg = QueryMsg::ConfigHistory {
+            start_after,
+            limit: Some(limit),
+        };
+        from_binary::<Vec<ConfigHistoryEntry>>(&query(deps.as_ref(), mock_env(), msg).unwrap())
+            .unwrap()
+    };
+
+    // no config has been replaced yet
+    assert_eq!(query_history(&deps, None, 10), vec![]);
+
+    // the config is updated twice; both replaced configs are recorded, newest first
+    update_config(&mut deps, 100, Decimal::percent(5));
+    update_config(&mut deps, 101, Decimal::percent(8));
+    let mut first_config = mock_config();
+    let mut second_config = mock_config();
+    second_config.fee_rate = Decimal::percent(5);
+    let history = query_history(&deps, None, 10);
+    assert_eq!(
+        history,
+        vec![
+            ConfigHistoryEntry {
+                id: 1,
+                config: second_config.into(),
+                changed_at_height: 101,
+                changed_at_time: mock_env().block.time.seconds(),
+                changed_by: "governance".to_string(),
+            },
+            ConfigHistoryEntry {
+                id: 0,
+                config: first_config.clone().into(),
+                changed_at_height: 100,
+                changed_at_time: mock_env().block.time.seconds(),
+                changed_by: "governance".to_string(),
+            },
+        ]
+    );
+    assert_eq!(query_history(&deps, Some(1), 10), history[1..].to_vec());
+    assert_eq!(CONFIG.load(deps.as_ref().storage).unwrap().fee_rate, Decimal::percent(8));
+
+    // only the most recent 50 entries are retained
+    for i in 0..50 {
+        update_config(&mut deps, 102 + i, Decimal::percent(8));
+    }
+    let history = query_history(&deps, None, 30);
+    assert_eq!(history[0].id, 51);
+    let history = query_history(&deps, Some(history[29].id), 30);
+    assert_eq!(history.len(), 20);
+    assert_eq!(history[19].id, 2);
+    first_config.fee_rate = Decimal::percent(8);
+    assert_eq!(history[19].config, first_config.into());
+}
+
+#[test]
+fn transferring_governance() {
+    let mut deps = setup_test();
+
+    let execute_as = |deps: &mut OwnedDeps<_, _, _>, sender: &str, msg: ExecuteMsg| {
+        execute(deps.as_mut(), mock_env(), mock_info(sender, &[]), msg)
+    };
+    let propose = |new_governance: &str| ExecuteMsg::ProposeNewGovernance {
+        new_governance: new_governance.to_string(),
+    };
+    let governance =
+        |deps: &OwnedDeps<_, _, _>| CONFIG.load(deps.as_ref().storage).unwrap().governance;
+
+    // `UpdateConfig` can't change governance
+    let mut new_config = mock_config();
+    new_config.governance = Addr::unchecked("typo");
+    new_config.fee_rate = Decimal::percent(10);
+    let msg = ExecuteMsg::UpdateConfig {
+        new_config: new_config.into(),
+    };
+    execute_as(&mut deps, "governance", msg).unwrap();
+    let config = CONFIG.load(deps.as_ref().storage).unwrap();
+    assert_eq!(config.governance, Addr::unchecked("governance"));
+    assert_eq!(config.fee_rate, Decimal::percent(10));
+
+    // only governance can propose a new governance
+    let res = execute_as(&mut deps, "alice", propose("alice"));
+    assert_eq!(res, Err(ContractError::unauthorized("only governance can propose new governance")));
+
+    // there is nothing to accept before a proposal is made
+    let res = execute_as(&mut deps, "new_governance", ExecuteMsg::AcceptGovernance {});
+    assert_generic_error_message(res, "no pending governance transfer");
+
+    // a proposal doesn't take effect until it is accepted
+    execute_as(&mut deps, "governance", propose("typo")).unwrap();
+    assert_eq!(governance(&deps), Addr::unchecked("governance"));
+    let res: AdminStatusResponse =
+        from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::AdminStatus {}).unwrap()).unwrap();
+    assert_eq!(res.pending_governance, Some("typo".to_string()));
+
+    // a new proposal overwrites the pending one
+    execute_as(&mut deps, "governance", propose("new_governance")).unwrap();
+
+    // only the proposed new governance can accept
+    for sender in ["typo", "alice", "governance"] {
+        let res = execute_as(&mut deps, sender, ExecuteMsg::AcceptGovernance {});
+        assert_eq!(
+            res,
+            Err(ContractError::unauthorized("only the proposed new governance can accept"))
+        );
+    }
+
+    let res = execute_as(&mut deps, "new_governance", ExecuteMsg::AcceptGovernance {}).unwrap();
+    assert_eq!(attr_value(&res, "previous_governance"), "governance");
+    assert_eq!(governance(&deps), Addr::unchecked("new_governance"));
+    let res: AdminStatusResponse =
+        from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::AdminStatus {}).unwrap()).unwrap();
+    assert_eq!(res.pending_governance, None);
+
+    // the previous governance has lost control
+    let res = execute_as(&mut deps, "governance", propose("governance"));
+    assert_eq!(res, Err(ContractError::unauthorized("only governance can propose new governance")));
+}
+
+#[test]
+fn managing_operators() {
+    let mut deps = setup_test();
+
+    let execute_as = |deps: &mut OwnedDeps<_, _, _>, sender: &str, msg: ExecuteMsg| {
+        execute(deps.as_mut(), mock_env(), mock_info(sender, &[]), msg)
+    };
+    let add = |operator: &str| ExecuteMsg::AddOperator {
+        operator: operator.to_string(),
+    };
+    let remove = |operator: &str| ExecuteMsg::RemoveOperator {
+        operator: operator.to_string(),
+    };
+    let operators =
+        |deps: &OwnedDeps<_, _, _>| CONFIG.load(deps.as_ref().storage).unwrap().operators;
+
+    // only governance can add or remove operators
+    let res = execute_as(&mut deps, "alice", add("alice"));
+    assert_eq!(res, Err(ContractError::unauthorized("only governance can add operators")));
+    let res = execute_as(&mut deps, "alice", remove("operator"));
+    assert_eq!(res, Err(ContractError::unauthorized("only governance can remove operators")));
+
+    // add an operator
+    let res = execute_as(&mut deps, "governance", add("keeper")).unwrap();
+    assert_eq!(res.attributes.iter().find(|attr| attr.key == "warning"), None);
+    assert_eq!(operators(&deps), vec![Addr::unchecked("operator"), Addr::unchecked("keeper")]);
+
+    // adding an existing operator changes nothing
+    let res = execute_as(&mut deps, "governance", add("keeper")).unwrap();
+    assert_eq!(attr_value(&res, "warning"), "keeper is already an operator");
+    assert_eq!(operators(&deps), vec![Addr::unchecked("operator"), Addr::unchecked("keeper")]);
+
+    // remove an operator
+    execute_as(&mut deps, "governance", remove("operator")).unwrap();
+    assert_eq!(operators(&deps), vec![Addr::unchecked("keeper")]);
+
+    // removing an account that isn't an operator fails
+    let res = execute_as(&mut deps, "governance", remove("bob"));
+    assert_generic_error_message(res, "bob is not an operator");
+}
+
+#[test]
+fn simulating_update_position() {
+    let mut deps = setup_test();
+
+    // bond value is 2,000,000 uusd and debt is 500,000 uusd
+    setup_leveraged_position(&mut deps, 500_000);
+
+    let alice = Addr::unchecked("alice");
+    let actions = vec![
+        Action::Deposit(Asset::native("uluna", 100u128).into()),
+        Action::Borrow {
+            amount: Uint128::new(1000),
+        },
+        Action::Bond {
+            slippage_tolerance: None,
+        },
+    ];
+
+    // simulate the update. the query must not write to storage
+    let state_before = STATE.load(deps.as_ref().storage).unwrap();
+    let position_before = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    let msg = QueryMsg::SimulateUpdatePosition {
+        user: Some("alice".to_string()),
+        actions: actions.clone(),
+    };
+    let sim: SimulateResponse =
+        from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+    assert_eq!(STATE.load(deps.as_ref().storage).unwrap(), state_before);
+    assert_eq!(POSITION.load(deps.as_ref().storage, &alice).unwrap(), position_before);
+
+    // 100 uluna and 1000 uusd are provided, minting 100 liquidity tokens
+    assert_eq!(sim.secondary_borrowed, Uint128::new(1000));
+    assert_eq!(sim.primary_provided, Uint128::new(100));
+    assert_eq!(sim.secondary_provided, Uint128::new(1000));
+    assert_eq!(sim.shares_bonded, Uint128::new(100));
+    assert_eq!(sim.refunded, vec![]);
+
+    // execute the same actions, running each callback in turn
+    let info = mock_info("alice", &[Coin::new(100, "uluna")]);
+    execute(deps.as_mut(), mock_env(), info, ExecuteMsg::UpdatePosition(actions)).unwrap();
+
+    execute_callback(
+        &mut deps,
+        CallbackMsg::Borrow {
+            user_addr: alice.clone(),
+            borrow_amount: Uint128::new(1000),
+        },
+    )
+    .unwrap();
+    deps.querier.set_user_debt(MOCK_CONTRACT_ADDR, &AssetInfo::native("uusd"), 501_000);
+
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::ProvideLiquidity {
+            user_addr: Some(alice.clone()),
+            slippage_tolerance: None,
+            min_lp_out: None,
+        },
+    )
+    .unwrap();
+    assert_eq!(attr_value(&res, "primary_provided"), sim.primary_provided.to_string());
+    assert_eq!(attr_value(&res, "secondary_provided"), sim.secondary_provided.to_string());
+    let reply_msg = Reply {
+        id: 0,
+        result: ContractResult::Ok(SubMsgExecutionResponse {
+            events: vec![Event::new("from_contract")
+                .add_attribute("action", "provide_liquidity")
+                .add_attribute("share", "100")],
+            data: None,
+        }),
+    };
+    reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+    deps.querier.set_pool(
+        "uluna_uusd_pair",
+        &[Asset::native("uluna", 1_000_100u128), Asset::native("uusd", 10_001_000u128)],
+        1_000_100,
+    );
+
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::Bond {
+            user_addr: Some(alice.clone()),
+        },
+    )
+    .unwrap();
+    assert_eq!(attr_value(&res, "shares_bonded"), sim.shares_bonded.to_string());
+    deps.querier.set_bonded_amount("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 100_100);
+
+    // the resulting position should be as simulated
+    let msg = QueryMsg::Position {
+        user: "alice".to_string(),
+    };
+    let position: PositionResponse =
+        from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+    assert_eq!(position.bond_units, sim.bond_units);
+    assert_eq!(position.debt_units, sim.debt_units);
+    assert_eq!(position.bond_value, sim.bond_value);
+    assert_eq!(position.debt_value, sim.debt_value);
+    assert_eq!(position.ltv, sim.ltv);
+}
+
+#[test]
+fn keeping_position_snapshots() {
+    let mut deps = setup_test();
+    setup_leveraged_position(&mut deps, 1_000_000);
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.max_snapshots = 3;
+            Ok(config)
+        })
+        .unwrap();
+
+    let alice = Addr::unchecked("alice");
+    let env_at = |height: u64| {
+        let mut env = mock_env();
+        env.block.height = height;
+        env
+    };
+    let query_snapshots = |deps: &OwnedDeps<_, _, _>, start_after: Option<u64>| -> Vec<Snapshot> {
+        let msg = QueryMsg::Snapshots {
+            user: "alice".to_string(),
+            start_after,
+            limit: None,
+        };
+        from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap()
+    };
+
+    // a snapshot is recorded at the end of each `UpdatePosition`
+    let msg = ExecuteMsg::UpdatePosition(vec![Action::Repay {
+        amount: Uint128::new(100),
+        user: None,
+    }]);
+    let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+    let snapshot_msg = CallbackMsg::Snapshot {
+        user_addr: alice.clone(),
+    }
+    .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+    .unwrap();
+    assert!(res.messages.iter().any(|submsg| submsg.msg == snapshot_msg));
+    release_lock(&mut deps);
+
+    // record snapshots across five blocks. only the latest three are kept
+    for height in 101..=105 {
+        let msg = ExecuteMsg::Callback(CallbackMsg::Snapshot {
+            user_addr: alice.clone(),
+        });
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        let res = execute(deps.as_mut(), env_at(height), info, msg).unwrap();
+        let expected_evicted = if height > 103 { "1" } else { "0" };
+        assert_eq!(attr_value(&res, "snapshots_evicted"), expected_evicted);
+    }
+
+    let snapshots = query_snapshots(&deps, None);
+    let heights = snapshots.iter().map(|snapshot| snapshot.height).collect::<Vec<_>>();
+    assert_eq!(heights, vec![105, 104, 103]);
+    assert_eq!(snapshots[0].position.bond_value, Uint128::new(2_000_000));
+    assert_eq!(snapshots[0].position.debt_value, Uint128::new(1_000_000));
+
+    // snapshots are paginated by height
+    let snapshots = query_snapshots(&deps, Some(104));
+    let heights = snapshots.iter().map(|snapshot| snapshot.height).collect::<Vec<_>>();
+    assert_eq!(heights, vec![103]);
+
+    // with snapshots disabled, none is recorded
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.max_snapshots = 0;
+            Ok(config)
+        })
+        .unwrap();
+    let msg = ExecuteMsg::UpdatePosition(vec![Action::Repay {
+        amount: Uint128::new(100),
+        user: None,
+    }]);
+    let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+    assert!(!res.messages.iter().any(|submsg| submsg.msg == snapshot_msg));
+}
+
+#[test]
+fn computing_balancing_swap() {
+    let commission_rate = Decimal::permille(3);
+
+    // how far the assets are from the pool's ratio after offering `s`, per Astroport's XYK math
+    // without rounding. the imbalance is scaled and truncated so that amounts can be compared
+    let imbalance = |offer_depth: u128, ask_depth: u128, offer: u128, ask: u128, s: u128| {
+        let (big_x, big_y, x, y, s) =
+            (offer_depth as f64, ask_depth as f64, offer as f64, ask as f64, s as f64);
+        let o = 0.997 * big_y * s / (big_x + s);
+        let diff = (x - s) * (big_y - o) - (big_x + s) * (y + o);
+        (diff.abs() * 1000.0) as u128
+    };
+
+    // (primary depth, secondary depth, primary amount, secondary amount)
+    let samples: [(u128, u128, u128, u128); 6] = [
+        (1_000_000, 10_000_000, 0, 10_000),
+        (1_000_000, 10_000_000, 1_000, 0),
+        (1_000_000, 10_000_000, 800, 2_000),
+        (123_456, 987_654, 5_000, 300),
+        (987_654, 123_456, 70, 40_000),
+        (50_000, 50_000, 0, 60_000),
+    ];
+    for (primary_depth, secondary_depth, primary_amount, secondary_amount) in samples {
+        let (offer_asset_info, offer_amount) = compute_balancing_swap(
+            &Asset::native("uluna", primary_depth),
+            &Asset::native("uusd", secondary_depth),
+            Uint128::new(primary_amount),
+            Uint128::new(secondary_amount),
+            commission_rate,
+        );
+
+        // the asset in excess relative to the pool is offered
+        let (offer_depth, ask_depth, offer, ask) =
+            if primary_amount * secondary_depth > secondary_amount * primary_depth {
+                assert_eq!(offer_asset_info, AssetInfo::native("uluna"));
+                (primary_depth, secondary_depth, primary_amount, secondary_amount)
+            } else {
+                assert_eq!(offer_asset_info, AssetInfo::native("uusd"));
+                (secondary_depth, primary_depth, secondary_amount, primary_amount)
+            };
+
+        // the computed amount should be within rounding of the best found by trying every amount
+        let best = (0..=offer)
+            .min_by_key(|s| imbalance(offer_depth, ask_depth, offer, ask, *s))
+            .unwrap();
+        assert!(
+            offer_amount.u128() + 1 >= best && offer_amount.u128() <= best + 1,
+            "pool {}/{}, amounts {}/{}: computed {}, best {}",
+            primary_depth,
+            secondary_depth,
+            primary_amount,
+            secondary_amount,
+            offer_amount,
+            best
+        );
+    }
+
+    // no swap is needed if the assets are already in the pool's ratio, or the pool is empty
+    let swap = |primary_depth: u128, secondary_depth: u128, primary: u128, secondary: u128| {
+        compute_balancing_swap(
+            &Asset::native("uluna", primary_depth),
+            &Asset::native("uusd", secondary_depth),
+            Uint128::new(primary),
+            Uint128::new(secondary),
+            commission_rate,
+        )
+        .1
+    };
+    assert_eq!(swap(1_000_000, 10_000_000, 100, 1_000), Uint128::zero());
+    assert_eq!(swap(0, 0, 100, 0), Uint128::zero());
+
+    // the 512-bit math doesn't overflow with 18-decimal tokens in deep pools
+    let amount = swap(10u128.pow(33), 10u128.pow(34), 10u128.pow(30), 0);
+    assert!(amount > Uint128::new(499 * 10u128.pow(27)));
+    assert!(amount < Uint128::new(501 * 10u128.pow(27)));
+}
+
+#[test]
+fn validating_liquidity_tokens() {
+    let config = mock_config();
+
+    // the configured liquidity token of the primary pair must be the one reported by the pair
+    let mut deps = mock_dependencies();
+    set_pair_infos(&mut deps, PairType::Xyk);
+    deps.querier.set_pair_info(
+        "uluna_uusd_pair",
+        &[AssetInfo::native("uluna"), AssetInfo::native("uusd")],
+        "fake_lp_token",
+        PairType::Xyk,
+    );
+    let msg = InstantiateMsg {
+        config: config.clone().into(),
+        limits: None,
+    };
+    let res = instantiate(deps.as_mut(), mock_env(), mock_info("deployer", &[]), msg);
+    assert_generic_error_message(
+        res,
+        "liquidity token mismatch for uluna_uusd_pair: configured uluna_uusd_lp_token, on-chain \
+         fake_lp_token",
+    );
+
+    // the same goes for the ASTRO pair
+    let mut deps = mock_dependencies();
+    set_pair_infos(&mut deps, PairType::Xyk);
+    deps.querier.set_pair_info(
+        "astro_uusd_pair",
+        &[AssetInfo::cw20(Addr::unchecked("astro_token")), AssetInfo::native("uusd")],
+        "fake_lp_token",
+        PairType::Xyk,
+    );
+    let msg = InstantiateMsg {
+        config: config.clone().into(),
+        limits: None,
+    };
+    let res = instantiate(deps.as_mut(), mock_env(), mock_info("deployer", &[]), msg);
+    assert_generic_error_message(
+        res,
+        "liquidity token mismatch for astro_uusd_pair: configured astro_uusd_lp_token, on-chain \
+         fake_lp_token",
+    );
+
+    // a config update is checked the same way
+    let mut deps = setup_test();
+    deps.querier.set_pair_info(
+        "uluna_uusd_pair",
+        &[AssetInfo::native("uluna"), AssetInfo::native("uusd")],
+        "fake_lp_token",
+        PairType::Xyk,
+    );
+    let msg = ExecuteMsg::UpdateConfig {
+        new_config: config.into(),
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), msg);
+    assert_generic_error_message(
+        res,
+        "liquidity token mismatch for uluna_uusd_pair: configured uluna_uusd_lp_token, on-chain \
+         fake_lp_token",
+    );
+}
+
+#[test]
+fn reverse_simulating_with_ceiling() {
+    let mut deps = mock_dependencies();
+    let pair = mock_config().primary_pair;
+    let offer_info = AssetInfo::native("uluna");
+
+    // pool depths of uluna and uusd, the amount of uusd to ask for, and the amount of uluna the
+    // ceiling variant offers. in each case, offering the reverse-simulated amount falls short
+    for (uluna_depth, uusd_depth, ask_amount, expected_offer) in [
+        (1_497_005_315u128, 24_450_395_383u128, 1_291_320_960u128, 83_736_356u128),
+        (1_000_000, 10_000_000, 200_000, 20_471),
+        (123_456_789, 987_654_321, 55_555_555, 7_381_817),
+        (7, 1_000_000_007, 3, 1),
+    ] {
+        deps.querier.set_pool(
+            "uluna_uusd_pair",
+            &[Asset::native("uluna", uluna_depth), Asset::native("uusd", uusd_depth)],
+            1_000_000,
+        );
+        let querier = QuerierWrapper::new(&deps.querier);
+        let ask_asset = Asset::native("uusd", ask_amount);
+        let simulate = |offer_amount: Uint128| {
+            pair.query_simulate(&querier, &Asset::new(offer_info.clone(), offer_amount)).unwrap()
+        };
+
+        let naive_offer = pair.query_reverse_simulate(&querier, &ask_asset).unwrap();
+        assert!(simulate(naive_offer) < ask_asset.amount);
+
+        let offer = pair.query_reverse_simulate_ceil(&querier, &offer_info, &ask_asset).unwrap();
+        assert_eq!(offer, Uint128::new(expected_offer));
+        assert!(simulate(offer) >= ask_asset.amount);
+    }
+}
+
+#[test]
+fn supporting_stableswap_pairs() {
+    let mut config = mock_config();
+    config.primary_pair.pair_type = PairType::Stable;
+
+    // the configured pair type must match the one reported by the pair contract
+    let mut deps = mock_dependencies();
+    set_pair_infos(&mut deps, PairType::Xyk);
+    let msg = InstantiateMsg {
+        config: config.clone().into(),
+        limits: None,
+    };
+    let res = instantiate(deps.as_mut(), mock_env(), mock_info("deployer", &[]), msg);
+    assert_generic_error_message(
+        res,
+        "pair type mismatch for uluna_uusd_pair: configured stable, on-chain xyk",
+    );
+
+    let mut deps = mock_dependencies();
+    set_pair_infos(&mut deps, PairType::Stable);
+    let msg = InstantiateMsg {
+        config: config.clone().into(),
+        limits: None,
+    };
+    instantiate(deps.as_mut(), mock_env(), mock_info("deployer", &[]), msg).unwrap();
+
+    let pair = config.primary_pair;
+    let ask_asset = Asset::native("uusd", 10_000u128);
+
+    // the amount of uluna to offer for 10,000 uusd; and the amount of uusd to swap for balancing
+    // 10,000 uusd, as well as the uluna it returns; in a pool with the specified depths
+    let mut simulate = |uluna_depth: u128, uusd_depth: u128| -> (u128, u128, u128) {
+        deps.querier.set_pool(
+            "uluna_uusd_pair",
+            &[Asset::native("uluna", uluna_depth), Asset::native("uusd", uusd_depth)],
+            1_000_000,
+        );
+        let querier = QuerierWrapper::new(&deps.querier);
+
+        let offer_info = AssetInfo::native("uluna");
+        let offer_amount =
+            pair.query_reverse_simulate_ceil(&querier, &offer_info, &ask_asset).unwrap();
+        let offer_asset = Asset::new(offer_info, offer_amount);
+        assert!(pair.query_simulate(&querier, &offer_asset).unwrap() >= ask_asset.amount);
+
+        let (swap_info, swap_amount) = compute_stable_balancing_swap(
+            &Asset::native("uluna", uluna_depth),
+            &Asset::native("uusd", uusd_depth),
+            Uint128::zero(),
+            Uint128::new(10_000),
+            PairType::Stable.commission_rate(),
+        );
+        assert_eq!(swap_info, AssetInfo::native("uusd"));
+        let swap_asset = Asset::new(swap_info, swap_amount);
+        let swap_return = pair.query_simulate(&querier, &swap_asset).unwrap();
+
+        (offer_amount.u128(), swap_amount.u128(), swap_return.u128())
+    };
+
+    // at the peg, the offer amount only covers the 0.05% commission, and about half of the uusd
+    // is swapped
+    let (offer_at_peg, swap_at_peg, swap_return) = simulate(1_000_000, 1_000_000);
+    assert_eq!(offer_at_peg, 10_005);
+    assert_eq!(swap_at_peg, 4_976);
+
+    // away from the peg, uluna is cheaper when it is in excess in the pool, and dearer when uusd is
+    let (offer, _, _) = simulate(3_000_000, 1_000_000);
+    assert!(offer > offer_at_peg);
+    let (offer, _, _) = simulate(1_000_000, 3_000_000);
+    assert!(offer < ask_asset.amount.u128());
+
+    // balancing at the peg leaves the assets in the pool's ratio after the swap, to within 0.01%
+    let (uluna_depth, uusd_depth) = (1_000_000 - swap_return, 1_000_000 + swap_at_peg);
+    let (uluna, uusd) = (swap_return, 10_000 - swap_at_peg);
+    let user_ratio = uluna * 1_000_000 / uusd;
+    let pool_ratio = uluna_depth * 1_000_000 / uusd_depth;
+    assert!(user_ratio.max(pool_ratio) - user_ratio.min(pool_ratio) < 100);
+}
+
+#[test]
+fn zapping_single_asset_into_position() {
+    let mut deps = setup_test();
+    setup_leveraged_position(&mut deps, 0);
+
+    let alice = Addr::unchecked("alice");
+    let actions = vec![
+        Action::Deposit(Asset::native("uusd", 1000u128).into()),
+        Action::Zap {
+            slippage_tolerance: None,
+        },
+    ];
+
+    // the simulation should predict a balanced provision. half of the uusd is swapped to uluna
+    let msg = QueryMsg::SimulateUpdatePosition {
+        user: Some("alice".to_string()),
+        actions: actions.clone(),
+    };
+    let sim: SimulateResponse =
+        from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+    assert_eq!(sim.secondary_provided, Uint128::new(500));
+    assert_eq!(sim.primary_provided, Uint128::new(50));
+
+    // alice deposits only uusd, and zaps it into her position
+    let info = mock_info("alice", &[Coin::new(1000, "uusd")]);
+    let msg = ExecuteMsg::UpdatePosition(actions);
+    let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+    let expected_callbacks = [
+        CallbackMsg::Balance {
+            user_addr: Some(alice.clone()),
+            max_spread: None,
+        },
+        CallbackMsg::ProvideLiquidity {
+            user_addr: Some(alice.clone()),
+            slippage_tolerance: None,
+            min_lp_out: None,
+        },
+        CallbackMsg::Bond {
+            user_addr: Some(alice.clone()),
+        },
+    ];
+    let expected_msgs = expected_callbacks
+        .iter()
+        .map(|callback| callback.into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR)).unwrap())
+        .collect::<Vec<_>>();
+    let msgs = res.messages.iter().take(3).map(|submsg| submsg.msg.clone()).collect::<Vec<_>>();
+    assert_eq!(msgs, expected_msgs);
+
+    // balance: the uusd is worth $1000 and there is no uluna, so $500 worth of uusd is swapped
+    let res = execute_callback(&mut deps, expected_callbacks[0].clone()).unwrap();
+    assert_eq!(attr_value(&res, "asset_offered"), "native:uusd:500");
+    let reply_msg = Reply {
+        id: res.messages[0].id,
+        result: ContractResult::Ok(SubMsgExecutionResponse {
+            events: vec![Event::new("from_contract")
+                .add_attribute("action", "swap")
+                .add_attribute("ask_asset", "uluna")
+                .add_attribute("return_amount", "50")
+                .add_attribute("tax_amount", "0")],
+            data: None,
+        }),
+    };
+    reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+
+    // provide: the two assets are provided with roughly equal values
+    let res = execute_callback(&mut deps, expected_callbacks[1].clone()).unwrap();
+    assert_eq!(attr_value(&res, "primary_provided"), "50");
+    assert_eq!(attr_value(&res, "secondary_provided"), "500");
+    let position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    assert_eq!(position.unlocked_assets, AssetList::default());
+}
+
+#[test]
+fn zapping_out_to_single_asset() {
+    let mut deps = setup_test();
+    setup_leveraged_position(&mut deps, 0);
+
+    let alice = Addr::unchecked("alice");
+    let unbond = |swap_to: AssetInfo| {
+        ExecuteMsg::UpdatePosition(vec![Action::Unbond {
+            bond_units_to_reduce: Uint128::new(10_000_000),
+            swap_to: Some(swap_to.into()),
+        }])
+    };
+
+    // only the primary or the secondary asset can be swapped to
+    let msg = unbond(AssetInfo::cw20(Addr::unchecked("astro_token")));
+    let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg);
+    assert_generic_error_message(
+        res,
+        "invalid swap_to asset: cw20:astro_token; must be primary or secondary asset",
+    );
+
+    // unbonding with `swap_to = uusd` swaps all of the withdrawn uluna before the refund
+    let msg = unbond(AssetInfo::native("uusd"));
+    let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+    let expected_callbacks = [
+        CallbackMsg::Unbond {
+            user_addr: alice.clone(),
+            bond_units_to_reduce: Uint128::new(10_000_000),
+        },
+        CallbackMsg::WithdrawLiquidity {
+            user_addr: alice.clone(),
+        },
+        CallbackMsg::Swap {
+            user_addr: Some(alice.clone()),
+            offer_asset_info: AssetInfo::native("uluna"),
+            offer_amount: None,
+            max_spread: None,
+            min_return: None,
+        },
+        CallbackMsg::Refund {
+            user_addr: alice.clone(),
+            recipient_addr: alice.clone(),
+            percentage: Decimal::one(),
+        },
+    ];
+    let expected_msgs = expected_callbacks
+        .iter()
+        .map(|callback| callback.into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR)).unwrap())
+        .collect::<Vec<_>>();
+    let msgs = res.messages.iter().take(4).map(|submsg| submsg.msg.clone()).collect::<Vec<_>>();
+    assert_eq!(msgs, expected_msgs);
+
+    // the withdrawal returns 1,000 uluna and 10,000 uusd
+    let mut position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    position.unlocked_assets = AssetList::from(vec![
+        Asset::native("uluna", 1000u128),
+        Asset::native("uusd", 10_000u128),
+    ]);
+    POSITION.save(deps.as_mut().storage, &alice, &position).unwrap();
+
+    // swap: all of the uluna is offered, returning 9,900 uusd
+    let res = execute_callback(&mut deps, expected_callbacks[2].clone()).unwrap();
+    assert_eq!(attr_value(&res, "asset_offered"), "native:uluna:1000");
+    let reply_msg = Reply {
+        id: res.messages[0].id,
+        result: ContractResult::Ok(SubMsgExecutionResponse {
+            events: vec![Event::new("from_contract")
+                .add_attribute("action", "swap")
+                .add_attribute("ask_asset", "uusd")
+                .add_attribute("return_amount", "9900")
+                .add_attribute("tax_amount", "0")],
+            data: None,
+        }),
+    };
+    reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+
+    // refund: alice receives only uusd
+    let res = execute_callback(&mut deps, expected_callbacks[3].clone()).unwrap();
+    let msgs = res.messages.into_iter().map(|submsg| submsg.msg).collect::<Vec<_>>();
+    assert_eq!(msgs, vec![Asset::native("uusd", 19_900u128).transfer_msg("alice").unwrap()]);
+}
+
+#[test]
+fn closing_position() {
+    let mut deps = setup_test();
+
+    // alice's LTV is 60%; the position is healthy
+    setup_leveraged_position(&mut deps, 1_200_000);
+
+    let alice = Addr::unchecked("alice");
+    let msg = ExecuteMsg::UpdatePosition(vec![Action::Close {}]);
+    let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+    let expected_callbacks = [
+        CallbackMsg::Unbond {
+            user_addr: alice.clone(),
+            bond_units_to_reduce: Uint128::new(100_000_000),
+        },
+        CallbackMsg::WithdrawLiquidity {
+            user_addr: alice.clone(),
+        },
+        CallbackMsg::Cover {
+            user_addr: alice.clone(),
+            max_spread: Decimal::percent(5),
+        },
+        CallbackMsg::Repay {
+            user_addr: Some(alice.clone()),
+            repay_amount: None,
+        },
+        CallbackMsg::Refund {
+            user_addr: alice.clone(),
+            recipient_addr: alice.clone(),
+            percentage: Decimal::one(),
+        },
+        CallbackMsg::AssertHealth {
+            user_addr: alice.clone(),
+        },
+    ];
+    let expected_msgs = expected_callbacks
+        .iter()
+        .map(|callback| callback.into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR)).unwrap())
+        .collect::<Vec<_>>();
+    let msgs = res.messages.iter().take(6).map(|submsg| submsg.msg.clone()).collect::<Vec<_>>();
+    assert_eq!(msgs, expected_msgs);
+
+    // unbond: all of alice's bond units are burned
+    execute_callback(&mut deps, expected_callbacks[0].clone()).unwrap();
+    deps.querier.set_bonded_amount("uluna_uusd_lp_token", MOCK_CONTRACT_ADDR, 0);
+    let position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    assert_eq!(position.bond_units, Uint128::zero());
+
+    // the withdrawal returns 100,000 uluna and 1,000,000 uusd
+    let mut position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    position.unlocked_assets = AssetList::from(vec![
+        Asset::native("uluna", 100_000u128),
+        Asset::native("uusd", 1_000_000u128),
+    ]);
+    POSITION.save(deps.as_mut().storage, &alice, &position).unwrap();
+
+    // cover: just enough uluna is sold to pay off the remaining 200,000 uusd of debt
+    let res = execute_callback(&mut deps, expected_callbacks[2].clone()).unwrap();
+    assert_eq!(attr_value(&res, "secondary_needed"), "200000");
+    assert_eq!(attr_value(&res, "primary_sold"), "20471");
+    let reply_msg = Reply {
+        id: res.messages[0].id,
+        result: ContractResult::Ok(SubMsgExecutionResponse {
+            events: vec![Event::new("from_contract")
+                .add_attribute("action", "swap")
+                .add_attribute("ask_asset", "uusd")
+                .add_attribute("return_amount", "200050")
+                .add_attribute("tax_amount", "0")],
+            data: None,
+        }),
+    };
+    reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+
+    // repay: all of the debt is paid off
+    execute_callback(&mut deps, expected_callbacks[3].clone()).unwrap();
+    let position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    assert_eq!(position.bond_units, Uint128::zero());
+    assert_eq!(position.debt_units, Uint128::zero());
+
+    // refund: alice receives the remaining uluna and uusd
+    let res = execute_callback(&mut deps, expected_callbacks[4].clone()).unwrap();
+    let msgs = res.messages.into_iter().map(|submsg| submsg.msg).collect::<Vec<_>>();
+    assert_eq!(
+        msgs,
+        vec![
+            Asset::native("uluna", 79_529u128).transfer_msg("alice").unwrap(),
+            Asset::native("uusd", 50u128).transfer_msg("alice").unwrap(),
+        ]
+    );
+
+    // with no debt left, the position passes the health check
+    execute_callback(&mut deps, expected_callbacks[5].clone()).unwrap();
+
+    // bob's position has no debt, so closing it only unbonds, withdraws and refunds. there is
+    // nothing to repay, and no health check is needed
+    let bob = Addr::unchecked("bob");
+    release_lock(&mut deps);
+    let position = Position {
+        bond_units: Uint128::new(100_000_000),
+        ..Default::default()
+    };
+    POSITION.save(deps.as_mut().storage, &bob, &position).unwrap();
+    let msg = ExecuteMsg::UpdatePosition(vec![Action::Close {}]);
+    let res = execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), msg).unwrap();
+    let expected_callbacks = [
+        CallbackMsg::Unbond {
+            user_addr: bob.clone(),
+            bond_units_to_reduce: Uint128::new(100_000_000),
+        },
+        CallbackMsg::WithdrawLiquidity {
+            user_addr: bob.clone(),
+        },
+        CallbackMsg::Refund {
+            user_addr: bob.clone(),
+            recipient_addr: bob.clone(),
+            percentage: Decimal::one(),
+        },
+    ];
+    let expected_msgs = expected_callbacks
+        .iter()
+        .map(|callback| callback.into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR)).unwrap())
+        .collect::<Vec<_>>();
+    let msgs = res.messages.iter().take(3).map(|submsg| submsg.msg.clone()).collect::<Vec<_>>();
+    assert_eq!(msgs, expected_msgs);
+    let assert_health = CallbackMsg::AssertHealth {
+        user_addr: bob.clone(),
+    }
+    .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+    .unwrap();
+    assert!(!res.messages.iter().any(|submsg| submsg.msg == assert_health));
+}
+
+/// Configure the strategy to borrow uatom, which is swapped to and from uusd using the uatom-uusd
+/// pair (100,000 uatom + 1,000,000 uusd; uatom price 10)
+fn set_borrow_asset(deps: &mut OwnedDeps<MockStorage, MockApi, CustomMockQuerier>) -> Pair {
+    let borrow_pair = Pair {
+        contract_addr: Addr::unchecked("uatom_uusd_pair"),
+        liquidity_token: Addr::unchecked("uatom_uusd_lp_token"),
+        pair_type: PairType::Xyk,
+    };
+    let mut config = CONFIG.load(deps.as_ref().storage).unwrap();
+    config.borrow_asset_info = Some(AssetInfo::native("uatom"));
+    config.borrow_pair = Some(borrow_pair.clone());
+    CONFIG.save(deps.as_mut().storage, &config).unwrap();
+
+    deps.querier.set_pool(
+        "uatom_uusd_pair",
+        &[Asset::native("uatom", 100_000u128), Asset::native("uusd", 1_000_000u128)],
+        100_000,
+    );
+    deps.querier.set_oracle_price(&AssetInfo::native("uatom"), Decimal::percent(1000));
+    borrow_pair
+}
+
+#[test]
+fn validating_borrow_asset() {
+    let mut config = mock_config();
+    config.borrow_asset_info = Some(AssetInfo::native("uatom"));
+    assert_generic_error_message(
+        config.validate(&Limits::default()),
+        "invalid borrow pair: required if the borrowed asset is not the secondary asset",
+    );
+
+    // borrowing the secondary asset explicitly is the same as not configuring a borrowed asset
+    config.borrow_asset_info = Some(AssetInfo::native("uusd"));
+    config.validate(&Limits::default()).unwrap();
+    assert_eq!(config.borrow_pair(), None);
+
+    config.borrow_asset_info = Some(AssetInfo::native("uluna"));
+    assert_generic_error_message(
+        config.validate(&Limits::default()),
+        "invalid assets: borrowed asset must be different from primary asset",
+    );
+}
+
+#[test]
+fn borrowing_asset_other_than_secondary() {
+    let mut deps = setup_test();
+    let alice = Addr::unchecked("alice");
+
+    // alice owes 10,000 uatom, worth 100,000 uusd; the uusd debt reported by Red Bank is ignored
+    setup_leveraged_position(&mut deps, 10_000);
+    let borrow_pair = set_borrow_asset(&mut deps);
+    deps.querier.set_user_debt(MOCK_CONTRACT_ADDR, &AssetInfo::native("uatom"), 10_000);
+
+    let msg = QueryMsg::Position {
+        user: "alice".to_string(),
+    };
+    let position: PositionResponse =
+        from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+    assert_eq!(position.debt_amount, Uint128::new(10_000));
+    assert_eq!(position.debt_value, Uint128::new(100_000));
+
+    // the uatom borrowed is swapped to uusd right away, so that it can be provided
+    let msg = ExecuteMsg::UpdatePosition(vec![Action::Borrow {
+        amount: Uint128::new(500),
+    }]);
+    let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+    let expected_callbacks = [
+        CallbackMsg::Borrow {
+            user_addr: alice.clone(),
+            borrow_amount: Uint128::new(500),
+        },
+        CallbackMsg::Swap {
+            user_addr: Some(alice.clone()),
+            offer_asset_info: AssetInfo::native("uatom"),
+            offer_amount: Some(Uint128::new(500)),
+            max_spread: None,
+            min_return: None,
+        },
+    ];
+    let expected_msgs = expected_callbacks
+        .iter()
+        .map(|callback| callback.into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR)).unwrap())
+        .collect::<Vec<_>>();
+    let msgs = res.messages.iter().take(2).map(|submsg| submsg.msg.clone()).collect::<Vec<_>>();
+    assert_eq!(msgs, expected_msgs);
+
+    let res = execute_callback(&mut deps, expected_callbacks[0].clone()).unwrap();
+    let red_bank = RedBank {
+        contract_addr: Addr::unchecked("red_bank"),
+    };
+    assert_eq!(
+        res.messages[0].msg,
+        red_bank.borrow_msg(&Asset::native("uatom", 500u128)).unwrap()
+    );
+    let position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    assert_eq!(position.unlocked_assets, AssetList::from(vec![Asset::native("uatom", 500u128)]));
+
+    let res = execute_callback(&mut deps, expected_callbacks[1].clone()).unwrap();
+    assert_eq!(
+        res.messages,
+        vec![borrow_pair
+            .swap_submsg(encode_reply_id(2, 0), &Asset::native("uatom", 500u128), None, None)
+            .unwrap()]
+    );
+
+    // repaying takes the user's unlocked uatom
+    let mut position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    position.unlocked_assets = AssetList::from(vec![Asset::native("uatom", 300u128)]);
+    POSITION.save(deps.as_mut().storage, &alice, &position).unwrap();
+    let callback = CallbackMsg::Repay {
+        user_addr: Some(alice.clone()),
+        repay_amount: None,
+    };
+    let res = execute_callback(&mut deps, callback).unwrap();
+    assert_eq!(
+        res.messages[0].msg,
+        red_bank.repay_msg(&Asset::native("uatom", 300u128)).unwrap()
+    );
+
+    // the simulations only support borrowing the secondary asset
+    let msg = QueryMsg::SimulateUpdatePosition {
+        user: None,
+        actions: vec![],
+    };
+    assert_generic_error_message(
+        query(deps.as_ref(), mock_env(), msg),
+        "simulation not supported when borrowing native:uatom instead of the secondary asset",
+    );
+}
+
+#[test]
+fn covering_debt_in_asset_other_than_secondary() {
+    let mut deps = setup_test();
+    let alice = Addr::unchecked("alice");
+
+    setup_leveraged_position(&mut deps, 10_000);
+    let borrow_pair = set_borrow_asset(&mut deps);
+    deps.querier.set_user_debt(MOCK_CONTRACT_ADDR, &AssetInfo::native("uatom"), 10_000);
+
+    let mut position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    position.unlocked_assets = AssetList::from(vec![
+        Asset::native("uluna", 50_000u128),
+        Asset::native("uusd", 40_000u128),
+    ]);
+    POSITION.save(deps.as_mut().storage, &alice, &position).unwrap();
+
+    let swap_reply = |ask_asset: &str, return_amount: &str, nonce: u64| Reply {
+        id: encode_reply_id(2, nonce),
+        result: ContractResult::Ok(SubMsgExecutionResponse {
+            events: vec![Event::new("from_contract")
+                .add_attribute("action", "swap")
+                .add_attribute("ask_asset", ask_asset)
+                .add_attribute("return_amount", return_amount)
+                .add_attribute("tax_amount", "0")],
+            data: None,
+        }),
+    };
+
+    // buying 10,000 uatom takes more uusd than alice has, so uluna is sold for the shortfall first,
+    // and cover is invoked again to make the second hop
+    let callback = CallbackMsg::Cover {
+        user_addr: alice.clone(),
+        max_spread: Decimal::percent(5),
+    };
+    let res = execute_callback(&mut deps, callback.clone()).unwrap();
+    let primary_pair = CONFIG.load(deps.as_ref().storage).unwrap().primary_pair;
+    assert_eq!(attr_value(&res, "secondary_needed"), "10000");
+    assert_eq!(attr_value(&res, "primary_sold"), "7222");
+    assert_eq!(
+        res.messages[0],
+        primary_pair
+            .swap_submsg(
+                encode_reply_id(2, 0),
+                &Asset::native("uluna", 7222u128),
+                None,
+                Some(Decimal::percent(5))
+            )
+            .unwrap()
+    );
+    assert_eq!(
+        res.messages[1].msg,
+        callback.clone().into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR)).unwrap()
+    );
+    reply(deps.as_mut(), mock_env(), swap_reply("uusd", "71600", 0)).unwrap();
+
+    // alice now has enough uusd to buy the uatom, which is done without selling more uluna
+    let res = execute_callback(&mut deps, callback).unwrap();
+    assert_eq!(attr_value(&res, "asset_sold"), "native:uusd:111481");
+    assert_eq!(
+        res.messages,
+        vec![borrow_pair
+            .swap_submsg(
+                encode_reply_id(2, 1),
+                &Asset::native("uusd", 111_481u128),
+                None,
+                Some(Decimal::percent(5))
+            )
+            .unwrap()]
+    );
+    reply(deps.as_mut(), mock_env(), swap_reply("uatom", "10000", 1)).unwrap();
+
+    // all of the debt is repaid in uatom
+    let callback = CallbackMsg::Repay {
+        user_addr: Some(alice.clone()),
+        repay_amount: None,
+    };
+    let res = execute_callback(&mut deps, callback).unwrap();
+    let red_bank = RedBank {
+        contract_addr: Addr::unchecked("red_bank"),
+    };
+    assert_eq!(
+        res.messages[0].msg,
+        red_bank.repay_msg(&Asset::native("uatom", 10_000u128)).unwrap()
+    );
+    let position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    assert_eq!(position.debt_units, Uint128::zero());
+    assert_eq!(
+        position.unlocked_assets,
+        AssetList::from(vec![Asset::native("uluna", 42_778u128), Asset::native("uusd", 119u128)])
+    );
+}
+
+#[test]
+fn handling_empty_pool() {
+    let mut deps = setup_test();
+    setup_leveraged_position(&mut deps, 200_000);
+
+    let mut position = POSITION.load(deps.as_ref().storage, &Addr::unchecked("alice")).unwrap();
+    position.unlocked_assets = AssetList::from(vec![
+        Asset::native("uluna", 1000u128),
+        Asset::native("uusd", 10_000u128),
+    ]);
+    POSITION.save(deps.as_mut().storage, &Addr::unchecked("alice"), &position).unwrap();
+
+    // the pool is fully drained, so its price is undefined
+    deps.querier.set_pool(
+        "uluna_uusd_pair",
+        &[Asset::native("uluna", 0u128), Asset::native("uusd", 0u128)],
+        0,
+    );
+    let err_msg = "pool uluna_uusd_pair has no liquidity: primary depth 0, secondary depth 0, \
+        total share 0";
+
+    // providing liquidity or balancing assets against the pool should fail with a descriptive
+    // error, rather than seeding the pool at an arbitrary price
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::ProvideLiquidity {
+            user_addr: Some(Addr::unchecked("alice")),
+            slippage_tolerance: None,
+            min_lp_out: None,
+        },
+    );
+    assert_generic_error_message(res, err_msg);
+
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::Balance {
+            user_addr: Some(Addr::unchecked("alice")),
+            max_spread: None,
+        },
+    );
+    assert_generic_error_message(res, err_msg);
+
+    // same for queries that derive amounts from the pool's depths
+    let msg = QueryMsg::SimulateUpdatePosition {
+        user: Some("alice".to_string()),
+        actions: vec![Action::Bond {
+            slippage_tolerance: None,
+        }],
+    };
+    assert_generic_error_message(query(deps.as_ref(), mock_env(), msg), err_msg);
+
+    // the health of the position can still be computed: the bonded shares have no value, and LTV is
+    // undefined
+    let msg = QueryMsg::Position {
+        user: "alice".to_string(),
+    };
+    let res: PositionResponse =
+        from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+    assert_eq!(res.bond_value, Uint128::zero());
+    assert_eq!(res.ltv, None);
+
+    let msg = QueryMsg::LiquidationProfit {
+        user: "alice".to_string(),
+    };
+    let res: LiquidationProfitResponse =
+        from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+    assert!(!res.liquidatable);
+}
+
+#[test]
+fn returning_specific_errors() {
+    let mut deps = setup_test();
+
+    // alice's LTV is 50%; the position is healthy
+    setup_leveraged_position(&mut deps, 1_000_000);
+    let liquidate = |deps: &mut OwnedDeps<_, _, _>, user: &str| {
+        let msg = ExecuteMsg::Liquidate {
+            user: user.to_string(),
+            max_bond_units: None,
+            max_spread: None,
+            bonus_asset: None,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("liquidator", &[]), msg)
+    };
+    assert_eq!(liquidate(&mut deps, "alice"), Err(ContractError::PositionHealthy));
+
+    // bob has no position
+    assert_eq!(liquidate(&mut deps, "bob"), Err(ContractError::PositionClosed));
+
+    // alice's LTV is 75%, above the max LTV
+    setup_leveraged_position(&mut deps, 1_500_000);
+    let callback = CallbackMsg::AssertHealth {
+        user_addr: Addr::unchecked("alice"),
+    };
+    let err = execute_callback(&mut deps, callback).unwrap_err();
+    assert_eq!(
+        err,
+        ContractError::LtvTooHigh {
+            ltv: Some(Decimal::percent(75)),
+        }
+    );
+
+    // the messages are the same as those of the generic errors the variants replace
+    assert_eq!(err.to_string(), "ltv greater than threshold: 0.75");
+    let err = ContractError::LtvTooHigh {
+        ltv: None,
+    };
+    assert_eq!(err.to_string(), "ltv greater than threshold: null");
+    assert_eq!(ContractError::PositionHealthy.to_string(), "position is healthy");
+    assert_eq!(ContractError::PositionClosed.to_string(), "position is already closed");
+    let err = ContractError::Paused {
+        kind: ActionKind::Borrow,
+    };
+    assert_eq!(err.to_string(), "contract is paused: cannot borrow");
+
+    // other errors are wrapped `StdError`s
+    let msg = ExecuteMsg::Liquidate {
+        user: "alice".to_string(),
+        max_bond_units: None,
+        max_spread: Some(Decimal::percent(50)),
+        bonus_asset: None,
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("liquidator", &[]), msg);
+    assert_eq!(
+        res,
+        Err(ContractError::Std(StdError::generic_err(
+            "max spread 0.5 exceeds liquidation max spread 0.05"
+        )))
+    );
+}
+
+#[test]
+fn rejecting_reentrant_calls() {
+    let mut deps = setup_test();
+
+    // alice's LTV is 90% and liquidatable
+    setup_leveraged_position(&mut deps, 1_800_000);
+
+    let liquidate_msg = ExecuteMsg::Liquidate {
+        user: "alice".to_string(),
+        max_bond_units: None,
+        max_spread: None,
+        bonus_asset: None,
+    };
+    let res =
+        execute(deps.as_mut(), mock_env(), mock_info("liquidator", &[]), liquidate_msg).unwrap();
+
+    // the lock is released by the last message of the liquidation, after all other callbacks
+    let release_msg = CallbackMsg::ReleaseLock {}
+        .into_cosmos_msg(&Addr::unchecked(MOCK_CONTRACT_ADDR))
+        .unwrap();
+    assert_eq!(res.messages.last().unwrap().msg, release_msg);
+
+    // while the callbacks are being executed, a contract invoked by one of them re-enters. any
+    // top-level message is rejected
+    let res = execute_callback(
+        &mut deps,
+        CallbackMsg::Unbond {
+            user_addr: Addr::unchecked("alice"),
+            bond_units_to_reduce: Uint128::new(100_000_000),
+        },
+    );
+    assert!(res.is_ok());
+
+    let msg = ExecuteMsg::UpdatePosition(vec![Action::Repay {
+        amount: Uint128::new(100),
+        user: None,
+    }]);
+    let res = execute(deps.as_mut(), mock_env(), mock_info("malicious_token", &[]), msg);
+    assert_eq!(res, Err(ContractError::Locked));
+
+    let msg = ExecuteMsg::Harvest {
+        max_spread: None,
+        slippage_tolerance: None,
+        min_lp_out: None,
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("operator", &[]), msg);
+    assert_eq!(res, Err(ContractError::Locked));
+
+    // nor can the lock be released externally, since callbacks can only be sent by the contract
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("malicious_token", &[]),
+        ExecuteMsg::Callback(CallbackMsg::ReleaseLock {}),
+    );
+    assert_eq!(res, Err(ContractError::unauthorized("callbacks cannot be invoked externally")));
+
+    // once the lock is released, other operations can be executed again
+    release_lock(&mut deps);
+    let msg = ExecuteMsg::UpdatePosition(vec![Action::Deposit(
+        Asset::native("uusd", 100u128).into(),
+    )]);
+    let deposits = [Coin::new(100, "uusd")];
+    execute(deps.as_mut(), mock_env(), mock_info("bob", &deposits), msg).unwrap();
+}
+
+#[test]
+fn querying_underlying_assets() {
+    let mut deps = setup_test();
+    setup_leveraged_position(&mut deps, 0);
+
+    // alice holds 3/4 of the bond units, bob the other 1/4
+    for (user, bond_units) in [("alice", 75_000_000u128), ("bob", 25_000_000u128)] {
+        let position = Position {
+            bond_units: Uint128::new(bond_units),
+            ..Default::default()
+        };
+        POSITION.save(deps.as_mut().storage, &Addr::unchecked(user), &position).unwrap();
+    }
+
+    let query_position = |deps: &OwnedDeps<_, _, _>, user: &str| -> PositionResponse {
+        let msg = QueryMsg::Position {
+            user: user.to_string(),
+        };
+        from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap()
+    };
+
+    // the contract has 100,000 of the pool's 1,000,000 shares bonded, which are redeemable for a
+    // tenth of the pool's reserves, i.e. 100,000 uluna + 1,000,000 uusd
+    let state: PositionResponse =
+        from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::State {}).unwrap()).unwrap();
+    assert_eq!(state.underlying_primary, Uint128::new(100_000));
+    assert_eq!(state.underlying_secondary, Uint128::new(1_000_000));
+
+    // each position's underlying assets are pro-rated by its bond amount, and together they add up
+    // to the contract's total
+    let alice = query_position(&deps, "alice");
+    assert_eq!(alice.bond_amount, Uint128::new(75_000));
+    assert_eq!(alice.underlying_primary, Uint128::new(75_000));
+    assert_eq!(alice.underlying_secondary, Uint128::new(750_000));
+
+    let bob = query_position(&deps, "bob");
+    assert_eq!(bob.underlying_primary, Uint128::new(25_000));
+    assert_eq!(bob.underlying_secondary, Uint128::new(250_000));
+
+    assert_eq!(alice.underlying_primary + bob.underlying_primary, state.underlying_primary);
+    assert_eq!(alice.underlying_secondary + bob.underlying_secondary, state.underlying_secondary);
+
+    // underlying assets are amounts of the assets themselves, in their own decimals. if the
+    // primary asset has 8 decimals, the pool's depths are normalized to be valued, but the
+    // underlying amounts are not
+    CONFIG
+        .update(deps.as_mut().storage, |mut config| -> StdResult<_> {
+            config.primary_asset_decimals = 8;
+            Ok(config)
+        })
+        .unwrap();
+    let alice = query_position(&deps, "alice");
+    assert_eq!(alice.underlying_primary, Uint128::new(75_000));
+    assert_eq!(alice.underlying_secondary, Uint128::new(750_000));
+}
+
+#[test]
+fn summing_positions_to_state_aggregates() {
+    let mut deps = setup_test();
+
+    // the strategy has 100,000 LP bonded, worth 2,000,000 uusd, and 1,000,000 uusd of debt, split
+    // unevenly between alice and bob
+    setup_leveraged_position(&mut deps, 1_000_000);
+    let positions = [("alice", 75_000_000u128, 700_000_000u128), ("bob", 25_000_000, 300_000_000)];
+    for (user, bond_units, debt_units) in positions {
+        let position = Position {
+            bond_units: Uint128::new(bond_units),
+            debt_units: Uint128::new(debt_units),
+            ..Default::default()
+        };
+        POSITION.save(deps.as_mut().storage, &Addr::unchecked(user), &position).unwrap();
+    }
+
+    let query_position = |user: &str| -> PositionResponse {
+        let msg = QueryMsg::Position {
+            user: user.to_string(),
+        };
+        from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap()
+    };
+    let alice = query_position("alice");
+    let bob = query_position("bob");
+
+    // the state's live aggregates are those of all positions combined
+    let state: PositionResponse =
+        from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::State {}).unwrap()).unwrap();
+    assert_eq!(state.bond_units, Uint128::new(100_000_000));
+    assert_eq!(state.bond_amount, Uint128::new(100_000));
+    assert_eq!(state.bond_amount, alice.bond_amount + bob.bond_amount);
+    assert_eq!(state.bond_value, Uint128::new(2_000_000));
+    assert_eq!(state.bond_value, alice.bond_value + bob.bond_value);
+    assert_eq!(state.debt_units, Uint128::new(1_000_000_000));
+    assert_eq!(state.debt_amount, Uint128::new(1_000_000));
+    assert_eq!(state.debt_amount, alice.debt_amount + bob.debt_amount);
+    assert_eq!(state.debt_value, alice.debt_value + bob.debt_value);
+    assert_eq!(state.ltv, Some(Decimal::from_ratio(state.debt_value, state.bond_value)));
+    assert_eq!(state.ltv, Some(Decimal::percent(50)));
+
+    // the strategy-wide health agrees with the state
+    let msg = QueryMsg::Health {
+        user: None,
+    };
+    let health: HealthResponse =
+        from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+    assert_eq!(
+        health,
+        HealthResponse {
+            bond_amount: state.bond_amount,
+            bond_value: state.bond_value,
+            debt_amount: state.debt_amount,
+            debt_value: state.debt_value,
+            ltv: state.ltv,
+        }
+    );
+}
+
+#[test]
+fn classifying_asset_labels() {
+    let terra_token = "terra1e2tczyk2rw7u47kzxxee5g7ufkncdmlcz37yuu4espmcttlwfzasmf670x";
+    let juno_token = "juno18c37s9sq89v55vuffajkfcd3xj9m67sq3r2zcjktw0h2a4vuqzws3rdsth";
+    let osmo_token = "osmo19e7jcqaf2pawye0v7k6n26y955ee8gsz4qdn6d";
+
+    // CW20 addresses are classified by their bech32 checksum, whatever their prefix
+    for label in [terra_token, juno_token, osmo_token] {
+        assert!(is_cw20(label), "{} should be a CW20", label);
+        assert_eq!(asset_info_from_label(label), AssetInfoUnchecked::cw20(label));
+    }
+
+    // native denoms are never mistaken for CW20s, even if they start with a chain's prefix
+    let ibc_denom = "ibc/27394FB092D2ECCD56123C74F36E4C1F926001CEADA9CA97EA622B25F41E5EB2";
+    for label in ["uluna", "uusd", "uterra", "terra1", "factory/terra1abc/utoken", ibc_denom] {
+        assert!(!is_cw20(label), "{} should be a native denom", label);
+        assert_eq!(asset_info_from_label(label), AssetInfoUnchecked::native(label));
+    }
+
+    // a single mistyped character breaks the checksum
+    assert!(!is_cw20("terra1e2tczyk2rw7u47kzxxee5g7ufkncdmlcz37yuu4espmcttlwfzasmf670y"));
+    assert!(!is_cw20(&terra_token.to_uppercase()));
+
+    // the label of an asset round-trips through parsing
+    let token = AssetInfo::cw20(Addr::unchecked(juno_token));
+    assert_eq!(asset_info_from_label(&asset_label(&token)), token.clone().into());
+    let coin = AssetInfo::native("uterra");
+    assert_eq!(asset_info_from_label(&asset_label(&coin)), coin.clone().into());
+
+    // asset infos convert to and from Astroport's
+    assert_eq!(
+        AstroportAssetInfo::from(&token),
+        AstroportAssetInfo::Token {
+            contract_addr: Addr::unchecked(juno_token),
+        }
+    );
+    assert_eq!(AssetInfo::from(AstroportAssetInfo::from(&coin)), coin);
+    assert_eq!(
+        Asset::from(AstroportAsset::from(Asset::new(token.clone(), 123u128))),
+        Asset::new(token, 123u128)
+    );
+
+    // the swap return is parsed into the asset it is denominated in
+    let swap_event = |ask_asset: &str| {
+        Event::new("from_contract")
+            .add_attribute("action", "swap")
+            .add_attribute("ask_asset", ask_asset)
+            .add_attribute("return_amount", "100")
+            .add_attribute("tax_amount", "1")
+    };
+    assert_eq!(
+        Pair::parse_swap_events(&[swap_event(osmo_token)], &[]).unwrap(),
+        AssetUnchecked::cw20(osmo_token, 99u128)
+    );
+    assert_eq!(
+        Pair::parse_swap_events(&[swap_event("uterra")], &[]).unwrap(),
+        AssetUnchecked::native("uterra", 99u128)
+    );
+}
+
+#[test]
+fn parsing_swap_events_against_known_assets() {
+    let swap_events = |ask_asset: &str| {
+        vec![Event::new("from_contract")
+            .add_attribute("action", "swap")
+            .add_attribute("ask_asset", ask_asset)
+            .add_attribute("return_amount", "100")
+            .add_attribute("tax_amount", "0")]
+    };
+    let asset_infos = [
+        AssetInfo::native("uterra"),
+        AssetInfo::native("terra1denom"),
+        AssetInfo::cw20(Addr::unchecked("uusd_token")),
+        AssetInfo::cw20(Addr::unchecked("neutron1token")),
+    ];
+
+    // the ask asset is identified by the known asset info whose label matches, whether or not the
+    // label looks like a terra address
+    for info in &asset_infos {
+        let returned = Pair::parse_swap_events(&swap_events(&asset_label(info)), &asset_infos);
+        assert_eq!(returned.unwrap(), AssetUnchecked::new(info.clone(), 100u128));
+    }
+
+    // the swap callbacks credit the returned asset as the strategy's own asset info
+    let mut deps = setup_test();
+    let config = CONFIG.load(deps.as_ref().storage).unwrap();
+    assert!(config.swap_asset_infos().contains(&config.astro_token_info));
+    let returned = Pair::parse_swap_events(&swap_events("astro_token"), &config.swap_asset_infos());
+    assert_eq!(returned.unwrap(), AssetUnchecked::cw20("astro_token", 100u128));
+
+    let alice = Addr::unchecked("alice");
+    PENDING_OPS
+        .save(
+            deps.as_mut().storage,
+            U64Key::new(0),
+            &CachedUserAddr {
+                user_addr: alice.clone(),
+                height: mock_env().block.height,
+            },
+        )
+        .unwrap();
+    let swap_reply = Reply {
+        id: encode_reply_id(2, 0),
+        result: ContractResult::Ok(SubMsgExecutionResponse {
+            events: swap_events("astro_token"),
+            data: None,
+        }),
+    };
+    reply(deps.as_mut(), mock_env(), swap_reply).unwrap();
+
+    let position = POSITION.load(deps.as_ref().storage, &alice).unwrap();
+    assert_eq!(
+        position.unlocked_assets,
+        AssetList::from(vec![Asset::cw20(Addr::unchecked("astro_token"), 100u128)])
+    );
 }
@@ -0,0 +1,68 @@
+use cosmwasm_std::{Decimal, OverflowError, StdError, Uint128};
+use thiserror::Error;
+
+use fields_of_mars::martian_field::ActionKind;
+
+/// Errors returned by the contract's entry points. Failures that integrators may want to handle
+/// programmatically have dedicated variants; all others are wrapped `StdError`s. The messages are
+/// the same as those of the generic errors they replace
+#[derive(Error, Debug, PartialEq)]
+pub enum ContractError {
+    #[error("{0}")]
+    Std(#[from] StdError),
+
+    #[error("{reason}")]
+    Unauthorized {
+        reason: String,
+    },
+
+    #[error("contract is paused: cannot {kind}")]
+    Paused {
+        kind: ActionKind,
+    },
+
+    #[error("contract is locked: another operation is in progress")]
+    Locked,
+
+    #[error("position is healthy")]
+    PositionHealthy,
+
+    #[error("position is already closed")]
+    PositionClosed,
+
+    /// `ltv` is `None` if the position has debt but no bond
+    #[error(
+        "ltv greater than threshold: {}",
+        .ltv.map(|ltv| ltv.to_string()).unwrap_or_else(|| "null".to_string())
+    )]
+    LtvTooHigh {
+        ltv: Option<Decimal>,
+    },
+
+    #[error("deposit cap exceeded: {deposit_cap}")]
+    DepositCapExceeded {
+        deposit_cap: Uint128,
+    },
+
+    #[error("deposit value {deposit_value} below minimum {min_deposit_value} for a new position")]
+    DepositTooSmall {
+        deposit_value: Uint128,
+        min_deposit_value: Uint128,
+    },
+}
+
+// checked arithmetic is converted the same way as in functions returning `StdResult`, so that
+// overflows are reported as `StdError::Overflow`
+impl From<OverflowError> for ContractError {
+    fn from(err: OverflowError) -> Self {
+        ContractError::Std(err.into())
+    }
+}
+
+impl ContractError {
+    pub fn unauthorized(reason: impl Into<String>) -> Self {
+        ContractError::Unauthorized {
+            reason: reason.into(),
+        }
+    }
+}
@@ -1,18 +1,41 @@
+use std::cmp;
+
 use cosmwasm_std::{
-    attr, Addr, Attribute, CosmosMsg, Decimal, DepsMut, Env, Event, MessageInfo, Response,
-    StdError, StdResult, Storage,
+    attr, Addr, Attribute, CosmosMsg, Decimal, DepsMut, Env, Event, Fraction, MessageInfo, Order,
+    QuerierWrapper, Response, StdError, StdResult, Storage, Uint128,
 };
 
+use cw2::{get_contract_version, set_contract_version};
 use cw_asset::{Asset, AssetInfo, AssetList};
+use cw_storage_plus::U64Key;
 
-use fields_of_mars::martian_field::{Config, Action, CallbackMsg};
+use fields_of_mars::martian_field::{
+    Action, ActionKind, AstroHandling, Authorization, CallbackMsg, Config, ConfigHistoryEntry,
+    FeeBenchmark, FeeMode, Limits, ReinvestMode,
+};
 
-use crate::health::compute_health;
-use crate::helpers::assert_sent_fund;
-use crate::state::{State, CONFIG, POSITION, STATE};
+use crate::error::ContractError;
+use crate::health::{
+    compute_bond_units_to_liquidate, compute_bond_value_per_unit, compute_health,
+    query_price_ranges, Totals,
+};
+use crate::helpers::{
+    acquire_lock, assert_sent_fund, assert_unlocked_assets_bounded, close_reward_epoch,
+    fee_transfer_msgs, load_position, parse_version, value_of, AssetListExt,
+};
+use crate::legacy;
+use crate::state::{
+    Position, State, AUTHORIZATIONS, CACHED_BALANCES, CACHED_MIN_LP_OUT, CACHED_MIN_RETURN,
+    CACHED_REWARDS, CACHED_ROUTE, CONFIG, CONFIG_HISTORY, LIMITS, PAUSED, PENDING_GOVERNANCE,
+    POSITION, STATE,
+};
+
+/// Maximum number of past configs retained in the config history
+const MAX_CONFIG_HISTORY: usize = 50;
 
-pub fn init_storage(deps: DepsMut, config: Config) -> StdResult<Response> {
+pub fn init_storage(deps: DepsMut, config: Config, limits: Limits) -> StdResult<Response> {
     CONFIG.save(deps.storage, &config)?;
+    LIMITS.save(deps.storage, &limits)?;
     STATE.save(deps.storage, &State::default())?;
     Ok(Response::default())
 }
@@ -21,66 +44,256 @@ pub fn update_position(
     deps: DepsMut,
     env: Env,
     info: MessageInfo,
+    user_addr: Addr,
     actions: Vec<Action>,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
     let api = deps.api;
     let config = CONFIG.load(deps.storage)?;
 
+    // if the sender is not the position's owner, they must have been authorized by the owner as a
+    // keeper for every kind of action to be performed. an empty list of actions requires no
+    // authorization, but would still open a position and record a snapshot on the owner's behalf,
+    // so only the owner may submit one
+    if info.sender != user_addr {
+        if actions.is_empty() {
+            return Err(ContractError::unauthorized(format!(
+                "{} is not authorized to submit an empty list of actions on behalf of {}",
+                info.sender, user_addr
+            )));
+        }
+        let authorization = AUTHORIZATIONS
+            .may_load(deps.storage, (&user_addr, &info.sender))?
+            .unwrap_or_default();
+        for action in &actions {
+            if !authorization.actions.contains(&action.kind()) {
+                return Err(ContractError::unauthorized(format!(
+                    "{} is not authorized to {} on behalf of {}",
+                    info.sender,
+                    action.kind(),
+                    user_addr
+                )));
+            }
+            // authorization to repay covers the owner's own debt only. paying another user's debt
+            // with the owner's assets would let a keeper move them out of the position
+            if let Action::Repay {
+                user: Some(debtor),
+                ..
+            } = action
+            {
+                if api.addr_validate(debtor)? != user_addr {
+                    return Err(ContractError::unauthorized(format!(
+                        "{} is not authorized to repay the debt of {} on behalf of {}",
+                        info.sender, debtor, user_addr
+                    )));
+                }
+            }
+        }
+    }
+
+    // record the block in which the position is opened. the callbacks load and save the position
+    // as a whole, so the height is kept until the position is purged
+    let opens_position = !POSITION.has(deps.storage, &user_addr);
+    if opens_position {
+        let position = Position {
+            opened_at_height: env.block.height,
+            ..Default::default()
+        };
+        POSITION.save(deps.storage, &user_addr, &position)?;
+    }
+
     let mut received_coins = AssetList::from(info.funds);
+    let mut cw20_balances_before = AssetList::new();
+    let mut deposits = AssetList::new();
     let mut msgs: Vec<CosmosMsg> = vec![];
     let mut attrs: Vec<Attribute> = vec![];
     let mut callbacks: Vec<CallbackMsg> = vec![];
 
+    // while paused, actions that increase exposure are rejected, but those that reduce it are not
+    if PAUSED.may_load(deps.storage)?.unwrap_or(false) {
+        for action in &actions {
+            let kind = action.kind();
+            if matches!(
+                kind,
+                ActionKind::Deposit
+                    | ActionKind::Borrow
+                    | ActionKind::Bond
+                    | ActionKind::Swap
+                    | ActionKind::Zap
+            ) {
+                return Err(ContractError::Paused {
+                    kind,
+                });
+            }
+        }
+    }
+
+    // a list of actions that strictly reduces risk can only make the position healthier, in which
+    // case the final health check is skipped to save the pool and oracle queries. unbonding is only
+    // considered risk-reducing if the position has no debt, since the withdrawn liquidity is
+    // refunded to the user rather than used to repay. the same goes for closing, since the primary
+    // asset sold may not cover the whole debt
+    let has_debt = POSITION
+        .may_load(deps.storage, &user_addr)?
+        .map(|position| !position.debt_units.is_zero())
+        .unwrap_or(false);
+    let reduces_risk = actions.iter().all(|action| match action.kind() {
+        ActionKind::Deposit | ActionKind::Repay => true,
+        ActionKind::Unbond | ActionKind::Close => !has_debt,
+        ActionKind::Borrow | ActionKind::Bond | ActionKind::Swap | ActionKind::Zap => false,
+    });
+
     // compose a list of callback messages based on user-selected actions
+    //
+    // callbacks are executed in the same order as the actions. since each callback is a separate
+    // message, any change to the bonded amount made by one callback (e.g. `Unbond`) is already
+    // reflected when a subsequent callback (e.g. `Bond`) queries it, so mixing the two in one
+    // message is safe
     for action in actions {
         match action {
-            Action::Deposit(asset) => handle_deposit(
-                deps.storage,
-                &env.contract.address,
-                &info.sender,
-                &mut received_coins,
-                &asset.check(api, None)?,
-                &mut msgs,
-                &mut attrs,
-            )?,
-            Action::Borrow { amount } => callbacks.push(
-                CallbackMsg::Borrow {
-                    user_addr: info.sender.clone(),
+            Action::Deposit(asset) => {
+                let asset = asset.check(api, None)?;
+                assert_depositable(&config, &asset.info)?;
+                deposits.add(&asset)?;
+                match &asset.info {
+                    AssetInfo::Cw20(_) => handle_cw20_deposit(
+                        &deps.querier,
+                        &env.contract.address,
+                        &info.sender,
+                        &asset,
+                        &mut cw20_balances_before,
+                        &mut msgs,
+                    )?,
+                    AssetInfo::Native(_) => handle_native_deposit(
+                        deps.storage,
+                        &user_addr,
+                        &mut received_coins,
+                        &asset,
+                        &mut attrs,
+                    )?,
+                }
+            }
+            Action::Borrow { amount } => {
+                callbacks.push(CallbackMsg::Borrow {
+                    user_addr: user_addr.clone(),
                     borrow_amount: amount,
+                });
+                // an asset borrowed other than the secondary asset can't be provided to the pair,
+                // so it is swapped to the secondary asset right away
+                if config.borrow_pair().is_some() {
+                    callbacks.push(CallbackMsg::Swap {
+                        user_addr: Some(user_addr.clone()),
+                        offer_asset_info: config.borrow_asset_info().clone(),
+                        offer_amount: Some(amount),
+                        max_spread: None,
+                        min_return: None,
+                    });
                 }
-            ),
-            Action::Repay { amount } => callbacks.push(
-                CallbackMsg::Repay {
-                    user_addr: info.sender.clone(),
-                    repay_amount: Some(amount),
+            }
+            Action::Repay { amount, user } => {
+                let debtor_addr = user.map(|user| api.addr_validate(&user)).transpose()?;
+                match debtor_addr {
+                    Some(debtor_addr) if debtor_addr != user_addr => {
+                        callbacks.push(CallbackMsg::RepayFor {
+                            user_addr: user_addr.clone(),
+                            debtor_addr,
+                            repay_amount: amount,
+                        });
+                    }
+                    _ => callbacks.push(CallbackMsg::Repay {
+                        user_addr: Some(user_addr.clone()),
+                        repay_amount: Some(amount),
+                    }),
                 }
-            ),
-            Action::Bond { slippage_tolerance } => callbacks.extend([
-                CallbackMsg::ProvideLiquidity {
-                    user_addr: Some(info.sender.clone()),
-                    slippage_tolerance,
-                },
-                CallbackMsg::Bond {
-                    user_addr: Some(info.sender.clone()),
-                },
-            ]),
-            Action::Unbond { bond_units_to_reduce } => callbacks.extend([
-                CallbackMsg::Unbond {
-                    user_addr: info.sender.clone(),
-                    bond_units_to_reduce,
-                },
-                CallbackMsg::WithdrawLiquidity {
-                    user_addr: info.sender.clone(),
-                },
-            ]),
-            Action::Swap { offer_amount, max_spread } => callbacks.push(
-                CallbackMsg::Swap {
-                    user_addr: Some(info.sender.clone()),
+            }
+            Action::Bond { slippage_tolerance } => {
+                config.assert_allowed_spread("slippage tolerance", slippage_tolerance)?;
+                callbacks.extend([
+                    CallbackMsg::ProvideLiquidity {
+                        user_addr: Some(user_addr.clone()),
+                        slippage_tolerance,
+                        min_lp_out: None,
+                    },
+                    CallbackMsg::Bond {
+                        user_addr: Some(user_addr.clone()),
+                    },
+                ]);
+            }
+            Action::Unbond { bond_units_to_reduce, swap_to } => {
+                callbacks.extend([
+                    CallbackMsg::Unbond {
+                        user_addr: user_addr.clone(),
+                        bond_units_to_reduce,
+                    },
+                    CallbackMsg::WithdrawLiquidity {
+                        user_addr: user_addr.clone(),
+                    },
+                ]);
+                if let Some(swap_to) = swap_to {
+                    let swap_to = swap_to.check(api, None)?;
+                    callbacks.push(CallbackMsg::Swap {
+                        user_addr: Some(user_addr.clone()),
+                        offer_asset_info: zap_out_offer_asset_info(&config, &swap_to)?,
+                        offer_amount: None,
+                        max_spread: None,
+                        min_return: None,
+                    });
+                }
+            }
+            Action::Swap { offer_amount, max_spread, min_secondary_from_swap } => {
+                config.assert_allowed_spread("max spread", max_spread)?;
+                callbacks.push(CallbackMsg::Swap {
+                    user_addr: Some(user_addr.clone()),
                     offer_asset_info: config.primary_asset_info.clone(),
                     offer_amount: Some(offer_amount),
                     max_spread,
+                    min_return: min_secondary_from_swap,
+                });
+            }
+            Action::Zap { slippage_tolerance } => {
+                config.assert_allowed_spread("slippage tolerance", slippage_tolerance)?;
+                callbacks.extend([
+                    CallbackMsg::Balance {
+                        user_addr: Some(user_addr.clone()),
+                        max_spread: None,
+                    },
+                    CallbackMsg::ProvideLiquidity {
+                        user_addr: Some(user_addr.clone()),
+                        slippage_tolerance,
+                        min_lp_out: None,
+                    },
+                    CallbackMsg::Bond {
+                        user_addr: Some(user_addr.clone()),
+                    },
+                ]);
+            }
+            Action::Close {} => {
+                let position = load_position(deps.storage, &user_addr)?;
+                if !position.bond_units.is_zero() {
+                    callbacks.extend([
+                        CallbackMsg::Unbond {
+                            user_addr: user_addr.clone(),
+                            bond_units_to_reduce: position.bond_units,
+                        },
+                        CallbackMsg::WithdrawLiquidity {
+                            user_addr: user_addr.clone(),
+                        },
+                    ]);
+                }
+                // a position without debt has nothing to repay. a zero repayment would be rejected
+                // by Red Bank, so `Cover` and `Repay` are skipped
+                if !position.debt_units.is_zero() {
+                    callbacks.extend([
+                        CallbackMsg::Cover {
+                            user_addr: user_addr.clone(),
+                            max_spread: config.liquidation_max_spread,
+                        },
+                        CallbackMsg::Repay {
+                            user_addr: Some(user_addr.clone()),
+                            repay_amount: None,
+                        },
+                    ]);
                 }
-            ),
+            }
         }
     }
 
@@ -88,27 +301,60 @@ pub fn update_position(
     // this way, we ensure that the user does not send any extra fund which will get lost in the 
     // contract
     if received_coins.len() > 0 {
-        return Err(StdError::generic_err(
-            format!("extra funds received: {}", received_coins)
-        ));
+        return Err(StdError::generic_err(format!(
+            "extra funds received, not claimed by any deposit action: {}",
+            received_coins
+        )).into());
+    }
+
+    // a new position must be opened with a deposit of at least the minimum value, so that dust
+    // positions can't bloat storage. existing positions may be topped up by any amount
+    if opens_position && !config.min_deposit_value.is_zero() {
+        assert_min_deposit_value(&deps.querier, &env, &config, &deposits)?;
+    }
+
+    // CW20 deposits are credited once the transfers have been executed, but before any other
+    // callback, so that the actions see the same unlocked assets as if they were credited here
+    if cw20_balances_before.len() > 0 {
+        callbacks.insert(
+            0,
+            CallbackMsg::CreditDeposits {
+                user_addr: user_addr.clone(),
+                balances_before: cw20_balances_before,
+            },
+        );
     }
 
     // after user selected actions, we executes two more callbacks:
     // - refund assets that are not deployed in the yield farm to user
     // - assert LTV is healthy; if not, throw error and revert all actions
-    callbacks.extend([
-        CallbackMsg::Refund {
-            user_addr: info.sender.clone(),
-            recipient_addr: info.sender.clone(),
-            percentage: Decimal::one(),
-        },
-        CallbackMsg::AssertHealth {
-            user_addr: info.sender.clone(),
-        },
-        CallbackMsg::PurgeStorage {
-            user_addr: info.sender.clone(),
-        },
-    ]);
+    callbacks.push(CallbackMsg::Refund {
+        user_addr: user_addr.clone(),
+        recipient_addr: user_addr.clone(),
+        percentage: Decimal::one(),
+    });
+    if !reduces_risk {
+        callbacks.push(CallbackMsg::AssertHealth {
+            user_addr: user_addr.clone(),
+        });
+    }
+    if config.max_snapshots > 0 {
+        callbacks.push(CallbackMsg::Snapshot {
+            user_addr: user_addr.clone(),
+        });
+    }
+    callbacks.push(CallbackMsg::PurgeStorage {
+        user_addr: user_addr.clone(),
+    });
+
+    // if a deposit cap is configured, we assert it last, once all of the actions have been applied
+    if config.deposit_cap.is_some() {
+        callbacks.push(CallbackMsg::AssertDepositCap {});
+    }
+
+    // the contract is locked until the last callback, so that the chain can't be re-entered
+    acquire_lock(deps.storage)?;
+    callbacks.push(CallbackMsg::ReleaseLock {});
 
     let callback_msgs = callbacks
         .iter()
@@ -122,13 +368,165 @@ pub fn update_position(
         .add_attributes(attrs))
 }
 
-fn handle_deposit(
-    storage: &mut dyn Storage,
+pub fn authorize_keeper(
+    deps: DepsMut,
+    info: MessageInfo,
+    keeper_addr: Addr,
+    actions: Vec<ActionKind>,
+) -> StdResult<Response> {
+    if keeper_addr == info.sender {
+        return Err(StdError::generic_err("cannot authorize self as keeper"));
+    }
+
+    let authorization = Authorization {
+        actions,
+    };
+    AUTHORIZATIONS.save(deps.storage, (&info.sender, &keeper_addr), &authorization)?;
+
+    let actions_str = authorization.actions.iter().map(|kind| kind.to_string());
+    Ok(Response::new()
+        .add_attribute("action", "martian_field/execute/authorize_keeper")
+        .add_attribute("user", info.sender)
+        .add_attribute("keeper", keeper_addr)
+        .add_attribute("actions", actions_str.collect::<Vec<String>>().join(",")))
+}
+
+pub fn revoke_keeper(deps: DepsMut, info: MessageInfo, keeper_addr: Addr) -> StdResult<Response> {
+    AUTHORIZATIONS.remove(deps.storage, (&info.sender, &keeper_addr));
+
+    Ok(Response::new()
+        .add_attribute("action", "martian_field/execute/revoke_keeper")
+        .add_attribute("user", info.sender)
+        .add_attribute("keeper", keeper_addr))
+}
+
+/// Find the asset to be swapped when unbonding with `swap_to`, which is the other one of the
+/// primary and secondary assets
+pub fn zap_out_offer_asset_info(config: &Config, swap_to: &AssetInfo) -> StdResult<AssetInfo> {
+    if *swap_to == config.secondary_asset_info {
+        Ok(config.primary_asset_info.clone())
+    } else if *swap_to == config.primary_asset_info {
+        Ok(config.secondary_asset_info.clone())
+    } else {
+        Err(StdError::generic_err(format!(
+            "invalid swap_to asset: {}; must be primary or secondary asset",
+            swap_to
+        )))
+    }
+}
+
+/// Assert that an asset is one that the strategy handles, i.e. the primary, secondary or borrowed
+/// asset, the primary pair's liquidity token, or ASTRO. Any other token would sit in the position
+/// unused, and a position holding many of them could become too expensive to refund
+fn assert_depositable(config: &Config, asset_info: &AssetInfo) -> StdResult<()> {
+    let liquidity_token_info = AssetInfo::cw20(config.primary_pair.liquidity_token.clone());
+    let depositable = [
+        &config.primary_asset_info,
+        &config.secondary_asset_info,
+        config.borrow_asset_info(),
+        &liquidity_token_info,
+        &config.astro_token_info,
+    ];
+    if !depositable.contains(&asset_info) {
+        return Err(StdError::generic_err(format!(
+            "cannot deposit {}: not an asset of this strategy",
+            asset_info
+        )));
+    }
+    Ok(())
+}
+
+/// Assert that the assets deposited are worth at least `min_deposit_value`
+///
+/// Deposited liquidity tokens are valued by the assets they are redeemable for; the rest are valued
+/// by the oracle. The minimum is denominated in the secondary asset, so it is converted to the
+/// oracle's base unit the same way as the deposit cap
+fn assert_min_deposit_value(
+    querier: &QuerierWrapper,
+    env: &Env,
+    config: &Config,
+    deposits: &AssetList,
+) -> Result<(), ContractError> {
+    let liquidity_token_info = AssetInfo::cw20(config.primary_pair.liquidity_token.clone());
+    let mut assets = AssetList::new();
+    for asset in deposits.to_vec() {
+        if asset.info != liquidity_token_info {
+            assets.add(&asset)?;
+            continue;
+        }
+        let (primary_depth, secondary_depth, total_shares) = config.primary_pair.query_pool(
+            querier,
+            &config.primary_asset_info,
+            &config.secondary_asset_info,
+        )?;
+        assets.add(&Asset::new(
+            config.primary_asset_info.clone(),
+            primary_depth.multiply_ratio(asset.amount, total_shares),
+        ))?;
+        assets.add(&Asset::new(
+            config.secondary_asset_info.clone(),
+            secondary_depth.multiply_ratio(asset.amount, total_shares),
+        ))?;
+    }
+
+    let deposit_value = value_of(querier, env, config, &assets)?;
+    let secondary_price = config.oracle.query_fresh_price(
+        querier,
+        &config.secondary_asset_info,
+        env.block.time.seconds(),
+        config.max_price_age,
+    )?;
+    let min_deposit_value = config
+        .normalize_amount(&config.secondary_asset_info, config.min_deposit_value)?
+        * secondary_price;
+    if deposit_value < min_deposit_value {
+        return Err(ContractError::DepositTooSmall {
+            deposit_value,
+            min_deposit_value,
+        });
+    }
+    Ok(())
+}
+
+/// Transfer the specified amount of a CW20 token from the sender's wallet
+///
+/// The amount isn't credited to the user here, since a token that charges a fee on transfer
+/// delivers less than the specified amount. Instead, the contract's balance before the first
+/// deposit of each token is recorded, and the `CreditDeposits` callback credits the increase
+fn handle_cw20_deposit(
+    querier: &QuerierWrapper,
     contract_addr: &Addr,
     sender_addr: &Addr,
-    received_coins: &mut AssetList,
     asset: &Asset,
+    balances_before: &mut AssetList,
     msgs: &mut Vec<CosmosMsg>,
+) -> StdResult<()> {
+    // if deposit amount is zero, we do nothing
+    if asset.amount.is_zero() {
+        return Ok(());
+    }
+
+    if balances_before.find(&asset.info).is_none() {
+        let balance = asset.info.query_balance(querier, contract_addr)?;
+        balances_before.add(&Asset::new(asset.info.clone(), balance))?;
+    }
+
+    msgs.push(asset.transfer_from_msg(sender_addr, contract_addr)?);
+
+    Ok(())
+}
+
+/// Assert that the specified amount of a native token was received, and credit it to the user
+///
+/// The asset is deducted from the list of received coins, so several deposit actions may each
+/// claim part of the same coin. After every deposit action has been processed, we assert that the
+/// list is empty. This way, we ensure the user doesn't send any extra fund, which will be lost in
+/// the contract
+fn handle_native_deposit(
+    storage: &mut dyn Storage,
+    user_addr: &Addr,
+    received_coins: &mut AssetList,
+    asset: &Asset,
     attrs: &mut Vec<Attribute>,
 ) -> StdResult<()> {
     // if deposit amount is zero, we do nothing
@@ -136,26 +534,20 @@ fn handle_deposit(
         return Ok(());
     }
 
-    // If asset is a CW20 token, we transfer the specified amount from the user's wallet
-    // If asset is a native token, we:
-    // - Assert that the same amount was indeed received
-    // - Remove the asset from the list. After every deposit action has been processed, assert that
-    //   the asset list is empty. This way, we ensure the user doesn't send any extra fund, which will
-    //   be lost in the contract
-    match &asset.info {
-        AssetInfo::Cw20(_) => {
-            msgs.push(asset.transfer_from_msg(sender_addr, contract_addr)?);
-        }
-        AssetInfo::Native(_) => {
-            assert_sent_fund(asset, received_coins)?;
-            received_coins.deduct(asset)?;
-        }
+    let remaining = received_coins.find(&asset.info).map(|coin| coin.amount).unwrap_or_default();
+    if remaining < asset.amount {
+        return Err(StdError::generic_err(format!(
+            "insufficient funds for deposit: expected {}, remaining {}",
+            asset, remaining
+        )));
     }
+    received_coins.deduct(asset)?;
 
     // increase the user's unlocked asset amount
-    let mut position = POSITION.load(storage, sender_addr).unwrap_or_default();
+    let mut position = load_position(storage, user_addr)?;
     position.unlocked_assets.add(asset)?;
-    POSITION.save(storage, sender_addr, &position)?;
+    assert_unlocked_assets_bounded(&position)?;
+    POSITION.save(storage, user_addr, &position)?;
 
     attrs.push(attr("deposit_received", asset.to_string()));
 
@@ -168,15 +560,30 @@ pub fn harvest(
     info: MessageInfo,
     max_spread: Option<Decimal>,
     slippage_tolerance: Option<Decimal>,
-) -> StdResult<Response> {
+    min_lp_out: Option<Uint128>,
+) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
     let mut state = STATE.load(deps.storage)?;
 
     // only whitelisted operators can harvest
     if !config.operators.contains(&info.sender) {
-        return Err(StdError::generic_err("caller is not a whitelisted operator"));
+        return Err(ContractError::unauthorized("caller is not a whitelisted operator"));
     }
 
+    config.assert_allowed_spread("max spread", max_spread)?;
+    config.assert_allowed_spread("slippage tolerance", slippage_tolerance)?;
+
+    // harvests must be at least `harvest_cooldown` seconds apart
+    let current_time = env.block.time.seconds();
+    let next_harvest_time = state.last_harvest_time + config.harvest_cooldown;
+    if current_time < next_harvest_time {
+        return Err(StdError::generic_err(format!(
+            "harvest is on cooldown: {} seconds remaining",
+            next_harvest_time - current_time
+        )).into());
+    }
+    state.last_harvest_time = current_time;
+
     // find how much reward is available to be claimed
     let rewards = config.astro_generator.query_rewards(
         &deps.querier,
@@ -192,46 +599,121 @@ pub fn harvest(
             config.astro_generator.claim_rewards_msg(&config.primary_pair.liquidity_token)?
         );
         state.pending_rewards.add_many(&rewards)?;
+        state.uncharged_rewards.add_many(&rewards)?;
     }
 
-    // a portion of the pending rewards will be charged as fees
-    let mut fees = state.pending_rewards.clone();
-    fees.apply(|asset| asset.amount = asset.amount * config.fee_rate);
-    fees.purge();
-    msgs.extend(fees.transfer_msgs(&config.treasury)?);
+    // a portion of the rewards credited since the last harvest, including those the generator
+    // withdrew on bond and unbond, will be charged as fees. rewards retained from previous harvests
+    // (e.g. those below their swap thresholds) have already been charged
+    let fee_rate = match &config.fee_mode {
+        FeeMode::Flat => config.fee_rate,
+        FeeMode::HighWaterMark {
+            benchmark,
+        } => compute_high_water_mark_fee_rate(&deps.querier, &env, &config, &mut state, benchmark)?,
+        FeeMode::Withdrawal => {
+            let totals = Totals::query(&deps.querier, &env, &config)?;
+            let value_per_unit =
+                compute_bond_value_per_unit(&deps.querier, &env, &config, &totals, &state)?;
+            state.hwm_bond_value_per_unit =
+                cmp::max(state.hwm_bond_value_per_unit, value_per_unit);
+            Decimal::zero()
+        }
+    };
+    let (fees, _) = state.uncharged_rewards.split_fee(fee_rate);
+    msgs.extend(fee_transfer_msgs(&config.fee_recipients, &fees)?);
+    state.uncharged_rewards = AssetList::default();
 
-    // deduct fees from available rewards. the remaining amounts are to be reinvested
+    // deduct fees from available rewards. the remainder of the uncharged rewards, which have
+    // already been added to the pending rewards, is to be reinvested
     state.pending_rewards.deduct_many(&fees)?;
-    STATE.save(deps.storage, &state)?;
+    state.total_fees_collected.add_many(&fees)?;
 
     // if there are ASTRO tokens available to be reinvested, we first swap it to the secondary asset
-    // asset
+    // asset. in accumulate mode, we instead move them to the accumulated balance
+    //
+    // if the amount is below the swap threshold, we skip the swap, leaving ASTRO in the pending
+    // rewards to accumulate until the next harvest
     let mut callbacks: Vec<CallbackMsg> = vec![];
-    if let Some(astro_token) = state.pending_rewards.find(&config.astro_token_info) {
-        callbacks.push(CallbackMsg::Swap {
-            user_addr: None,
-            offer_asset_info: config.astro_token_info.clone(),
-            offer_amount: Some(astro_token.amount),
-            max_spread,
-        });
+    if let Some(astro_token) = state.pending_rewards.find(&config.astro_token_info).cloned() {
+        match config.astro_handling {
+            AstroHandling::Reinvest
+                if astro_token.amount < config.reward_swap_threshold(&astro_token.info) => {}
+            AstroHandling::Reinvest => callbacks.push(CallbackMsg::Swap {
+                user_addr: None,
+                offer_asset_info: config.astro_token_info.clone(),
+                offer_amount: Some(astro_token.amount),
+                max_spread,
+                min_return: None,
+            }),
+            AstroHandling::Accumulate => {
+                state.pending_rewards.deduct(&astro_token)?;
+                state.accumulated_astro = state.accumulated_astro.checked_add(astro_token.amount)?;
+            }
+        }
+    }
+
+    // other reward tokens, e.g. proxy rewards that are neither the primary nor secondary asset, are
+    // swapped to the secondary asset if a pair is configured for them, subject to the same swap
+    // thresholds as ASTRO
+    for reward in state.pending_rewards.to_vec() {
+        if config.reward_pair(&reward.info).is_some()
+            && reward.amount >= config.reward_swap_threshold(&reward.info)
+        {
+            callbacks.push(CallbackMsg::Swap {
+                user_addr: None,
+                offer_asset_info: reward.info.clone(),
+                offer_amount: Some(reward.amount),
+                max_spread,
+                min_return: None,
+            });
+        }
     }
 
-    // once ASTRO is sold, pending rewards should only consist of primary and secondary assets
+    // once reward tokens are sold, pending rewards should only consist of primary and secondary
+    // assets. in compound mode:
     // 1. doing a swap so that their values are balanced
     // 2. provide liquidity
-    // 3. bond liquidity tokens (without increasing total bond units)
-    callbacks.extend([
-        CallbackMsg::Balance {
-            max_spread,
-        },
-        CallbackMsg::ProvideLiquidity {
-            user_addr: None,
-            slippage_tolerance,
-        },
-        CallbackMsg::Bond {
-            user_addr: None,
-        },
-    ]);
+    // 3. bond liquidity tokens (minting bond units for the eligible bond units, which ends the
+    // reward epoch)
+    //
+    // in repay debt mode, the secondary asset is instead used to repay the aggregate debt (without
+    // decreasing total debt units)
+    //
+    // if ASTRO is accumulated and there is no other reward, there is nothing to reinvest
+    let has_reinvestable = !callbacks.is_empty()
+        || state.pending_rewards.find(&config.primary_asset_info).is_some()
+        || state.pending_rewards.find(&config.secondary_asset_info).is_some();
+
+    // if no liquidity tokens are to be bonded, the reward epoch ends here
+    if !has_reinvestable || config.reinvest_mode == ReinvestMode::RepayDebt {
+        close_reward_epoch(deps.storage, &mut state)?;
+    }
+    STATE.save(deps.storage, &state)?;
+
+    if has_reinvestable {
+        match config.reinvest_mode {
+            ReinvestMode::Compound => callbacks.extend([
+                CallbackMsg::Balance {
+                    user_addr: None,
+                    max_spread,
+                },
+                CallbackMsg::ProvideLiquidity {
+                    user_addr: None,
+                    slippage_tolerance,
+                    min_lp_out,
+                },
+                CallbackMsg::Bond {
+                    user_addr: None,
+                },
+            ]),
+            ReinvestMode::RepayDebt => callbacks.push(CallbackMsg::Repay {
+                user_addr: None,
+                repay_amount: None,
+            }),
+        }
+    }
+    acquire_lock(deps.storage)?;
+    callbacks.push(CallbackMsg::ReleaseLock {});
 
     let callback_msgs = callbacks
         .iter()
@@ -250,31 +732,154 @@ pub fn harvest(
         .add_event(event))
 }
 
+/// Compute the portion of pending rewards to be charged as performance fee, such that the fee
+/// amounts to `fee_rate` of the appreciation of the value per bond unit above the high-water mark.
+/// Update the high-water mark in `state` accordingly
+fn compute_high_water_mark_fee_rate(
+    querier: &QuerierWrapper,
+    env: &Env,
+    config: &Config,
+    state: &mut State,
+    benchmark: &FeeBenchmark,
+) -> StdResult<Decimal> {
+    if state.total_bond_units.is_zero() {
+        return Ok(Decimal::zero());
+    }
+
+    let benchmark_asset_info = match benchmark {
+        FeeBenchmark::Primary => &config.primary_asset_info,
+        FeeBenchmark::Secondary => &config.secondary_asset_info,
+    };
+
+    // prices are queried fresh, and from the secondary oracle as well if configured, same as the
+    // bond value. rewards are valued at the lower price and the benchmark at the higher one, so
+    // that the appreciation is valued conservatively, same as collateral
+    let pending_rewards = state.pending_rewards.to_vec();
+    let mut asset_infos = vec![benchmark_asset_info.clone()];
+    asset_infos.extend(pending_rewards.iter().map(|asset| asset.info.clone()));
+    let price_ranges = query_price_ranges(querier, env, config, &asset_infos)?;
+    let (_, benchmark_price) = price_ranges[0];
+    if benchmark_price.is_zero() {
+        return Err(StdError::generic_err("benchmark asset has zero price"));
+    }
+
+    // value of all bonded liquidity tokens
+    let bond_value = compute_health(querier, env, config, state, &state.clone().into())?.bond_value;
+
+    // value of pending rewards, including unsold ASTRO
+    let mut rewards_value = Uint128::zero();
+    for (asset, (price, _)) in pending_rewards.iter().zip(&price_ranges[1..]) {
+        rewards_value += config.normalize_amount(&asset.info, asset.amount)? * *price;
+    }
+
+    // convert values to units of the benchmark asset
+    let to_benchmark = |value: Uint128| {
+        value.multiply_ratio(benchmark_price.denominator(), benchmark_price.numerator())
+    };
+    let bond_value = to_benchmark(bond_value);
+    let rewards_value = to_benchmark(rewards_value);
+
+    // bond units minted by harvests dilute the value per bond unit, so it is measured per bond unit
+    // held since the first harvest, which has grown into `reward_index` bond units
+    let total_value = bond_value + rewards_value;
+    let value_per_unit =
+        Decimal::from_ratio(total_value * state.reward_index, state.total_bond_units);
+
+    // no fee is charged unless the value per bond unit exceeds the high-water mark
+    if value_per_unit <= state.high_water_mark || rewards_value.is_zero() {
+        return Ok(Decimal::zero());
+    }
+
+    // fee is charged on the appreciation above the high-water mark, which can be no greater than
+    // the value of the rewards
+    let gain = ((value_per_unit - state.high_water_mark) * state.total_bond_units)
+        .multiply_ratio(state.reward_index.denominator(), state.reward_index.numerator());
+    let fee_rate = if gain >= rewards_value {
+        config.fee_rate
+    } else {
+        let numerator = gain.multiply_ratio(config.fee_rate.numerator(), rewards_value);
+        Decimal::from_ratio(numerator, config.fee_rate.denominator())
+    };
+
+    // the new high-water mark is the value per bond unit after fees are deducted
+    let fee_value = rewards_value * fee_rate;
+    let value_per_unit_after_fee = Decimal::from_ratio(
+        (total_value - fee_value) * state.reward_index,
+        state.total_bond_units,
+    );
+    state.high_water_mark = cmp::max(value_per_unit_after_fee, state.high_water_mark);
+
+    Ok(fee_rate)
+}
+
 pub fn liquidate(
     deps: DepsMut,
     env: Env,
     info: MessageInfo,
     user_addr: Addr,
-) -> StdResult<Response> {
+    max_bond_units: Option<Uint128>,
+    max_spread: Option<Decimal>,
+    bonus_asset: Option<AssetInfo>,
+) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
-    let state = STATE.load(deps.storage)?;
-    let position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
+    let mut state = STATE.load(deps.storage)?;
+    let position = load_position(deps.storage, &user_addr)?;
+
+    // the liquidator may elect to be awarded the bonus in the primary asset, in which case it is
+    // refunded before the primary asset is sold
+    let bonus_in_primary = match &bonus_asset {
+        Some(info) if *info == config.primary_asset_info => true,
+        Some(info) if *info == config.secondary_asset_info => false,
+        Some(info) => {
+            return Err(StdError::generic_err(format!(
+                "invalid bonus asset: {}; must be the primary or secondary asset",
+                info
+            )).into());
+        }
+        None => false,
+    };
+
+    // the liquidator may tighten the spread of the swaps selling the user's primary asset, but not
+    // loosen it beyond what governance allows
+    let max_spread = match max_spread {
+        Some(max_spread) if max_spread > config.liquidation_max_spread => {
+            return Err(StdError::generic_err(format!(
+                "max spread {} exceeds liquidation max spread {}",
+                max_spread, config.liquidation_max_spread
+            )).into());
+        }
+        Some(max_spread) => max_spread,
+        None => config.liquidation_max_spread,
+    };
+
+    // a liquidation of zero bond units would do nothing but pay the liquidator the incentive
+    if max_bond_units == Some(Uint128::zero()) {
+        return Err(StdError::generic_err("max bond units must be greater than zero").into());
+    }
+
+    // a position can't be liquidated in the block in which it is opened. otherwise, a momentary
+    // disagreement between the oracle and the pool could be used to open and liquidate a position
+    // within the same block
+    if env.block.height <= position.opened_at_height {
+        return Err(StdError::generic_err("position was opened in the current block").into());
+    }
 
     // position must be active (LTV is not `None`) and the LTV must be greater than `max_ltv`
     let health = compute_health(&deps.querier, &env, &config, &state, &position)?;
 
     // if `health.ltv` is `Some`, it must be greater than `max_ltv`
     // if `health.ltv` is `None`, indicating the position is already closed, then it is not liquidatable
-    let ltv = health.ltv.ok_or_else(|| StdError::generic_err("position is already closed"))?;
+    let ltv = health.ltv.ok_or(ContractError::PositionClosed)?;
     if ltv <= config.max_ltv {
-        return Err(StdError::generic_err("position is healthy"));
+        return Err(ContractError::PositionHealthy);
     }
 
     // 1. unbond the user's liquidity tokens from Astro generator
     // 2. burn liquidity tokens, withdraw primary + secondary assets from the pool
     // 3. swap all primary assets to secondary assets
     // 4. repay all debts
-    // 5. among all remaining assets, send the amount corresponding to `bonus_rate` to the liquidator
+    // 5. among all remaining assets, send the amount corresponding to the bonus rate to the
+    //    liquidator
     // 6. refund all assets that're left to the user
     //
     // NOTE: in the previous versions, we sell **all** primary assets, which is not optimal because 
@@ -283,26 +888,168 @@ pub fn liquidate(
     //
     // now, we calculate how much additional secondary asset is needed to fully pay off debt, and 
     // reverse-simulate how much primary asset needs to be sold
-    let callbacks = [
+    //
+    // if the liquidator requests a partial liquidation, we only liquidate the minimum amount needed
+    // to bring the LTV back to `max_ltv`, capped at the requested amount. if this amounts to the
+    // whole position, we do a full liquidation instead
+    let bond_units_to_liquidate = match max_bond_units {
+        Some(max_bond_units) => cmp::min(
+            compute_bond_units_to_liquidate(&config, &position, &health)?,
+            max_bond_units,
+        ),
+        None => position.bond_units,
+    };
+    // the bonus rate depends on how far the position's LTV is above `max_ltv`
+    let bonus_rate = config.bonus_rate_at(ltv);
+    let is_partial = bond_units_to_liquidate < position.bond_units;
+
+    // a bonus elected in the primary asset is refunded before the primary asset is sold, but it
+    // must be worth no more than the bonus it replaces. in a partial liquidation, that is the bonus
+    // rate of the liquidated portion's value. in a full liquidation, it is the bonus paid out of
+    // what remains after repayment, i.e. the bonus rate of the position's bond value less its debt
+    // value
+    let bonus_primary = if bonus_in_primary {
+        let bonus_base_value = if is_partial {
+            health.bond_value.multiply_ratio(bond_units_to_liquidate, position.bond_units)
+        } else {
+            health.bond_value.saturating_sub(health.debt_value)
+        };
+        let primary_price = config.oracle.query_fresh_price(
+            &deps.querier,
+            &config.primary_asset_info,
+            env.block.time.seconds(),
+            config.max_price_age,
+        )?;
+        let bonus_amount = (bonus_base_value * bonus_rate)
+            .multiply_ratio(primary_price.denominator(), primary_price.numerator());
+        Some(Asset::new(
+            config.primary_asset_info.clone(),
+            config.denormalize_amount(&config.primary_asset_info, bonus_amount)?,
+        ))
+    } else {
+        None
+    };
+    let callbacks = if is_partial {
+        partial_liquidation_callbacks(
+            &config,
+            &info.sender,
+            &user_addr,
+            bond_units_to_liquidate,
+            bonus_rate,
+            max_spread,
+            bonus_primary,
+        )
+    } else {
+        full_liquidation_callbacks(
+            &info.sender,
+            &user_addr,
+            position.bond_units,
+            bonus_rate,
+            max_spread,
+            bonus_primary,
+        )
+    };
+
+    let mut callback_msgs = callbacks
+        .iter()
+        .map(|callback| callback.into_cosmos_msg(&env.contract.address))
+        .collect::<StdResult<Vec<CosmosMsg>>>()?;
+
+    // if a liquidation incentive is configured, we pay it to the liquidator out of the reserve. if
+    // the reserve is insufficient, we skip it instead of failing the liquidation
+    //
+    // the incentive is pro-rated by the portion of the position liquidated, so that splitting a
+    // liquidation into many small ones doesn't drain the reserve
+    let mut attrs: Vec<Attribute> = vec![];
+    let incentive_option = config.liquidation_incentive.as_ref().map(|incentive| {
+        Asset::new(
+            incentive.info.clone(),
+            incentive.amount.multiply_ratio(bond_units_to_liquidate, position.bond_units),
+        )
+    });
+    if let Some(incentive) = incentive_option.filter(|asset| !asset.amount.is_zero()) {
+        let reserve_amount = state
+            .incentive_reserve
+            .find(&incentive.info)
+            .map(|asset| asset.amount)
+            .unwrap_or_else(Uint128::zero);
+        if reserve_amount >= incentive.amount {
+            state.incentive_reserve.deduct(&incentive)?;
+            STATE.save(deps.storage, &state)?;
+            callback_msgs.push(incentive.transfer_msg(&info.sender)?);
+            attrs.push(attr("incentive_paid", incentive.to_string()));
+        } else {
+            attrs.push(attr("incentive_skipped", "insufficient reserve"));
+        }
+    }
+
+    // the lock is released last, after the incentive is paid
+    acquire_lock(deps.storage)?;
+    callback_msgs.push(CallbackMsg::ReleaseLock {}.into_cosmos_msg(&env.contract.address)?);
+
+    let event = Event::new("liquidated")
+        .add_attribute("liquidator", info.sender)
+        .add_attribute("user", user_addr)
+        .add_attribute("bond_units", position.bond_units)
+        .add_attribute("bond_units_liquidated", bond_units_to_liquidate)
+        .add_attribute("debt_units", position.debt_units)
+        .add_attribute("bond_value", health.bond_value)
+        .add_attribute("debt_value", health.debt_value)
+        .add_attribute("ltv", ltv.to_string())
+        .add_attribute("bonus_rate", bonus_rate.to_string())
+        .add_attribute("bonus_in_primary", bonus_in_primary.to_string());
+
+    Ok(Response::new()
+        .add_messages(callback_msgs)
+        .add_attribute("action", "martian_field/execute/liquidate")
+        .add_attributes(attrs)
+        .add_event(event))
+}
+
+/// In a full liquidation, the liquidator is awarded the bonus rate of the assets remaining after
+/// repayment, or, if the liquidator elects to be paid in the primary asset, the amount of primary
+/// asset of the same value, refunded before the primary asset is sold to repay debt
+fn full_liquidation_callbacks(
+    liquidator_addr: &Addr,
+    user_addr: &Addr,
+    bond_units: Uint128,
+    bonus_rate: Decimal,
+    max_spread: Decimal,
+    bonus_primary: Option<Asset>,
+) -> Vec<CallbackMsg> {
+    let mut callbacks = vec![
         CallbackMsg::Unbond {
             user_addr: user_addr.clone(),
-            bond_units_to_reduce: position.bond_units,
+            bond_units_to_reduce: bond_units,
         },
         CallbackMsg::WithdrawLiquidity {
             user_addr: user_addr.clone(),
         },
         CallbackMsg::Cover {
             user_addr: user_addr.clone(),
+            max_spread,
         },
         CallbackMsg::Repay {
-            user_addr: user_addr.clone(),
+            user_addr: Some(user_addr.clone()),
             repay_amount: None,
         },
-        CallbackMsg::Refund {
+    ];
+    match bonus_primary {
+        Some(asset) => callbacks.insert(
+            2,
+            CallbackMsg::RefundAsset {
+                user_addr: user_addr.clone(),
+                recipient_addr: liquidator_addr.clone(),
+                asset,
+            },
+        ),
+        None => callbacks.push(CallbackMsg::Refund {
             user_addr: user_addr.clone(),
-            recipient_addr: info.sender.clone(),
-            percentage: config.bonus_rate,
-        },
+            recipient_addr: liquidator_addr.clone(),
+            percentage: bonus_rate,
+        }),
+    }
+    callbacks.extend([
         CallbackMsg::Refund {
             user_addr: user_addr.clone(),
             recipient_addr: user_addr.clone(),
@@ -314,38 +1061,471 @@ pub fn liquidate(
         CallbackMsg::PurgeStorage {
             user_addr: user_addr.clone(),
         },
-    ];
-
-    let callback_msgs = callbacks
-        .iter()
-        .map(|callback| callback.into_cosmos_msg(&env.contract.address))
-        .collect::<StdResult<Vec<CosmosMsg>>>()?;
+    ]);
+    callbacks
+}
 
-    let event = Event::new("liquidated")
-        .add_attribute("liquidator", info.sender)
-        .add_attribute("user", user_addr)
-        .add_attribute("bond_units", position.bond_units)
-        .add_attribute("debt_units", position.debt_units)
-        .add_attribute("bond_value", health.bond_value)
-        .add_attribute("debt_value", health.debt_value)
-        .add_attribute("ltv", ltv.to_string());
+/// In a partial liquidation, the liquidator is awarded the bonus rate of the assets withdrawn from
+/// the liquidated portion of the position, or, if the liquidator elects to be paid in the primary
+/// asset, the amount of primary asset of the same value. All remaining primary asset is sold, and
+/// the proceeds are used to repay debt. There is no bad debt to clear since the position remains
+/// open
+fn partial_liquidation_callbacks(
+    config: &Config,
+    liquidator_addr: &Addr,
+    user_addr: &Addr,
+    bond_units: Uint128,
+    bonus_rate: Decimal,
+    max_spread: Decimal,
+    bonus_primary: Option<Asset>,
+) -> Vec<CallbackMsg> {
+    // the bonus is paid before the primary asset withdrawn is sold, either as the bonus rate of
+    // all withdrawn assets, or in the primary asset only if the liquidator elects so
+    let bonus_callback = match bonus_primary {
+        Some(asset) => CallbackMsg::RefundAsset {
+            user_addr: user_addr.clone(),
+            recipient_addr: liquidator_addr.clone(),
+            asset,
+        },
+        None => CallbackMsg::Refund {
+            user_addr: user_addr.clone(),
+            recipient_addr: liquidator_addr.clone(),
+            percentage: bonus_rate,
+        },
+    };
 
-    Ok(Response::new()
-        .add_messages(callback_msgs)
-        .add_attribute("action", "martian_field/execute/liquidate")
-        .add_event(event))
+    // the primary asset withdrawn is sold to repay debt. if the borrowed asset is not the secondary
+    // asset, `Cover` is used instead, which also buys the borrowed asset with the secondary asset
+    let sell_callback = if config.borrow_pair().is_some() {
+        CallbackMsg::Cover {
+            user_addr: user_addr.clone(),
+            max_spread,
+        }
+    } else {
+        CallbackMsg::Swap {
+            user_addr: Some(user_addr.clone()),
+            offer_asset_info: config.primary_asset_info.clone(),
+            offer_amount: None,
+            max_spread: Some(max_spread),
+            min_return: None,
+        }
+    };
+    vec![
+        CallbackMsg::Unbond {
+            user_addr: user_addr.clone(),
+            bond_units_to_reduce: bond_units,
+        },
+        CallbackMsg::WithdrawLiquidity {
+            user_addr: user_addr.clone(),
+        },
+        bonus_callback,
+        sell_callback,
+        CallbackMsg::Repay {
+            user_addr: Some(user_addr.clone()),
+            repay_amount: None,
+        },
+        CallbackMsg::Refund {
+            user_addr: user_addr.clone(),
+            recipient_addr: user_addr.clone(),
+            percentage: Decimal::one(),
+        },
+    ]
 }
 
-pub fn update_config(deps: DepsMut, info: MessageInfo, new_config: Config) -> StdResult<Response> {
+pub fn update_config(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    new_config: Config,
+) -> Result<Response, ContractError> {
     // Only governance can update config
     let config = CONFIG.load(deps.storage)?;
     if info.sender != config.governance {
-        return Err(StdError::generic_err("only governance can update config"));
+        return Err(ContractError::unauthorized("only governance can update config"));
     }
 
-    // New config must be valid
-    config.validate()?;
+    // Governance can only be transferred in two steps, with `propose_new_governance` and
+    // `accept_governance`, so that a typo can't lock it out
+    let mut new_config = Config {
+        governance: config.governance.clone(),
+        ..new_config
+    };
+
+    // New config must be valid, within the limits set at instantiation
+    new_config.validate(&LIMITS.load(deps.storage)?)?;
+    new_config.assert_pairs(&deps.querier)?;
+    new_config.cache_decimals(&deps.querier)?;
+
+    // the config being replaced is recorded, so that past parameter values can be audited. only the
+    // most recent entries are retained
+    let id = match CONFIG_HISTORY.range(deps.storage, None, None, Order::Descending).next() {
+        Some(item) => item?.1.id + 1,
+        None => 0,
+    };
+    let entry = ConfigHistoryEntry {
+        id,
+        config: config.into(),
+        changed_at_height: env.block.height,
+        changed_at_time: env.block.time.seconds(),
+        changed_by: info.sender.to_string(),
+    };
+    CONFIG_HISTORY.save(deps.storage, U64Key::new(id), &entry)?;
+
+    let ids = CONFIG_HISTORY.keys(deps.storage, None, None, Order::Ascending).collect::<Vec<_>>();
+    let num_to_evict = ids.len().saturating_sub(MAX_CONFIG_HISTORY);
+    for id in ids.into_iter().take(num_to_evict) {
+        CONFIG_HISTORY.remove(deps.storage, U64Key::from(id));
+    }
 
     CONFIG.save(deps.storage, &new_config)?;
     Ok(Response::default())
 }
+
+pub fn fund_incentive_reserve(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    // Only governance can fund the incentive reserve
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.governance {
+        return Err(ContractError::unauthorized("only governance can fund incentive reserve"));
+    }
+
+    let incentive = config
+        .liquidation_incentive
+        .ok_or_else(|| StdError::generic_err("liquidation incentive is not configured"))?;
+    let asset = Asset::new(incentive.info, amount);
+
+    // If asset is a CW20 token, we transfer the specified amount from the sender's wallet
+    // If asset is a native token, we assert that exactly the same amount was received
+    let mut received_coins = AssetList::from(info.funds);
+    let mut msgs: Vec<CosmosMsg> = vec![];
+    match &asset.info {
+        AssetInfo::Cw20(_) => {
+            msgs.push(asset.transfer_from_msg(&info.sender, &env.contract.address)?);
+        }
+        AssetInfo::Native(_) => {
+            assert_sent_fund(&asset, &received_coins)?;
+            received_coins.deduct(&asset)?;
+        }
+    }
+    if received_coins.len() > 0 {
+        return Err(
+            StdError::generic_err(format!("extra funds received: {}", received_coins)).into()
+        );
+    }
+
+    let mut state = STATE.load(deps.storage)?;
+    state.incentive_reserve.add(&asset)?;
+    STATE.save(deps.storage, &state)?;
+
+    Ok(Response::new()
+        .add_messages(msgs)
+        .add_attribute("action", "martian_field/execute/fund_incentive_reserve")
+        .add_attribute("funded", asset.to_string()))
+}
+
+pub fn withdraw_accumulated_astro(
+    deps: DepsMut,
+    info: MessageInfo,
+    recipient_addr: Addr,
+) -> Result<Response, ContractError> {
+    // Only governance can withdraw accumulated ASTRO
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.governance {
+        return Err(ContractError::unauthorized("only governance can withdraw accumulated astro"));
+    }
+
+    let mut state = STATE.load(deps.storage)?;
+    let astro_to_withdraw = Asset::new(config.astro_token_info.clone(), state.accumulated_astro);
+    if astro_to_withdraw.amount.is_zero() {
+        return Err(StdError::generic_err("no accumulated astro to withdraw").into());
+    }
+
+    state.accumulated_astro = Uint128::zero();
+    STATE.save(deps.storage, &state)?;
+
+    Ok(Response::new()
+        .add_message(astro_to_withdraw.transfer_msg(&recipient_addr)?)
+        .add_attribute("action", "martian_field/execute/withdraw_accumulated_astro")
+        .add_attribute("recipient", recipient_addr)
+        .add_attribute("astro_withdrawn", astro_to_withdraw.amount))
+}
+
+pub fn sweep(
+    deps: DepsMut,
+    info: MessageInfo,
+    asset: Asset,
+    recipient_addr: Addr,
+) -> Result<Response, ContractError> {
+    // Only governance can sweep
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.governance {
+        return Err(ContractError::unauthorized("only governance can sweep"));
+    }
+
+    // assets the strategy holds on behalf of its users can't be swept, so that governance can't
+    // drain user funds
+    let mut protected_infos = config.swap_asset_infos();
+    protected_infos.push(AssetInfo::cw20(config.primary_pair.liquidity_token.clone()));
+    if let Some(incentive) = &config.liquidation_incentive {
+        protected_infos.push(incentive.info.clone());
+    }
+    if protected_infos.contains(&asset.info) {
+        return Err(StdError::generic_err(format!(
+            "cannot sweep {}: asset is held by the strategy",
+            asset.info
+        )).into());
+    }
+    if asset.amount.is_zero() {
+        return Err(StdError::generic_err("sweep amount must be non-zero").into());
+    }
+
+    Ok(Response::new()
+        .add_message(asset.transfer_msg(&recipient_addr)?)
+        .add_attribute("action", "martian_field/execute/sweep")
+        .add_attribute("recipient", recipient_addr)
+        .add_attribute("asset_swept", asset.to_string()))
+}
+
+pub fn set_paused(
+    deps: DepsMut,
+    info: MessageInfo,
+    paused: bool,
+) -> Result<Response, ContractError> {
+    // Only governance can pause or unpause
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.governance {
+        return Err(ContractError::unauthorized("only governance can set paused"));
+    }
+
+    PAUSED.save(deps.storage, &paused)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "martian_field/execute/set_paused")
+        .add_attribute("paused", paused.to_string()))
+}
+
+pub fn propose_new_governance(
+    deps: DepsMut,
+    info: MessageInfo,
+    new_governance: Addr,
+) -> Result<Response, ContractError> {
+    // Only governance can propose a new governance
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.governance {
+        return Err(ContractError::unauthorized("only governance can propose new governance"));
+    }
+
+    PENDING_GOVERNANCE.save(deps.storage, &new_governance)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "martian_field/execute/propose_new_governance")
+        .add_attribute("new_governance", new_governance))
+}
+
+pub fn accept_governance(deps: DepsMut, info: MessageInfo) -> Result<Response, ContractError> {
+    // Only the proposed new governance can accept
+    let pending_governance = PENDING_GOVERNANCE
+        .may_load(deps.storage)?
+        .ok_or_else(|| StdError::generic_err("no pending governance transfer"))?;
+    if info.sender != pending_governance {
+        return Err(ContractError::unauthorized("only the proposed new governance can accept"));
+    }
+
+    let mut config = CONFIG.load(deps.storage)?;
+    let previous_governance = config.governance;
+    config.governance = pending_governance;
+    CONFIG.save(deps.storage, &config)?;
+    PENDING_GOVERNANCE.remove(deps.storage);
+
+    Ok(Response::new()
+        .add_attribute("action", "martian_field/execute/accept_governance")
+        .add_attribute("previous_governance", previous_governance)
+        .add_attribute("governance", config.governance))
+}
+
+pub fn add_operator(
+    deps: DepsMut,
+    info: MessageInfo,
+    operator: Addr,
+) -> Result<Response, ContractError> {
+    // Only governance can add operators
+    let mut config = CONFIG.load(deps.storage)?;
+    if info.sender != config.governance {
+        return Err(ContractError::unauthorized("only governance can add operators"));
+    }
+
+    let mut res = Response::new()
+        .add_attribute("action", "martian_field/execute/add_operator")
+        .add_attribute("operator", &operator);
+
+    if config.operators.contains(&operator) {
+        res = res.add_attribute("warning", format!("{} is already an operator", operator));
+    } else {
+        config.operators.push(operator);
+        CONFIG.save(deps.storage, &config)?;
+    }
+
+    Ok(res)
+}
+
+pub fn remove_operator(
+    deps: DepsMut,
+    info: MessageInfo,
+    operator: Addr,
+) -> Result<Response, ContractError> {
+    // Only governance can remove operators
+    let mut config = CONFIG.load(deps.storage)?;
+    if info.sender != config.governance {
+        return Err(ContractError::unauthorized("only governance can remove operators"));
+    }
+
+    let len_before = config.operators.len();
+    config.operators.retain(|op| op != &operator);
+    if config.operators.len() == len_before {
+        return Err(StdError::generic_err(format!("{} is not an operator", operator)).into());
+    }
+
+    CONFIG.save(deps.storage, &config)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "martian_field/execute/remove_operator")
+        .add_attribute("operator", operator))
+}
+
+pub fn admin_adjust_position(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    user_addr: Addr,
+    bond_units: Uint128,
+    debt_units: Uint128,
+) -> Result<Response, ContractError> {
+    // Only governance can adjust positions
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.governance {
+        return Err(ContractError::unauthorized("only governance can adjust positions"));
+    }
+
+    let mut state = STATE.load(deps.storage)?;
+    let mut position = load_position(deps.storage, &user_addr)?;
+
+    // Adjust the global totals by the same amounts as the position, so that the sum of all
+    // positions' units always equals the total units
+    let bond_units_before = position.bond_units;
+    let debt_units_before = position.debt_units;
+    state.total_bond_units =
+        state.total_bond_units.checked_sub(bond_units_before)?.checked_add(bond_units)?;
+    state.total_debt_units =
+        state.total_debt_units.checked_sub(debt_units_before)?.checked_add(debt_units)?;
+    let entering_bond_units = cmp::min(position.entering_bond_units, bond_units);
+    state.entering_bond_units = state
+        .entering_bond_units
+        .checked_sub(position.entering_bond_units)?
+        .checked_add(entering_bond_units)?;
+    position.bond_units = bond_units;
+    position.entering_bond_units = entering_bond_units;
+    position.debt_units = debt_units;
+
+    STATE.save(deps.storage, &state)?;
+    if position.is_empty() {
+        POSITION.remove(deps.storage, &user_addr);
+    } else {
+        POSITION.save(deps.storage, &user_addr, &position)?;
+    }
+
+    let event = Event::new("admin_adjustment")
+        .add_attribute("time", env.block.time.seconds().to_string())
+        .add_attribute("height", env.block.height.to_string())
+        .add_attribute("user", &user_addr)
+        .add_attribute("bond_units_before", bond_units_before)
+        .add_attribute("bond_units_after", bond_units)
+        .add_attribute("debt_units_before", debt_units_before)
+        .add_attribute("debt_units_after", debt_units);
+
+    Ok(Response::new()
+        .add_event(event)
+        .add_attribute("action", "martian_field/execute/admin_adjust_position"))
+}
+
+pub fn migrate(
+    mut deps: DepsMut,
+    contract_name: &str,
+    contract_version: &str,
+    new_config: Option<Config>,
+) -> StdResult<Response> {
+    // contracts deployed before version info was recorded are treated as version 0.0.0
+    let stored = get_contract_version(deps.storage).ok();
+    if let Some(stored) = &stored {
+        if stored.contract != contract_name {
+            return Err(StdError::generic_err(format!(
+                "cannot migrate from a different contract: {}",
+                stored.contract
+            )));
+        }
+    }
+    let stored_version = stored.map(|stored| stored.version).unwrap_or_else(|| "0.0.0".to_string());
+
+    // downgrades are not allowed. migrating to the same version is allowed, in which case the
+    // migration is a no-op apart from the optional config update
+    if parse_version(&stored_version)? > parse_version(contract_version)? {
+        return Err(StdError::generic_err(format!(
+            "cannot migrate from version {} to older version {}",
+            stored_version, contract_version
+        )));
+    }
+
+    // delete on-chain snapshots, which are no longer used
+    legacy::delete_snapshots(deps.branch())?;
+
+    // contracts instantiated by older versions have no limits saved, and are given the defaults
+    let limits = LIMITS.may_load(deps.storage)?.unwrap_or_default();
+    LIMITS.save(deps.storage, &limits)?;
+
+    // config and state written by older versions lack the newly added fields, which take their
+    // default values when loaded. we save them back so that they are stored in the current schema
+    let mut config = match new_config {
+        Some(new_config) => {
+            new_config.validate(&limits)?;
+            new_config.assert_pairs(&deps.querier)?;
+            new_config
+        }
+        None => {
+            // configs written by older versions have a single treasury account, which receives all
+            // of the fees
+            let mut config = CONFIG.load(deps.storage)?;
+            if config.fee_recipients.is_empty() {
+                let treasury = legacy::load_treasury(deps.storage)?;
+                config.fee_recipients = vec![(treasury, Decimal::one())];
+            }
+            config
+        }
+    };
+    config.cache_decimals(&deps.querier)?;
+    CONFIG.save(deps.storage, &config)?;
+
+    // state written by older versions starts from the initial reward index. positions written by
+    // older versions take the same index when loaded, so they are credited with all rewards
+    // reinvested from now on
+    let state = STATE.load(deps.storage)?;
+    STATE.save(deps.storage, &state)?;
+
+    // data cached between a submessage and its reply should never persist across transactions.
+    // older versions cached the user address under a single key, which is no longer used
+    legacy::clear_cached_user_addr(deps.storage);
+    CACHED_BALANCES.remove(deps.storage);
+    CACHED_MIN_RETURN.remove(deps.storage);
+    CACHED_MIN_LP_OUT.remove(deps.storage);
+    CACHED_REWARDS.remove(deps.storage);
+    CACHED_ROUTE.remove(deps.storage);
+
+    set_contract_version(deps.storage, contract_name, contract_version)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "martian_field/execute/migrate")
+        .add_attribute("from_version", stored_version)
+        .add_attribute("to_version", contract_version))
+}
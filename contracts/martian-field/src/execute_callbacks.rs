@@ -1,47 +1,152 @@
 use std::cmp;
 use std::cmp::Ordering;
+use std::convert::TryFrom;
 
 use cosmwasm_std::{
-    attr, Addr, Attribute, Decimal, DepsMut, Env, Event, Response, StdError, StdResult, Uint128,
+    attr, Addr, Attribute, CosmosMsg, Decimal, DepsMut, Env, Event, Fraction, Order,
+    QuerierWrapper, Response, StdError, StdResult, Storage, SubMsg, Uint128, Uint512,
 };
 
 use cw_asset::{Asset, AssetInfo, AssetList};
+use cw_storage_plus::U64Key;
+
+use fields_of_mars::adapters::PairType;
+use fields_of_mars::martian_field::{CallbackMsg, Config, FeeMode, Snapshot};
+
+use crate::error::ContractError;
+use crate::health::{compute_bond_value_per_unit, compute_health, Totals};
+use crate::helpers::{
+    assert_unlocked_assets_bounded, close_reward_epoch, compute_received, emit_position_event,
+    emit_position_event_with_totals, fee_transfer_msgs, harvest_aborted, load_position,
+    query_balances, query_cw20_allowance, recoverable_harvest_submsgs, register_op, seed_units,
+    skip_harvest_step, value_of, AssetListExt,
+};
+use crate::queries::_query_position;
+use crate::state::{
+    CachedRewards, CachedRoute, Position, State, CACHED_BALANCES, CACHED_MIN_LP_OUT,
+    CACHED_MIN_RETURN, CACHED_REWARDS, CACHED_ROUTE, CONFIG, HARVEST_ABORTED, LOCK, POSITION,
+    SNAPSHOTS, STATE,
+};
 
-use crate::health::compute_health;
-use crate::state::{CACHED_USER_ADDR, CONFIG, POSITION, STATE, Position, State};
+pub fn credit_deposits(
+    deps: DepsMut,
+    env: Env,
+    user_addr: Addr,
+    balances_before: AssetList,
+) -> StdResult<Response> {
+    let mut position = load_position(deps.storage, &user_addr)?;
+
+    let mut attrs: Vec<Attribute> = vec![];
+    for balance_before in balances_before.to_vec() {
+        let received = compute_received(
+            &deps.querier,
+            &env.contract.address,
+            &balance_before.info,
+            &balances_before,
+        )?;
+        position.unlocked_assets.add(&received)?;
+        attrs.push(attr("deposit_received", received.to_string()));
+    }
+    assert_unlocked_assets_bounded(&position)?;
+
+    POSITION.save(deps.storage, &user_addr, &position)?;
 
-static DEFAULT_BOND_UNITS_PER_SHARE_BONDED: Uint128 = Uint128::new(1_000_000);
-static DEFAULT_DEBT_UNITS_PER_ASSET_BORROWED: Uint128 = Uint128::new(1_000_000);
+    Ok(Response::new()
+        .add_attribute("action", "martian_field/callback/credit_deposits")
+        .add_attributes(attrs))
+}
 
 pub fn provide_liquidity(
     deps: DepsMut,
+    env: Env,
     user_addr_option: Option<Addr>,
     slippage_tolerance: Option<Decimal>,
+    min_lp_out_option: Option<Uint128>,
 ) -> StdResult<Response> {
+    if user_addr_option.is_none() && harvest_aborted(deps.storage)? {
+        return Ok(skip_harvest_step("martian_field/callback/provide_liquidity"));
+    }
+
     let config = CONFIG.load(deps.storage)?;
 
+    // providing to an empty pool would let the provided amounts set the pool's price, which then
+    // isn't tied to the oracle's. we refuse to seed the pool, so it must be seeded externally first
+    config.primary_pair.query_liquid_pool(
+        &deps.querier,
+        &config.primary_asset_info,
+        &config.secondary_asset_info,
+    )?;
+
     // if `user_addr` is provided, we load the user's position and provide the user's unlocked assets
     // if not provided, we load the state and provide the state's pending rewards
     let mut state = State::default();
     let mut position = Position::default();
     let assets: &mut AssetList;
     if let Some(user_addr) = &user_addr_option {
-        position = POSITION.load(deps.storage, user_addr).unwrap_or_default();
+        position = load_position(deps.storage, user_addr)?;
         assets = &mut position.unlocked_assets;
     } else {
         state = STATE.load(deps.storage)?;
         assets = &mut state.pending_rewards;
     }
 
+    let primary_asset_option = assets.find(&config.primary_asset_info).cloned();
+    let secondary_asset_option = assets.find(&config.secondary_asset_info).cloned();
+
+    // if only one of the two assets is available, and one-sided provide is allowed, we swap half of
+    // the available asset to the other, then invoke this callback again
+    if config.allow_one_sided_provide {
+        let one_sided_asset = match (&primary_asset_option, &secondary_asset_option) {
+            (Some(primary_asset), None) => Some(primary_asset),
+            (None, Some(secondary_asset)) => Some(secondary_asset),
+            _ => None,
+        };
+        if let Some(one_sided_asset) = one_sided_asset {
+            let offer_asset = Asset::new(
+                one_sided_asset.info.clone(),
+                one_sided_asset.amount.multiply_ratio(1u128, 2u128),
+            );
+            if offer_asset.amount.is_zero() {
+                return Err(StdError::generic_err("insufficient amount for one-sided provide"));
+            }
+
+            assets.deduct(&offer_asset)?;
+
+            if let Some(user_addr) = &user_addr_option {
+                POSITION.save(deps.storage, user_addr, &position)?;
+            } else {
+                STATE.save(deps.storage, &state)?;
+            }
+            let reply_id = register_op(deps.storage, &env, 2, user_addr_option.as_ref())?;
+
+            let swap_submsgs = recoverable_harvest_submsgs(
+                deps.storage,
+                user_addr_option.as_ref(),
+                reply_id,
+                std::slice::from_ref(&offer_asset),
+                vec![config.primary_pair.swap_submsg(reply_id, &offer_asset, None, None)?],
+            )?;
+
+            let callback = CallbackMsg::ProvideLiquidity {
+                user_addr: user_addr_option,
+                slippage_tolerance,
+                min_lp_out: min_lp_out_option,
+            };
+            return Ok(Response::new()
+                .add_submessages(swap_submsgs)
+                .add_message(callback.into_cosmos_msg(&env.contract.address)?)
+                .add_attribute("action", "martian_field/callback/provide_liquidity")
+                .add_attribute("one_sided_asset_swapped", offer_asset.to_string()));
+        }
+    }
+
     // we provide *all* available primary and secondary assets, assuming they are close in value.
-    // it is strongly recommended to use `slippage_tolerance` parameter here
-    let primary_asset_to_provide = assets
-        .find(&config.primary_asset_info)
-        .cloned()
-        .ok_or_else(|| StdError::generic_err("no primary asset available"))?;
-    let secondary_asset_to_provide = assets
-        .find(&config.secondary_asset_info)
-        .cloned()
+    // it is strongly recommended to use `slippage_tolerance` parameter here. if not specified, we
+    // use the configured default, so that the provision can't be sandwiched
+    let slippage_tolerance = config.slippage_tolerance_or_default(slippage_tolerance);
+    let primary_asset_to_provide =
+        primary_asset_option.ok_or_else(|| StdError::generic_err("no primary asset available"))?;
+    let secondary_asset_to_provide = secondary_asset_option
         .ok_or_else(|| StdError::generic_err("no secondary asset available"))?;
 
     // deduct assets that will be provided from available asset list
@@ -52,45 +157,69 @@ pub fn provide_liquidity(
     // if `user_addr` is provided, we cache it so that it can be accessed when handling the reply
     if let Some(user_addr) = &user_addr_option {
         POSITION.save(deps.storage, user_addr, &position)?;
-        CACHED_USER_ADDR.save(deps.storage, user_addr)?;
     } else {
         STATE.save(deps.storage, &state)?;
     }
+    let reply_id = register_op(deps.storage, &env, 0, user_addr_option.as_ref())?;
+
+    // if a minimum amount of liquidity tokens is specified, we cache it so that it can be enforced
+    // when handling the reply
+    if let Some(min_lp_out) = min_lp_out_option {
+        CACHED_MIN_LP_OUT.save(deps.storage, &min_lp_out)?;
+    }
+
+    let assets_to_provide = [primary_asset_to_provide.clone(), secondary_asset_to_provide.clone()];
+    let submsgs = recoverable_harvest_submsgs(
+        deps.storage,
+        user_addr_option.as_ref(),
+        reply_id,
+        &assets_to_provide,
+        config.primary_pair.provide_submsgs(reply_id, &assets_to_provide, slippage_tolerance)?,
+    )?;
 
     Ok(Response::new()
-        .add_submessages(config.primary_pair.provide_submsgs(
-            0,
-            &[primary_asset_to_provide.clone(), secondary_asset_to_provide.clone()],
-            slippage_tolerance,
-        )?)
+        .add_submessages(submsgs)
         .add_attribute("action", "martian_field/callback/provide_liquidity")
         .add_attribute("primary_provided", primary_asset_to_provide.amount)
         .add_attribute("secondary_provided", secondary_asset_to_provide.amount))
 }
 
-pub fn withdraw_liquidity(deps: DepsMut, user_addr: Addr) -> StdResult<Response> {
+pub fn withdraw_liquidity(deps: DepsMut, env: Env, user_addr: Addr) -> StdResult<Response> {
     let config = CONFIG.load(deps.storage)?;
-    let mut position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
+    let mut position = load_position(deps.storage, &user_addr)?;
 
     // We burn *all* of the user's unlocked liquidity tokens
     let liquidity_token_info = AssetInfo::cw20(config.primary_pair.liquidity_token.clone());
     let liquidity_token_to_burn = position
         .unlocked_assets
-        .find(&liquidity_token_info)
-        .cloned()
+        .take(&liquidity_token_info)
         .ok_or_else(|| StdError::generic_err("no unlocked share token available"))?;
 
-    position.unlocked_assets.deduct(&liquidity_token_to_burn)?;
     POSITION.save(deps.storage, &user_addr, &position)?;
-    CACHED_USER_ADDR.save(deps.storage, &user_addr)?;
+    let reply_id = register_op(deps.storage, &env, 1, Some(&user_addr))?;
+
+    // record the contract's balances before the withdrawal, so that the exact amounts returned by
+    // the pool can be computed in the reply
+    let balances_before = query_balances(
+        &deps.querier,
+        &env.contract.address,
+        &[&config.primary_asset_info, &config.secondary_asset_info],
+    )?;
+    CACHED_BALANCES.save(deps.storage, &balances_before)?;
 
     Ok(Response::new()
-        .add_submessage(config.primary_pair.withdraw_submsg(1, liquidity_token_to_burn.amount)?)
+        .add_submessage(
+            config.primary_pair.withdraw_submsg(reply_id, liquidity_token_to_burn.amount)?,
+        )
         .add_attribute("action", "martian_field/callback/withdraw_liquidity")
         .add_attribute("shares_burned", liquidity_token_to_burn.amount))
 }
 
 pub fn bond(deps: DepsMut, env: Env, user_addr_option: Option<Addr>) -> StdResult<Response> {
+    if user_addr_option.is_none() && harvest_aborted(deps.storage)? {
+        return Ok(skip_harvest_step("martian_field/callback/bond"));
+    }
+
     let config = CONFIG.load(deps.storage)?;
     let mut state = STATE.load(deps.storage)?;
 
@@ -99,7 +228,7 @@ pub fn bond(deps: DepsMut, env: Env, user_addr_option: Option<Addr>) -> StdResul
     let mut position = Position::default();
     let assets: &mut AssetList;
     if let Some(user_addr) = &user_addr_option {
-        position = POSITION.load(deps.storage, user_addr).unwrap_or_default();
+        position = load_position(deps.storage, user_addr)?;
         assets = &mut position.unlocked_assets;
     } else {
         assets = &mut state.pending_rewards;
@@ -108,8 +237,7 @@ pub fn bond(deps: DepsMut, env: Env, user_addr_option: Option<Addr>) -> StdResul
     // we bond *all* of the available liquidity tokens
     let liquidity_token_info = AssetInfo::cw20(config.primary_pair.liquidity_token.clone());
     let liquidity_tokens_to_bond = assets
-        .find(&liquidity_token_info)
-        .cloned()
+        .take(&liquidity_token_info)
         .ok_or_else(|| StdError::generic_err("no liquidity token available"))?;
 
     // query how many liquidity tokens is currently being bonded by us
@@ -119,47 +247,101 @@ pub fn bond(deps: DepsMut, env: Env, user_addr_option: Option<Addr>) -> StdResul
         &config.primary_pair.liquidity_token,
     )?;
 
-    // calculate how by many the user's bond units should be increased
-    // 1. if no user address is provided (meaning this is a harvest operation) then we don't
-    // increment bond units
-    // 2. if total bonded shares is zero, then we use the default value, which is defined as:
+    // calculate how by many the bond units should be increased
+    // 1. if no user address is provided (meaning this is a harvest operation) then the units are
+    // minted for the bond units eligible for rewards, i.e. those not added in the current reward
+    // epoch. if there is no eligible unit, we don't increment bond units
+    // 2. if total bonded shares is zero, then we use the configured value, which is by default
     // 1 unit of liquidity token bonded = 1,000,000 bond units
-    let bond_units_to_add = if user_addr_option.is_none() {
+    let eligible_bond_units = state.total_bond_units.checked_sub(state.entering_bond_units)?;
+    let bond_units_to_add = if user_addr_option.is_none() && eligible_bond_units.is_zero() {
         Uint128::zero()
     } else if total_bonded_amount.is_zero() {
-        liquidity_tokens_to_bond.amount.checked_mul(DEFAULT_BOND_UNITS_PER_SHARE_BONDED)?
+        seed_units(liquidity_tokens_to_bond.amount, config.bond_units_per_share)?
     } else {
         state.total_bond_units.multiply_ratio(liquidity_tokens_to_bond.amount, total_bonded_amount)
     };
 
     // Astro generator automatically withdraws pending rewards when bonding liquidity tokens
-    // we query how much claimable rewards are there (assume exactly the same amount will be
-    // withdrawn!) and increment the state's reinvestable rewards
+    // we query how much claimable rewards are there and increment the state's reinvestable rewards.
+    // the generator may actually pay a different amount, which is reconciled in the reply
     let rewards = config.astro_generator.query_rewards(
         &deps.querier,
         &env.contract.address,
         &config.primary_pair.liquidity_token,
     )?;
+    cache_rewards(deps.storage, &deps.querier, &env, &config, &rewards)?;
+
+    // the total bonded amount after this operation is derived from the delta, instead of being
+    // re-queried from the generator, which saves us one query
+    let total_bonded_after = total_bonded_amount.checked_add(liquidity_tokens_to_bond.amount)?;
 
-    assets.deduct(&liquidity_tokens_to_bond)?;
     state.pending_rewards.add_many(&rewards)?;
+    state.uncharged_rewards.add_many(&rewards)?;
     state.total_bond_units = state.total_bond_units.checked_add(bond_units_to_add)?;
+
+    // units minted by a harvest are credited to the eligible bond units, which are settled lazily,
+    // by growing the reward index. units added by a user are entering until the epoch ends
+    if user_addr_option.is_none() {
+        if !bond_units_to_add.is_zero() {
+            let index = Uint128::new(state.reward_index.numerator()).multiply_ratio(
+                eligible_bond_units.checked_add(bond_units_to_add)?,
+                eligible_bond_units,
+            );
+            state.reward_index = Decimal::from_ratio(index, state.reward_index.denominator());
+        }
+        close_reward_epoch(deps.storage, &mut state)?;
+    } else {
+        state.entering_bond_units = state.entering_bond_units.checked_add(bond_units_to_add)?;
+    }
     STATE.save(deps.storage, &state)?;
 
+    let mut events: Vec<Event> = vec![];
     if let Some(user_addr) = &user_addr_option {
+        // the liquidity tokens are bonded after this callback, so the totals are adjusted to
+        // reflect the state after bonding
+        let mut totals = Totals::query(&deps.querier, &env, &config)?;
+        totals.bond_amount = total_bonded_after;
+
+        // the value at which the new bond units are acquired is averaged into the user's entry
+        // value, which withdrawal fees are charged relative to
+        if config.fee_mode == FeeMode::Withdrawal && !bond_units_to_add.is_zero() {
+            let value_per_unit =
+                compute_bond_value_per_unit(&deps.querier, &env, &config, &totals, &state)?;
+            let bond_units_after = position.bond_units.checked_add(bond_units_to_add)?;
+            let entry_value = position.entry_bond_value_per_unit * position.bond_units
+                + value_per_unit * bond_units_to_add;
+            position.entry_bond_value_per_unit = Decimal::from_ratio(entry_value, bond_units_after);
+        }
         position.bond_units = position.bond_units.checked_add(bond_units_to_add)?;
+        position.entering_bond_units =
+            position.entering_bond_units.checked_add(bond_units_to_add)?;
+        position.entry_epoch = state.reward_epoch;
         POSITION.save(deps.storage, user_addr, &position)?;
+
+        events.push(emit_position_event_with_totals(
+            &deps.querier,
+            &env,
+            &config,
+            &totals,
+            &state,
+            user_addr,
+            &position,
+        ));
     }
 
     Ok(Response::new()
-        .add_message(
+        .add_submessage(SubMsg::reply_on_success(
             config
                 .astro_generator
                 .bond_msg(&config.primary_pair.liquidity_token, liquidity_tokens_to_bond.amount)?,
-        )
+            register_op(deps.storage, &env, 3, None)?,
+        ))
         .add_attribute("action", "martian_field/callback/bond")
         .add_attribute("bond_units_added", bond_units_to_add)
-        .add_attribute("shares_bonded", liquidity_tokens_to_bond.amount))
+        .add_attribute("shares_bonded", liquidity_tokens_to_bond.amount)
+        .add_attribute("total_bonded_after", total_bonded_after)
+        .add_events(events))
 }
 
 pub fn unbond(
@@ -170,7 +352,7 @@ pub fn unbond(
 ) -> StdResult<Response> {
     let config = CONFIG.load(deps.storage)?;
     let mut state = STATE.load(deps.storage)?;
-    let mut position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
+    let mut position = load_position(deps.storage, &user_addr)?;
 
     // Query how many share tokens is currently being bonded by us
     let total_bonded_amount = config.astro_generator.query_bonded_amount(
@@ -184,33 +366,135 @@ pub fn unbond(
         total_bonded_amount.multiply_ratio(bond_units_to_deduct, state.total_bond_units);
     let liquidity_token_to_unbond =
         Asset::cw20(config.primary_pair.liquidity_token.clone(), amount_to_unbond);
+    let total_bonded_after = total_bonded_amount.checked_sub(amount_to_unbond)?;
 
     // Astro generator automatically withdraws pending rewards when unbonding liquidity tokens
-    // we query how much claimable rewards are there (assume exactly the same amount will be
-    // withdrawn!) and increment the state's reinvestable rewards
+    // we query how much claimable rewards are there and increment the state's reinvestable rewards.
+    // the generator may actually pay a different amount, which is reconciled in the reply
     let rewards = config.astro_generator.query_rewards(
         &deps.querier,
         &env.contract.address,
         &config.primary_pair.liquidity_token,
     )?;
+    cache_rewards(deps.storage, &deps.querier, &env, &config, &rewards)?;
+
+    // in withdrawal fee mode, a portion of the unbonded liquidity tokens is charged as fee
+    let mut totals = Totals::query(&deps.querier, &env, &config)?;
+    let fee_amount = if config.fee_mode == FeeMode::Withdrawal {
+        compute_withdrawal_fee(
+            &deps.querier,
+            &env,
+            &config,
+            &totals,
+            &state,
+            &position,
+            amount_to_unbond,
+        )?
+    } else {
+        Uint128::zero()
+    };
+    let fee = Asset::cw20(config.primary_pair.liquidity_token.clone(), fee_amount);
+
+    // bond units entering in the current epoch are deducted first
+    let entering_to_deduct = cmp::min(position.entering_bond_units, bond_units_to_deduct);
 
     state.total_bond_units = state.total_bond_units.checked_sub(bond_units_to_deduct)?;
+    state.entering_bond_units = state.entering_bond_units.checked_sub(entering_to_deduct)?;
     state.pending_rewards.add_many(&rewards)?;
+    state.uncharged_rewards.add_many(&rewards)?;
     position.bond_units = position.bond_units.checked_sub(bond_units_to_deduct)?;
+    position.entering_bond_units = position.entering_bond_units.checked_sub(entering_to_deduct)?;
     position.unlocked_assets.add(&liquidity_token_to_unbond)?;
+    position.unlocked_assets.deduct(&fee)?;
+    if !fee_amount.is_zero() {
+        state.total_fees_collected.add(&fee)?;
+    }
 
     STATE.save(deps.storage, &state)?;
     POSITION.save(deps.storage, &user_addr, &position)?;
 
+    // the liquidity tokens are unbonded after this callback, so the totals are adjusted to reflect
+    // the state after unbonding
+    totals.bond_amount = total_bonded_after;
+    let event = emit_position_event_with_totals(
+        &deps.querier,
+        &env,
+        &config,
+        &totals,
+        &state,
+        &user_addr,
+        &position,
+    );
+
+    // the fee is transferred once the liquidity tokens are unbonded
+    let mut msgs: Vec<CosmosMsg> = vec![];
+    if !fee_amount.is_zero() {
+        msgs.extend(fee_transfer_msgs(&config.fee_recipients, &AssetList::from(vec![fee]))?);
+    }
+
     Ok(Response::new()
-        .add_message(
+        .add_submessage(SubMsg::reply_on_success(
             config
                 .astro_generator
                 .unbond_msg(&config.primary_pair.liquidity_token, amount_to_unbond)?,
-        )
+            register_op(deps.storage, &env, 3, None)?,
+        ))
+        .add_messages(msgs)
         .add_attribute("action", "martian_field/callback/unbond")
         .add_attribute("bond_units_deducted", bond_units_to_deduct)
-        .add_attribute("shares_unbonded", amount_to_unbond))
+        .add_attribute("shares_unbonded", amount_to_unbond)
+        .add_attribute("withdrawal_fee", fee_amount)
+        .add_attribute("total_bonded_after", total_bonded_after)
+        .add_event(event))
+}
+
+/// Compute the amount of liquidity tokens charged as withdrawal fee when a user unbonds the
+/// specified amount. The fee is `fee_rate` of the appreciation of the value per bond unit since the
+/// user bonded, recognized up to the high-water mark observed at harvest
+pub fn compute_withdrawal_fee(
+    querier: &QuerierWrapper,
+    env: &Env,
+    config: &Config,
+    totals: &Totals,
+    state: &State,
+    position: &Position,
+    amount_to_unbond: Uint128,
+) -> StdResult<Uint128> {
+    let value_per_unit = compute_bond_value_per_unit(querier, env, config, totals, state)?;
+    let chargeable_value = cmp::min(value_per_unit, state.hwm_bond_value_per_unit);
+    if chargeable_value <= position.entry_bond_value_per_unit {
+        return Ok(Uint128::zero());
+    }
+
+    let gain = chargeable_value - position.entry_bond_value_per_unit;
+    Ok(amount_to_unbond.multiply_ratio(gain.numerator(), value_per_unit.numerator())
+        * config.fee_rate)
+}
+
+/// Cache the contract's balances of reward tokens, as well as the predicted amounts of rewards to
+/// be withdrawn, before bonding or unbonding. ASTRO is always included, even if none is predicted
+fn cache_rewards(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper,
+    env: &Env,
+    config: &Config,
+    rewards: &AssetList,
+) -> StdResult<()> {
+    let rewards_vec = rewards.to_vec();
+    let mut reward_infos = vec![&config.astro_token_info];
+    for reward in &rewards_vec {
+        if !reward_infos.contains(&&reward.info) {
+            reward_infos.push(&reward.info);
+        }
+    }
+
+    CACHED_REWARDS.save(
+        storage,
+        &CachedRewards {
+            balances_before: query_balances(querier, &env.contract.address, &reward_infos)?,
+            predicted: rewards.clone(),
+        },
+    )
 }
 
 pub fn borrow(
@@ -221,98 +505,281 @@ pub fn borrow(
 ) -> StdResult<Response> {
     let config = CONFIG.load(deps.storage)?;
     let mut state = STATE.load(deps.storage)?;
-    let mut position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
+    let mut position = load_position(deps.storage, &user_addr)?;
 
     // calculate how by many the user's debt units should be increased
-    // if total debt is zero, then we define 1 unit of asset borrowed = 1,000,000 debt unit
+    // if total debt is zero, then we use the configured value, which is by default
+    // 1 unit of asset borrowed = 1,000,000 debt units
     let total_debt_amount = config.red_bank.query_user_debt(
         &deps.querier,
         &env.contract.address,
-        &config.secondary_asset_info,
+        config.borrow_asset_info(),
     )?;
 
+    // the borrowed asset arrives net of tax, so we gross up the amount to borrow such that the
+    // amount received is the requested amount. the user's debt is the grossed up amount
+    let gross_borrow_amount = gross_up_for_tax(borrow_amount, config.borrow_tax_rate);
+
+    // fail fast if Red Bank can't lend the full amount, instead of letting the borrow message fail
+    if config.assert_liquidity {
+        let available = config
+            .red_bank
+            .query_available_liquidity(&deps.querier, config.borrow_asset_info())?;
+        if gross_borrow_amount > available {
+            return Err(StdError::generic_err(format!(
+                "insufficient red bank liquidity: requested {}, available {}",
+                gross_borrow_amount, available
+            )));
+        }
+    }
+
     let debt_units_to_add = if total_debt_amount.is_zero() {
-        borrow_amount.checked_mul(DEFAULT_DEBT_UNITS_PER_ASSET_BORROWED)?
+        seed_units(gross_borrow_amount, config.debt_units_per_asset)?
     } else {
-        state.total_debt_units.multiply_ratio(borrow_amount, total_debt_amount)
+        state.total_debt_units.multiply_ratio(gross_borrow_amount, total_debt_amount)
     };
 
-    let secondary_asset_to_borrow = Asset::new(config.secondary_asset_info.clone(), borrow_amount);
+    let asset_to_borrow = Asset::new(config.borrow_asset_info().clone(), gross_borrow_amount);
+    let asset_to_receive = Asset::new(config.borrow_asset_info().clone(), borrow_amount);
 
     state.total_debt_units = state.total_debt_units.checked_add(debt_units_to_add)?;
     position.debt_units = position.debt_units.checked_add(debt_units_to_add)?;
-    position.unlocked_assets.add(&secondary_asset_to_borrow)?;
+    position.unlocked_assets.add(&asset_to_receive)?;
+
+    let total_debt_after = total_debt_amount.checked_add(gross_borrow_amount)?;
+    let debt_amount = total_debt_after.multiply_ratio(position.debt_units, state.total_debt_units);
+    assert_max_debt_per_user(&config, debt_amount)?;
 
     STATE.save(deps.storage, &state)?;
     POSITION.save(deps.storage, &user_addr, &position)?;
 
+    // the asset is borrowed after this callback, so the totals are adjusted to reflect the debt
+    // after borrowing
+    let mut totals = Totals::query(&deps.querier, &env, &config)?;
+    totals.debt_amount = total_debt_after;
+    let event = emit_position_event_with_totals(
+        &deps.querier,
+        &env,
+        &config,
+        &totals,
+        &state,
+        &user_addr,
+        &position,
+    );
+
     Ok(Response::new()
-        .add_message(config.red_bank.borrow_msg(&secondary_asset_to_borrow)?)
+        .add_message(config.red_bank.borrow_msg(&asset_to_borrow)?)
         .add_attribute("action", "martian_field/callback/borrow")
         .add_attribute("debt_units_added", debt_units_to_add)
-        .add_attribute("secondary_borrowed", secondary_asset_to_borrow.amount))
+        .add_attribute("secondary_borrowed", asset_to_borrow.amount)
+        .add_attribute("secondary_received", asset_to_receive.amount)
+        .add_event(event))
+}
+
+/// Assert that a user's debt, in borrowed asset, does not exceed the per-user cap, if any
+pub fn assert_max_debt_per_user(config: &Config, debt_amount: Uint128) -> StdResult<()> {
+    match config.max_debt_per_user {
+        Some(max_debt_per_user) if debt_amount > max_debt_per_user => {
+            Err(StdError::generic_err(format!(
+                "max debt per user exceeded: {}; must be <= {}",
+                debt_amount, max_debt_per_user
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Find the smallest amount which, after deducting tax at the given rate, is no less than the
+/// specified net amount
+pub fn gross_up_for_tax(net_amount: Uint128, tax_rate: Decimal) -> Uint128 {
+    if tax_rate.is_zero() {
+        return net_amount;
+    }
+
+    let one = Decimal::one();
+    let gross_amount = net_amount.multiply_ratio(one.numerator(), (one - tax_rate).numerator());
+    if gross_amount - gross_amount * tax_rate < net_amount {
+        gross_amount + Uint128::new(1)
+    } else {
+        gross_amount
+    }
 }
 
 pub fn repay(
     deps: DepsMut,
     env: Env,
-    user_addr: Addr,
+    user_addr_option: Option<Addr>,
     repay_amount: Option<Uint128>,
 ) -> StdResult<Response> {
+    if user_addr_option.is_none() && harvest_aborted(deps.storage)? {
+        return Ok(skip_harvest_step("martian_field/callback/repay"));
+    }
+
     let config = CONFIG.load(deps.storage)?;
     let mut state = STATE.load(deps.storage)?;
-    let mut position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
 
     let total_debt_amount = config.red_bank.query_user_debt(
         &deps.querier,
         &env.contract.address,
-        &config.secondary_asset_info,
+        config.borrow_asset_info(),
     )?;
 
-    let debt_amount = total_debt_amount.multiply_ratio(position.debt_units, state.total_debt_units);
+    // if a user address is provided, we repay the user's debt using the user's unlocked assets
+    // if not, we repay the aggregate debt using the state's pending rewards
+    let mut position = Position::default();
+    let debt_amount: Uint128;
+    let assets: &mut AssetList;
+    if let Some(user_addr) = &user_addr_option {
+        position = load_position(deps.storage, user_addr)?;
+        debt_amount = total_debt_amount.multiply_ratio(position.debt_units, state.total_debt_units);
+        assets = &mut position.unlocked_assets;
+    } else {
+        debt_amount = total_debt_amount;
+        assets = &mut state.pending_rewards;
+    }
 
-    // If `repay_amount` is not specified, default to all of the user's unlocked secondary asset
-    let repay_amount = repay_amount.unwrap_or_else(|| {
-        position
-            .unlocked_assets
-            .find(&config.secondary_asset_info)
-            .map(|asset| asset.amount)
-            .unwrap_or_else(Uint128::zero)
-    });
+    // If `repay_amount` is not specified, default to all of the available borrowed asset
+    let repay_amount = repay_amount.unwrap_or_else(|| assets.amount_of(config.borrow_asset_info()));
 
     // We only repay up to the debt amount
     let repay_amount = cmp::min(repay_amount, debt_amount);
 
+    // when repaying the aggregate debt, e.g. if the rewards are all in the primary asset, there may
+    // be nothing to repay, in which case we skip the repayment
+    if user_addr_option.is_none() && repay_amount.is_zero() {
+        return Ok(Response::new()
+            .add_attribute("action", "martian_field/callback/repay")
+            .add_attribute("secondary_repaid", repay_amount));
+    }
+
     // Calculate how by many the user's debt units should be deducted
-    let debt_units_to_deduct = if debt_amount.is_zero() {
+    // 1. if no user address is provided (meaning this is a harvest operation) then we don't
+    // deduct debt units, so that the debt per unit is reduced for all users
+    // 2. if the debt amount is zero, there is nothing to deduct
+    let debt_units_to_deduct = if user_addr_option.is_none() || debt_amount.is_zero() {
         Uint128::zero()
     } else {
         position.debt_units.multiply_ratio(repay_amount, debt_amount)
     };
 
-    let secondary_asset_to_repay = Asset::new(config.secondary_asset_info.clone(), repay_amount);
+    let asset_to_repay = Asset::new(config.borrow_asset_info().clone(), repay_amount);
 
+    assets.deduct(&asset_to_repay)?;
     state.total_debt_units = state.total_debt_units.checked_sub(debt_units_to_deduct)?;
     position.debt_units = position.debt_units.checked_sub(debt_units_to_deduct)?;
-    position.unlocked_assets.deduct(&secondary_asset_to_repay)?;
+    STATE.save(deps.storage, &state)?;
+
+    let mut events: Vec<Event> = vec![];
+    if let Some(user_addr) = &user_addr_option {
+        POSITION.save(deps.storage, user_addr, &position)?;
+
+        // the debt is repaid after this callback, so the totals are adjusted to reflect the debt
+        // after repaying
+        let mut totals = Totals::query(&deps.querier, &env, &config)?;
+        totals.debt_amount = total_debt_amount.checked_sub(repay_amount)?;
+        events.push(emit_position_event_with_totals(
+            &deps.querier,
+            &env,
+            &config,
+            &totals,
+            &state,
+            user_addr,
+            &position,
+        ));
+    }
+
+    Ok(Response::new()
+        .add_message(config.red_bank.repay_msg(&asset_to_repay)?)
+        .add_attribute("action", "martian_field/callback/repay")
+        .add_attribute("debt_units_deducted", debt_units_to_deduct)
+        .add_attribute("secondary_repaid", asset_to_repay.amount)
+        .add_events(events))
+}
+
+pub fn repay_for(
+    deps: DepsMut,
+    env: Env,
+    user_addr: Addr,
+    debtor_addr: Addr,
+    repay_amount: Uint128,
+) -> StdResult<Response> {
+    let config = CONFIG.load(deps.storage)?;
+    let mut state = STATE.load(deps.storage)?;
+    let mut position = load_position(deps.storage, &user_addr)?;
+    let mut debtor_position = POSITION
+        .may_load(deps.storage, &debtor_addr)?
+        .ok_or_else(|| StdError::generic_err(format!("{} has no position", debtor_addr)))?;
+
+    if debtor_position.debt_units.is_zero() {
+        return Err(StdError::generic_err(format!("{} has no debt to repay", debtor_addr)));
+    }
+
+    let total_debt_amount = config.red_bank.query_user_debt(
+        &deps.querier,
+        &env.contract.address,
+        config.borrow_asset_info(),
+    )?;
+
+    let debt_amount =
+        total_debt_amount.multiply_ratio(debtor_position.debt_units, state.total_debt_units);
+
+    // We only repay up to the debtor's debt amount
+    let repay_amount = cmp::min(repay_amount, debt_amount);
+
+    // Calculate how by many the debtor's debt units should be deducted
+    let debt_units_to_deduct = if debt_amount.is_zero() {
+        Uint128::zero()
+    } else {
+        debtor_position.debt_units.multiply_ratio(repay_amount, debt_amount)
+    };
+
+    let asset_to_repay = Asset::new(config.borrow_asset_info().clone(), repay_amount);
+
+    state.total_debt_units = state.total_debt_units.checked_sub(debt_units_to_deduct)?;
+    debtor_position.debt_units = debtor_position.debt_units.checked_sub(debt_units_to_deduct)?;
+    position.unlocked_assets.deduct(&asset_to_repay)?;
 
     STATE.save(deps.storage, &state)?;
     POSITION.save(deps.storage, &user_addr, &position)?;
+    POSITION.save(deps.storage, &debtor_addr, &debtor_position)?;
+
+    // the debt is repaid after this callback, so the totals are adjusted to reflect the debt after
+    // repaying
+    let mut totals = Totals::query(&deps.querier, &env, &config)?;
+    totals.debt_amount = total_debt_amount.checked_sub(repay_amount)?;
+    let event = emit_position_event_with_totals(
+        &deps.querier,
+        &env,
+        &config,
+        &totals,
+        &state,
+        &debtor_addr,
+        &debtor_position,
+    );
 
     Ok(Response::new()
-        .add_message(config.red_bank.repay_msg(&secondary_asset_to_repay)?)
-        .add_attribute("action", "martian_field/callback/repay")
+        .add_message(config.red_bank.repay_msg(&asset_to_repay)?)
+        .add_attribute("action", "martian_field/callback/repay_for")
+        .add_attribute("payer", &user_addr)
+        .add_attribute("debtor", &debtor_addr)
         .add_attribute("debt_units_deducted", debt_units_to_deduct)
-        .add_attribute("secondary_repaid", secondary_asset_to_repay.amount))
+        .add_attribute("secondary_repaid", asset_to_repay.amount)
+        .add_event(event))
 }
 
 pub fn swap(
     deps: DepsMut,
+    env: Env,
     user_addr_option: Option<Addr>,
     offer_asset_info: AssetInfo,
     offer_amount_option: Option<Uint128>,
     max_spread: Option<Decimal>,
+    min_return_option: Option<Uint128>,
 ) -> StdResult<Response> {
+    if user_addr_option.is_none() && harvest_aborted(deps.storage)? {
+        return Ok(skip_harvest_step("martian_field/callback/swap"));
+    }
+
     let config = CONFIG.load(deps.storage)?;
 
     // if `user_addr` is provided, we load the user's position and swap the user's unlocked assets
@@ -321,20 +788,52 @@ pub fn swap(
     let mut position = Position::default();
     let assets: &mut AssetList;
     if let Some(user_addr) = &user_addr_option {
-        position = POSITION.load(deps.storage, user_addr).unwrap_or_default();
+        position = load_position(deps.storage, user_addr)?;
         assets = &mut position.unlocked_assets;
     } else {
         state = STATE.load(deps.storage)?;
         assets = &mut state.pending_rewards;
     }
 
-    // we only perform two kinds of swaps:
+    // we only perform five kinds of swaps:
     // primary >> secondary; in this case, we use the primary-secondary pair
-    // ASTRO >> secondary; in this case, we use the ASTRO-secondary pair
-    let pair = if offer_asset_info == config.primary_asset_info {
+    // secondary >> primary; when unbonding with `swap_to`, also using the primary-secondary pair
+    // borrowed >> secondary; after borrowing an asset other than the secondary asset, using the
+    // borrow pair
+    // ASTRO >> secondary; in this case, we use the ASTRO-secondary pair, or the first pair of the
+    // ASTRO route if one is configured
+    // other reward token >> secondary; in this case, we use the pair configured for the token
+    //
+    // the reply of every hop of a multi-hop route but the last sends the next hop. the last hop is
+    // handled the same way as a single swap
+    let mut reply_kind = 2;
+    let pair = if offer_asset_info == config.primary_asset_info
+        || offer_asset_info == config.secondary_asset_info
+    {
         &config.primary_pair
+    } else if let Some(borrow_pair) =
+        config.borrow_pair().filter(|_| offer_asset_info == *config.borrow_asset_info())
+    {
+        borrow_pair
     } else if offer_asset_info == config.astro_token_info {
-        &config.astro_pair
+        match config.astro_route.first() {
+            Some(first_hop) => {
+                if config.astro_route.len() > 1 {
+                    reply_kind = 4;
+                    CACHED_ROUTE.save(
+                        deps.storage,
+                        &CachedRoute {
+                            hop: 0,
+                            max_spread,
+                        },
+                    )?;
+                }
+                first_hop
+            }
+            None => &config.astro_pair,
+        }
+    } else if let Some(reward_pair) = config.reward_pair(&offer_asset_info) {
+        reward_pair
     } else {
         return Err(StdError::generic_err(
             format!("invalid offer asset: {}", offer_asset_info)
@@ -358,88 +857,111 @@ pub fn swap(
     // if `user_addr` is provided, we cache it so that it can be accessed when handling the reply
     if let Some(user_addr) = &user_addr_option {
         POSITION.save(deps.storage, user_addr, &position)?;
-        CACHED_USER_ADDR.save(deps.storage, user_addr)?;
     } else {
         STATE.save(deps.storage, &state)?;
     }
+    let reply_id = register_op(deps.storage, &env, reply_kind, user_addr_option.as_ref())?;
+
+    // if a minimum return amount is specified, we cache it so that it can be enforced when handling
+    // the reply
+    if let Some(min_return) = min_return_option {
+        CACHED_MIN_RETURN.save(deps.storage, &min_return)?;
+    }
+
+    let submsgs = recoverable_harvest_submsgs(
+        deps.storage,
+        user_addr_option.as_ref(),
+        reply_id,
+        std::slice::from_ref(&offer_asset),
+        vec![pair.swap_submsg(reply_id, &offer_asset, None, max_spread)?],
+    )?;
 
     Ok(Response::new()
-        .add_submessage(pair.swap_submsg(2, &offer_asset, None, max_spread)?)
+        .add_submessages(submsgs)
         .add_attribute("action", "martian_field/callback/swap")
         .add_attribute("asset_offered", offer_asset.to_string()))
 }
 
-pub fn balance(deps: DepsMut, max_spread: Option<Decimal>) -> StdResult<Response> {
+pub fn balance(
+    deps: DepsMut,
+    env: Env,
+    user_addr_option: Option<Addr>,
+    max_spread: Option<Decimal>,
+) -> StdResult<Response> {
+    if user_addr_option.is_none() && harvest_aborted(deps.storage)? {
+        return Ok(skip_harvest_step("martian_field/callback/balance"));
+    }
+
     let config = CONFIG.load(deps.storage)?;
-    let mut state = STATE.load(deps.storage)?;
+
+    // if `user_addr` is provided, we load the user's position and balance the user's unlocked
+    // assets. if not provided, we load the state and balance the state's pending rewards
+    let mut state = State::default();
+    let mut position = Position::default();
+    let assets: &mut AssetList;
+    if let Some(user_addr) = &user_addr_option {
+        position = load_position(deps.storage, user_addr)?;
+        assets = &mut position.unlocked_assets;
+    } else {
+        state = STATE.load(deps.storage)?;
+        assets = &mut state.pending_rewards;
+    }
 
     // find the available amounts of primary and secondary assets
-    let primary_asset_amount = match state.pending_rewards.find(&config.primary_asset_info) {
-        Some(asset) => asset.amount,
-        None => Uint128::zero(),
-    };
-    let secondary_asset_amount = match state.pending_rewards.find(&config.secondary_asset_info) {
-        Some(asset) => asset.amount,
-        None => Uint128::zero(),
-    };
+    let primary_asset_amount = assets.amount_of(&config.primary_asset_info);
+    let secondary_asset_amount = assets.amount_of(&config.secondary_asset_info);
 
-    // query the prices of the two assets
-    let primary_asset_price =
-        config.oracle.query_price(&deps.querier, &config.primary_asset_info)?;
-    let secondary_asset_price =
-        config.oracle.query_price(&deps.querier, &config.secondary_asset_info)?;
-
-    // calculate the values of available assets
-    let primary_asset_value = primary_asset_amount * primary_asset_price;
-    let secondary_asset_value = secondary_asset_amount * secondary_asset_price;
-
-    // if primary_asset_value > secondary_asset_value, we swap primary >> secondary
-    // if secondary_asset_value > primary_asset_value, we swap secondary >> primary
-    // if equal, we skip
-    let (offer_asset_info, offer_asset_available_amount) = 
-        match primary_asset_value.cmp(&secondary_asset_value) 
-    {
-        Ordering::Greater => (config.primary_asset_info.clone(), primary_asset_amount),
-        Ordering::Less => (config.secondary_asset_info.clone(), secondary_asset_amount),
-        Ordering::Equal => return Ok(Response::default()),
+    // the swap is sized according to the pool's depths, so that the balanced assets can be provided
+    // in full. the oracle prices are not used for sizing, but the assets are still valued so that
+    // balancing fails if the prices are stale, same as the health check
+    let value = value_of(
+        &deps.querier,
+        &env,
+        &config,
+        &AssetList::from(vec![
+            Asset::new(config.primary_asset_info.clone(), primary_asset_amount),
+            Asset::new(config.secondary_asset_info.clone(), secondary_asset_amount),
+        ]),
+    )?;
+    let (primary_depth, secondary_depth, _) = config.primary_pair.query_liquid_pool(
+        &deps.querier,
+        &config.primary_asset_info,
+        &config.secondary_asset_info,
+    )?;
+    let compute_swap = match config.primary_pair.pair_type {
+        PairType::Xyk => compute_balancing_swap,
+        PairType::Stable => compute_stable_balancing_swap,
     };
-
-    // the amount to be swapped is the amount corresponding to half of the value difference
-    //
-    // e.g. we have $120 worth of UST and $100 worth of ANC. the diff in value is $20, so we swap
-    // $20 / 2 = $10 worth of UST to ANC. ideally, this should leave us $110 worth of each
-    //
-    // in reality, considering slippage, commission, we will end up with $110 worth of UST and 
-    // **slightly less than $110 worth** of ANC, so this method is not very optimized. the best way
-    // is to solve a quadratic function which contains terms describing slippage and commission rate
-    // to find the optimal swap amount. i have worked out the math somewhere else and will later
-    // implement it as a separate smart contract
-    //
-    // for the time being, the less optimial method is ok as long as we harvest frequently; that is,
-    // the amount that needs to be swapped is not very large at each harvest, so it should not incur
-    // too much slippage
-    let higher_value = cmp::max(primary_asset_value, secondary_asset_value);
-    let lower_value = cmp::min(primary_asset_value, secondary_asset_value);
-    let value_diff = higher_value.checked_sub(lower_value)?;
-    let value_to_swap = value_diff.multiply_ratio(1u128, 2u128);
-
-    let offer_asset = Asset::new(
-        offer_asset_info, 
-        offer_asset_available_amount.multiply_ratio(value_to_swap, higher_value)
+    let (offer_asset_info, offer_amount) = compute_swap(
+        &Asset::new(config.primary_asset_info.clone(), primary_depth),
+        &Asset::new(config.secondary_asset_info.clone(), secondary_depth),
+        primary_asset_amount,
+        secondary_asset_amount,
+        config.primary_pair.pair_type.commission_rate(),
     );
+    let offer_asset = Asset::new(offer_asset_info, offer_amount);
 
-    state.pending_rewards.deduct(&offer_asset)?;
-    STATE.save(deps.storage, &state)?;
+    assets.deduct(&offer_asset)?;
+
+    // update storage
+    // if `user_addr` is provided, we cache it so that it can be accessed when handling the reply
+    if let Some(user_addr) = &user_addr_option {
+        POSITION.save(deps.storage, user_addr, &position)?;
+    } else {
+        STATE.save(deps.storage, &state)?;
+    }
 
     // if amount to swap is zero, we do nothing
     // if amount to swap is non-zero, we invoke the `Swap` callback
     let mut res = Response::new();
     if !offer_asset.amount.is_zero() {
-        res = res.add_submessage(config.primary_pair.swap_submsg(
-            2,
-            &offer_asset,
-            None,
-            max_spread,
+        let reply_id = register_op(deps.storage, &env, 2, user_addr_option.as_ref())?;
+        res = res.add_submessages(recoverable_harvest_submsgs(
+            deps.storage,
+            user_addr_option.as_ref(),
+            reply_id,
+            std::slice::from_ref(&offer_asset),
+            vec![config.primary_pair.swap_submsg(reply_id, &offer_asset, None, max_spread)?],
         )?);
     }
 
@@ -447,94 +969,272 @@ pub fn balance(deps: DepsMut, max_spread: Option<Decimal>) -> StdResult<Response
         .add_attribute("action", "martian_field/callback/balance")
         .add_attribute("primary_amount", primary_asset_amount)
         .add_attribute("secondary_amount", secondary_asset_amount)
-        .add_attribute("primary_price", primary_asset_price.to_string())
-        .add_attribute("secondary_price", secondary_asset_price.to_string())
+        .add_attribute("value", value)
         .add_attribute("asset_offered", offer_asset.to_string()))
 }
 
+/// Find the asset to be swapped, and the amount, such that after the swap the assets available are
+/// in the same ratio as the pool's depths, and can therefore be provided to the pool in full
+///
+/// Denote the offer asset's pool depth as `X` and available amount `x`, the ask asset's pool depth
+/// `Y` and available amount `y`, and the commission rate `c`. Offering `s`, the swap returns
+/// `o = (1 - c) * Y * s / (X + s)`, after which the pool's depths are `X + s` and `Y - o`. Solving
+/// `(x - s) / (y + o) = (X + s) / (Y - o)` for `s` gives the quadratic
+///
+/// ```plain
+/// (Y + y) * s^2 + (2 * X * y + (2 - c) * X * Y - c * x * Y) * s - X * (x * Y - X * y) = 0
+/// ```
+///
+/// whose positive root is the offer amount. The asset that is in excess relative to the pool is
+/// offered. If the assets are already in the pool's ratio, or the pool is empty, the amount is zero
+pub fn compute_balancing_swap(
+    primary_depth: &Asset,
+    secondary_depth: &Asset,
+    primary_amount: Uint128,
+    secondary_amount: Uint128,
+    commission_rate: Decimal,
+) -> (AssetInfo, Uint128) {
+    let (offer_info, offer_depth, ask_depth, offer_amount, ask_amount) =
+        match find_balancing_side(primary_depth, secondary_depth, primary_amount, secondary_amount)
+        {
+            Some(side) => side,
+            None => return (primary_depth.info.clone(), Uint128::zero()),
+        };
+
+    // the coefficients are multiplied by 10^6 so that they are integers, taking the commission rate
+    // to 6 decimal places. the discriminant can be as large as the 4th power of a pool's depth
+    // multiplied by 10^12, so we work with 512-bit integers
+    let scale = Uint128::new(1_000_000);
+    let one = Uint512::from(scale);
+    let c = Uint512::from(scale * commission_rate);
+    let two = Uint512::from(2u128);
+    let four = Uint512::from(4u128);
+    let (big_x, big_y) = (Uint512::from(offer_depth), Uint512::from(ask_depth));
+    let (x, y) = (Uint512::from(offer_amount), Uint512::from(ask_amount));
+
+    // the linear coefficient is `positive - negative`, which can be negative if `x` is huge
+    // relative to `X`, so we keep the two parts separate
+    let a = one * (big_y + y);
+    let positive = two * one * big_x * y + big_x * big_y * (two * one - c);
+    let negative = c * x * big_y;
+    let minus_constant = one * big_x * (x * big_y - big_x * y);
+
+    let b_abs = if positive > negative {
+        positive - negative
+    } else {
+        negative - positive
+    };
+    let sqrt_discriminant = isqrt(b_abs * b_abs + four * a * minus_constant);
+    let numerator = if positive > negative {
+        sqrt_discriminant - b_abs
+    } else {
+        sqrt_discriminant + b_abs
+    };
+    let swap_amount = Uint128::try_from(numerator / (two * a)).unwrap_or(offer_amount);
+
+    (offer_info.clone(), cmp::min(swap_amount, offer_amount))
+}
+
+/// Same as `compute_balancing_swap`, but for a stableswap pool near the peg, where the swap returns
+/// `o = (1 - c) * s` with negligible slippage. The quadratic terms then cancel out, and
+///
+/// ```plain
+/// s = (x * Y - X * y) / (Y + y + (1 - c) * (X + x))
+/// ```
+///
+/// Away from the peg, the return differs from what is assumed, and a small amount of one of the
+/// assets is left over after providing liquidity
+pub fn compute_stable_balancing_swap(
+    primary_depth: &Asset,
+    secondary_depth: &Asset,
+    primary_amount: Uint128,
+    secondary_amount: Uint128,
+    commission_rate: Decimal,
+) -> (AssetInfo, Uint128) {
+    let (offer_info, offer_depth, ask_depth, offer_amount, ask_amount) =
+        match find_balancing_side(primary_depth, secondary_depth, primary_amount, secondary_amount)
+        {
+            Some(side) => side,
+            None => return (primary_depth.info.clone(), Uint128::zero()),
+        };
+
+    // same scaling as in `compute_balancing_swap`
+    let scale = Uint128::new(1_000_000);
+    let one = Uint512::from(scale);
+    let c = Uint512::from(scale * commission_rate);
+    let (big_x, big_y) = (Uint512::from(offer_depth), Uint512::from(ask_depth));
+    let (x, y) = (Uint512::from(offer_amount), Uint512::from(ask_amount));
+
+    let numerator = one * (x * big_y - big_x * y);
+    let denominator = one * (big_y + y) + (one - c) * (big_x + x);
+    let swap_amount = Uint128::try_from(numerator / denominator).unwrap_or(offer_amount);
+
+    (offer_info.clone(), cmp::min(swap_amount, offer_amount))
+}
+
+/// Find which asset is in excess relative to the pool, and should be offered when balancing.
+/// Return the offer asset's info, then the depths of the offer and ask assets, then the available
+/// amounts of them; or `None` if the assets are already in the pool's ratio, or the pool is empty
+fn find_balancing_side<'a>(
+    primary_depth: &'a Asset,
+    secondary_depth: &'a Asset,
+    primary_amount: Uint128,
+    secondary_amount: Uint128,
+) -> Option<(&'a AssetInfo, Uint128, Uint128, Uint128, Uint128)> {
+    if primary_depth.amount.is_zero() || secondary_depth.amount.is_zero() {
+        return None;
+    }
+
+    // if primary_amount / secondary_amount > primary_depth / secondary_depth, we swap primary >>
+    // secondary; if smaller, secondary >> primary; if equal, we skip
+    let primary_weighted = primary_amount.full_mul(secondary_depth.amount);
+    let secondary_weighted = secondary_amount.full_mul(primary_depth.amount);
+    match primary_weighted.cmp(&secondary_weighted) {
+        Ordering::Greater => Some((
+            &primary_depth.info,
+            primary_depth.amount,
+            secondary_depth.amount,
+            primary_amount,
+            secondary_amount,
+        )),
+        Ordering::Less => Some((
+            &secondary_depth.info,
+            secondary_depth.amount,
+            primary_depth.amount,
+            secondary_amount,
+            primary_amount,
+        )),
+        Ordering::Equal => None,
+    }
+}
+
+/// Integer square root, rounded down, using Newton's method
+fn isqrt(n: Uint512) -> Uint512 {
+    let two = Uint512::from(2u128);
+    if n < two {
+        return n;
+    }
+    let mut x0 = n;
+    let mut x1 = (x0 + n / x0) / two;
+    while x1 < x0 {
+        x0 = x1;
+        x1 = (x0 + n / x0) / two;
+    }
+    x0
+}
+
 pub fn cover(
     deps: DepsMut,
     env: Env,
     user_addr: Addr,
+    max_spread: Decimal,
 ) -> StdResult<Response> {
     let config = CONFIG.load(deps.storage)?;
     let state = STATE.load(deps.storage)?;
-    let mut position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
+    let mut position = load_position(deps.storage, &user_addr)?;
+    let borrow_asset_info = config.borrow_asset_info();
 
-    // find out how much secondary asset the user owes
+    // find out how much borrowed asset the user owes
     let total_debt_amount = config.red_bank.query_user_debt(
         &deps.querier,
         &env.contract.address,
-        &config.secondary_asset_info,
+        borrow_asset_info,
     )?;
     let debt_amount = total_debt_amount.multiply_ratio(position.debt_units, state.total_debt_units);
 
-    // find out how much unlocked secondary asset the user has available
-    let secondary_available = position
-        .unlocked_assets
-        .find(&config.secondary_asset_info)
-        .cloned()
-        .unwrap_or_else(|| Asset::new(config.secondary_asset_info.clone(), 0u128));
+    // find out how much unlocked borrowed asset the user has available
+    let find_unlocked = |position: &Position, asset_info: &AssetInfo| {
+        position.unlocked_assets.find(asset_info).map(|asset| asset.amount).unwrap_or_default()
+    };
+    let borrow_available_amount = find_unlocked(&position, borrow_asset_info);
 
-    // calculate how much additional secondary asset is needed to fully pay off the user's debt 
-    let secondary_needed_amount = if debt_amount > secondary_available.amount {
-        debt_amount.checked_sub(secondary_available.amount)?
+    // calculate how much additional borrowed asset is needed to fully pay off the user's debt,
+    // plus a reserve to pay the transfer tax when repaying
+    let borrow_target_amount = if debt_amount.is_zero() {
+        Uint128::zero()
+    } else {
+        debt_amount.checked_add(config.repay_tax_reserve)?
+    };
+    let borrow_needed_amount = if borrow_target_amount > borrow_available_amount {
+        borrow_target_amount.checked_sub(borrow_available_amount)?
     } else {
         return Ok(Response::default());
     };
-    let secondary_needed = Asset::new(config.secondary_asset_info.clone(), secondary_needed_amount);
-
-    // reverse-simulate how much primary asset needs to be sold
-    let mut primary_sell_amount = config.primary_pair.query_reverse_simulate(
-        &deps.querier, 
-        &secondary_needed
-    )?;
+    let borrow_needed = Asset::new(borrow_asset_info.clone(), borrow_needed_amount);
 
-    // NOTE: due to integer rounding, if we estimate offer amount using exactly the needed return
-    // amount, the actual return amount may be one unit less than what we need
-    //
-    // for example, consider LUNA-UST pair with depths 1497005315 uluna + 24450395383 uusd
-    // we want the swap to return 1291320960 uusd, so we calculate
-    // computeXykSwapInput(1291320960, 1497005315, 24450395383) = 83736355
-    //
-    // however, if we offer 80617260 uluna, the swap returns
-    // computeXykSwapOutput(83736355, 1497005315, 24450395383) = 1291320945
-    // which is 15uusd short of what we need
-    //
-    // to get our desired output, we actually need to offer 1 unit of LUNA more than the reverse-
-    // simulated amount: 83736355 + 1 = 83736356
-    // computeXykSwapOutput(83736356, 1497005315, 24450395383) = 1291320960
-    // which is exactly the amount we need 
+    // if the secondary asset is borrowed, primary asset is sold for it directly. otherwise, the
+    // borrowed asset is bought with secondary asset using the borrow pair; if the user doesn't have
+    // enough unlocked secondary asset for this, primary asset is sold for the shortfall first, and
+    // `Cover` is invoked again to make the second hop once the first one has returned
     //
-    // not a very elegant solution, but it works and is the best i can come up with rn
-    primary_sell_amount = primary_sell_amount.checked_add(Uint128::new(1))?;
-
-    // we only sell up to the user's available unlocked primary asset amount
-    let primary_available_amount = position
-        .unlocked_assets
-        .find(&config.primary_asset_info)
-        .map(|asset| asset.amount)
-        .unwrap_or_else(Uint128::zero);
-    primary_sell_amount = cmp::min(primary_sell_amount, primary_available_amount);
+    // see `Pair::query_reverse_simulate_ceil` for why the amounts to sell may be more than the
+    // reverse-simulated amounts
+    let primary_available_amount = find_unlocked(&position, &config.primary_asset_info);
+    let mut next_callback: Option<CallbackMsg> = None;
+    let (pair, sell_asset_info, sell_amount) = match config.borrow_pair() {
+        None => {
+            let primary_sell_amount = config.primary_pair.query_reverse_simulate_ceil(
+                &deps.querier,
+                &config.primary_asset_info,
+                &borrow_needed,
+            )?;
+            (&config.primary_pair, &config.primary_asset_info, primary_sell_amount)
+        }
+        Some(borrow_pair) => {
+            let secondary_sell_amount = borrow_pair.query_reverse_simulate_ceil(
+                &deps.querier,
+                &config.secondary_asset_info,
+                &borrow_needed,
+            )?;
+            let secondary_available_amount =
+                find_unlocked(&position, &config.secondary_asset_info);
+            if secondary_sell_amount > secondary_available_amount
+                && !primary_available_amount.is_zero()
+            {
+                let secondary_shortfall = Asset::new(
+                    config.secondary_asset_info.clone(),
+                    secondary_sell_amount - secondary_available_amount,
+                );
+                let primary_sell_amount = config.primary_pair.query_reverse_simulate_ceil(
+                    &deps.querier,
+                    &config.primary_asset_info,
+                    &secondary_shortfall,
+                )?;
+                next_callback = Some(CallbackMsg::Cover {
+                    user_addr: user_addr.clone(),
+                    max_spread,
+                });
+                (&config.primary_pair, &config.primary_asset_info, primary_sell_amount)
+            } else {
+                let sell_amount = cmp::min(secondary_sell_amount, secondary_available_amount);
+                (borrow_pair, &config.secondary_asset_info, sell_amount)
+            }
+        }
+    };
 
-    let primary_to_sell = Asset::new(config.primary_asset_info.clone(), primary_sell_amount);
+    // we only sell up to the user's available unlocked amount
+    let sell_amount = cmp::min(sell_amount, find_unlocked(&position, sell_asset_info));
+    let asset_to_sell = Asset::new(sell_asset_info.clone(), sell_amount);
 
-    position.unlocked_assets.deduct(&primary_to_sell)?;
+    position.unlocked_assets.deduct(&asset_to_sell)?;
     POSITION.save(deps.storage, &user_addr, &position)?;
-    CACHED_USER_ADDR.save(deps.storage, &user_addr)?;
-    
-    Ok(Response::new()
-        .add_submessage(config.primary_pair.swap_submsg(
-            2,
-            &primary_to_sell,
-            None,
-            Some(Decimal::from_ratio(1u128, 20u128)), // 5%. NOTE: switch this to 50% to pass the integration test
-        )?)
+    let reply_id = register_op(deps.storage, &env, 2, Some(&user_addr))?;
+
+    let mut res = Response::new()
+        .add_submessage(pair.swap_submsg(reply_id, &asset_to_sell, None, Some(max_spread))?)
         .add_attribute("action", "martian_field/callback/cover")
         .add_attribute("debt_amount", debt_amount)
-        .add_attribute("secondary_available", secondary_available.amount)
-        .add_attribute("secondary_needed", secondary_needed.amount)
-        .add_attribute("primary_sold", primary_sell_amount))
+        .add_attribute("secondary_available", borrow_available_amount)
+        .add_attribute("secondary_needed", borrow_needed.amount)
+        .add_attribute("asset_sold", asset_to_sell.to_string());
+    if *sell_asset_info == config.primary_asset_info {
+        res = res.add_attribute("primary_sold", sell_amount);
+    }
+    if let Some(next_callback) = next_callback {
+        res = res.add_message(next_callback.into_cosmos_msg(&env.contract.address)?);
+    }
+
+    Ok(res)
 }
 
 pub fn refund(
@@ -543,9 +1243,19 @@ pub fn refund(
     recipient_addr: Addr,
     percentage: Decimal,
 ) -> StdResult<Response> {
-    let mut position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
+    // a percentage above 100% would refund more than the user holds, so that the deduction below
+    // underflows
+    if percentage > Decimal::one() {
+        return Err(StdError::generic_err(format!(
+            "invalid refund percentage: {}; must be <= 1",
+            percentage
+        )));
+    }
 
-    // apply percentage and purge assets with zero amount
+    let config = CONFIG.load(deps.storage)?;
+    let mut position = load_position(deps.storage, &user_addr)?;
+
+    // apply percentage and purge assets with zero amount, so that no zero-amount transfer is sent
     let mut assets_to_refund = position.unlocked_assets.clone();
     assets_to_refund.apply(|asset| asset.amount = asset.amount * percentage).purge();
 
@@ -558,17 +1268,62 @@ pub fn refund(
         .map(|asset| attr("asset_refunded", asset.to_string()))
         .collect();
 
+    // if a refund batcher is configured, we send the refund to the batcher to be credited to the
+    // recipient; otherwise, we transfer directly to the recipient
+    let refund_msgs = if let Some(refund_batcher) = &config.refund_batcher {
+        refund_batcher.credit_msgs(&assets_to_refund, &recipient_addr)?
+    } else {
+        assets_to_refund.transfer_msgs(&recipient_addr)?
+    };
+
+    // a refund only changes the position's unlocked assets, which the `position_changed` event
+    // doesn't carry, so no event is emitted. this way, an action that ends with a refund, e.g. a
+    // pure repayment, emits exactly one event
     Ok(Response::new()
-        .add_messages(assets_to_refund.transfer_msgs(&recipient_addr)?)
+        .add_messages(refund_msgs)
         .add_attribute("action", "martian_field/callback/refund")
         .add_attribute("recipient", recipient_addr.to_string())
         .add_attributes(refund_attrs))
 }
 
-pub fn assert_health(deps: DepsMut, env: Env, user_addr: Addr) -> StdResult<Response> {
+pub fn refund_asset(
+    deps: DepsMut,
+    user_addr: Addr,
+    recipient_addr: Addr,
+    asset: Asset,
+) -> StdResult<Response> {
+    let config = CONFIG.load(deps.storage)?;
+    let mut position = load_position(deps.storage, &user_addr)?;
+
+    // the amount is computed before the callback chain is executed, so it is capped at what the
+    // user actually holds
+    let available = position.unlocked_assets.amount_of(&asset.info);
+    let asset_to_refund = Asset::new(asset.info, cmp::min(asset.amount, available));
+    if asset_to_refund.amount.is_zero() {
+        return Ok(Response::new().add_attribute("action", "martian_field/callback/refund_asset"));
+    }
+
+    position.unlocked_assets.deduct(&asset_to_refund)?;
+    POSITION.save(deps.storage, &user_addr, &position)?;
+
+    let assets_to_refund = AssetList::from(vec![asset_to_refund.clone()]);
+    let refund_msgs = if let Some(refund_batcher) = &config.refund_batcher {
+        refund_batcher.credit_msgs(&assets_to_refund, &recipient_addr)?
+    } else {
+        assets_to_refund.transfer_msgs(&recipient_addr)?
+    };
+
+    Ok(Response::new()
+        .add_messages(refund_msgs)
+        .add_attribute("action", "martian_field/callback/refund_asset")
+        .add_attribute("recipient", recipient_addr.to_string())
+        .add_attribute("asset_refunded", asset_to_refund.to_string()))
+}
+
+pub fn assert_health(deps: DepsMut, env: Env, user_addr: Addr) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
     let state = STATE.load(deps.storage)?;
-    let position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
+    let position = load_position(deps.storage, &user_addr)?;
     let health = compute_health(&deps.querier, &env, &config, &state, &position)?;
 
     // If ltv is Some(ltv), we assert it is no larger than `config.max_ltv`
@@ -582,70 +1337,185 @@ pub fn assert_health(deps: DepsMut, env: Env, user_addr: Addr) -> StdResult<Resp
         position.debt_units.is_zero()
     };
 
-    // Convert `ltv` to String so that it can be recorded in logs
-    let ltv_str = if let Some(ltv) = health.ltv {
-        ltv.to_string()
-    } else {
-        "null".to_string()
-    };
-
     if !healthy {
-        return Err(StdError::generic_err(format!("ltv greater than threshold: {}", ltv_str)));
+        // `ltv` can only be None here if the position has debt but no bond
+        return Err(ContractError::LtvTooHigh {
+            ltv: health.ltv,
+        });
     }
 
-    let event = Event::new("position_changed")
-        .add_attribute("timestamp", env.block.time.seconds().to_string())
-        .add_attribute("height", env.block.height.to_string())
-        .add_attribute("user", &user_addr)
-        .add_attribute("bond_units", position.bond_units)
-        .add_attribute("bond_amount", health.bond_amount)
-        .add_attribute("bond_value", health.bond_value)
-        .add_attribute("debt_units", position.debt_units)
-        .add_attribute("debt_amount", health.debt_amount)
-        .add_attribute("debt_value", health.debt_value)
-        .add_attribute("ltv", &ltv_str);
-
     Ok(Response::new()
         .add_attribute("action", "martian_field/callback/assert_health")
-        .add_event(event))
+        .add_event(emit_position_event(&env, &user_addr, &position, &health)))
+}
+
+pub fn assert_deposit_cap(deps: DepsMut, env: Env) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let state = STATE.load(deps.storage)?;
+
+    let res = Response::new().add_attribute("action", "martian_field/callback/assert_deposit_cap");
+    let deposit_cap = match config.deposit_cap {
+        Some(deposit_cap) => deposit_cap,
+        None => return Ok(res),
+    };
+
+    // the cap is denominated in the secondary asset, whereas bond value is in the oracle's base
+    // unit, so we convert the cap to the same unit
+    let total_bond_value =
+        compute_health(&deps.querier, &env, &config, &state, &state.clone().into())?.bond_value;
+    let secondary_price = config.oracle.query_fresh_price(
+        &deps.querier,
+        &config.secondary_asset_info,
+        env.block.time.seconds(),
+        config.max_price_age,
+    )?;
+    let deposit_cap_value =
+        config.normalize_amount(&config.secondary_asset_info, deposit_cap)? * secondary_price;
+    if total_bond_value > deposit_cap_value {
+        return Err(ContractError::DepositCapExceeded {
+            deposit_cap,
+        });
+    }
+
+    Ok(res.add_attribute("total_bond_value", total_bond_value))
 }
 
 pub fn clear_bad_debt(deps: DepsMut, env: Env, user_addr: Addr) -> StdResult<Response> {
     let config = CONFIG.load(deps.storage)?;
     let mut state = STATE.load(deps.storage)?;
-    let mut position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
+    let mut position = load_position(deps.storage, &user_addr)?;
 
     let res = Response::new().add_attribute("action", "martian_field/callback/clear_bad_debt");
     if position.debt_units.is_zero() {
         return Ok(res);
     }
 
+    // a position that still has liquidity tokens bonded remains open, and its debt is still backed
+    // by collateral, so it is not bad debt. it may be liquidated again if it becomes unhealthy
+    if !position.bond_units.is_zero() {
+        return Ok(res.add_attribute("skipped", "position_open"));
+    }
+
     // compute the amount of bad debt
     let total_debt_amount = config.red_bank.query_user_debt(
         &deps.querier,
         &env.contract.address,
-        &config.secondary_asset_info,
+        config.borrow_asset_info(),
     )?;
     let bad_debt_amount = total_debt_amount.multiply_ratio(position.debt_units, state.total_debt_units);
-    let bad_debt = Asset::new(config.secondary_asset_info, bad_debt_amount);
+    let bad_debt = Asset::new(config.borrow_asset_info().clone(), bad_debt_amount);
+
+    // if an insurance fund is configured and has approved enough borrowed asset, we draw the bad
+    // debt from it and repay it to Red Bank. otherwise, the debt is only waived, and is effectively
+    // socialized among the remaining users
+    let mut msgs: Vec<CosmosMsg> = vec![];
+    let recovery = match (&config.insurance_fund, config.borrow_asset_info()) {
+        (None, _) => Err("no insurance fund configured"),
+        (Some(_), AssetInfo::Native(_)) => Err("native borrowed asset cannot be drawn"),
+        (Some(insurance_fund), AssetInfo::Cw20(token)) => {
+            let balance = config.borrow_asset_info().query_balance(&deps.querier, insurance_fund)?;
+            let allowance = query_cw20_allowance(
+                &deps.querier,
+                token,
+                insurance_fund,
+                &env.contract.address,
+            )?;
+            if cmp::min(balance, allowance) >= bad_debt_amount {
+                msgs.push(bad_debt.transfer_from_msg(insurance_fund, &env.contract.address)?);
+                msgs.push(config.red_bank.repay_msg(&bad_debt)?);
+                Ok(insurance_fund)
+            } else {
+                Err("insufficient insurance fund")
+            }
+        }
+    };
 
     // waive the user's debt
     let debt_units_to_waive = position.debt_units;
     position.debt_units = position.debt_units.checked_sub(debt_units_to_waive)?;
     POSITION.save(deps.storage, &user_addr, &position)?;
     state.total_debt_units = state.total_debt_units.checked_sub(debt_units_to_waive)?;
+    state.total_bad_debt = state.total_bad_debt.checked_add(bad_debt_amount)?;
     STATE.save(deps.storage, &state)?;
 
+    // if the bad debt is recovered, it is repaid after this callback, so the totals are adjusted to
+    // reflect the debt after repaying
+    let mut totals = Totals::query(&deps.querier, &env, &config)?;
+    if recovery.is_ok() {
+        totals.debt_amount = total_debt_amount.checked_sub(bad_debt_amount)?;
+    }
+    let position_event = emit_position_event_with_totals(
+        &deps.querier,
+        &env,
+        &config,
+        &totals,
+        &state,
+        &user_addr,
+        &position,
+    );
+
     let event = Event::new("bad_debt")
-        .add_attribute("user", user_addr)
+        .add_attribute("user", &user_addr)
         .add_attribute("bad_debt", bad_debt.to_string())
         .add_attribute("debt_units_waived", debt_units_to_waive);
 
-    Ok(res.add_event(event))
+    let recovery_event = match recovery {
+        Ok(insurance_fund) => Event::new("bad_debt_recovered")
+            .add_attribute("user", &user_addr)
+            .add_attribute("insurance_fund", insurance_fund)
+            .add_attribute("amount", bad_debt.to_string()),
+        Err(reason) => Event::new("bad_debt_unrecovered")
+            .add_attribute("user", &user_addr)
+            .add_attribute("bad_debt", bad_debt.to_string())
+            .add_attribute("reason", reason),
+    };
+
+    Ok(res
+        .add_messages(msgs)
+        .add_event(event)
+        .add_event(recovery_event)
+        .add_event(position_event))
+}
+
+pub fn snapshot(deps: DepsMut, env: Env, user_addr: Addr) -> StdResult<Response> {
+    let config = CONFIG.load(deps.storage)?;
+    let state = STATE.load(deps.storage)?;
+    let position = load_position(deps.storage, &user_addr)?;
+
+    // a snapshot records the position's values, which are computed from prices. a de-risking
+    // update must not fail if prices are stale, so in that case, the snapshot is skipped instead
+    let position = match _query_position(&deps.querier, &env, &config, &state, &position) {
+        Ok(position) => position,
+        Err(_) => {
+            return Ok(Response::new()
+                .add_attribute("action", "martian_field/callback/snapshot")
+                .add_attribute("skipped", "health_unavailable"))
+        }
+    };
+    let snapshot = Snapshot {
+        height: env.block.height,
+        time: env.block.time.seconds(),
+        position,
+    };
+    SNAPSHOTS.save(deps.storage, (&user_addr, U64Key::new(env.block.height)), &snapshot)?;
+
+    // if the user has more than the maximum number of snapshots, we evict the oldest ones
+    let heights = SNAPSHOTS
+        .prefix(&user_addr)
+        .keys(deps.storage, None, None, Order::Ascending)
+        .collect::<Vec<_>>();
+    let num_to_evict = heights.len().saturating_sub(config.max_snapshots as usize);
+    for height in heights.into_iter().take(num_to_evict) {
+        SNAPSHOTS.remove(deps.storage, (&user_addr, U64Key::from(height)));
+    }
+
+    Ok(Response::new()
+        .add_attribute("action", "martian_field/callback/snapshot")
+        .add_attribute("snapshots_evicted", num_to_evict.to_string()))
 }
 
 pub fn purge_storage(deps: DepsMut, user_addr: Addr) -> StdResult<Response> {
-    let position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
+    let position = load_position(deps.storage, &user_addr)?;
 
     if position.is_empty() {
         POSITION.remove(deps.storage, &user_addr);
@@ -653,3 +1523,9 @@ pub fn purge_storage(deps: DepsMut, user_addr: Addr) -> StdResult<Response> {
 
     Ok(Response::new().add_attribute("action", "martian_field/callback/purge_storage"))
 }
+
+pub fn release_lock(deps: DepsMut) -> StdResult<Response> {
+    LOCK.remove(deps.storage);
+    HARVEST_ABORTED.remove(deps.storage);
+    Ok(Response::new().add_attribute("action", "martian_field/callback/release_lock"))
+}
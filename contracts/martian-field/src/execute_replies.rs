@@ -1,13 +1,25 @@
-use cosmwasm_std::{DepsMut, Response, StdResult, SubMsgExecutionResponse};
+use std::cmp;
+
+use cosmwasm_std::{DepsMut, Env, Event, Response, StdError, StdResult, SubMsgExecutionResponse};
 
 use cw_asset::{Asset, AssetList};
+use cw_storage_plus::U64Key;
 
 use fields_of_mars::adapters::Pair;
 
-use crate::state::{Position, State, CACHED_USER_ADDR, CONFIG, POSITION, STATE};
+use crate::helpers::{
+    compute_received, load_op_user_addr, load_position, may_load_op_user_addr,
+    recoverable_harvest_submsgs, register_op, AssetListExt,
+};
+use crate::state::{
+    Position, State, CACHED_BALANCES, CACHED_HARVEST_OFFERS, CACHED_MIN_LP_OUT, CACHED_MIN_RETURN,
+    CACHED_REWARDS, CACHED_ROUTE, CONFIG, HARVEST_ABORTED, PENDING_OPS, POSITION, STATE,
+};
 
 pub fn after_provide_liquidity(
     deps: DepsMut,
+    env: Env,
+    nonce: u64,
     response: SubMsgExecutionResponse,
 ) -> StdResult<Response> {
     let config = CONFIG.load(deps.storage)?;
@@ -15,7 +27,7 @@ pub fn after_provide_liquidity(
     // if this is a user providing their unlocked assets, the user's address should have been cached
     // if this is a reward harvesting operation, no user address should have been cached. `may_load`
     // should return `None` in this case
-    let user_addr_option = CACHED_USER_ADDR.may_load(deps.storage)?;
+    let user_addr_option = may_load_op_user_addr(deps.storage, &env, nonce)?;
 
     // if a user address is cached, we update the user's unlocked assets
     // if not, we update the state's pending rewards
@@ -23,7 +35,7 @@ pub fn after_provide_liquidity(
     let mut position = Position::default();
     let assets: &mut AssetList;
     if let Some(user_addr) = &user_addr_option {
-        position = POSITION.load(deps.storage, user_addr).unwrap_or_default();
+        position = load_position(deps.storage, user_addr)?;
         assets = &mut position.unlocked_assets;
     } else {
         state = STATE.load(deps.storage)?;
@@ -32,6 +44,19 @@ pub fn after_provide_liquidity(
 
     // parse event log to find the amount of liquidity tokens minted
     let minted_amount = Pair::parse_provide_events(&response.events)?;
+
+    // if a minimum amount was specified, the provision must have minted at least this amount.
+    // otherwise, revert the whole transaction
+    if let Some(min_lp_out) = CACHED_MIN_LP_OUT.may_load(deps.storage)? {
+        if minted_amount < min_lp_out {
+            return Err(StdError::generic_err(format!(
+                "insufficient liquidity tokens minted: {}; minimum {}",
+                minted_amount, min_lp_out
+            )));
+        }
+        CACHED_MIN_LP_OUT.remove(deps.storage);
+    }
+
     assets.add(&Asset::cw20(config.primary_pair.liquidity_token, minted_amount))?;
 
     // save the updated state/position
@@ -42,7 +67,8 @@ pub fn after_provide_liquidity(
     }
 
     // finally, clear cached data
-    CACHED_USER_ADDR.remove(deps.storage);
+    PENDING_OPS.remove(deps.storage, U64Key::new(nonce));
+    CACHED_HARVEST_OFFERS.remove(deps.storage, U64Key::new(nonce));
 
     // `shares_minted` should really be `liquidity_token_minted` according to my naming convention,
     // but it's a bit too long and doesn't look very good on Terra Finder's UI, so I opt for a shorter one
@@ -53,37 +79,86 @@ pub fn after_provide_liquidity(
 
 pub fn after_withdraw_liquidity(
     deps: DepsMut,
+    env: Env,
+    nonce: u64,
     response: SubMsgExecutionResponse,
 ) -> StdResult<Response> {
     let config = CONFIG.load(deps.storage)?;
-    let user_addr = CACHED_USER_ADDR.load(deps.storage)?;
-    let mut position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
+    let user_addr = load_op_user_addr(deps.storage, &env, nonce)?;
+    let mut position = load_position(deps.storage, &user_addr)?;
 
-    // parse event log to find the amounts of assets returned
-    let (primary_asset_withdrawn, secondary_asset_withdrawn) = Pair::parse_withdraw_events(
-        &response.events,
+    // compare the contract's balances before and after the withdrawal to find the exact amounts of
+    // assets returned
+    let balances_before = CACHED_BALANCES.load(deps.storage)?;
+    let primary_asset_withdrawn = compute_received(
+        &deps.querier,
+        &env.contract.address,
         &config.primary_asset_info,
+        &balances_before,
+    )?;
+    let secondary_asset_withdrawn = compute_received(
+        &deps.querier,
+        &env.contract.address,
         &config.secondary_asset_info,
+        &balances_before,
     )?;
 
     position.unlocked_assets.add(&primary_asset_withdrawn)?;
     position.unlocked_assets.add(&secondary_asset_withdrawn)?;
 
     POSITION.save(deps.storage, &user_addr, &position)?;
-    CACHED_USER_ADDR.remove(deps.storage);
+    PENDING_OPS.remove(deps.storage, U64Key::new(nonce));
+    CACHED_BALANCES.remove(deps.storage);
+
+    // the amounts parsed from the pool's event log are only used as a cross-check. if they disagree
+    // with the amounts actually received, we emit an event so that the discrepancy can be noticed
+    let mut events: Vec<Event> = vec![];
+    let parsed = Pair::parse_withdraw_events(
+        &response.events,
+        &config.primary_asset_info,
+        &config.secondary_asset_info,
+    );
+    let mismatch = match &parsed {
+        Ok((primary_parsed, secondary_parsed)) => {
+            primary_parsed.amount != primary_asset_withdrawn.amount
+                || secondary_parsed.amount != secondary_asset_withdrawn.amount
+        }
+        Err(_) => true,
+    };
+    if mismatch {
+        let mut event = Event::new("withdraw_parse_mismatch")
+            .add_attribute("user", &user_addr)
+            .add_attribute("primary_received", primary_asset_withdrawn.amount)
+            .add_attribute("secondary_received", secondary_asset_withdrawn.amount);
+        event = match parsed {
+            Ok((primary_parsed, secondary_parsed)) => event
+                .add_attribute("primary_parsed", primary_parsed.amount)
+                .add_attribute("secondary_parsed", secondary_parsed.amount),
+            Err(err) => event.add_attribute("parse_error", err.to_string()),
+        };
+        events.push(event);
+    }
 
     Ok(Response::new()
+        .add_events(events)
         .add_attribute("action", "martian_field/reply/after_withdraw_liquidity")
         .add_attribute("user", user_addr)
         .add_attribute("primary_withdrawn", primary_asset_withdrawn.amount)
         .add_attribute("secondary_withdrawn", secondary_asset_withdrawn.amount))
 }
 
-pub fn after_swap(deps: DepsMut, response: SubMsgExecutionResponse) -> StdResult<Response> {
+pub fn after_swap(
+    deps: DepsMut,
+    env: Env,
+    nonce: u64,
+    response: SubMsgExecutionResponse,
+) -> StdResult<Response> {
+    let config = CONFIG.load(deps.storage)?;
+
     // if this is a user swapping their unlocked assets, the user's address should have been cached
     // if this is a reward harvesting operation, no user address should have been cached. `may_load`
     // should return `None` in this case
-    let user_addr_option = CACHED_USER_ADDR.may_load(deps.storage)?;
+    let user_addr_option = may_load_op_user_addr(deps.storage, &env, nonce)?;
 
     // if a user address is cached, we update the user's unlocked assets
     // if not, we update the state's pending rewards
@@ -91,7 +166,7 @@ pub fn after_swap(deps: DepsMut, response: SubMsgExecutionResponse) -> StdResult
     let mut position = Position::default();
     let assets: &mut AssetList;
     if let Some(user_addr) = &user_addr_option {
-        position = POSITION.load(deps.storage, user_addr).unwrap_or_default();
+        position = load_position(deps.storage, user_addr)?;
         assets = &mut position.unlocked_assets;
     } else {
         state = STATE.load(deps.storage)?;
@@ -99,8 +174,22 @@ pub fn after_swap(deps: DepsMut, response: SubMsgExecutionResponse) -> StdResult
     }
 
     // parse Astroport's event log to find out how much asset was returned from the swap
-    let returned_asset_unchecked = Pair::parse_swap_events(&response.events)?;
+    let returned_asset_unchecked =
+        Pair::parse_swap_events(&response.events, &config.swap_asset_infos())?;
     let returned_asset = returned_asset_unchecked.check(deps.api, None)?;
+
+    // if a minimum return amount was specified, the swap must have returned at least this amount.
+    // otherwise, revert the whole transaction
+    if let Some(min_return) = CACHED_MIN_RETURN.may_load(deps.storage)? {
+        if returned_asset.amount < min_return {
+            return Err(StdError::generic_err(format!(
+                "insufficient swap return: {}; minimum {}",
+                returned_asset.amount, min_return
+            )));
+        }
+        CACHED_MIN_RETURN.remove(deps.storage);
+    }
+
     assets.add(&returned_asset)?;
 
     // save the updated state/position
@@ -111,9 +200,149 @@ pub fn after_swap(deps: DepsMut, response: SubMsgExecutionResponse) -> StdResult
     }
 
     // finally, clear cached data
-    CACHED_USER_ADDR.remove(deps.storage);
+    PENDING_OPS.remove(deps.storage, U64Key::new(nonce));
+    CACHED_HARVEST_OFFERS.remove(deps.storage, U64Key::new(nonce));
 
     Ok(Response::new()
         .add_attribute("action", "martian_field/reply/after_swap")
         .add_attribute("returned_asset", returned_asset.to_string()))
 }
+
+pub fn after_route_hop(
+    deps: DepsMut,
+    env: Env,
+    nonce: u64,
+    response: SubMsgExecutionResponse,
+) -> StdResult<Response> {
+    let config = CONFIG.load(deps.storage)?;
+    let mut cached = CACHED_ROUTE.load(deps.storage)?;
+
+    // the asset returned from this hop is offered in full to the next one. it is never credited to
+    // the pending rewards, so an intermediate asset already held by the contract is not touched
+    let returned_asset_unchecked =
+        Pair::parse_swap_events(&response.events, &config.swap_asset_infos())?;
+    let returned_asset = returned_asset_unchecked.check(deps.api, None)?;
+
+    cached.hop += 1;
+    let next_pair = config
+        .astro_route
+        .get(cached.hop as usize)
+        .ok_or_else(|| StdError::generic_err(format!("invalid astro route hop: {}", cached.hop)))?;
+
+    // the reply of the last hop is handled by `after_swap`, which credits the returned asset
+    let reply_kind = if cached.hop as usize + 1 < config.astro_route.len() {
+        CACHED_ROUTE.save(deps.storage, &cached)?;
+        4
+    } else {
+        CACHED_ROUTE.remove(deps.storage);
+        2
+    };
+
+    // the user address cached for the route, if any, is carried over to the next hop
+    let user_addr_option = may_load_op_user_addr(deps.storage, &env, nonce)?;
+    PENDING_OPS.remove(deps.storage, U64Key::new(nonce));
+    CACHED_HARVEST_OFFERS.remove(deps.storage, U64Key::new(nonce));
+    let reply_id = register_op(deps.storage, &env, reply_kind, user_addr_option.as_ref())?;
+
+    // if the next hop of a harvest fails, the asset returned from this hop is what is left pending
+    let submsgs = recoverable_harvest_submsgs(
+        deps.storage,
+        user_addr_option.as_ref(),
+        reply_id,
+        std::slice::from_ref(&returned_asset),
+        vec![next_pair.swap_submsg(reply_id, &returned_asset, None, cached.max_spread)?],
+    )?;
+
+    Ok(Response::new()
+        .add_submessages(submsgs)
+        .add_attribute("action", "martian_field/reply/after_route_hop")
+        .add_attribute("hop", cached.hop.to_string())
+        .add_attribute("asset_offered", returned_asset.to_string()))
+}
+
+/// Handle the failure of a harvest step's submessage, i.e. a swap, a hop of a swap route, or a
+/// liquidity provision, instead of reverting the whole harvest
+///
+/// The assets the step offered are restored to the pending rewards, and the remaining steps of the
+/// harvest are skipped, so that the rewards are retained for the next harvest. Submessages of
+/// users' operations only reply on success, so any other failure is propagated
+pub fn after_harvest_step_failed(
+    deps: DepsMut,
+    kind: u64,
+    nonce: u64,
+    error: String,
+) -> StdResult<Response> {
+    let offered = CACHED_HARVEST_OFFERS
+        .may_load(deps.storage, U64Key::new(nonce))?
+        .ok_or_else(|| StdError::generic_err(error.clone()))?;
+
+    let mut state = STATE.load(deps.storage)?;
+    state.pending_rewards.add_many(&offered)?;
+    STATE.save(deps.storage, &state)?;
+    HARVEST_ABORTED.save(deps.storage, &true)?;
+
+    // data cached for the step's reply is no longer needed
+    PENDING_OPS.remove(deps.storage, U64Key::new(nonce));
+    CACHED_HARVEST_OFFERS.remove(deps.storage, U64Key::new(nonce));
+    CACHED_MIN_LP_OUT.remove(deps.storage);
+    CACHED_MIN_RETURN.remove(deps.storage);
+    CACHED_ROUTE.remove(deps.storage);
+
+    let step = match kind {
+        0 => "provide_liquidity",
+        4 => "route_hop",
+        _ => "swap",
+    };
+    let event = Event::new("harvest_step_failed")
+        .add_attribute("step", step)
+        .add_attribute("assets_retained", offered.to_string())
+        .add_attribute("error", error);
+
+    Ok(Response::new()
+        .add_event(event)
+        .add_attribute("action", "martian_field/reply/after_harvest_step_failed"))
+}
+
+pub fn after_bond_or_unbond(deps: DepsMut, env: Env) -> StdResult<Response> {
+    let mut state = STATE.load(deps.storage)?;
+    let cached = CACHED_REWARDS.load(deps.storage)?;
+
+    // the predicted amounts of rewards have already been credited to pending rewards. we compare
+    // them with the amounts actually received, and credit or debit the difference
+    let mut diffs: Vec<String> = vec![];
+    for balance_before in cached.balances_before.to_vec() {
+        let info = &balance_before.info;
+        let predicted = cached.predicted.find(info).map(|asset| asset.amount).unwrap_or_default();
+        let received =
+            compute_received(&deps.querier, &env.contract.address, info, &cached.balances_before)?
+                .amount;
+
+        // the uncharged rewards are reconciled likewise, so that fees are charged on the amounts
+        // actually received
+        if received > predicted {
+            let surplus = Asset::new(info.clone(), received - predicted);
+            state.pending_rewards.add(&surplus)?;
+            state.uncharged_rewards.add(&surplus)?;
+        } else if received < predicted {
+            let shortfall = predicted - received;
+            state.pending_rewards.deduct(&Asset::new(info.clone(), shortfall))?;
+            let uncharged = cmp::min(shortfall, state.uncharged_rewards.amount_of(info));
+            state.uncharged_rewards.deduct(&Asset::new(info.clone(), uncharged))?;
+        } else {
+            continue;
+        }
+
+        diffs.push(format!("{}: predicted {}, received {}", info, predicted, received));
+    }
+
+    STATE.save(deps.storage, &state)?;
+    CACHED_REWARDS.remove(deps.storage);
+
+    let mut res =
+        Response::new().add_attribute("action", "martian_field/reply/after_bond_or_unbond");
+    if !diffs.is_empty() {
+        res = res.add_attribute("reward_prediction_diff", diffs.join("; "));
+    }
+
+    Ok(res)
+}
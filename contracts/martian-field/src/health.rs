@@ -1,5 +1,9 @@
+use std::cmp;
+
 use cosmwasm_std::{Decimal, Env, QuerierWrapper, StdResult, Uint128};
 
+use cw_asset::AssetInfo;
+
 use fields_of_mars::martian_field::Config;
 
 use crate::state::{Position, State};
@@ -19,11 +23,88 @@ use uints::U256;
 pub struct Health {
     pub bond_amount: Uint128,
     pub bond_value: Uint128,
+    pub underlying_primary: Uint128,
+    pub underlying_secondary: Uint128,
     pub debt_amount: Uint128,
     pub debt_value: Uint128,
     pub ltv: Option<Decimal>,
 }
 
+/// Query the prices of assets from the oracle, as well as the secondary oracle if configured, with
+/// one batch query per oracle. For each asset, return the lower and the higher of the prices; the
+/// two are equal if there is only one oracle
+pub fn query_price_ranges(
+    querier: &QuerierWrapper,
+    env: &Env,
+    config: &Config,
+    asset_infos: &[AssetInfo],
+) -> StdResult<Vec<(Decimal, Decimal)>> {
+    // health must not be computed on stale prices, so that liquidations and health checks fail
+    // closed if the oracle stops updating
+    let current_time = env.block.time.seconds();
+    let prices =
+        config.oracle.query_fresh_prices(querier, asset_infos, current_time, config.max_price_age)?;
+
+    match &config.secondary_oracle {
+        Some(secondary_oracle) => {
+            let secondary_prices = secondary_oracle.query_fresh_prices(
+                querier,
+                asset_infos,
+                current_time,
+                config.max_price_age,
+            )?;
+            Ok(prices
+                .into_iter()
+                .zip(secondary_prices)
+                .map(|(price, secondary_price)| {
+                    (cmp::min(price, secondary_price), cmp::max(price, secondary_price))
+                })
+                .collect())
+        }
+        None => Ok(prices.into_iter().map(|price| (price, price)).collect()),
+    }
+}
+
+/// The contract's total bonded liquidity tokens and debt, and the depths of the pool, which the
+/// health of every position is computed from
+pub struct Totals {
+    pub bond_amount: Uint128,
+    pub debt_amount: Uint128,
+    pub primary_depth: Uint128,
+    pub secondary_depth: Uint128,
+    pub total_shares: Uint128,
+}
+
+impl Totals {
+    pub fn query(querier: &QuerierWrapper, env: &Env, config: &Config) -> StdResult<Self> {
+        let bond_amount = config.astro_generator.query_bonded_amount(
+            querier,
+            &env.contract.address,
+            &config.primary_pair.liquidity_token,
+        )?;
+
+        let debt_amount = config.red_bank.query_user_debt(
+            querier,
+            &env.contract.address,
+            config.borrow_asset_info(),
+        )?;
+
+        let (primary_depth, secondary_depth, total_shares) = config.primary_pair.query_pool(
+            querier,
+            &config.primary_asset_info,
+            &config.secondary_asset_info,
+        )?;
+
+        Ok(Self {
+            bond_amount,
+            debt_amount,
+            primary_depth,
+            secondary_depth,
+            total_shares,
+        })
+    }
+}
+
 /// Compute the health of a user's position; combine
 pub fn compute_health(
     querier: &QuerierWrapper,
@@ -32,41 +113,68 @@ pub fn compute_health(
     state: &State,
     position: &Position,
 ) -> StdResult<Health> {
-    let total_bond_amount = config.astro_generator.query_bonded_amount(
-        querier,
-        &env.contract.address,
-        &config.primary_pair.liquidity_token,
-    )?;
-
-    let total_debt_amount = config.red_bank.query_user_debt(
-        querier,
-        &env.contract.address,
-        &config.secondary_asset_info,
-    )?;
-
-    let (primary_depth, secondary_depth, total_shares) = config.primary_pair.query_pool(
-        querier,
-        &config.primary_asset_info,
-        &config.secondary_asset_info,
-    )?;
-
-    let primary_price = config.oracle.query_price(querier, &config.primary_asset_info)?;
-    let secondary_price = config.oracle.query_price(querier, &config.secondary_asset_info)?;
+    let totals = Totals::query(querier, env, config)?;
+    compute_health_with_totals(querier, env, config, &totals, state, position)
+}
+
+/// Compute the health of a user's position given the totals, instead of querying them. Used to
+/// compute the health a position would have after a simulated update
+pub fn compute_health_with_totals(
+    querier: &QuerierWrapper,
+    env: &Env,
+    config: &Config,
+    totals: &Totals,
+    state: &State,
+    position: &Position,
+) -> StdResult<Health> {
+    let Totals {
+        bond_amount: total_bond_amount,
+        debt_amount: total_debt_amount,
+        primary_depth,
+        secondary_depth,
+        total_shares,
+    } = *totals;
+
+    // if a secondary oracle is configured, collateral is valued at the lower of the two oracles'
+    // prices, and debt at the higher one. debt is valued at the price of the borrowed asset, which
+    // is only queried separately if it is not the secondary asset
+    let mut asset_infos =
+        vec![config.primary_asset_info.clone(), config.secondary_asset_info.clone()];
+    if config.borrow_asset_info() != &config.secondary_asset_info {
+        asset_infos.push(config.borrow_asset_info().clone());
+    }
+    let price_ranges = query_price_ranges(querier, env, config, &asset_infos)?;
+    let (primary_price, _) = price_ranges[0];
+    let (secondary_price, _) = price_ranges[1];
+    let (_, debt_price) = price_ranges[asset_infos.len() - 1];
 
     // RE the calculation of the value of liquidity token, see:
     // https://blog.alphafinance.io/fair-lp-token-pricing/
     // this formulation avoids a potential sandwich attack that distorts asset prices by a flashloan
     //
     // NOTE: we need to use U256 here, because Uint128 * Uint128 may overflow the 128-bit limit
-    let primary_value = U256::from(u128::from(primary_depth * primary_price));
-    let secondary_value = U256::from(u128::from(secondary_depth * secondary_price));
+    //
+    // amounts are normalized to the same decimals before being valued, so that the values of assets
+    // with different decimals are comparable
+    let primary_depth_normalized =
+        config.normalize_amount(&config.primary_asset_info, primary_depth)?;
+    let secondary_depth_normalized =
+        config.normalize_amount(&config.secondary_asset_info, secondary_depth)?;
+    let primary_value = U256::from(u128::from(primary_depth_normalized * primary_price));
+    let secondary_value = U256::from(u128::from(secondary_depth_normalized * secondary_price));
     let pool_value = U256::from(2) * (primary_value * secondary_value).integer_sqrt();
 
     let pool_value_u128 = Uint128::new(pool_value.as_u128());
-    let total_bond_value = pool_value_u128.multiply_ratio(total_bond_amount, total_shares);
+    // an empty pool has no value, and neither have any shares of it
+    let total_bond_value = if total_shares.is_zero() {
+        Uint128::zero()
+    } else {
+        pool_value_u128.multiply_ratio(total_bond_amount, total_shares)
+    };
 
     // compute the value of the contract's total debt
-    let total_debt_value = total_debt_amount * secondary_price;
+    let total_debt_value =
+        config.normalize_amount(config.borrow_asset_info(), total_debt_amount)? * debt_price;
 
     // compute the value of the user's bonded liquidity tokens
     let bond_value = if state.total_bond_units.is_zero() {
@@ -96,24 +204,108 @@ pub fn compute_health(
     // numbers massively simplifies frontend develoment
     //
     // This does increase gas cost, but only marginally so I consider it ok
-    //
-    // NOTE: Must handle the case where total units is zero, otherwise will get `unreachable` error
-    let bond_amount = if state.total_bond_units.is_zero() {
-        Uint128::zero()
-    } else {
-        total_bond_amount.multiply_ratio(position.bond_units, state.total_bond_units)
-    };
-    let debt_amount = if state.total_debt_units.is_zero() {
-        Uint128::zero()
+    let (bond_amount, debt_amount) = compute_position_amounts(totals, state, position);
+
+    // amounts of primary and secondary assets the bonded liquidity tokens are redeemable for, i.e.
+    // the pool's depths pro-rated by the bond amount. these are amounts of the assets themselves,
+    // so they are pro-rated from the raw depths, not the normalized ones
+    let (underlying_primary, underlying_secondary) = if total_shares.is_zero() {
+        (Uint128::zero(), Uint128::zero())
     } else {
-        total_debt_amount.multiply_ratio(position.debt_units, state.total_debt_units)
+        (
+            primary_depth.multiply_ratio(bond_amount, total_shares),
+            secondary_depth.multiply_ratio(bond_amount, total_shares),
+        )
     };
 
     Ok(Health {
         bond_amount,
         bond_value,
+        underlying_primary,
+        underlying_secondary,
         debt_amount,
         debt_value,
         ltv,
     })
 }
+
+/// Compute the amounts of liquidity tokens and debt a position's units are worth, pro-rated from
+/// the totals. Unlike values, these don't depend on prices
+pub fn compute_position_amounts(
+    totals: &Totals,
+    state: &State,
+    position: &Position,
+) -> (Uint128, Uint128) {
+    // NOTE: Must handle the case where total units is zero, otherwise will get `unreachable` error
+    let bond_amount = if state.total_bond_units.is_zero() {
+        Uint128::zero()
+    } else {
+        totals.bond_amount.multiply_ratio(position.bond_units, state.total_bond_units)
+    };
+    let debt_amount = if state.total_debt_units.is_zero() {
+        Uint128::zero()
+    } else {
+        totals.debt_amount.multiply_ratio(position.debt_units, state.total_debt_units)
+    };
+    (bond_amount, debt_amount)
+}
+
+/// Compute the minimum amount of bond units to liquidate, such that the position's LTV is brought
+/// back to `max_ltv`
+///
+/// Denote the position's bond value as `B`, debt value `D`, the portion of bond units liquidated
+/// `f`, `max_ltv` as `m`, and the bonus rate at the position's LTV as `b`. The liquidator is
+/// awarded `b` of the liquidated assets, and the rest is used to repay debt, so we solve for `f`
+/// in:
+///
+/// ```plain
+/// (D - f * B * (1 - b)) / ((1 - f) * B) = m
+/// ```
+///
+/// which gives `f = (D - m * B) / (B * (1 - b - m))`. The result is rounded up, and capped at the
+/// position's total bond units
+pub fn compute_bond_units_to_liquidate(
+    config: &Config,
+    position: &Position,
+    health: &Health,
+) -> StdResult<Uint128> {
+    let numerator = health.debt_value.saturating_sub(health.bond_value * config.max_ltv);
+    let bonus_rate = config.bonus_rate_at(health.ltv.unwrap_or_default());
+    // the bonus rate and `max_ltv` are not required to sum to less than 100%, so the bonus may be
+    // high enough that no partial liquidation can bring the LTV back to `max_ltv`, in which case
+    // the position is liquidated in full
+    //
+    // NOTE: `Decimal` has no checked subtraction in this version of cosmwasm-std, so the rates are
+    // compared first
+    let deducted_rate = bonus_rate + config.max_ltv;
+    if deducted_rate >= Decimal::one() {
+        return Ok(position.bond_units);
+    }
+    let denominator = health.bond_value * (Decimal::one() - deducted_rate);
+    if denominator.is_zero() {
+        return Ok(position.bond_units);
+    }
+
+    let bond_units = position.bond_units.multiply_ratio(numerator, denominator) + Uint128::new(1);
+    Ok(cmp::min(bond_units, position.bond_units))
+}
+
+/// Compute the value of one bond unit, in the oracle's base unit, given the totals. Zero if there
+/// is no bond unit
+pub fn compute_bond_value_per_unit(
+    querier: &QuerierWrapper,
+    env: &Env,
+    config: &Config,
+    totals: &Totals,
+    state: &State,
+) -> StdResult<Decimal> {
+    if state.total_bond_units.is_zero() {
+        return Ok(Decimal::zero());
+    }
+    let position = Position {
+        bond_units: state.total_bond_units,
+        ..Default::default()
+    };
+    let health = compute_health_with_totals(querier, env, config, totals, state, &position)?;
+    Ok(Decimal::from_ratio(health.bond_value, state.total_bond_units))
+}
@@ -1,6 +1,56 @@
-use cosmwasm_std::{Reply, StdError, StdResult, SubMsgExecutionResponse, Uint128};
+use std::cmp;
 
-use cw_asset::{Asset, AssetList};
+use cosmwasm_std::{
+    Addr, CosmosMsg, Decimal, Env, Event, Fraction, QuerierWrapper, Reply, ReplyOn, Response,
+    StdError, StdResult, Storage, SubMsg, SubMsgExecutionResponse, Uint128,
+};
+
+use cw20::{AllowanceResponse, Cw20QueryMsg};
+use cw_storage_plus::U64Key;
+use cw_asset::{Asset, AssetInfo, AssetList};
+
+use fields_of_mars::martian_field::Config;
+
+use crate::error::ContractError;
+use crate::health::{compute_health_with_totals, compute_position_amounts, Health, Totals};
+use crate::state::{
+    CachedUserAddr, Position, State, CACHED_HARVEST_OFFERS, EPOCH_REWARD_INDEX, HARVEST_ABORTED,
+    LOCK, NEXT_OP_NONCE, PENDING_OPS, POSITION, STATE,
+};
+
+// Default settings for pagination
+const MAX_LIMIT: u32 = 30;
+const DEFAULT_LIMIT: u32 = 10;
+
+// Maximum number of asset types a position can hold unlocked. Refunding a position transfers each
+// of its unlocked assets, so this bounds the gas a refund takes
+pub const MAX_UNLOCKED_ASSETS: usize = 5;
+
+// Maximum number of units minted when no units exist yet. Units minted later are pro-rated from the
+// existing ones, so this leaves room for the totals to grow by a factor of 2^32 before overflowing
+const MAX_SEED_UNITS: u128 = 1 << 96;
+
+/// Compute the number of items to be returned by an enumerating query. Default to `DEFAULT_LIMIT`
+/// if unspecified, and never exceed `MAX_LIMIT`
+pub fn calc_limit(requested: Option<u32>) -> usize {
+    requested.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize
+}
+
+/// Compute the number of units to mint for the first bond or borrow, when no units exist yet
+///
+/// Normally `units_per_amount` units are minted per unit of amount. If that would exceed
+/// `MAX_SEED_UNITS`, e.g. for the first bond of a token with many decimals, the multiplier is
+/// scaled down by powers of ten, to no less than one unit per unit of amount, so that the first
+/// bond or borrow doesn't overflow, and later ones have room to mint more units
+pub fn seed_units(amount: Uint128, units_per_amount: Uint128) -> StdResult<Uint128> {
+    let mut multiplier = units_per_amount;
+    while multiplier > Uint128::new(1)
+        && amount.checked_mul(multiplier).map_or(true, |units| units.u128() > MAX_SEED_UNITS)
+    {
+        multiplier = cmp::max(multiplier / Uint128::new(10), Uint128::new(1));
+    }
+    Ok(amount.checked_mul(multiplier)?)
+}
 
 /// Extract response from reply
 pub fn unwrap_reply(reply: Reply) -> StdResult<SubMsgExecutionResponse> {
@@ -23,3 +73,433 @@ pub fn assert_sent_fund(expected: &Asset, received_coins: &AssetList) -> StdResu
 
     Ok(())
 }
+
+/// Number of low bits of a reply id that hold the nonce of the operation. The high bits hold the
+/// kind of the operation, which determines how the reply is handled
+const REPLY_NONCE_BITS: u32 = 56;
+
+/// Compose the reply id of an operation of the given kind and nonce
+pub fn encode_reply_id(kind: u64, nonce: u64) -> u64 {
+    (kind << REPLY_NONCE_BITS) | nonce
+}
+
+/// Split a reply id into the kind and the nonce of the operation
+pub fn decode_reply_id(id: u64) -> (u64, u64) {
+    (id >> REPLY_NONCE_BITS, id & ((1 << REPLY_NONCE_BITS) - 1))
+}
+
+/// Throw an error if a top-level operation's callback chain is in progress
+///
+/// Callbacks are only guarded by their sender being the contract itself. If a contract invoked
+/// during the chain re-enters this contract, it could otherwise act on the chain's intermediate
+/// state, e.g. a position whose liquidity has been withdrawn but whose debt is not yet repaid
+pub fn assert_unlocked(storage: &dyn Storage) -> Result<(), ContractError> {
+    if LOCK.may_load(storage)?.unwrap_or(false) {
+        return Err(ContractError::Locked);
+    }
+    Ok(())
+}
+
+/// Lock the contract until the `ReleaseLock` callback, which must be the last message dispatched
+/// by the operation acquiring the lock
+pub fn acquire_lock(storage: &mut dyn Storage) -> Result<(), ContractError> {
+    assert_unlocked(storage)?;
+    Ok(LOCK.save(storage, &true)?)
+}
+
+/// Whether a step of the harvest in progress has failed, in which case its remaining steps are
+/// skipped
+pub fn harvest_aborted(storage: &dyn Storage) -> StdResult<bool> {
+    Ok(HARVEST_ABORTED.may_load(storage)?.unwrap_or(false))
+}
+
+/// Response of a harvest step skipped because an earlier step of the harvest has failed
+pub fn skip_harvest_step(action: &str) -> Response {
+    Response::new().add_attribute("action", action).add_attribute("skipped", "harvest_aborted")
+}
+
+/// If no user address is provided, i.e. the submessages are dispatched by a harvest step, make them
+/// reply even if they fail, and cache the pending rewards offered under the nonce of the reply id.
+/// This way, a failed step leaves the rewards pending for the next harvest instead of reverting the
+/// whole harvest. A user's operation still reverts on failure
+pub fn recoverable_harvest_submsgs(
+    storage: &mut dyn Storage,
+    user_addr_option: Option<&Addr>,
+    reply_id: u64,
+    offered: &[Asset],
+    submsgs: Vec<SubMsg>,
+) -> StdResult<Vec<SubMsg>> {
+    if user_addr_option.is_some() {
+        return Ok(submsgs);
+    }
+
+    let (_, nonce) = decode_reply_id(reply_id);
+    CACHED_HARVEST_OFFERS.save(storage, U64Key::new(nonce), &AssetList::from(offered.to_vec()))?;
+
+    Ok(submsgs
+        .into_iter()
+        .map(|mut submsg| {
+            if submsg.reply_on != ReplyOn::Never {
+                submsg.reply_on = ReplyOn::Always;
+            }
+            submsg
+        })
+        .collect())
+}
+
+/// Register an operation which dispatches a submessage, and return the reply id of the submessage,
+/// which encodes the kind of the operation and a nonce unique to it
+///
+/// If a user address is provided, it is cached under the nonce, along with the current block
+/// height, so that the reply is attributed to this user regardless of other pending operations
+pub fn register_op(
+    storage: &mut dyn Storage,
+    env: &Env,
+    kind: u64,
+    user_addr: Option<&Addr>,
+) -> StdResult<u64> {
+    let nonce = NEXT_OP_NONCE.may_load(storage)?.unwrap_or_default();
+    NEXT_OP_NONCE.save(storage, &(nonce + 1))?;
+
+    if let Some(user_addr) = user_addr {
+        let cached = CachedUserAddr {
+            user_addr: user_addr.clone(),
+            height: env.block.height,
+        };
+        PENDING_OPS.save(storage, U64Key::new(nonce), &cached)?;
+    }
+
+    Ok(encode_reply_id(kind, nonce))
+}
+
+/// Load the user address cached for a pending operation, if any, and assert that it was cached in
+/// the current block
+///
+/// Replies should always be processed in the same block as the submessage is dispatched. This is
+/// a defense-in-depth check, so that a stale cache never gets funds misattributed to a user
+pub fn may_load_op_user_addr(
+    storage: &dyn Storage,
+    env: &Env,
+    nonce: u64,
+) -> StdResult<Option<Addr>> {
+    match PENDING_OPS.may_load(storage, U64Key::new(nonce))? {
+        Some(cached) if cached.height != env.block.height => Err(StdError::generic_err(format!(
+            "stale cached user address: cached at height {}, current height {}",
+            cached.height, env.block.height
+        ))),
+        cached => Ok(cached.map(|cached| cached.user_addr)),
+    }
+}
+
+/// Same as `may_load_op_user_addr`, but throws an error if no user address is cached
+pub fn load_op_user_addr(storage: &dyn Storage, env: &Env, nonce: u64) -> StdResult<Addr> {
+    may_load_op_user_addr(storage, env, nonce)?
+        .ok_or_else(|| StdError::generic_err(format!("no user address cached for op {}", nonce)))
+}
+
+/// Load a user's position, settled against the current reward index. Default to an empty position
+/// if the user has none
+pub fn load_position(storage: &dyn Storage, user_addr: &Addr) -> StdResult<Position> {
+    let state = STATE.load(storage)?;
+    let position = POSITION.load(storage, user_addr).unwrap_or_default();
+    settle_position(storage, &state, position)
+}
+
+/// Credit a position with the bond units minted for it by harvests since it was last settled
+///
+/// Bond units that are not entering grow by the ratio of the current reward index to the index
+/// at which the position was last settled. Entering bond units, once their epoch has ended, grow
+/// by the ratio of the current index to the index at the end of that epoch. The credited units
+/// cost the user nothing, so the entry value per bond unit is diluted accordingly
+pub fn settle_position(
+    storage: &dyn Storage,
+    state: &State,
+    mut position: Position,
+) -> StdResult<Position> {
+    let grow = |units: Uint128, index: Decimal| {
+        units.multiply_ratio(state.reward_index.numerator(), index.numerator())
+    };
+
+    let eligible_bond_units = position.bond_units.checked_sub(position.entering_bond_units)?;
+    let mut bond_units = grow(eligible_bond_units, position.reward_index);
+    if !position.entering_bond_units.is_zero() && state.reward_epoch > position.entry_epoch {
+        let index = EPOCH_REWARD_INDEX.load(storage, U64Key::new(position.entry_epoch))?;
+        bond_units = bond_units.checked_add(grow(position.entering_bond_units, index))?;
+        position.entering_bond_units = Uint128::zero();
+    } else {
+        bond_units = bond_units.checked_add(position.entering_bond_units)?;
+    }
+
+    if bond_units > position.bond_units {
+        let entry_value = position.entry_bond_value_per_unit * position.bond_units;
+        position.entry_bond_value_per_unit = Decimal::from_ratio(entry_value, bond_units);
+    }
+    position.bond_units = bond_units;
+    position.reward_index = state.reward_index;
+
+    Ok(position)
+}
+
+/// End the current reward epoch, after which the bond units added in it are eligible for the
+/// rewards reinvested by harvests
+pub fn close_reward_epoch(storage: &mut dyn Storage, state: &mut State) -> StdResult<()> {
+    EPOCH_REWARD_INDEX.save(storage, U64Key::new(state.reward_epoch), &state.reward_index)?;
+    state.reward_epoch += 1;
+    state.entering_bond_units = Uint128::zero();
+    Ok(())
+}
+
+/// Throw an error if a position holds more unlocked asset types than `MAX_UNLOCKED_ASSETS`
+pub fn assert_unlocked_assets_bounded(position: &Position) -> StdResult<()> {
+    let count = position.unlocked_assets.len();
+    if count > MAX_UNLOCKED_ASSETS {
+        return Err(StdError::generic_err(format!(
+            "too many unlocked asset types: {}, max {}",
+            count, MAX_UNLOCKED_ASSETS
+        )));
+    }
+    Ok(())
+}
+
+/// Build the `position_changed` event, which is emitted by every callback that mutates a position
+/// so that indexers can track positions without replaying the contract's logic
+pub fn emit_position_event(
+    env: &Env,
+    user_addr: &Addr,
+    position: &Position,
+    health: &Health,
+) -> Event {
+    position_event(env, user_addr, position, health.bond_amount, health.debt_amount, Some(health))
+}
+
+/// Build the `position_changed` event given the totals, instead of a computed health
+///
+/// De-risking callbacks, e.g. repaying or unbonding, must not fail if prices are stale or can't be
+/// computed, so if the health can't be computed, the event only carries the amounts, which don't
+/// depend on prices, leaving out the `bond_value`, `debt_value` and `ltv` attributes
+pub fn emit_position_event_with_totals(
+    querier: &QuerierWrapper,
+    env: &Env,
+    config: &Config,
+    totals: &Totals,
+    state: &State,
+    user_addr: &Addr,
+    position: &Position,
+) -> Event {
+    match compute_health_with_totals(querier, env, config, totals, state, position) {
+        Ok(health) => emit_position_event(env, user_addr, position, &health),
+        Err(_) => {
+            let (bond_amount, debt_amount) = compute_position_amounts(totals, state, position);
+            position_event(env, user_addr, position, bond_amount, debt_amount, None)
+        }
+    }
+}
+
+fn position_event(
+    env: &Env,
+    user_addr: &Addr,
+    position: &Position,
+    bond_amount: Uint128,
+    debt_amount: Uint128,
+    health: Option<&Health>,
+) -> Event {
+    let mut event = Event::new("position_changed")
+        .add_attribute("timestamp", env.block.time.seconds().to_string())
+        .add_attribute("height", env.block.height.to_string())
+        .add_attribute("user", user_addr)
+        .add_attribute("bond_units", position.bond_units)
+        .add_attribute("bond_amount", bond_amount);
+    if let Some(health) = health {
+        event = event.add_attribute("bond_value", health.bond_value);
+    }
+    event = event
+        .add_attribute("debt_units", position.debt_units)
+        .add_attribute("debt_amount", debt_amount);
+    if let Some(health) = health {
+        // `ltv` is None if the position has no bond, which is recorded as "null"
+        let ltv_str =
+            health.ltv.map(|ltv| ltv.to_string()).unwrap_or_else(|| "null".to_string());
+        event = event.add_attribute("debt_value", health.debt_value).add_attribute("ltv", ltv_str);
+    }
+    event
+}
+
+/// Arithmetic on asset lists used in reward accounting, which `cw_asset` does not provide
+pub trait AssetListExt {
+    /// Multiply the amount of each asset by `ratio`, rounding down. Assets whose amounts round down
+    /// to zero are removed
+    fn scaled(&self, ratio: Decimal) -> AssetList;
+
+    /// Split the list into the fees charged at `rate` and the remainder. The remainder is what is
+    /// left of each asset after its fee is deducted, so that fees and remainder always add up to
+    /// the original list, without dust lost to rounding. A rate above 100% charges everything
+    fn split_fee(&self, rate: Decimal) -> (AssetList, AssetList);
+
+    /// Mutate the asset of the specified info in place, returning the closure's result, or `None`
+    /// if the list doesn't hold the asset. Assets left with zero amount are removed, same as
+    /// `deduct` does
+    ///
+    /// `AssetList` doesn't expose its inner vector, so a mutable reference can't be handed out;
+    /// the asset is lent to a closure instead
+    fn find_mut<R, F: FnOnce(&mut Asset) -> R>(&mut self, info: &AssetInfo, f: F) -> Option<R>;
+
+    /// Return the amount of the asset of the specified info held in the list; zero if not held
+    fn amount_of(&self, info: &AssetInfo) -> Uint128;
+
+    /// Remove the asset of the specified info from the list, returning it, or `None` if not held
+    fn take(&mut self, info: &AssetInfo) -> Option<Asset>;
+}
+
+impl AssetListExt for AssetList {
+    fn scaled(&self, ratio: Decimal) -> AssetList {
+        let mut scaled = self.clone();
+        scaled.apply(|asset| asset.amount = asset.amount * ratio).purge();
+        scaled
+    }
+
+    fn split_fee(&self, rate: Decimal) -> (AssetList, AssetList) {
+        let (fees, remainder): (Vec<Asset>, Vec<Asset>) = self
+            .to_vec()
+            .into_iter()
+            .map(|asset| {
+                let fee = cmp::min(asset.amount * rate, asset.amount);
+                (Asset::new(asset.info.clone(), fee), Asset::new(asset.info, asset.amount - fee))
+            })
+            .unzip();
+        let mut fees = AssetList::from(fees);
+        let mut remainder = AssetList::from(remainder);
+        fees.purge();
+        remainder.purge();
+        (fees, remainder)
+    }
+
+    fn find_mut<R, F: FnOnce(&mut Asset) -> R>(&mut self, info: &AssetInfo, f: F) -> Option<R> {
+        let mut f = Some(f);
+        let mut result = None;
+        self.apply(|asset| {
+            if asset.info == *info {
+                result = f.take().map(|f| f(asset));
+            }
+        });
+        self.purge();
+        result
+    }
+
+    fn amount_of(&self, info: &AssetInfo) -> Uint128 {
+        self.find(info).map(|asset| asset.amount).unwrap_or_default()
+    }
+
+    fn take(&mut self, info: &AssetInfo) -> Option<Asset> {
+        self.find_mut(info, |asset| {
+            let taken = asset.clone();
+            asset.amount = Uint128::zero();
+            taken
+        })
+    }
+}
+
+/// Split fees among the fee recipients in proportion to their weights, and build the messages to
+/// transfer them. The last recipient receives what remains after the others, so that no dust is
+/// left in the contract
+pub fn fee_transfer_msgs(
+    fee_recipients: &[(Addr, Decimal)],
+    fees: &AssetList,
+) -> StdResult<Vec<CosmosMsg>> {
+    let mut msgs: Vec<CosmosMsg> = vec![];
+    let mut remaining = fees.clone();
+    for (i, (recipient, weight)) in fee_recipients.iter().enumerate() {
+        let mut share = if i == fee_recipients.len() - 1 {
+            remaining.clone()
+        } else {
+            fees.scaled(*weight)
+        };
+        share.purge();
+        remaining.deduct_many(&share)?;
+        msgs.extend(share.transfer_msgs(recipient)?);
+    }
+    Ok(msgs)
+}
+
+/// Compute the total value of a list of assets, in the oracle's base unit, same as the values
+/// used in health checks. Zero-amount entries are valued too, so the result is consistently an
+/// error if the oracle has no price, or only a stale one, for any asset in the list
+pub fn value_of(
+    querier: &QuerierWrapper,
+    env: &Env,
+    config: &Config,
+    assets: &AssetList,
+) -> StdResult<Uint128> {
+    let assets = assets.to_vec();
+    let asset_infos = assets.iter().map(|asset| asset.info.clone()).collect::<Vec<_>>();
+    let prices = config.oracle.query_fresh_prices(
+        querier,
+        &asset_infos,
+        env.block.time.seconds(),
+        config.max_price_age,
+    )?;
+    assets.iter().zip(prices).try_fold(Uint128::zero(), |value, (asset, price)| {
+        let amount = config.normalize_amount(&asset.info, asset.amount)?;
+        Ok(value.checked_add(amount * price)?)
+    })
+}
+
+/// Query an account's balances of the specified assets
+pub fn query_balances(
+    querier: &QuerierWrapper,
+    addr: &Addr,
+    asset_infos: &[&AssetInfo],
+) -> StdResult<AssetList> {
+    asset_infos
+        .iter()
+        .map(|info| Ok(Asset::new((*info).clone(), info.query_balance(querier, addr)?)))
+        .collect::<StdResult<Vec<Asset>>>()
+        .map(AssetList::from)
+}
+
+/// Query the amount of a CW20 token that `owner` has approved `spender` to spend
+pub fn query_cw20_allowance(
+    querier: &QuerierWrapper,
+    token: &Addr,
+    owner: &Addr,
+    spender: &Addr,
+) -> StdResult<Uint128> {
+    let response: AllowanceResponse = querier.query_wasm_smart(
+        token,
+        &Cw20QueryMsg::Allowance {
+            owner: owner.to_string(),
+            spender: spender.to_string(),
+        },
+    )?;
+    Ok(response.allowance)
+}
+
+/// Compute how much of an asset an account has received, given its balances at an earlier time
+pub fn compute_received(
+    querier: &QuerierWrapper,
+    addr: &Addr,
+    asset_info: &AssetInfo,
+    balances_before: &AssetList,
+) -> StdResult<Asset> {
+    let balance_before = balances_before
+        .find(asset_info)
+        .map(|asset| asset.amount)
+        .unwrap_or_else(Uint128::zero);
+    let balance_after = asset_info.query_balance(querier, addr)?;
+    Ok(Asset::new(asset_info.clone(), balance_after.checked_sub(balance_before)?))
+}
+
+/// Parse a version string in the format of `major.minor.patch` into a tuple that can be compared
+pub fn parse_version(version: &str) -> StdResult<(u64, u64, u64)> {
+    let parts = version
+        .split('.')
+        .map(|part| {
+            part.parse::<u64>()
+                .map_err(|_| StdError::generic_err(format!("invalid version: {}", version)))
+        })
+        .collect::<StdResult<Vec<u64>>>()?;
+
+    match parts[..] {
+        [major, minor, patch] => Ok((major, minor, patch)),
+        _ => Err(StdError::generic_err(format!("invalid version: {}", version))),
+    }
+}
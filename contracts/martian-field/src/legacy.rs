@@ -1,5 +1,6 @@
-use cosmwasm_std::{Addr, Binary, DepsMut, Order, StdResult};
-use cw_storage_plus::Map;
+use cosmwasm_std::{Addr, Binary, DepsMut, Order, StdError, StdResult, Storage};
+use cw_storage_plus::{Item, Map};
+use serde::{Deserialize, Serialize};
 
 /// Snapshot is used by the frontend calculate user PnL. Once we build a transaction indexer that can
 /// calculate PnL without relying on on-chain snapshots, this will be removed
@@ -38,3 +39,29 @@ pub fn delete_snapshots(deps: DepsMut) -> StdResult<()> {
 
     Ok(())
 }
+
+/// Before each operation dispatching a submessage was given its own nonce, the address of the user
+/// whose position was being updated was cached under a single key. We only need to clear it
+const CACHED_USER_ADDR: Item<Binary> = Item::new("cached_user_addr");
+
+/// Delete the user address cached by an older version, if any
+pub fn clear_cached_user_addr(storage: &mut dyn Storage) {
+    CACHED_USER_ADDR.remove(storage);
+}
+
+/// Before fees could be split among multiple recipients, all fees were paid to a single `treasury`
+/// account. We only need this one field from the config written by older versions
+#[derive(Serialize, Deserialize)]
+struct LegacyConfig {
+    treasury: Option<Addr>,
+}
+
+const LEGACY_CONFIG: Item<LegacyConfig> = Item::new("config");
+
+/// Load the single treasury account from a config written by an older version
+pub fn load_treasury(storage: &dyn Storage) -> StdResult<Addr> {
+    LEGACY_CONFIG
+        .load(storage)?
+        .treasury
+        .ok_or_else(|| StdError::generic_err("no fee recipients or legacy treasury in config"))
+}
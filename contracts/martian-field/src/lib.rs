@@ -1,5 +1,6 @@
 #[cfg(not(feature = "library"))]
 pub mod contract;
+pub mod error;
 pub mod execute;
 pub mod execute_callbacks;
 pub mod execute_replies;
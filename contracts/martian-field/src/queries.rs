@@ -1,20 +1,65 @@
-use cosmwasm_std::{Addr, Deps, Env, Order, QuerierWrapper, StdResult};
+use std::cmp;
+
+use cosmwasm_std::{
+    Addr, Decimal, Deps, Env, Fraction, Order, QuerierWrapper, StdError, StdResult, Uint128,
+};
+use cw_asset::{Asset, AssetInfo};
 use cw_storage_plus::Bound;
 
-use fields_of_mars::martian_field::{Config, ConfigUnchecked, PositionResponse, PositionsResponseItem};
+use fields_of_mars::adapters::PairType;
+use fields_of_mars::martian_field::{
+    Action, AdminStatusResponse, Authorization, Config, ConfigHistoryEntry, ConfigUnchecked,
+    FeeMode, FeeStatsResponse, HealthResponse, Limits, LiquidatablePositionsResponseItem,
+    LiquidationParamsResponse, LiquidationProfitResponse, PendingRewardsResponseItem,
+    PositionOrder, PositionResponse, PositionsResponseItem, ReconcileResponse, SimulateResponse,
+    Snapshot, StateSnapshotResponse,
+};
 
-use crate::health::compute_health;
-use crate::state::{Position, State, CONFIG, POSITION, STATE};
+use crate::execute_callbacks::{
+    assert_max_debt_per_user, compute_balancing_swap, compute_stable_balancing_swap,
+    compute_withdrawal_fee, gross_up_for_tax,
+};
+use crate::execute::zap_out_offer_asset_info;
+use crate::health::{
+    compute_bond_units_to_liquidate, compute_health, compute_health_with_totals,
+    query_price_ranges, Totals,
+};
+use crate::helpers::{calc_limit, load_position, seed_units, settle_position};
+use crate::state::{
+    Position, State, AUTHORIZATIONS, CONFIG, CONFIG_HISTORY, LIMITS, PAUSED, PENDING_GOVERNANCE,
+    POSITION, SNAPSHOTS, STATE,
+};
 
-// Default settings for pagination
-const MAX_LIMIT: u32 = 30;
-const DEFAULT_LIMIT: u32 = 10;
+/// Version of the layout of `StateSnapshotResponse`
+const STATE_SNAPSHOT_VERSION: u32 = 2;
 
 pub fn query_config(deps: Deps) -> StdResult<ConfigUnchecked> {
     let config = CONFIG.load(deps.storage)?;
     Ok(config.into())
 }
 
+pub fn query_limits(deps: Deps) -> StdResult<Limits> {
+    LIMITS.load(deps.storage)
+}
+
+pub fn query_admin_status(deps: Deps) -> StdResult<AdminStatusResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    let paused = PAUSED.may_load(deps.storage)?.unwrap_or(false);
+    Ok(AdminStatusResponse {
+        governance: config.governance.to_string(),
+        operators: config.operators.iter().map(|op| op.to_string()).collect(),
+        fee_recipients: config
+            .fee_recipients
+            .iter()
+            .map(|(recipient, weight)| (recipient.to_string(), *weight))
+            .collect(),
+        paused,
+        deposits_enabled: !paused,
+        deposit_cap: config.deposit_cap,
+        pending_governance: PENDING_GOVERNANCE.may_load(deps.storage)?.map(|addr| addr.to_string()),
+    })
+}
+
 pub fn query_state(deps: Deps, env: Env) -> StdResult<PositionResponse> {
     let config = CONFIG.load(deps.storage)?;
     let state = STATE.load(deps.storage)?;
@@ -22,41 +67,712 @@ pub fn query_state(deps: Deps, env: Env) -> StdResult<PositionResponse> {
     _query_position(&deps.querier, &env, &config, &state, &state.clone().into())
 }
 
+pub fn query_state_snapshot(deps: Deps, env: Env) -> StdResult<StateSnapshotResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    let state = STATE.load(deps.storage)?;
+    let health = _query_position(&deps.querier, &env, &config, &state, &state.clone().into())?;
+
+    Ok(StateSnapshotResponse {
+        snapshot_version: STATE_SNAPSHOT_VERSION,
+        height: env.block.height,
+        time: env.block.time.seconds(),
+        config: config.into(),
+        total_bond_units: state.total_bond_units,
+        total_debt_units: state.total_debt_units,
+        pending_rewards: state.pending_rewards.into(),
+        total_bad_debt: state.total_bad_debt,
+        health,
+    })
+}
+
 pub fn query_positions(
     deps: Deps,
     env: Env,
     start_after: Option<String>,
     limit: Option<u32>,
+    order_by: Option<PositionOrder>,
 ) -> StdResult<Vec<PositionsResponseItem>> {
     let config = CONFIG.load(deps.storage)?;
     let state = STATE.load(deps.storage)?;
 
-    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let limit = calc_limit(limit);
     let start = start_after.map(Bound::exclusive);
 
-    POSITION
+    let mut items = POSITION
         .range(deps.storage, start, None, Order::Ascending)
         .take(limit)
         .map(|item| {
             let (k, v) = item?;
+            let v = settle_position(deps.storage, &state, v)?;
             Ok(PositionsResponseItem {
                 user: String::from_utf8(k)?,
                 position: _query_position(&deps.querier, &env, &config, &state, &v)?,
             })
         })
-        .collect()
+        .collect::<StdResult<Vec<_>>>()?;
+
+    // the sort is stable, so positions that tie remain in the order of user addresses
+    if let Some(order_by) = order_by {
+        match order_by {
+            PositionOrder::BondValueDesc => {
+                items.sort_by_key(|item| cmp::Reverse(item.position.bond_value))
+            }
+            PositionOrder::LtvDesc => items.sort_by_key(|item| cmp::Reverse(item.position.ltv)),
+            PositionOrder::DebtValueDesc => {
+                items.sort_by_key(|item| cmp::Reverse(item.position.debt_value))
+            }
+        }
+    }
+
+    Ok(items)
+}
+
+pub fn query_liquidatable_positions(
+    deps: Deps,
+    env: Env,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<Vec<LiquidatablePositionsResponseItem>> {
+    let config = CONFIG.load(deps.storage)?;
+    let state = STATE.load(deps.storage)?;
+
+    let limit = calc_limit(limit);
+    let start = start_after.map(Bound::exclusive);
+
+    let mut items = vec![];
+    for item in POSITION.range(deps.storage, start, None, Order::Ascending) {
+        if items.len() >= limit {
+            break;
+        }
+
+        let (k, v) = item?;
+        let v = settle_position(deps.storage, &state, v)?;
+        let health = compute_health(&deps.querier, &env, &config, &state, &v)?;
+
+        // closed positions, whose ltv is `None`, are skipped
+        match health.ltv {
+            Some(ltv) if ltv > config.max_ltv => items.push(LiquidatablePositionsResponseItem {
+                user: String::from_utf8(k)?,
+                ltv,
+                bond_value: health.bond_value,
+                debt_value: health.debt_value,
+            }),
+            _ => continue,
+        }
+    }
+
+    Ok(items)
 }
 
 pub fn query_position(deps: Deps, env: Env, user_addr: Addr) -> StdResult<PositionResponse> {
     let config = CONFIG.load(deps.storage)?;
     let state = STATE.load(deps.storage)?;
-    let position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
+    let position = load_position(deps.storage, &user_addr)?;
 
     _query_position(&deps.querier, &env, &config, &state, &position)
 }
 
+pub fn query_health(deps: Deps, env: Env, user_addr: Option<Addr>) -> StdResult<HealthResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    let state = STATE.load(deps.storage)?;
+    let position = match &user_addr {
+        Some(user_addr) => load_position(deps.storage, user_addr)?,
+        None => state.clone().into(),
+    };
+
+    // a user without bond or debt has no health to compute, so no price is queried
+    if position.bond_units.is_zero() && position.debt_units.is_zero() {
+        return Ok(HealthResponse::default());
+    }
+
+    let health = compute_health(&deps.querier, &env, &config, &state, &position)?;
+    Ok(HealthResponse {
+        bond_amount: health.bond_amount,
+        bond_value: health.bond_value,
+        debt_amount: health.debt_amount,
+        debt_value: health.debt_value,
+        ltv: health.ltv,
+    })
+}
+
+pub fn query_liquidation_profit(
+    deps: Deps,
+    env: Env,
+    user_addr: Addr,
+) -> StdResult<LiquidationProfitResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    let state = STATE.load(deps.storage)?;
+    let position = load_position(deps.storage, &user_addr)?;
+
+    let health = compute_health(&deps.querier, &env, &config, &state, &position)?;
+    let liquidatable = matches!(health.ltv, Some(ltv) if ltv > config.max_ltv);
+    if !liquidatable {
+        return Ok(LiquidationProfitResponse {
+            liquidatable,
+            bonus_value: Uint128::zero(),
+            incentive_value: Uint128::zero(),
+            swap_cost_value: Uint128::zero(),
+            profit_value: Uint128::zero(),
+        });
+    }
+    assert_secondary_borrowed(&config)?;
+
+    let primary_price = config.oracle.query_price(&deps.querier, &config.primary_asset_info)?;
+    let secondary_price = config.oracle.query_price(&deps.querier, &config.secondary_asset_info)?;
+    let primary_value = |amount: Uint128| -> StdResult<Uint128> {
+        Ok(config.normalize_amount(&config.primary_asset_info, amount)? * primary_price)
+    };
+    let secondary_value = |amount: Uint128| -> StdResult<Uint128> {
+        Ok(config.normalize_amount(&config.secondary_asset_info, amount)? * secondary_price)
+    };
+
+    // estimate the amounts of primary and secondary assets the user will have after unbonding and
+    // withdrawing liquidity
+    let (primary_depth, secondary_depth, total_shares) = config.primary_pair.query_liquid_pool(
+        &deps.querier,
+        &config.primary_asset_info,
+        &config.secondary_asset_info,
+    )?;
+    let find_unlocked = |asset_info| {
+        position.unlocked_assets.find(asset_info).map(|asset| asset.amount).unwrap_or_default()
+    };
+    let primary_amount = primary_depth.multiply_ratio(health.bond_amount, total_shares)
+        + find_unlocked(&config.primary_asset_info);
+    let secondary_amount = secondary_depth.multiply_ratio(health.bond_amount, total_shares)
+        + find_unlocked(&config.secondary_asset_info);
+
+    // estimate how much primary asset needs to be sold to cover the debt, same as in `Cover`, and
+    // how much remains after repayment. if the position is underwater, nothing remains
+    let secondary_target_amount = health.debt_amount + config.repay_tax_reserve;
+    let secondary_needed_amount = secondary_target_amount.saturating_sub(secondary_amount);
+    let (primary_remaining, secondary_remaining, swap_cost_value) =
+        if secondary_needed_amount.is_zero() {
+            (primary_amount, secondary_amount - health.debt_amount, Uint128::zero())
+        } else {
+            let secondary_needed =
+                Asset::new(config.secondary_asset_info.clone(), secondary_needed_amount);
+            let primary_sell_amount = config.primary_pair.query_reverse_simulate_ceil(
+                &deps.querier,
+                &config.primary_asset_info,
+                &secondary_needed,
+            )?;
+            if primary_sell_amount > primary_amount {
+                (Uint128::zero(), Uint128::zero(), Uint128::zero())
+            } else {
+                let swap_cost_value = primary_value(primary_sell_amount)?
+                    .saturating_sub(secondary_value(secondary_needed_amount)?);
+                (
+                    primary_amount - primary_sell_amount,
+                    secondary_target_amount - health.debt_amount,
+                    swap_cost_value,
+                )
+            }
+        };
+
+    // the liquidator is paid a portion of what remains after repayment
+    let remaining_value = primary_value(primary_remaining)? + secondary_value(secondary_remaining)?;
+    let bonus_value = remaining_value * config.bonus_rate_at(health.ltv.unwrap_or_default());
+
+    // the incentive is only paid if the reserve is sufficient
+    let incentive_value = match &config.liquidation_incentive {
+        Some(incentive) if !incentive.amount.is_zero() => {
+            let reserve_amount = state
+                .incentive_reserve
+                .find(&incentive.info)
+                .map(|asset| asset.amount)
+                .unwrap_or_default();
+            if reserve_amount >= incentive.amount {
+                config.normalize_amount(&incentive.info, incentive.amount)?
+                    * config.oracle.query_price(&deps.querier, &incentive.info)?
+            } else {
+                Uint128::zero()
+            }
+        }
+        _ => Uint128::zero(),
+    };
+
+    Ok(LiquidationProfitResponse {
+        liquidatable,
+        bonus_value,
+        incentive_value,
+        swap_cost_value,
+        profit_value: bonus_value + incentive_value,
+    })
+}
+
+pub fn query_liquidation_params(
+    deps: Deps,
+    env: Env,
+    user_addr: Addr,
+) -> StdResult<LiquidationParamsResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    let state = STATE.load(deps.storage)?;
+    let position = load_position(deps.storage, &user_addr)?;
+
+    let health = compute_health(&deps.querier, &env, &config, &state, &position)?;
+    let liquidatable = matches!(health.ltv, Some(ltv) if ltv > config.max_ltv);
+    if !liquidatable {
+        return Ok(LiquidationParamsResponse {
+            liquidatable,
+            max_bond_units: Uint128::zero(),
+            full_liquidation: false,
+            bonus_value: Uint128::zero(),
+        });
+    }
+
+    // same as in `liquidate`. in a full liquidation, the bonus is paid out of what remains after
+    // repaying all debt, which is estimated by the `LiquidationProfit` query. in a partial
+    // liquidation, it is paid out of the assets withdrawn from the liquidated portion
+    let max_bond_units = compute_bond_units_to_liquidate(&config, &position, &health)?;
+    let full_liquidation = max_bond_units >= position.bond_units;
+    let bonus_value = if full_liquidation {
+        query_liquidation_profit(deps, env, user_addr)?.bonus_value
+    } else {
+        let bonus_rate = config.bonus_rate_at(health.ltv.unwrap_or_default());
+        health.bond_value.multiply_ratio(max_bond_units, position.bond_units) * bonus_rate
+    };
+
+    Ok(LiquidationParamsResponse {
+        liquidatable,
+        max_bond_units,
+        full_liquidation,
+        bonus_value,
+    })
+}
+
+/// Apply the actions to copies of the state, the position and the totals, following the same logic
+/// as the callbacks that `update_position` would invoke. Nothing is saved
+pub fn query_simulate_update_position(
+    deps: Deps,
+    env: Env,
+    user_addr: Option<Addr>,
+    actions: Vec<Action>,
+) -> StdResult<SimulateResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    assert_secondary_borrowed(&config)?;
+    let mut state = STATE.load(deps.storage)?;
+    let mut position = match &user_addr {
+        Some(user_addr) => load_position(deps.storage, user_addr)?,
+        None => Position::default(),
+    };
+    let mut totals = Totals::query(&deps.querier, &env, &config)?;
+    let initial_bond_units = position.bond_units;
+
+    let primary_asset_info = &config.primary_asset_info;
+    let secondary_asset_info = &config.secondary_asset_info;
+    let liquidity_token_info = AssetInfo::cw20(config.primary_pair.liquidity_token.clone());
+    let find_unlocked = |position: &Position, asset_info: &AssetInfo| {
+        position.unlocked_assets.find(asset_info).map(|asset| asset.amount).unwrap_or_default()
+    };
+
+    let mut secondary_borrowed = Uint128::zero();
+    let mut secondary_repaid = Uint128::zero();
+    let mut primary_provided = Uint128::zero();
+    let mut secondary_provided = Uint128::zero();
+    let mut shares_bonded = Uint128::zero();
+    let mut shares_unbonded = Uint128::zero();
+
+    for action in actions {
+        match action {
+            Action::Deposit(asset) => {
+                position.unlocked_assets.add(&asset.check(deps.api, None)?)?;
+            }
+
+            // same as `callbacks::borrow`
+            Action::Borrow {
+                amount,
+            } => {
+                let gross_borrow_amount = gross_up_for_tax(amount, config.borrow_tax_rate);
+                let debt_units_to_add = if totals.debt_amount.is_zero() {
+                    seed_units(gross_borrow_amount, config.debt_units_per_asset)?
+                } else {
+                    state.total_debt_units.multiply_ratio(gross_borrow_amount, totals.debt_amount)
+                };
+
+                state.total_debt_units = state.total_debt_units.checked_add(debt_units_to_add)?;
+                position.debt_units = position.debt_units.checked_add(debt_units_to_add)?;
+                position.unlocked_assets.add(&Asset::new(secondary_asset_info.clone(), amount))?;
+                totals.debt_amount = totals.debt_amount.checked_add(gross_borrow_amount)?;
+                secondary_borrowed += gross_borrow_amount;
+
+                let debt_amount =
+                    totals.debt_amount.multiply_ratio(position.debt_units, state.total_debt_units);
+                assert_max_debt_per_user(&config, debt_amount)?;
+            }
+
+            // same as `callbacks::repay`
+            Action::Repay {
+                amount,
+                user,
+            } => {
+                // repaying another user's debt leaves the position's own debt unchanged, which
+                // the simulation doesn't account for
+                if user.is_some() && user.as_deref() != user_addr.as_ref().map(Addr::as_str) {
+                    return Err(StdError::generic_err(
+                        "simulation not supported when repaying the debt of another user",
+                    ));
+                }
+                secondary_repaid +=
+                    simulate_repay(&config, &mut totals, &mut state, &mut position, amount)?;
+            }
+
+            // same as `callbacks::provide_liquidity` followed by `callbacks::bond`. the amount of
+            // liquidity tokens minted is computed the same way as Astroport's XYK pair does. the
+            // one-sided provide is not simulated, so both assets must be available
+            //
+            // for `Zap`, the assets are first balanced the same way as `callbacks::balance`
+            action @ (Action::Bond { .. } | Action::Zap { .. }) => {
+                if matches!(action, Action::Zap { .. }) {
+                    // the oracle prices are not used for sizing the swap, but stale prices fail
+                    // the balancing
+                    let current_time = env.block.time.seconds();
+                    for asset_info in [primary_asset_info, secondary_asset_info] {
+                        config.oracle.query_fresh_price(
+                            &deps.querier,
+                            asset_info,
+                            current_time,
+                            config.max_price_age,
+                        )?;
+                    }
+                    let compute_swap = match config.primary_pair.pair_type {
+                        PairType::Xyk => compute_balancing_swap,
+                        PairType::Stable => compute_stable_balancing_swap,
+                    };
+                    let (offer_asset_info, offer_amount) = compute_swap(
+                        &Asset::new(primary_asset_info.clone(), totals.primary_depth),
+                        &Asset::new(secondary_asset_info.clone(), totals.secondary_depth),
+                        find_unlocked(&position, primary_asset_info),
+                        find_unlocked(&position, secondary_asset_info),
+                        config.primary_pair.pair_type.commission_rate(),
+                    );
+                    if !offer_amount.is_zero() {
+                        let offer_asset = Asset::new(offer_asset_info, offer_amount);
+                        let ask_asset_info = if offer_asset.info == *primary_asset_info {
+                            secondary_asset_info
+                        } else {
+                            primary_asset_info
+                        };
+                        let return_amount =
+                            config.primary_pair.query_simulate(&deps.querier, &offer_asset)?;
+                        position.unlocked_assets.deduct(&offer_asset)?;
+                        position
+                            .unlocked_assets
+                            .add(&Asset::new(ask_asset_info.clone(), return_amount))?;
+                    }
+                }
+
+                let primary_amount = find_unlocked(&position, primary_asset_info);
+                let secondary_amount = find_unlocked(&position, secondary_asset_info);
+                if primary_amount.is_zero() {
+                    return Err(StdError::generic_err("no primary asset available"));
+                }
+                if secondary_amount.is_zero() {
+                    return Err(StdError::generic_err("no secondary asset available"));
+                }
+                config.primary_pair.assert_liquid(
+                    totals.primary_depth,
+                    totals.secondary_depth,
+                    totals.total_shares,
+                )?;
+
+                let shares_minted = cmp::min(
+                    primary_amount.multiply_ratio(totals.total_shares, totals.primary_depth),
+                    secondary_amount.multiply_ratio(totals.total_shares, totals.secondary_depth),
+                );
+                position
+                    .unlocked_assets
+                    .deduct(&Asset::new(primary_asset_info.clone(), primary_amount))?;
+                position
+                    .unlocked_assets
+                    .deduct(&Asset::new(secondary_asset_info.clone(), secondary_amount))?;
+                totals.primary_depth += primary_amount;
+                totals.secondary_depth += secondary_amount;
+                totals.total_shares += shares_minted;
+                position
+                    .unlocked_assets
+                    .add(&Asset::new(liquidity_token_info.clone(), shares_minted))?;
+
+                // we bond *all* of the unlocked liquidity tokens
+                let shares_to_bond = find_unlocked(&position, &liquidity_token_info);
+                let bond_units_to_add = if totals.bond_amount.is_zero() {
+                    seed_units(shares_to_bond, config.bond_units_per_share)?
+                } else {
+                    state.total_bond_units.multiply_ratio(shares_to_bond, totals.bond_amount)
+                };
+                position
+                    .unlocked_assets
+                    .deduct(&Asset::new(liquidity_token_info.clone(), shares_to_bond))?;
+
+                state.total_bond_units = state.total_bond_units.checked_add(bond_units_to_add)?;
+                position.bond_units = position.bond_units.checked_add(bond_units_to_add)?;
+                totals.bond_amount = totals.bond_amount.checked_add(shares_to_bond)?;
+                primary_provided += primary_amount;
+                secondary_provided += secondary_amount;
+                shares_bonded += shares_to_bond;
+            }
+
+            // same as `callbacks::unbond` followed by `callbacks::withdraw_liquidity`, and if
+            // `swap_to` is provided, `callbacks::swap` of all of the other asset
+            //
+            // for `Close`, all of the bond units the user has before the actions are unbonded, then
+            // the debt is paid off the same way as `callbacks::cover` and `callbacks::repay`
+            action @ (Action::Unbond { .. } | Action::Close {}) => {
+                let closing = matches!(action, Action::Close {});
+                let (bond_units_to_reduce, swap_to) = match action {
+                    Action::Unbond {
+                        bond_units_to_reduce,
+                        swap_to,
+                    } => (bond_units_to_reduce, swap_to),
+                    _ => (initial_bond_units, None),
+                };
+
+                if closing && bond_units_to_reduce.is_zero() {
+                    simulate_cover_and_repay(
+                        &deps.querier,
+                        &config,
+                        &mut totals,
+                        &mut state,
+                        &mut position,
+                        &mut secondary_repaid,
+                    )?;
+                    continue;
+                }
+
+                let shares_to_unbond =
+                    totals.bond_amount.multiply_ratio(bond_units_to_reduce, state.total_bond_units);
+                let fee_amount = if config.fee_mode == FeeMode::Withdrawal {
+                    compute_withdrawal_fee(
+                        &deps.querier,
+                        &env,
+                        &config,
+                        &totals,
+                        &state,
+                        &position,
+                        shares_to_unbond,
+                    )?
+                } else {
+                    Uint128::zero()
+                };
+                state.total_bond_units = state.total_bond_units.checked_sub(bond_units_to_reduce)?;
+                position.bond_units = position.bond_units.checked_sub(bond_units_to_reduce)?;
+                totals.bond_amount = totals.bond_amount.checked_sub(shares_to_unbond)?;
+                position.unlocked_assets.add(&Asset::new(
+                    liquidity_token_info.clone(),
+                    shares_to_unbond - fee_amount,
+                ))?;
+
+                // we burn *all* of the unlocked liquidity tokens
+                let shares_to_burn = find_unlocked(&position, &liquidity_token_info);
+                config.primary_pair.assert_liquid(
+                    totals.primary_depth,
+                    totals.secondary_depth,
+                    totals.total_shares,
+                )?;
+                let primary_withdrawn =
+                    totals.primary_depth.multiply_ratio(shares_to_burn, totals.total_shares);
+                let secondary_withdrawn =
+                    totals.secondary_depth.multiply_ratio(shares_to_burn, totals.total_shares);
+                position
+                    .unlocked_assets
+                    .deduct(&Asset::new(liquidity_token_info.clone(), shares_to_burn))?;
+                position
+                    .unlocked_assets
+                    .add(&Asset::new(primary_asset_info.clone(), primary_withdrawn))?;
+                position
+                    .unlocked_assets
+                    .add(&Asset::new(secondary_asset_info.clone(), secondary_withdrawn))?;
+                totals.primary_depth = totals.primary_depth.checked_sub(primary_withdrawn)?;
+                totals.secondary_depth = totals.secondary_depth.checked_sub(secondary_withdrawn)?;
+                totals.total_shares = totals.total_shares.checked_sub(shares_to_burn)?;
+                shares_unbonded += shares_to_unbond;
+
+                if let Some(swap_to) = swap_to {
+                    let swap_to = swap_to.check(deps.api, None)?;
+                    let offer_asset_info = zap_out_offer_asset_info(&config, &swap_to)?;
+                    let offer_amount = find_unlocked(&position, &offer_asset_info);
+                    let offer_asset = Asset::new(offer_asset_info, offer_amount);
+                    let return_amount =
+                        config.primary_pair.query_simulate(&deps.querier, &offer_asset)?;
+                    position.unlocked_assets.deduct(&offer_asset)?;
+                    position.unlocked_assets.add(&Asset::new(swap_to, return_amount))?;
+                }
+
+                if closing {
+                    simulate_cover_and_repay(
+                        &deps.querier,
+                        &config,
+                        &mut totals,
+                        &mut state,
+                        &mut position,
+                        &mut secondary_repaid,
+                    )?;
+                }
+            }
+
+            // same as `callbacks::swap` followed by `replies::after_swap`. the return amount is
+            // simulated against the pool's current reserves
+            Action::Swap {
+                offer_amount,
+                min_secondary_from_swap,
+                ..
+            } => {
+                let offer_asset = Asset::new(primary_asset_info.clone(), offer_amount);
+                let return_amount =
+                    config.primary_pair.query_simulate(&deps.querier, &offer_asset)?;
+                if let Some(min_return) = min_secondary_from_swap {
+                    if return_amount < min_return {
+                        return Err(StdError::generic_err(format!(
+                            "insufficient swap return: {}; minimum {}",
+                            return_amount, min_return
+                        )));
+                    }
+                }
+
+                position.unlocked_assets.deduct(&offer_asset)?;
+                position
+                    .unlocked_assets
+                    .add(&Asset::new(secondary_asset_info.clone(), return_amount))?;
+            }
+        }
+    }
+
+    let health =
+        compute_health_with_totals(&deps.querier, &env, &config, &totals, &state, &position)?;
+
+    Ok(SimulateResponse {
+        secondary_borrowed,
+        secondary_repaid,
+        primary_provided,
+        secondary_provided,
+        shares_bonded,
+        shares_unbonded,
+        bond_units: position.bond_units,
+        debt_units: position.debt_units,
+        bond_value: health.bond_value,
+        debt_value: health.debt_value,
+        ltv: health.ltv,
+        refunded: position.unlocked_assets.to_vec().into_iter().map(|asset| asset.into()).collect(),
+    })
+}
+
+/// The estimates of swaps and repayments made by the simulations assume that the secondary asset
+/// itself is borrowed. Rather than returning misleading numbers, they fail if it is not
+fn assert_secondary_borrowed(config: &Config) -> StdResult<()> {
+    if config.borrow_pair().is_some() {
+        return Err(StdError::generic_err(format!(
+            "simulation not supported when borrowing {} instead of the secondary asset",
+            config.borrow_asset_info()
+        )));
+    }
+    Ok(())
+}
+
+/// Same as `callbacks::repay`. Returns the amount of secondary asset repaid
+fn simulate_repay(
+    config: &Config,
+    totals: &mut Totals,
+    state: &mut State,
+    position: &mut Position,
+    amount: Uint128,
+) -> StdResult<Uint128> {
+    let debt_amount = if state.total_debt_units.is_zero() {
+        Uint128::zero()
+    } else {
+        totals.debt_amount.multiply_ratio(position.debt_units, state.total_debt_units)
+    };
+    let repay_amount = cmp::min(amount, debt_amount);
+    let debt_units_to_deduct = if debt_amount.is_zero() {
+        Uint128::zero()
+    } else {
+        position.debt_units.multiply_ratio(repay_amount, debt_amount)
+    };
+
+    state.total_debt_units = state.total_debt_units.checked_sub(debt_units_to_deduct)?;
+    position.debt_units = position.debt_units.checked_sub(debt_units_to_deduct)?;
+    position
+        .unlocked_assets
+        .deduct(&Asset::new(config.secondary_asset_info.clone(), repay_amount))?;
+    totals.debt_amount = totals.debt_amount.checked_sub(repay_amount)?;
+    Ok(repay_amount)
+}
+
+/// Same as `callbacks::cover` followed by `replies::after_swap` and `callbacks::repay` with no
+/// amount specified, i.e. repaying as much of the debt as the unlocked secondary asset allows
+fn simulate_cover_and_repay(
+    querier: &QuerierWrapper,
+    config: &Config,
+    totals: &mut Totals,
+    state: &mut State,
+    position: &mut Position,
+    secondary_repaid: &mut Uint128,
+) -> StdResult<()> {
+    let find_unlocked = |position: &Position, asset_info: &AssetInfo| {
+        position.unlocked_assets.find(asset_info).map(|asset| asset.amount).unwrap_or_default()
+    };
+
+    let debt_amount = if state.total_debt_units.is_zero() {
+        Uint128::zero()
+    } else {
+        totals.debt_amount.multiply_ratio(position.debt_units, state.total_debt_units)
+    };
+    let secondary_target_amount = if debt_amount.is_zero() {
+        Uint128::zero()
+    } else {
+        debt_amount.checked_add(config.repay_tax_reserve)?
+    };
+    let secondary_available_amount = find_unlocked(position, &config.secondary_asset_info);
+    if secondary_target_amount > secondary_available_amount {
+        let secondary_needed = Asset::new(
+            config.secondary_asset_info.clone(),
+            secondary_target_amount - secondary_available_amount,
+        );
+        let primary_sell_amount = cmp::min(
+            config.primary_pair.query_reverse_simulate_ceil(
+                querier,
+                &config.primary_asset_info,
+                &secondary_needed,
+            )?,
+            find_unlocked(position, &config.primary_asset_info),
+        );
+        let primary_to_sell = Asset::new(config.primary_asset_info.clone(), primary_sell_amount);
+        let return_amount = config.primary_pair.query_simulate(querier, &primary_to_sell)?;
+        position.unlocked_assets.deduct(&primary_to_sell)?;
+        position
+            .unlocked_assets
+            .add(&Asset::new(config.secondary_asset_info.clone(), return_amount))?;
+    }
+
+    let repay_amount = find_unlocked(position, &config.secondary_asset_info);
+    *secondary_repaid += simulate_repay(config, totals, state, position, repay_amount)?;
+    Ok(())
+}
+
+pub fn query_pending_rewards(deps: Deps) -> StdResult<Vec<PendingRewardsResponseItem>> {
+    let config = CONFIG.load(deps.storage)?;
+    let state = STATE.load(deps.storage)?;
+
+    // a missing price for one reward token should not fail the whole query
+    Ok(state
+        .pending_rewards
+        .to_vec()
+        .into_iter()
+        .map(|asset| {
+            let value = config
+                .oracle
+                .query_price(&deps.querier, &asset.info)
+                .ok()
+                .zip(config.normalize_amount(&asset.info, asset.amount).ok())
+                .map(|(price, amount)| amount * price);
+            PendingRewardsResponseItem {
+                asset: asset.into(),
+                value,
+            }
+        })
+        .collect())
+}
+
 /// Compute health, and compose the result into a `PositionResponse` object
-fn _query_position(
+pub fn _query_position(
     querier: &QuerierWrapper,
     env: &Env,
     config: &Config,
@@ -69,6 +785,8 @@ fn _query_position(
         bond_units: position.bond_units,
         bond_amount: health.bond_amount,
         bond_value: health.bond_value,
+        underlying_primary: health.underlying_primary,
+        underlying_secondary: health.underlying_secondary,
         debt_units: position.debt_units,
         debt_amount: health.debt_amount,
         debt_value: health.debt_value,
@@ -76,3 +794,133 @@ fn _query_position(
         unlocked_assets: position.unlocked_assets.clone().into(),
     })
 }
+
+pub fn query_snapshots(
+    deps: Deps,
+    user_addr: Addr,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<Vec<Snapshot>> {
+    let limit = calc_limit(limit);
+    let end = start_after.map(Bound::exclusive_int);
+
+    SNAPSHOTS
+        .prefix(&user_addr)
+        .range(deps.storage, None, end, Order::Descending)
+        .take(limit)
+        .map(|item| {
+            let (_, v) = item?;
+            Ok(v)
+        })
+        .collect()
+}
+
+pub fn query_config_history(
+    deps: Deps,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<Vec<ConfigHistoryEntry>> {
+    let limit = calc_limit(limit);
+    let end = start_after.map(Bound::exclusive_int);
+
+    CONFIG_HISTORY
+        .range(deps.storage, None, end, Order::Descending)
+        .take(limit)
+        .map(|item| {
+            let (_, v) = item?;
+            Ok(v)
+        })
+        .collect()
+}
+
+pub fn query_max_borrow(deps: Deps, env: Env, user_addr: Addr) -> StdResult<Uint128> {
+    let config = CONFIG.load(deps.storage)?;
+    let state = STATE.load(deps.storage)?;
+    let position = load_position(deps.storage, &user_addr)?;
+
+    // borrowing doesn't change the bond value, so the user can take on additional debt up to the
+    // value at which LTV reaches the max
+    let health = compute_health(&deps.querier, &env, &config, &state, &position)?;
+    let max_debt_value = health.bond_value * config.max_ltv;
+    if health.debt_value >= max_debt_value {
+        return Ok(Uint128::zero());
+    }
+
+    // debt is valued at the higher borrowed asset price if a secondary oracle is configured
+    let borrow_asset_infos = [config.borrow_asset_info().clone()];
+    let (_, borrow_price) =
+        query_price_ranges(&deps.querier, &env, &config, &borrow_asset_infos)?[0];
+    if borrow_price.is_zero() {
+        return Ok(Uint128::zero());
+    }
+
+    let mut max_debt_to_add = config.denormalize_amount(
+        config.borrow_asset_info(),
+        (max_debt_value - health.debt_value)
+            .multiply_ratio(borrow_price.denominator(), borrow_price.numerator()),
+    )?;
+    if let Some(max_debt_per_user) = config.max_debt_per_user {
+        let room = max_debt_per_user.checked_sub(health.debt_amount).unwrap_or_default();
+        max_debt_to_add = cmp::min(max_debt_to_add, room);
+    }
+
+    // the user's debt increases by the amount to borrow grossed up for tax, so the amount that can
+    // be borrowed is the additional debt net of tax
+    Ok(max_debt_to_add * (Decimal::one() - config.borrow_tax_rate))
+}
+
+pub fn query_authorization(
+    deps: Deps,
+    user_addr: Addr,
+    keeper_addr: Addr,
+) -> StdResult<Authorization> {
+    Ok(AUTHORIZATIONS.may_load(deps.storage, (&user_addr, &keeper_addr))?.unwrap_or_default())
+}
+
+pub fn query_reconcile(deps: Deps, env: Env) -> StdResult<ReconcileResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    let state = STATE.load(deps.storage)?;
+
+    let red_bank_debt = config.red_bank.query_user_debt(
+        &deps.querier,
+        &env.contract.address,
+        config.borrow_asset_info(),
+    )?;
+    let collateral_enabled = config.red_bank.query_user_collateral(
+        &deps.querier,
+        &env.contract.address,
+        config.borrow_asset_info(),
+    )?;
+
+    let summed_debt_units = POSITION
+        .range(deps.storage, None, None, Order::Ascending)
+        .try_fold(Uint128::zero(), |sum, item| -> StdResult<_> {
+            let (_, position) = item?;
+            Ok(sum.checked_add(position.debt_units)?)
+        })?;
+    let internal_debt = if state.total_debt_units.is_zero() {
+        Uint128::zero()
+    } else {
+        red_bank_debt.multiply_ratio(summed_debt_units, state.total_debt_units)
+    };
+    let delta = if red_bank_debt > internal_debt {
+        red_bank_debt - internal_debt
+    } else {
+        internal_debt - red_bank_debt
+    };
+
+    Ok(ReconcileResponse {
+        red_bank_debt,
+        internal_debt,
+        delta,
+        collateral_enabled,
+    })
+}
+
+pub fn query_fee_stats(deps: Deps) -> StdResult<FeeStatsResponse> {
+    let state = STATE.load(deps.storage)?;
+    Ok(FeeStatsResponse {
+        total_fees_collected: state.total_fees_collected.into(),
+        last_harvest_time: state.last_harvest_time,
+    })
+}
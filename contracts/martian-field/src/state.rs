@@ -1,13 +1,16 @@
-use cosmwasm_std::{Addr, Uint128};
+use cosmwasm_std::{Addr, Decimal, Uint128};
 use cw_asset::AssetList;
-use cw_storage_plus::{Item, Map};
+use cw_storage_plus::{Item, Map, U64Key};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use fields_of_mars::martian_field::Config;
+use fields_of_mars::martian_field::{Authorization, Config, ConfigHistoryEntry, Limits, Snapshot};
 
 pub const CONFIG: Item<Config> = Item::new("config");
 
+// bounds the config is validated against. saved at instantiation and never updated
+pub const LIMITS: Item<Limits> = Item::new("limits");
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct State {
     /// Total amount of bond units; used to calculate each user's share of bonded LP tokens
@@ -16,6 +19,52 @@ pub struct State {
     pub total_debt_units: Uint128,
     /// Reward tokens that can be reinvested in the next harvest
     pub pending_rewards: AssetList,
+    /// Highest value per bond unit, measured in the fee benchmark asset, on which performance fee
+    /// has been charged. Only used if fee mode is `HighWaterMark`
+    #[serde(default)]
+    pub high_water_mark: Decimal,
+    /// ASTRO rewards retained in the contract, claimable by governance. Only used if ASTRO handling
+    /// mode is `Accumulate`
+    #[serde(default)]
+    pub accumulated_astro: Uint128,
+    /// Assets deposited by governance, from which liquidation incentives are paid
+    #[serde(default)]
+    pub incentive_reserve: AssetList,
+    /// Cumulative amount of secondary asset debt waived as bad debt
+    #[serde(default)]
+    pub total_bad_debt: Uint128,
+    /// Highest value per bond unit, in the oracle's base unit, observed at harvest. Withdrawal fees
+    /// are charged on appreciation up to this value. Only used if fee mode is `Withdrawal`
+    #[serde(default)]
+    pub hwm_bond_value_per_unit: Decimal,
+    /// Timestamp, in seconds, of the last harvest. Zero if not harvested since this field was added
+    #[serde(default)]
+    pub last_harvest_time: u64,
+    /// Cumulative fees transferred to the fee recipients. Only counts fees collected since this
+    /// field was added
+    #[serde(default)]
+    pub total_fees_collected: AssetList,
+    /// Number of bond units that one bond unit, held since the first harvest that reinvested
+    /// rewards, has grown into. Each harvest mints bond units for the reinvested liquidity tokens
+    /// and grows the index by the ratio of the minted units to the eligible units
+    #[serde(default = "default_reward_index")]
+    pub reward_index: Decimal,
+    /// Number of harvests that have grown the reward index. Bond units added since the last of
+    /// them are not eligible for the rewards reinvested by the next one
+    #[serde(default)]
+    pub reward_epoch: u64,
+    /// Total amount of bond units added in the current reward epoch
+    #[serde(default)]
+    pub entering_bond_units: Uint128,
+    /// Rewards credited to the pending rewards, either claimed by a harvest or withdrawn by the
+    /// generator on bond and unbond, which have yet to be charged fees. Cleared once the next
+    /// harvest charges them, so that rewards retained across harvests are only charged once
+    #[serde(default)]
+    pub uncharged_rewards: AssetList,
+}
+
+fn default_reward_index() -> Decimal {
+    Decimal::one()
 }
 
 // `Addr` does not have `Default` implemented, so we can't derive the Default trait
@@ -25,12 +74,38 @@ impl Default for State {
             total_bond_units: Uint128::zero(),
             total_debt_units: Uint128::zero(),
             pending_rewards: AssetList::default(),
+            high_water_mark: Decimal::zero(),
+            accumulated_astro: Uint128::zero(),
+            incentive_reserve: AssetList::default(),
+            total_bad_debt: Uint128::zero(),
+            hwm_bond_value_per_unit: Decimal::zero(),
+            last_harvest_time: 0,
+            total_fees_collected: AssetList::default(),
+            reward_index: Decimal::one(),
+            reward_epoch: 0,
+            entering_bond_units: Uint128::zero(),
+            uncharged_rewards: AssetList::default(),
         }
     }
 }
 
 pub const STATE: Item<State> = Item::new("state");
 
+// whether actions that increase exposure are disabled. not saved until governance first pauses the
+// contract, in which case it is treated as false
+pub const PAUSED: Item<bool> = Item::new("paused");
+
+// whether a top-level operation's callback chain is in progress. set when `update_position`,
+// `harvest` or `liquidate` is executed, and cleared by the last message of the chain
+pub const LOCK: Item<bool> = Item::new("lock");
+
+// whether a step of the harvest in progress has failed, in which case its remaining steps are
+// skipped. cleared along with the lock
+pub const HARVEST_ABORTED: Item<bool> = Item::new("harvest_aborted");
+
+// the account proposed as the new governance, if there is a pending governance transfer
+pub const PENDING_GOVERNANCE: Item<Addr> = Item::new("pending_governance");
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct Position {
     /// Amount of bond units representing user's share of bonded LP tokens
@@ -39,6 +114,25 @@ pub struct Position {
     pub debt_units: Uint128,
     /// Amount of assets not locked in Astroport pool; pending refund or liquidation
     pub unlocked_assets: AssetList,
+    /// Value per bond unit, in the oracle's base unit, at which the user's bond units were
+    /// acquired; averaged over all bonds. Only used if fee mode is `Withdrawal`
+    #[serde(default)]
+    pub entry_bond_value_per_unit: Decimal,
+    /// Height of the block in which the position was opened. The position can't be liquidated in
+    /// this block. Zero for positions opened by older versions
+    #[serde(default)]
+    pub opened_at_height: u64,
+    /// The global reward index at the last time the position was settled. The bond units not
+    /// entering are credited with the growth of the index since then
+    #[serde(default = "default_reward_index")]
+    pub reward_index: Decimal,
+    /// Amount of bond units added in the reward epoch `entry_epoch`, which are only credited with
+    /// the growth of the index since the end of that epoch
+    #[serde(default)]
+    pub entering_bond_units: Uint128,
+    /// The reward epoch in which the entering bond units were added
+    #[serde(default)]
+    pub entry_epoch: u64,
 }
 
 // `Addr` does not have `Default` implemented, so we can't derive the Default trait
@@ -48,6 +142,11 @@ impl Default for Position {
             bond_units: Uint128::zero(),
             debt_units: Uint128::zero(),
             unlocked_assets: AssetList::default(),
+            entry_bond_value_per_unit: Decimal::zero(),
+            opened_at_height: 0,
+            reward_index: Decimal::one(),
+            entering_bond_units: Uint128::zero(),
+            entry_epoch: 0,
         }
     }
 }
@@ -58,6 +157,11 @@ impl From<State> for Position {
             bond_units: state.total_bond_units,
             debt_units: state.total_debt_units,
             unlocked_assets: state.pending_rewards,
+            entry_bond_value_per_unit: Decimal::zero(),
+            opened_at_height: 0,
+            reward_index: state.reward_index,
+            entering_bond_units: state.entering_bond_units,
+            entry_epoch: state.reward_epoch,
         }
     }
 }
@@ -70,5 +174,70 @@ impl Position {
 
 pub const POSITION: Map<&Addr, Position> = Map::new("position");
 
-// save user address temporarily between callbacks
-pub const CACHED_USER_ADDR: Item<Addr> = Item::new("cached_user_addr");
+// the reward index at the end of each reward epoch, keyed by epoch
+pub const EPOCH_REWARD_INDEX: Map<U64Key, Decimal> = Map::new("epoch_reward_index");
+
+// snapshots of each user's position, keyed by block height
+pub const SNAPSHOTS: Map<(&Addr, U64Key), Snapshot> = Map::new("snapshots");
+
+// past configs replaced by `update_config`, keyed by entry id
+pub const CONFIG_HISTORY: Map<U64Key, ConfigHistoryEntry> = Map::new("config_history");
+
+// maps (user, keeper) to the actions the keeper is authorized to perform on the user's position
+pub const AUTHORIZATIONS: Map<(&Addr, &Addr), Authorization> = Map::new("authorizations");
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CachedUserAddr {
+    /// Address of the user whose position is being updated
+    pub user_addr: Addr,
+    /// The block height at which the address is cached
+    pub height: u64,
+}
+
+// save the user address of each operation whose submessage is pending, keyed by the nonce encoded
+// in the reply id, so that each reply is attributed to the user of its own operation
+pub const PENDING_OPS: Map<U64Key, CachedUserAddr> = Map::new("pending_ops");
+
+// save the pending rewards offered by each harvest step whose submessage is pending, keyed by the
+// nonce encoded in the reply id, so that they can be restored if the submessage fails
+pub const CACHED_HARVEST_OFFERS: Map<U64Key, AssetList> = Map::new("cached_harvest_offers");
+
+// nonce to be given to the next operation dispatching a submessage
+pub const NEXT_OP_NONCE: Item<u64> = Item::new("next_op_nonce");
+
+// save contract balances of primary and secondary assets temporarily before withdrawing liquidity,
+// so that the exact amounts returned by the pool can be computed when handling the reply
+pub const CACHED_BALANCES: Item<AssetList> = Item::new("cached_balances");
+
+// save the minimum amount a swap must return temporarily, so that it can be enforced when handling
+// the reply
+pub const CACHED_MIN_RETURN: Item<Uint128> = Item::new("cached_min_return");
+
+// save the minimum amount of liquidity tokens a provision must mint temporarily, so that it can be
+// enforced when handling the reply
+pub const CACHED_MIN_LP_OUT: Item<Uint128> = Item::new("cached_min_lp_out");
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CachedRoute {
+    /// Index in `Config::astro_route` of the hop whose swap is being executed
+    pub hop: u32,
+    /// Maximum spread of each hop's swap
+    pub max_spread: Option<Decimal>,
+}
+
+// save the progress of a multi-hop ASTRO swap temporarily, so that the reply of each hop can send
+// the next one
+pub const CACHED_ROUTE: Item<CachedRoute> = Item::new("cached_route");
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CachedRewards {
+    /// The contract's balances of reward tokens before bonding or unbonding
+    pub balances_before: AssetList,
+    /// Amounts of rewards the generator reported claimable, which have been credited to pending
+    /// rewards in advance
+    pub predicted: AssetList,
+}
+
+// save reward token balances and the predicted reward amounts temporarily before bonding or
+// unbonding, so that the rewards actually withdrawn can be reconciled when handling the reply
+pub const CACHED_REWARDS: Item<CachedRewards> = Item::new("cached_rewards");
@@ -1,6 +1,6 @@
 use cosmwasm_std::{
     entry_point, from_binary, to_binary, to_vec, Addr, Binary, Deps, DepsMut, Empty, Env, 
-    MessageInfo, Response, StdError, StdResult, Uint128,
+    MessageInfo, Order, Response, StdError, StdResult, Uint128,
 };
 use cw20::Cw20ReceiveMsg;
 
@@ -8,10 +8,10 @@ use cw_asset::Asset;
 
 use mars_core::asset::{Asset as MarsAsset, AssetType as MarsAssetType};
 use mars_core::red_bank::msg::{QueryMsg, ReceiveMsg};
-use mars_core::red_bank::UserAssetDebtResponse;
+use mars_core::red_bank::{MarketInfo, MarketsListResponse, UserAssetDebtResponse};
 
 use crate::msg::ExecuteMsg;
-use crate::state::DEBT_AMOUNT;
+use crate::state::{DEBT_AMOUNT, MARKETS};
 
 // INIT
 
@@ -42,6 +42,9 @@ pub fn execute(deps: DepsMut, env: Env, info: MessageInfo, msg: ExecuteMsg) -> S
             let repay_amount = helpers::get_denom_amount_from_coins(&info.funds, &denom);
             execute_repay(deps, env, info, repayer_addr, &denom, repay_amount)
         }
+        ExecuteMsg::InitAsset {
+            asset,
+        } => execute_init_asset(deps, env, info, asset),
         ExecuteMsg::SetUserDebt {
             user_address,
             denom,
@@ -121,6 +124,17 @@ fn execute_repay(
     Ok(Response::default())
 }
 
+fn execute_init_asset(
+    deps: DepsMut,
+    _env: Env,
+    _info: MessageInfo,
+    asset: MarsAsset,
+) -> StdResult<Response> {
+    let (asset_label, _, _) = asset.get_attributes();
+    MARKETS.save(deps.storage, &asset_label, &asset)?;
+    Ok(Response::default())
+}
+
 fn execute_set_debt(
     deps: DepsMut,
     _env: Env,
@@ -143,6 +157,8 @@ pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
             asset,
         } => to_binary(&query_debt(deps, env, user_address, asset)?),
 
+        QueryMsg::MarketsList {} => to_binary(&query_markets_list(deps, env)?),
+
         _ => Err(StdError::generic_err(
             format!("[mock] unimplemented query: {}", String::from_utf8(to_vec(&msg)?)?)
         )),
@@ -170,6 +186,28 @@ fn query_debt(
     })
 }
 
+fn query_markets_list(deps: Deps, _env: Env) -> StdResult<MarketsListResponse> {
+    let markets_list = MARKETS
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| {
+            let (_, asset) = item?;
+            let (asset_label, asset_reference, asset_type) = asset.get_attributes();
+            Ok(MarketInfo {
+                // for attributes not relevant to our testing we just fill in some random value
+                denom: asset_label.clone(),
+                asset_label,
+                asset_reference,
+                asset_type,
+                ma_token_address: Addr::unchecked(""),
+            })
+        })
+        .collect::<StdResult<Vec<MarketInfo>>>()?;
+
+    Ok(MarketsListResponse {
+        markets_list,
+    })
+}
+
 // HELPERS
 
 pub mod helpers {
@@ -17,6 +17,11 @@ pub enum ExecuteMsg {
     RepayNative {
         denom: String,
     },
+    /// List a market for an asset. Unlike the actual Red Bank contract, no asset parameters are
+    /// required, as only the market's existence matters for our testing
+    InitAsset {
+        asset: MarsAsset,
+    },
     /// NOTE: Only used in mock contract! Not present in actual Red Bank contract
     /// Forcibly set a user's debt amount. Used in tests to simulate the accrual of debts
     SetUserDebt {
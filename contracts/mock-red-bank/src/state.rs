@@ -1,4 +1,9 @@
 use cosmwasm_std::{Addr, Uint128};
 use cw_storage_plus::Map;
 
+use mars_core::asset::Asset as MarsAsset;
+
 pub const DEBT_AMOUNT: Map<(&Addr, &str), Uint128> = Map::new("debt_amount");
+
+/// Maps asset label to the asset of each listed market
+pub const MARKETS: Map<&str, MarsAsset> = Map::new("markets");
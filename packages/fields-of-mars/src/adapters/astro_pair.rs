@@ -1,28 +1,81 @@
+use std::cmp;
+use std::convert::TryInto;
+use std::fmt;
 use std::str::FromStr;
 
 use cosmwasm_std::{
     to_binary, Addr, Api, Coin, CosmosMsg, Decimal, Event, QuerierWrapper, QueryRequest, StdError,
-    StdResult, SubMsg, Uint128, WasmMsg, WasmQuery,
+    StdResult, SubMsg, Uint128, Uint256, WasmMsg, WasmQuery,
 };
 use cw20::Cw20ExecuteMsg;
 
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use astroport::pair::{Cw20HookMsg, ExecuteMsg, PoolResponse, QueryMsg, ReverseSimulationResponse};
+use astroport::asset::PairInfo;
+use astroport::factory::PairType as AstroportPairType;
+use astroport::pair::{
+    Cw20HookMsg, ExecuteMsg, PoolResponse, QueryMsg, ReverseSimulationResponse, SimulationResponse,
+};
 
 use cw_asset::{Asset, AssetInfo, AssetUnchecked};
 
+use crate::asset::{asset_info_from_label, asset_label};
+
 //--------------------------------------------------------------------------------------------------
 // Pair
 //--------------------------------------------------------------------------------------------------
 
+/// Commission rate charged by Astroport XYK pairs, deducted from the ask asset
+pub const COMMISSION_RATE: &str = "0.003";
+
+/// Commission rate charged by Astroport stableswap pairs, deducted from the ask asset
+pub const STABLE_COMMISSION_RATE: &str = "0.0005";
+
+/// Maximum number of times the reverse-simulated offer amount is topped up in
+/// `query_reverse_simulate_ceil`
+const MAX_OFFER_TOP_UPS: usize = 5;
+
+/// The invariant of an Astroport pair
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PairType {
+    /// Constant-product pair
+    #[default]
+    Xyk,
+    /// Stableswap pair, whose assets trade close to 1:1 with little slippage near the peg
+    Stable,
+}
+
+impl PairType {
+    /// Return the commission rate charged by pairs of this type
+    pub fn commission_rate(&self) -> Decimal {
+        let rate = match self {
+            PairType::Xyk => COMMISSION_RATE,
+            PairType::Stable => STABLE_COMMISSION_RATE,
+        };
+        Decimal::from_str(rate).unwrap()
+    }
+}
+
+impl fmt::Display for PairType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PairType::Xyk => write!(f, "xyk"),
+            PairType::Stable => write!(f, "stable"),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct PairBase<T> {
     /// Address of the Astroport contract_addr contract
     pub contract_addr: T,
     /// Address of the Astroport LP token
     pub liquidity_token: T,
+    /// Type of the pair. Default to `PairType::Xyk` if not provided
+    #[serde(default)]
+    pub pair_type: PairType,
 }
 
 pub type PairUnchecked = PairBase<String>;
@@ -33,6 +86,7 @@ impl From<Pair> for PairUnchecked {
         PairUnchecked {
             contract_addr: pair.contract_addr.to_string(),
             liquidity_token: pair.liquidity_token.to_string(),
+            pair_type: pair.pair_type,
         }
     }
 }
@@ -42,21 +96,58 @@ impl PairUnchecked {
         Ok(Pair {
             contract_addr: api.addr_validate(&self.contract_addr)?,
             liquidity_token: api.addr_validate(&self.liquidity_token)?,
+            pair_type: self.pair_type,
         })
     }
 }
 
 impl Pair {
-    /// Create a new pair instance
+    /// Create a new XYK pair instance
     pub fn new(contract_addr: &Addr, liquidity_token: &Addr) -> Self {
         Self {
             contract_addr: contract_addr.clone(),
             liquidity_token: liquidity_token.clone(),
+            pair_type: PairType::Xyk,
+        }
+    }
+
+    /// Query the pair's info, and assert that its type and liquidity token are the ones configured
+    pub fn assert_pair_info(&self, querier: &QuerierWrapper) -> StdResult<()> {
+        let response: PairInfo = querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+            contract_addr: self.contract_addr.to_string(),
+            msg: to_binary(&QueryMsg::Pair {})?,
+        }))?;
+
+        let pair_type = match response.pair_type {
+            AstroportPairType::Xyk {} => PairType::Xyk,
+            AstroportPairType::Stable {} => PairType::Stable,
+            AstroportPairType::Custom(pair_type) => {
+                return Err(StdError::generic_err(format!(
+                    "unsupported pair type for {}: {}",
+                    self.contract_addr, pair_type
+                )));
+            }
+        };
+
+        if pair_type != self.pair_type {
+            return Err(StdError::generic_err(format!(
+                "pair type mismatch for {}: configured {}, on-chain {}",
+                self.contract_addr, self.pair_type, pair_type
+            )));
         }
+
+        // bonding a token that is not the pair's liquidity token would lose the user's funds
+        if response.liquidity_token != self.liquidity_token {
+            return Err(StdError::generic_err(format!(
+                "liquidity token mismatch for {}: configured {}, on-chain {}",
+                self.contract_addr, self.liquidity_token, response.liquidity_token
+            )));
+        }
+
+        Ok(())
     }
 
     /// Generate submessages for providing specified assets
-    /// NOTE: For now, we don't specify a slippage tolerance
     pub fn provide_submsgs(
         &self,
         id: u64,
@@ -84,6 +175,10 @@ impl Pair {
             }
         }
 
+        // the bank module requires coins to be sorted by denom; this matters if both assets are
+        // native, and the primary asset's denom comes after the secondary's alphabetically
+        funds.sort_by(|a, b| a.denom.cmp(&b.denom));
+
         submsgs.push(SubMsg::reply_on_success(
             WasmMsg::Execute {
                 contract_addr: self.contract_addr.to_string(),
@@ -184,6 +279,107 @@ impl Pair {
         Ok((primary_asset_depth, secondary_asset_depth, response.total_share))
     }
 
+    /// Same as `query_pool`, but throws an error if either of the depths or the total share supply
+    /// is zero, e.g. for a freshly created or fully drained pool
+    pub fn query_liquid_pool(
+        &self,
+        querier: &QuerierWrapper,
+        primary_asset_info: &AssetInfo,
+        secondary_asset_info: &AssetInfo,
+    ) -> StdResult<(Uint128, Uint128, Uint128)> {
+        let (primary_depth, secondary_depth, total_share) =
+            self.query_pool(querier, primary_asset_info, secondary_asset_info)?;
+        self.assert_liquid(primary_depth, secondary_depth, total_share)?;
+        Ok((primary_depth, secondary_depth, total_share))
+    }
+
+    /// Throw an error if either of the depths or the total share supply of the pool is zero. The
+    /// price of such a pool is undefined, so amounts cannot be derived from its depths
+    pub fn assert_liquid(
+        &self,
+        primary_depth: Uint128,
+        secondary_depth: Uint128,
+        total_share: Uint128,
+    ) -> StdResult<()> {
+        if primary_depth.is_zero() || secondary_depth.is_zero() || total_share.is_zero() {
+            return Err(StdError::generic_err(format!(
+                "pool {} has no liquidity: primary depth {}, secondary depth {}, total share {}",
+                self.contract_addr, primary_depth, secondary_depth, total_share
+            )));
+        }
+        Ok(())
+    }
+
+    /// Calculate how much ask asset is returned by swapping a specified amount of offer asset
+    pub fn query_simulate(
+        &self,
+        querier: &QuerierWrapper,
+        offer_asset: &Asset,
+    ) -> StdResult<Uint128> {
+        let response: SimulationResponse = querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+            contract_addr: self.contract_addr.to_string(),
+            msg: to_binary(&QueryMsg::Simulation {
+                offer_asset: offer_asset.into(),
+            })?,
+        }))?;
+        Ok(response.return_amount)
+    }
+
+    /// Calculate how much offer asset needs to be sold so that the swap returns at least the
+    /// specified amount of ask asset
+    ///
+    /// The reverse simulation alone may fall short, in different ways depending on the pair type:
+    ///
+    /// For XYK pairs, due to integer rounding, if we estimate offer amount using exactly the needed
+    /// return amount, the actual return amount may be one unit less than what we need. For
+    /// example, consider LUNA-UST pair with depths 1497005315 uluna + 24450395383 uusd. We want the
+    /// swap to return 1291320960 uusd, so we calculate
+    /// computeXykSwapInput(1291320960, 1497005315, 24450395383) = 83736355. However, if we offer
+    /// 83736355 uluna, the swap returns
+    /// computeXykSwapOutput(83736355, 1497005315, 24450395383) = 1291320945, which is 15 uusd
+    /// short of what we need. Offering 1 unit of LUNA more than the reverse-simulated amount,
+    /// computeXykSwapOutput(83736356, 1497005315, 24450395383) = 1291320960, which is exactly the
+    /// amount we need
+    ///
+    /// For stableswap pairs, the invariant is solved numerically, and the reverse simulation may be
+    /// off by more than one unit
+    ///
+    /// Either way, we verify the reverse-simulated amount with a forward simulation, and top up the
+    /// offer by the shortfall converted at the simulated rate, rounded up and at least one unit,
+    /// until the swap returns enough. An error is returned if it still falls short after a few
+    /// top-ups
+    pub fn query_reverse_simulate_ceil(
+        &self,
+        querier: &QuerierWrapper,
+        offer_asset_info: &AssetInfo,
+        ask_asset: &Asset,
+    ) -> StdResult<Uint128> {
+        let mut offer_amount = self.query_reverse_simulate(querier, ask_asset)?;
+        for _ in 0..=MAX_OFFER_TOP_UPS {
+            let offer_asset = Asset::new(offer_asset_info.clone(), offer_amount);
+            let return_amount = self.query_simulate(querier, &offer_asset)?;
+            if return_amount >= ask_asset.amount {
+                return Ok(offer_amount);
+            }
+
+            // if nothing is returned, the rate is unknown, so we double the offer instead
+            let shortfall = ask_asset.amount - return_amount;
+            let top_up = if return_amount.is_zero() {
+                offer_amount
+            } else {
+                // ceil(shortfall * offer_amount / return_amount)
+                (shortfall.full_mul(offer_amount) + Uint256::from(return_amount - Uint128::new(1)))
+                    .checked_div(Uint256::from(return_amount))?
+                    .try_into()?
+            };
+            offer_amount = offer_amount.checked_add(cmp::max(top_up, Uint128::new(1)))?;
+        }
+        Err(StdError::generic_err(format!(
+            "cannot find an offer amount for {} that returns {}",
+            offer_asset_info, ask_asset
+        )))
+    }
+
     /// Calculate how much offer asset is needed to return a specified amount of ask asset
     pub fn query_reverse_simulate(
         &self,
@@ -201,7 +397,14 @@ impl Pair {
     }
 
     /// Find the return amount when swapping in an Astroport pool
-    pub fn parse_swap_events(events: &[Event]) -> StdResult<AssetUnchecked> {
+    ///
+    /// The returned asset is identified by matching its label against the specified asset infos.
+    /// Only if it matches none of them, e.g. an intermediate asset of a multi-hop route, is it
+    /// classified by whether the label is a contract address
+    pub fn parse_swap_events(
+        events: &[Event],
+        asset_infos: &[AssetInfo],
+    ) -> StdResult<AssetUnchecked> {
         let event = events
             .iter()
             .find(|event| event_contains_attr(event, "action", "swap"))
@@ -210,39 +413,38 @@ impl Pair {
         let ask_asset_str = event
             .attributes
             .iter()
-            .cloned()
             .find(|attr| attr.key == "ask_asset")
             .ok_or_else(|| StdError::generic_err("cannot find `ask_asset` attribute"))?
-            .value;
+            .value
+            .clone();
 
         let return_amount_str = event
             .attributes
             .iter()
-            .cloned()
             .find(|attr| attr.key == "return_amount")
             .ok_or_else(|| StdError::generic_err("cannot find `return_amount` attribute"))?
-            .value;
+            .value
+            .clone();
 
         let tax_amount_str = event
             .attributes
             .iter()
-            .cloned()
             .find(|attr| attr.key == "tax_amount")
             .ok_or_else(|| StdError::generic_err("cannot find `tax_amount` attribute"))?
-            .value;
+            .value
+            .clone();
 
         let return_amount = Uint128::from_str(&return_amount_str)?;
         let tax_amount = Uint128::from_str(&tax_amount_str)?;
         let return_amount_after_tax = return_amount.checked_sub(tax_amount)?;
 
-        // If the asset's label starts with `terra` then we assume it is a CW20
-        // Otherwise, assume it is a native
-        // Not a very clean way of doin this, but ok
-        if ask_asset_str.starts_with("terra") {
-            Ok(AssetUnchecked::cw20(ask_asset_str, return_amount_after_tax))
-        } else {
-            Ok(AssetUnchecked::native(ask_asset_str, return_amount_after_tax))
-        }
+        let ask_asset_info = asset_infos
+            .iter()
+            .find(|info| asset_label(info) == ask_asset_str)
+            .map(|info| info.clone().into())
+            .unwrap_or_else(|| asset_info_from_label(&ask_asset_str));
+
+        Ok(AssetUnchecked::new(ask_asset_info, return_amount_after_tax))
     }
 
     /// Find the amount of share tokens minted when providing liquidity to an Astroport pool
@@ -255,10 +457,10 @@ impl Pair {
         let share_str = event
             .attributes
             .iter()
-            .cloned()
             .find(|attr| attr.key == "share")
             .ok_or_else(|| StdError::generic_err("cannot find `share` attribute"))?
-            .value;
+            .value
+            .clone();
 
         Uint128::from_str(&share_str)
     }
@@ -284,27 +486,18 @@ impl Pair {
             .split(", ")
             .collect();
 
-        let primary_asset_label = get_asset_label(primary_asset_info);
-        let primary_withdrawn_amount_str = asset_strs
-            .iter()
-            .find(|asset_str| asset_str.contains(&primary_asset_label))
-            .map(|asset_str| asset_str.replace(&primary_asset_label, ""))
+        let primary_withdrawn_amount_str = find_amount_str(&asset_strs, primary_asset_info)
             .ok_or_else(|| StdError::generic_err("failed to parse primary withdrawn amount"))?;
-
-        let secondary_asset_label = get_asset_label(secondary_asset_info);
-        let secondary_withdrawn_amount_str = asset_strs
-            .iter()
-            .find(|asset_str| asset_str.contains(&secondary_asset_label))
-            .map(|asset_str| asset_str.replace(&secondary_asset_label, ""))
+        let secondary_withdrawn_amount_str = find_amount_str(&asset_strs, secondary_asset_info)
             .ok_or_else(|| StdError::generic_err("failed to parse secondary withdrawn amount"))?;
 
         let primary_asset_withdrawn = Asset::new(
             primary_asset_info.clone(),
-            Uint128::from_str(&primary_withdrawn_amount_str)?,
+            Uint128::from_str(primary_withdrawn_amount_str)?,
         );
         let secondary_asset_withdrawn = Asset::new(
             secondary_asset_info.clone(),
-            Uint128::from_str(&secondary_withdrawn_amount_str)?,
+            Uint128::from_str(secondary_withdrawn_amount_str)?,
         );
 
         Ok((primary_asset_withdrawn, secondary_asset_withdrawn))
@@ -315,9 +508,20 @@ fn event_contains_attr(event: &Event, key: &str, value: &str) -> bool {
     event.attributes.iter().any(|attr| attr.key == key && attr.value == value)
 }
 
-fn get_asset_label(asset_info: &AssetInfo) -> String {
-    match asset_info {
-        AssetInfo::Cw20(contract_addr) => contract_addr.into(),
-        AssetInfo::Native(denom) => denom.clone(),
-    }
+/// Among strings in the format of `{amount}{label}`, e.g. `12345uluna`, find the amount of the
+/// specified asset
+///
+/// The label must match exactly, so that e.g. `uusd` is not confused with a native denom or a CW20
+/// address which contains it
+fn find_amount_str<'a>(asset_strs: &[&'a str], asset_info: &AssetInfo) -> Option<&'a str> {
+    let label = asset_label(asset_info);
+    asset_strs.iter().find_map(|asset_str| {
+        let split_at = asset_str.find(|c: char| !c.is_ascii_digit())?;
+        let (amount_str, asset_label) = asset_str.split_at(split_at);
+        if asset_label == label && !amount_str.is_empty() {
+            Some(amount_str)
+        } else {
+            None
+        }
+    })
 }
\ No newline at end of file
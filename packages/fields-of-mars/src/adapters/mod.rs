@@ -2,8 +2,10 @@ mod astro_generator;
 mod astro_pair;
 mod oracle;
 mod red_bank;
+mod refund_batcher;
 
 pub use self::astro_generator::*;
 pub use self::astro_pair::*;
 pub use self::oracle::*;
 pub use self::red_bank::*;
+pub use self::refund_batcher::*;
@@ -1,5 +1,5 @@
 use cosmwasm_std::{
-    to_binary, Addr, Api, Decimal, QuerierWrapper, QueryRequest, StdResult, WasmQuery,
+    to_binary, Addr, Api, Decimal, QuerierWrapper, QueryRequest, StdError, StdResult, WasmQuery,
 };
 
 use schemars::JsonSchema;
@@ -10,6 +10,37 @@ use mars_core::oracle::msg::QueryMsg;
 
 use cw_asset::AssetInfo;
 
+/// Queries supported by oracles that also report when each price was last updated. Mars oracle v1
+/// does not support them, so they are only used if a maximum price age is configured
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OracleTimestampedQueryMsg {
+    /// Query asset price and the time it was last updated given the asset's internal reference;
+    /// returns `PriceWithTimestampResponse`
+    AssetPriceWithTimestampByReference {
+        asset_reference: Vec<u8>,
+    },
+}
+
+/// Queries supported by oracles that can report multiple prices at once. Mars oracle v1 does not
+/// support them, in which case prices are queried one at a time
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OracleBatchQueryMsg {
+    /// Query the prices of multiple assets given their internal references; returns the prices as
+    /// `Vec<Decimal>`, in the same order as the references
+    AssetPricesByReference {
+        asset_references: Vec<Vec<u8>>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PriceWithTimestampResponse {
+    pub price: MarsDecimal,
+    /// Timestamp, in seconds, at which the price was last updated
+    pub last_updated: u64,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct OracleBase<T> {
     pub contract_addr: T,
@@ -48,9 +79,89 @@ impl Oracle {
         }))?;
         Ok(response.to_std_decimal()) // cast mars_core::math::decimal::Decimal to cosmwasm_std::Decimal
     }
+
+    /// Query the prices of multiple assets in a single batch query, in the same order as the assets.
+    /// If the oracle doesn't support batch queries, each price is queried separately
+    pub fn query_prices(
+        &self,
+        querier: &QuerierWrapper,
+        asset_infos: &[AssetInfo],
+    ) -> StdResult<Vec<Decimal>> {
+        let response: StdResult<Vec<MarsDecimal>> =
+            querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+                contract_addr: self.contract_addr.to_string(),
+                msg: to_binary(&OracleBatchQueryMsg::AssetPricesByReference {
+                    asset_references: asset_infos.iter().map(get_asset_reference).collect(),
+                })?,
+            }));
+
+        match response {
+            Ok(prices) if prices.len() == asset_infos.len() => {
+                Ok(prices.into_iter().map(|price| price.to_std_decimal()).collect())
+            }
+            _ => asset_infos
+                .iter()
+                .map(|asset_info| self.query_price(querier, asset_info))
+                .collect(),
+        }
+    }
+
+    /// Same as `query_fresh_price`, but for multiple assets. If `max_price_age` is zero, the prices
+    /// are queried in a batch; otherwise, each price is queried separately along with its timestamp
+    pub fn query_fresh_prices(
+        &self,
+        querier: &QuerierWrapper,
+        asset_infos: &[AssetInfo],
+        current_time: u64,
+        max_price_age: u64,
+    ) -> StdResult<Vec<Decimal>> {
+        if max_price_age == 0 {
+            return self.query_prices(querier, asset_infos);
+        }
+
+        asset_infos
+            .iter()
+            .map(|asset_info| {
+                self.query_fresh_price(querier, asset_info, current_time, max_price_age)
+            })
+            .collect()
+    }
+
+    /// Query the price of an asset, and assert it is no older than `max_price_age` seconds at
+    /// `current_time`. If `max_price_age` is zero, the age is not checked
+    pub fn query_fresh_price(
+        &self,
+        querier: &QuerierWrapper,
+        asset_info: &AssetInfo,
+        current_time: u64,
+        max_price_age: u64,
+    ) -> StdResult<Decimal> {
+        if max_price_age == 0 {
+            return self.query_price(querier, asset_info);
+        }
+
+        let response: PriceWithTimestampResponse =
+            querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+                contract_addr: self.contract_addr.to_string(),
+                msg: to_binary(&OracleTimestampedQueryMsg::AssetPriceWithTimestampByReference {
+                    asset_reference: get_asset_reference(asset_info),
+                })?,
+            }))?;
+
+        let price_age = current_time.saturating_sub(response.last_updated);
+        if price_age > max_price_age {
+            return Err(StdError::generic_err(format!(
+                "stale price for {}: last updated {} seconds ago; max age is {} seconds",
+                asset_info, price_age, max_price_age
+            )));
+        }
+
+        Ok(response.price.to_std_decimal())
+    }
 }
 
-fn get_asset_reference(asset_info: &AssetInfo) -> Vec<u8> {
+/// The reference by which Mars oracle identifies an asset
+pub(crate) fn get_asset_reference(asset_info: &AssetInfo) -> Vec<u8> {
     match asset_info {
         AssetInfo::Cw20(contract_addr) => contract_addr.as_bytes().to_vec(),
         AssetInfo::Native(denom) => denom.as_bytes().to_vec(),
@@ -1,18 +1,20 @@
 use cosmwasm_std::{
-    to_binary, Addr, Api, Coin, CosmosMsg, QuerierWrapper, QueryRequest, StdResult, Uint128,
-    WasmMsg, WasmQuery,
+    to_binary, Addr, Api, Coin, CosmosMsg, QuerierWrapper, QueryRequest, StdError, StdResult,
+    Uint128, WasmMsg, WasmQuery,
 };
 use cw20::Cw20ExecuteMsg;
 
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use mars_core::asset::Asset as MarsAsset;
+use mars_core::asset::{Asset as MarsAsset, AssetType as MarsAssetType};
 use mars_core::red_bank::msg::{ExecuteMsg, QueryMsg, ReceiveMsg};
-use mars_core::red_bank::UserAssetDebtResponse;
+use mars_core::red_bank::{MarketsListResponse, UserAssetDebtResponse, UserCollateralResponse};
 
 use cw_asset::{Asset, AssetInfo};
 
+use crate::asset::to_mars_asset;
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct RedBankBase<T> {
     pub contract_addr: T,
@@ -78,6 +80,59 @@ impl RedBank {
         }
     }
 
+    /// Resolve an asset to the exact label under which Red Bank lists its market
+    ///
+    /// Red Bank returns zero debt, instead of an error, for an asset whose label doesn't match any
+    /// market, which would make a position appear debt-free. We look the market up instead, and
+    /// return an error if it is not found. CW20 addresses are matched case-insensitively, as Red
+    /// Bank normalizes them to lower case; native denoms must match exactly
+    pub fn resolve_asset(
+        &self,
+        querier: &QuerierWrapper,
+        asset_info: &AssetInfo,
+    ) -> StdResult<MarsAsset> {
+        let response: MarketsListResponse =
+            querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+                contract_addr: self.contract_addr.to_string(),
+                msg: to_binary(&QueryMsg::MarketsList {})?,
+            }))?;
+
+        let market = response
+            .markets_list
+            .into_iter()
+            .find(|market| match asset_info {
+                AssetInfo::Cw20(contract_addr) => {
+                    market.asset_type == MarsAssetType::Cw20
+                        && market.asset_label.eq_ignore_ascii_case(contract_addr.as_str())
+                }
+                AssetInfo::Native(denom) => {
+                    market.asset_type == MarsAssetType::Native && &market.asset_label == denom
+                }
+            })
+            .ok_or_else(|| {
+                StdError::generic_err(format!("no red bank market found for {}", asset_info))
+            })?;
+
+        Ok(match market.asset_type {
+            MarsAssetType::Cw20 => MarsAsset::Cw20 {
+                contract_addr: market.asset_label,
+            },
+            MarsAssetType::Native => MarsAsset::Native {
+                denom: market.asset_label,
+            },
+        })
+    }
+
+    /// Query the amount of an asset available to be borrowed from Red Bank, i.e. the amount of the
+    /// underlying asset held by Red Bank
+    pub fn query_available_liquidity(
+        &self,
+        querier: &QuerierWrapper,
+        asset_info: &AssetInfo,
+    ) -> StdResult<Uint128> {
+        asset_info.query_balance(querier, &self.contract_addr)
+    }
+
     pub fn query_user_debt(
         &self,
         querier: &QuerierWrapper,
@@ -89,24 +144,39 @@ impl RedBank {
                 contract_addr: self.contract_addr.to_string(),
                 msg: to_binary(&QueryMsg::UserAssetDebt {
                     user_address: user_address.to_string(),
-                    asset: to_mars_asset(asset_info), // NOTE: to be replaced with `into` later
+                    asset: self.resolve_asset(querier, asset_info)?,
                 })?,
             }))?;
         Ok(response.amount)
     }
-}
 
-/// Cast `cw_asset::AssetInfo` to `mars_core::asset::Asset`
-///
-/// NOTE: Once `mars-core` is open sourced and published on crates.io, an `Into<MarsAsset>` trait
-/// will be implemented for `cw_asset::AssetInfo`. This helper function can be removed following that
-fn to_mars_asset(info: &AssetInfo) -> MarsAsset {
-    match info {
-        AssetInfo::Cw20(contract_addr) => MarsAsset::Cw20 {
-            contract_addr: contract_addr.to_string(),
-        },
-        AssetInfo::Native(denom) => MarsAsset::Native {
-            denom: denom.clone(),
-        },
+    /// Query whether a user has enabled an asset as collateral. Red Bank reports collateral as
+    /// a flag per market; assets the user has never deposited are reported as not enabled
+    pub fn query_user_collateral(
+        &self,
+        querier: &QuerierWrapper,
+        user_address: &Addr,
+        asset_info: &AssetInfo,
+    ) -> StdResult<bool> {
+        let asset = self.resolve_asset(querier, asset_info)?;
+        let asset_label = match &asset {
+            MarsAsset::Cw20 {
+                contract_addr,
+            } => contract_addr,
+            MarsAsset::Native {
+                denom,
+            } => denom,
+        };
+        let response: UserCollateralResponse =
+            querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+                contract_addr: self.contract_addr.to_string(),
+                msg: to_binary(&QueryMsg::UserCollateral {
+                    user_address: user_address.to_string(),
+                })?,
+            }))?;
+        Ok(response
+            .collateral
+            .iter()
+            .any(|collateral| &collateral.asset_label == asset_label && collateral.enabled))
     }
 }
@@ -0,0 +1,91 @@
+use cosmwasm_std::{to_binary, Addr, Api, Coin, CosmosMsg, StdResult, WasmMsg};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use cw_asset::{AssetInfo, AssetList};
+
+/// Interface of a refund batcher, a contract that receives refunds on behalf of many users and
+/// credits them internally, so that they can be settled in fewer taxed transfers
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RefundBatcherExecuteMsg {
+    /// Credit native coins sent along with this message to the recipient
+    Credit {
+        recipient: String,
+    },
+}
+
+/// Hook message to be sent along with CW20 tokens via `Cw20ExecuteMsg::Send`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RefundBatcherCw20HookMsg {
+    /// Credit CW20 tokens received to the recipient
+    Credit {
+        recipient: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct RefundBatcherBase<T> {
+    pub contract_addr: T,
+}
+
+pub type RefundBatcherUnchecked = RefundBatcherBase<String>;
+pub type RefundBatcher = RefundBatcherBase<Addr>;
+
+impl From<RefundBatcher> for RefundBatcherUnchecked {
+    fn from(refund_batcher: RefundBatcher) -> Self {
+        RefundBatcherUnchecked {
+            contract_addr: refund_batcher.contract_addr.to_string(),
+        }
+    }
+}
+
+impl RefundBatcherUnchecked {
+    pub fn check(&self, api: &dyn Api) -> StdResult<RefundBatcher> {
+        Ok(RefundBatcher {
+            contract_addr: api.addr_validate(&self.contract_addr)?,
+        })
+    }
+}
+
+impl RefundBatcher {
+    /// Generate messages that send the assets to the batcher, to be credited to the recipient
+    ///
+    /// All native coins are sent in a single message; each CW20 token is sent in its own message
+    pub fn credit_msgs(&self, assets: &AssetList, recipient: &Addr) -> StdResult<Vec<CosmosMsg>> {
+        let mut msgs: Vec<CosmosMsg> = vec![];
+        let mut funds: Vec<Coin> = vec![];
+
+        for asset in assets.to_vec() {
+            match &asset.info {
+                AssetInfo::Cw20(_) => msgs.push(asset.send_msg(
+                    &self.contract_addr,
+                    to_binary(&RefundBatcherCw20HookMsg::Credit {
+                        recipient: recipient.to_string(),
+                    })?,
+                )?),
+                AssetInfo::Native(denom) => funds.push(Coin {
+                    denom: denom.clone(),
+                    amount: asset.amount,
+                }),
+            }
+        }
+
+        // the bank module requires coins to be sorted by denom
+        funds.sort_by(|a, b| a.denom.cmp(&b.denom));
+
+        if !funds.is_empty() {
+            msgs.push(CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: self.contract_addr.to_string(),
+                msg: to_binary(&RefundBatcherExecuteMsg::Credit {
+                    recipient: recipient.to_string(),
+                })?,
+                funds,
+            }));
+        }
+
+        Ok(msgs)
+    }
+}
@@ -0,0 +1,97 @@
+use cw_asset::{AssetInfo, AssetInfoUnchecked};
+
+use mars_core::asset::Asset as MarsAsset;
+
+/// Astroport's asset types. `cw_asset` types, which are used throughout this crate, convert to and
+/// from these with `From`/`Into`, and compare with them with `PartialEq`, as implemented by the
+/// `legacy` feature of `cw-asset`. They should only be used in messages sent to, and responses
+/// received from, Astroport contracts
+pub use astroport::asset::{Asset as AstroportAsset, AssetInfo as AstroportAssetInfo};
+
+/// Characters used in the data part of a bech32 string, in the order of their 5-bit values
+const BECH32_CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// Generators of the BCH code used in bech32 checksums
+const BECH32_GENERATORS: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+
+/// Checksum constants of bech32 and bech32m strings respectively
+const BECH32_CONSTS: [u32; 2] = [1, 0x2bc830a3];
+
+/// Cast `cw_asset::AssetInfo` to `mars_core::asset::Asset`
+///
+/// NOTE: Once `mars-core` is open sourced and published on crates.io, an `Into<MarsAsset>` trait
+/// will be implemented for `cw_asset::AssetInfo`. This helper function can be removed following that
+pub fn to_mars_asset(info: &AssetInfo) -> MarsAsset {
+    match info {
+        AssetInfo::Cw20(contract_addr) => MarsAsset::Cw20 {
+            contract_addr: contract_addr.to_string(),
+        },
+        AssetInfo::Native(denom) => MarsAsset::Native {
+            denom: denom.clone(),
+        },
+    }
+}
+
+/// The label by which Astroport and Red Bank identify an asset in their events and queries, that
+/// is, the contract address of a CW20 token, or the denom of a native coin
+pub fn asset_label(info: &AssetInfo) -> String {
+    match info {
+        AssetInfo::Cw20(contract_addr) => contract_addr.into(),
+        AssetInfo::Native(denom) => denom.clone(),
+    }
+}
+
+/// Whether an asset label is the address of a CW20 token, rather than the denom of a native coin
+///
+/// Contract addresses are bech32 strings, so a label is classified as CW20 if it has a valid bech32
+/// checksum, whatever its human-readable prefix. Native denoms, including those that happen to start
+/// with a chain's prefix (e.g. `uterra`) or IBC denoms (`ibc/{hash}`), never do
+pub fn is_cw20(label: &str) -> bool {
+    // bech32 strings must not mix cases. addresses in CosmWasm are always lower case
+    if label.chars().any(|c| !c.is_ascii() || c.is_ascii_uppercase()) {
+        return false;
+    }
+
+    // the human-readable part is separated from the data part by the last `1`. the data part ends
+    // with a 6-character checksum
+    let (hrp, data) = match label.rfind('1') {
+        Some(index) => (&label[..index], &label[index + 1..]),
+        None => return false,
+    };
+    if hrp.is_empty() || data.len() < 6 || hrp.bytes().any(|b| !(33..=126).contains(&b)) {
+        return false;
+    }
+
+    let mut values: Vec<u32> = hrp.bytes().map(|b| (b >> 5) as u32).collect();
+    values.push(0);
+    values.extend(hrp.bytes().map(|b| (b & 31) as u32));
+    for c in data.chars() {
+        match BECH32_CHARSET.find(c) {
+            Some(value) => values.push(value as u32),
+            None => return false,
+        }
+    }
+
+    BECH32_CONSTS.contains(&bech32_polymod(&values))
+}
+
+/// Parse an asset label, as found in Astroport's events, into an unchecked asset info
+pub fn asset_info_from_label(label: &str) -> AssetInfoUnchecked {
+    if is_cw20(label) {
+        AssetInfoUnchecked::cw20(label)
+    } else {
+        AssetInfoUnchecked::native(label)
+    }
+}
+
+fn bech32_polymod(values: &[u32]) -> u32 {
+    values.iter().fold(1u32, |checksum, value| {
+        let top = checksum >> 25;
+        let checksum = ((checksum & 0x1ffffff) << 5) ^ value;
+        BECH32_GENERATORS
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| (top >> i) & 1 == 1)
+            .fold(checksum, |checksum, (_, generator)| checksum ^ generator)
+    })
+}
@@ -1,27 +1,53 @@
+use std::cmp;
+use std::fmt;
 use std::str::FromStr;
 
 use cosmwasm_std::{
-    to_binary, Addr, Api, CosmosMsg, Decimal, StdError, StdResult, Uint128, WasmMsg, Empty
+    to_binary, Addr, Api, CosmosMsg, Decimal, QuerierWrapper, StdError, StdResult, Uint128,
+    WasmMsg,
 };
 
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use cw_asset::{AssetInfoBase, AssetUnchecked, AssetInfo, AssetListUnchecked};
+use cw20::{Cw20QueryMsg, TokenInfoResponse};
 
-use crate::adapters::{GeneratorBase, OracleBase, PairBase, RedBankBase};
+use cw_asset::{
+    Asset, AssetBase, AssetInfoBase, AssetUnchecked, AssetInfo, AssetInfoUnchecked, AssetList,
+    AssetListUnchecked,
+};
+
+use crate::adapters::{
+    GeneratorBase, OracleBase, Pair, PairBase, PairUnchecked, RedBankBase, RefundBatcherBase,
+};
 
+// default limits, used if none are provided at instantiation
 const MIN_MAX_LTV: &str = "0.55";
 const MAX_MAX_LTV: &str = "0.75";
 const MAX_FEE_RATE: &str = "0.1";
 const MAX_BONUS_RATE: &str = "0.1";
+const MAX_BORROW_TAX_RATE: &str = "0.01";
+const MAX_DEFAULT_SLIPPAGE_TOLERANCE: &str = "0.05";
+const MAX_LIQUIDATION_MAX_SPREAD: &str = "0.5";
+const MAX_MAX_ALLOWED_SPREAD: &str = "0.1";
+const MAX_DECIMALS: u8 = 18;
+
+/// Number of decimals of native coins
+const NATIVE_DECIMALS: u8 = 6;
+
+/// Number of decimals to which asset amounts are normalized before being valued, so that the values
+/// of assets with different decimals are comparable
+pub const VALUE_DECIMALS: u8 = 6;
 
 //--------------------------------------------------------------------------------------------------
 // Config
 //--------------------------------------------------------------------------------------------------
 
+// fields of type `T` with default values require `T: Serialize` for the schema to include the
+// defaults. such a default is an empty list, so deserializing doesn't require `T: Default`
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
-pub struct ConfigBase<T> {
+#[serde(bound(deserialize = "T: Deserialize<'de>"))]
+pub struct ConfigBase<T: Serialize> {
     /// Info of the primary asset
     ///
     /// Primary asset is the asset which the user takes an implicit long position on when utilizing
@@ -34,6 +60,15 @@ pub struct ConfigBase<T> {
     /// Martian Field. Taking the ANC-UST strategy for example; if the user primarily deposits ANC
     /// and borrows UST from Red Bank, then UST is the secondary asset.
     pub secondary_asset_info: AssetInfoBase<T>,
+    /// Info of the asset borrowed from Red Bank, if different from the secondary asset
+    ///
+    /// Taking an ANC-LUNA strategy for example; the user may borrow UST, which is swapped to LUNA
+    /// using `borrow_pair` before providing liquidity, and bought back with LUNA when repaying. If
+    /// not provided, the secondary asset itself is borrowed.
+    pub borrow_asset_info: Option<AssetInfoBase<T>>,
+    /// Astroport pair consisting of the secondary asset and the borrowed asset. Required if the
+    /// borrowed asset is different from the secondary asset, and unused otherwise
+    pub borrow_pair: Option<PairBase<T>>,
     /// Info of the Astroport token, the staking reward that will be paid out by Astro generator
     ///
     /// Astro generator may also pay out a "proxy reward", e.g. ANC for the ANC-UST strategy. Here
@@ -50,14 +85,29 @@ pub struct ConfigBase<T> {
     ///
     /// This pair is used for swapping ASTRO reward so that it can be reinvested.
     pub astro_pair: PairBase<T>,
+    /// Astroport pairs to swap ASTRO reward through, in order, instead of `astro_pair`, e.g. the
+    /// ASTRO-UST pair then the UST-secondary pair, if the ASTRO pair of the secondary asset has
+    /// thin liquidity. Each hop offers all of the asset returned by the previous one, and the last
+    /// hop must return the secondary asset. If empty, ASTRO is swapped directly using `astro_pair`
+    #[serde(default = "Vec::new")]
+    #[schemars(default = "default_astro_route")]
+    pub astro_route: Vec<PairBase<T>>,
     /// The Astro generator contract
     pub astro_generator: GeneratorBase<T>,
-    /// The Mars Protocol money market contract. We borrow the secondary asset here
+    /// The Mars Protocol money market contract. We borrow the secondary asset, or the borrowed
+    /// asset if one is configured, here
     pub red_bank: RedBankBase<T>,
     /// The Mars Protocol oracle contract. We read prices of the primary and secondary assets here
     pub oracle: OracleBase<T>,
-    /// Account to receive fee payments
-    pub treasury: T,
+    /// An optional second oracle. If provided, health is computed using the more conservative of
+    /// the two oracles' prices for each asset: the lower one when valuing collateral, and the
+    /// higher one when valuing debt
+    pub secondary_oracle: Option<OracleBase<T>>,
+    /// Accounts to receive fee payments, as a list of `(account, weight)`. Fees are split among
+    /// the accounts in proportion to their weights, which must sum to one. Configs written by older
+    /// versions have a single `treasury` account instead, which is converted upon migration
+    #[serde(default)]
+    pub fee_recipients: Vec<(T, Decimal)>,
     /// Account who can update config
     pub governance: T,
     /// Accounts who can harvest
@@ -66,8 +116,160 @@ pub struct ConfigBase<T> {
     pub max_ltv: Decimal,
     /// Percentage of profit to be charged as performance fee
     pub fee_rate: Decimal,
+    /// How the performance fee is charged. Default to `FeeMode::Flat` if not provided
+    #[serde(default)]
+    pub fee_mode: FeeMode,
     /// During liquidation, percentage of the user's asset to be awared to the liquidator as bonus
     pub bonus_rate: Decimal,
+    /// Bonus rates that apply once a position's LTV reaches the given breakpoints, as a list of
+    /// `(ltv, bonus_rate)` in ascending order of LTV. A position is liquidated with the rate of the
+    /// highest breakpoint not above its LTV, or `bonus_rate` if there is no such breakpoint.
+    /// Default to an empty list if not provided
+    #[serde(default)]
+    pub bonus_schedule: Vec<(Decimal, Decimal)>,
+    /// Whether to allow providing liquidity when only one of primary and secondary assets is
+    /// available. If true, half of the available asset is swapped to the other before providing;
+    /// if false, such a provide fails. Default to false if not provided
+    #[serde(default)]
+    pub allow_one_sided_provide: bool,
+    /// Amount of borrowed asset that `Cover` retains in addition to the user's debt, so that the
+    /// subsequent `Repay` can deliver the full debt amount after paying transfer tax. Default to
+    /// zero if not provided
+    #[serde(default)]
+    pub repay_tax_reserve: Uint128,
+    /// Expected rate of transfer tax deducted from the asset borrowed from Red Bank. The
+    /// borrow amount is grossed up so that the amount received net of tax matches the requested
+    /// amount. Default to zero if not provided
+    #[serde(default)]
+    pub borrow_tax_rate: Decimal,
+    /// What to do with ASTRO rewards when harvesting. Default to `AstroHandling::Reinvest` if not
+    /// provided
+    #[serde(default)]
+    pub astro_handling: AstroHandling,
+    /// What to do with the net rewards when harvesting, after reward tokens are swapped to the
+    /// secondary asset. Default to `ReinvestMode::Compound` if not provided
+    #[serde(default)]
+    pub reinvest_mode: ReinvestMode,
+    /// Additional incentive paid to the liquidator on each liquidation, on top of the collateral
+    /// bonus, pro-rated by the portion of the position liquidated. Paid out of a reserve funded by
+    /// governance; skipped if the reserve is insufficient
+    pub liquidation_incentive: Option<AssetBase<T>>,
+    /// If provided, refunds are sent to this batcher contract, which credits the recipients
+    /// internally, instead of being transferred to the recipients directly
+    pub refund_batcher: Option<RefundBatcherBase<T>>,
+    /// Minimum amounts of reward tokens to be swapped during harvest. A reward token whose pending
+    /// amount is below its threshold is not swapped, but left in the pending rewards to accumulate
+    /// until the next harvest. Tokens not listed here are always swapped
+    #[serde(default = "Vec::new")]
+    #[schemars(default = "default_reward_swap_thresholds")]
+    pub reward_swap_thresholds: Vec<(AssetInfoBase<T>, Uint128)>,
+    /// Maximum age, in seconds, of oracle prices used to compute health and to balance assets
+    /// during harvest. Operations relying on older prices fail. If non-zero, the oracle must
+    /// report when each price was last updated. Default to zero, i.e. not checked, if not provided
+    #[serde(default)]
+    pub max_price_age: u64,
+    /// Slippage tolerance used when providing liquidity, if the caller of `Bond` or `Harvest` does
+    /// not specify one. Default to zero, in which case no tolerance is applied, if not provided
+    #[serde(default)]
+    pub default_slippage_tolerance: Decimal,
+    /// If provided, bad debt is repaid to Red Bank with borrowed asset drawn from this account,
+    /// which must have approved this contract to spend it, instead of being socialized among the
+    /// remaining users. Only effective if the borrowed asset is a CW20 token
+    pub insurance_fund: Option<T>,
+    /// Astroport pairs used for swapping reward tokens other than ASTRO and the primary asset to
+    /// the secondary asset during harvest. A reward token without a pair here is not swapped, but
+    /// left in the pending rewards
+    #[serde(default = "Vec::new")]
+    #[schemars(default = "default_reward_pairs")]
+    pub reward_pairs: Vec<(AssetInfoBase<T>, PairBase<T>)>,
+    /// Maximum total value of liquidity tokens bonded by the strategy, denominated in the secondary
+    /// asset. An `UpdatePosition` that leaves the total bond value above the cap is rejected. No
+    /// cap if not provided
+    pub deposit_cap: Option<Uint128>,
+    /// Maximum amount of borrowed asset a single user may owe. A borrow that takes the user's
+    /// debt above the cap is rejected. No cap if not provided
+    pub max_debt_per_user: Option<Uint128>,
+    /// Minimum value of the assets deposited when opening a position, denominated in the
+    /// secondary asset, so that dust positions can't be opened. An existing position may be topped
+    /// up by any amount. Default to zero, i.e. no minimum, if not provided
+    #[serde(default)]
+    pub min_deposit_value: Uint128,
+    /// Whether to check that Red Bank has enough liquidity before borrowing from it, so that a
+    /// borrow exceeding it fails with a clear error. Default to false if not provided
+    #[serde(default)]
+    pub assert_liquidity: bool,
+    /// Maximum number of snapshots of a position kept in storage; the oldest ones are evicted
+    /// first. Zero disables snapshots
+    #[serde(default = "default_max_snapshots")]
+    pub max_snapshots: u32,
+    /// Maximum spread of the swaps selling primary asset during liquidation, if the liquidator does
+    /// not specify one. Default to 5% if not provided
+    #[serde(default = "default_liquidation_max_spread")]
+    pub liquidation_max_spread: Decimal,
+    /// Number of bond units minted per liquidity token bonded when no units exist yet, scaled down
+    /// if the first bond is so large that the units would approach overflow. Default to 1,000,000
+    /// if not provided
+    #[serde(default = "default_units_seed")]
+    pub bond_units_per_share: Uint128,
+    /// Number of debt units minted per unit of asset borrowed when no units exist yet, scaled down
+    /// the same way. Default to 1,000,000 if not provided
+    #[serde(default = "default_units_seed")]
+    pub debt_units_per_asset: Uint128,
+    /// Minimum number of seconds between two harvests, so that rewards can't be harvested in tiny
+    /// amounts whose swaps lose to slippage. Default to zero, i.e. no cooldown, if not provided
+    #[serde(default)]
+    pub harvest_cooldown: u64,
+    /// Maximum spread or slippage tolerance a caller may specify when swapping, providing liquidity
+    /// or harvesting; a larger one is rejected. Default to 10%, the largest value allowed, if not
+    /// provided
+    #[serde(default = "default_max_allowed_spread")]
+    pub max_allowed_spread: Decimal,
+    /// Number of decimals of the primary asset. Queried from the token and cached by the contract
+    /// whenever the config is set; any value provided is overwritten
+    #[serde(default = "default_decimals")]
+    pub primary_asset_decimals: u8,
+    /// Number of decimals of the secondary asset. Queried and cached the same way
+    #[serde(default = "default_decimals")]
+    pub secondary_asset_decimals: u8,
+    /// Number of decimals of the borrowed asset. Queried and cached the same way
+    #[serde(default = "default_decimals")]
+    pub borrow_asset_decimals: u8,
+}
+
+/// Default value of `reward_swap_thresholds` as shown in the JSON schema. schemars can't serialize
+/// the default value of a field whose type is generic, so we provide one of a concrete type
+fn default_reward_swap_thresholds() -> Vec<(AssetInfoUnchecked, Uint128)> {
+    vec![]
+}
+
+/// Default value of `reward_pairs` as shown in the JSON schema
+fn default_reward_pairs() -> Vec<(AssetInfoUnchecked, PairUnchecked)> {
+    vec![]
+}
+
+/// Default value of `astro_route` as shown in the JSON schema
+fn default_astro_route() -> Vec<PairUnchecked> {
+    vec![]
+}
+
+fn default_max_snapshots() -> u32 {
+    30
+}
+
+fn default_liquidation_max_spread() -> Decimal {
+    Decimal::percent(5)
+}
+
+fn default_max_allowed_spread() -> Decimal {
+    Decimal::percent(10)
+}
+
+fn default_decimals() -> u8 {
+    NATIVE_DECIMALS
+}
+
+fn default_units_seed() -> Uint128 {
+    Uint128::new(1_000_000)
 }
 
 pub type ConfigUnchecked = ConfigBase<String>;
@@ -78,18 +280,61 @@ impl From<Config> for ConfigUnchecked {
         ConfigUnchecked {
             primary_asset_info: config.primary_asset_info.into(),
             secondary_asset_info: config.secondary_asset_info.into(),
+            borrow_asset_info: config.borrow_asset_info.map(Into::into),
+            borrow_pair: config.borrow_pair.map(Into::into),
             astro_token_info: config.astro_token_info.into(),
             primary_pair: config.primary_pair.into(),
             astro_pair: config.astro_pair.into(),
+            astro_route: config.astro_route.into_iter().map(Into::into).collect(),
             astro_generator: config.astro_generator.into(),
             red_bank: config.red_bank.into(),
             oracle: config.oracle.into(),
-            treasury: config.treasury.into(),
+            secondary_oracle: config.secondary_oracle.map(Into::into),
+            fee_recipients: config
+                .fee_recipients
+                .into_iter()
+                .map(|(recipient, weight)| (recipient.into(), weight))
+                .collect(),
             governance: config.governance.into(),
             operators: config.operators.iter().map(|op| op.to_string()).collect(),
             max_ltv: config.max_ltv,
             fee_rate: config.fee_rate,
+            fee_mode: config.fee_mode,
             bonus_rate: config.bonus_rate,
+            bonus_schedule: config.bonus_schedule,
+            allow_one_sided_provide: config.allow_one_sided_provide,
+            repay_tax_reserve: config.repay_tax_reserve,
+            borrow_tax_rate: config.borrow_tax_rate,
+            astro_handling: config.astro_handling,
+            reinvest_mode: config.reinvest_mode,
+            liquidation_incentive: config.liquidation_incentive.map(Into::into),
+            refund_batcher: config.refund_batcher.map(Into::into),
+            reward_swap_thresholds: config
+                .reward_swap_thresholds
+                .into_iter()
+                .map(|(info, threshold)| (info.into(), threshold))
+                .collect(),
+            max_price_age: config.max_price_age,
+            default_slippage_tolerance: config.default_slippage_tolerance,
+            insurance_fund: config.insurance_fund.map(Into::into),
+            reward_pairs: config
+                .reward_pairs
+                .into_iter()
+                .map(|(info, pair)| (info.into(), pair.into()))
+                .collect(),
+            deposit_cap: config.deposit_cap,
+            max_debt_per_user: config.max_debt_per_user,
+            min_deposit_value: config.min_deposit_value,
+            assert_liquidity: config.assert_liquidity,
+            max_snapshots: config.max_snapshots,
+            liquidation_max_spread: config.liquidation_max_spread,
+            bond_units_per_share: config.bond_units_per_share,
+            debt_units_per_asset: config.debt_units_per_asset,
+            harvest_cooldown: config.harvest_cooldown,
+            max_allowed_spread: config.max_allowed_spread,
+            primary_asset_decimals: config.primary_asset_decimals,
+            secondary_asset_decimals: config.secondary_asset_decimals,
+            borrow_asset_decimals: config.borrow_asset_decimals,
         }
     }
 }
@@ -99,13 +344,33 @@ impl ConfigUnchecked {
         Ok(Config {
             primary_asset_info: self.primary_asset_info.check(api, None)?,
             secondary_asset_info: self.secondary_asset_info.check(api, None)?,
+            borrow_asset_info: self
+                .borrow_asset_info
+                .as_ref()
+                .map(|info| info.check(api, None))
+                .transpose()?,
+            borrow_pair: self.borrow_pair.as_ref().map(|pair| pair.check(api)).transpose()?,
             astro_token_info: self.astro_token_info.check(api, None)?,
             primary_pair: self.primary_pair.check(api)?,
             astro_pair: self.astro_pair.check(api)?,
+            astro_route: self
+                .astro_route
+                .iter()
+                .map(|pair| pair.check(api))
+                .collect::<StdResult<Vec<Pair>>>()?,
             astro_generator: self.astro_generator.check(api)?,
             red_bank: self.red_bank.check(api)?,
             oracle: self.oracle.check(api)?,
-            treasury: api.addr_validate(&self.treasury)?,
+            secondary_oracle: self
+                .secondary_oracle
+                .as_ref()
+                .map(|oracle| oracle.check(api))
+                .transpose()?,
+            fee_recipients: self
+                .fee_recipients
+                .iter()
+                .map(|(recipient, weight)| Ok((api.addr_validate(recipient)?, *weight)))
+                .collect::<StdResult<Vec<(Addr, Decimal)>>>()?,
             governance: api.addr_validate(&self.governance)?,
             operators: self
                 .operators
@@ -114,40 +379,491 @@ impl ConfigUnchecked {
                 .collect::<StdResult<Vec<Addr>>>()?,
             max_ltv: self.max_ltv,
             fee_rate: self.fee_rate,
+            fee_mode: self.fee_mode.clone(),
             bonus_rate: self.bonus_rate,
+            bonus_schedule: self.bonus_schedule.clone(),
+            allow_one_sided_provide: self.allow_one_sided_provide,
+            repay_tax_reserve: self.repay_tax_reserve,
+            borrow_tax_rate: self.borrow_tax_rate,
+            astro_handling: self.astro_handling.clone(),
+            reinvest_mode: self.reinvest_mode.clone(),
+            liquidation_incentive: self
+                .liquidation_incentive
+                .as_ref()
+                .map(|asset| asset.check(api, None))
+                .transpose()?,
+            refund_batcher: self
+                .refund_batcher
+                .as_ref()
+                .map(|refund_batcher| refund_batcher.check(api))
+                .transpose()?,
+            reward_swap_thresholds: self
+                .reward_swap_thresholds
+                .iter()
+                .map(|(info, threshold)| Ok((info.check(api, None)?, *threshold)))
+                .collect::<StdResult<Vec<(AssetInfo, Uint128)>>>()?,
+            max_price_age: self.max_price_age,
+            default_slippage_tolerance: self.default_slippage_tolerance,
+            insurance_fund: self
+                .insurance_fund
+                .as_ref()
+                .map(|insurance_fund| api.addr_validate(insurance_fund))
+                .transpose()?,
+            reward_pairs: self
+                .reward_pairs
+                .iter()
+                .map(|(info, pair)| Ok((info.check(api, None)?, pair.check(api)?)))
+                .collect::<StdResult<Vec<(AssetInfo, Pair)>>>()?,
+            deposit_cap: self.deposit_cap,
+            max_debt_per_user: self.max_debt_per_user,
+            min_deposit_value: self.min_deposit_value,
+            assert_liquidity: self.assert_liquidity,
+            max_snapshots: self.max_snapshots,
+            liquidation_max_spread: self.liquidation_max_spread,
+            bond_units_per_share: self.bond_units_per_share,
+            debt_units_per_asset: self.debt_units_per_asset,
+            harvest_cooldown: self.harvest_cooldown,
+            max_allowed_spread: self.max_allowed_spread,
+            primary_asset_decimals: self.primary_asset_decimals,
+            secondary_asset_decimals: self.secondary_asset_decimals,
+            borrow_asset_decimals: self.borrow_asset_decimals,
         })
     }
 }
 
 impl Config {
-    pub fn validate(&self) -> StdResult<()> {
-        let min_max_ltv = Decimal::from_str(MIN_MAX_LTV)?;
-        let max_max_ltv = Decimal::from_str(MAX_MAX_LTV)?;
-        if self.max_ltv < min_max_ltv || self.max_ltv > max_max_ltv {
+    /// Validate the config's parameters, the risk parameters against the specified limits
+    pub fn validate(&self, limits: &Limits) -> StdResult<()> {
+        if self.primary_asset_info == self.secondary_asset_info {
+            return Err(StdError::generic_err(
+                "invalid assets: primary and secondary assets must be different",
+            ));
+        }
+
+        if self.borrow_asset_info() == &self.primary_asset_info {
+            return Err(StdError::generic_err(
+                "invalid assets: borrowed asset must be different from primary asset",
+            ));
+        }
+
+        if self.borrow_asset_info() != &self.secondary_asset_info && self.borrow_pair.is_none() {
+            return Err(StdError::generic_err(
+                "invalid borrow pair: required if the borrowed asset is not the secondary asset",
+            ));
+        }
+
+        if self.reinvest_mode == ReinvestMode::RepayDebt
+            && self.borrow_asset_info() != &self.secondary_asset_info
+        {
+            return Err(StdError::generic_err(
+                "invalid reinvest mode: repaying debt requires borrowing the secondary asset",
+            ));
+        }
+
+        if self.max_ltv.is_zero() {
+            return Err(StdError::generic_err("invalid max ltv: must be greater than zero"));
+        }
+
+        if self.max_ltv < limits.min_max_ltv || self.max_ltv > limits.max_max_ltv {
             return Err(StdError::generic_err(format!(
                 "invalid max ltv: {}; must be in [{}, {}]",
-                self.max_ltv, MIN_MAX_LTV, MAX_MAX_LTV
+                self.max_ltv, limits.min_max_ltv, limits.max_max_ltv
             )));
         }
 
-        let max_fee_rate = Decimal::from_str(MAX_FEE_RATE)?;
-        if self.fee_rate > max_fee_rate {
+        if self.fee_rate > limits.max_fee_rate {
             return Err(StdError::generic_err(format!(
                 "invalid fee rate: {}; must be <= {}",
-                self.fee_rate, MAX_FEE_RATE
+                self.fee_rate, limits.max_fee_rate
+            )));
+        }
+
+        let total_weight = self
+            .fee_recipients
+            .iter()
+            .fold(Decimal::zero(), |total, (_, weight)| total + *weight);
+        if total_weight != Decimal::one() {
+            return Err(StdError::generic_err(format!(
+                "invalid fee recipients: weights sum to {}; must be 1",
+                total_weight
             )));
         }
 
-        let max_bonus_rate = Decimal::from_str(MAX_BONUS_RATE)?;
-        if self.bonus_rate > max_bonus_rate {
+        if self.bonus_rate > limits.max_bonus_rate {
             return Err(StdError::generic_err(format!(
                 "invalid bonus rate: {}; must be <= {}",
-                self.bonus_rate, MAX_BONUS_RATE
+                self.bonus_rate, limits.max_bonus_rate
+            )));
+        }
+
+        let max_borrow_tax_rate = Decimal::from_str(MAX_BORROW_TAX_RATE)?;
+        if self.borrow_tax_rate > max_borrow_tax_rate {
+            return Err(StdError::generic_err(format!(
+                "invalid borrow tax rate: {}; must be <= {}",
+                self.borrow_tax_rate, MAX_BORROW_TAX_RATE
+            )));
+        }
+
+        let max_default_slippage_tolerance = Decimal::from_str(MAX_DEFAULT_SLIPPAGE_TOLERANCE)?;
+        if self.default_slippage_tolerance > max_default_slippage_tolerance {
+            return Err(StdError::generic_err(format!(
+                "invalid default slippage tolerance: {}; must be <= {}",
+                self.default_slippage_tolerance, MAX_DEFAULT_SLIPPAGE_TOLERANCE
+            )));
+        }
+
+        if self.bond_units_per_share.is_zero() {
+            return Err(StdError::generic_err(
+                "invalid bond units per share: must be greater than zero",
+            ));
+        }
+
+        if self.debt_units_per_asset.is_zero() {
+            return Err(StdError::generic_err(
+                "invalid debt units per asset: must be greater than zero",
+            ));
+        }
+
+        if self.liquidation_max_spread.is_zero() {
+            return Err(StdError::generic_err(
+                "invalid liquidation max spread: must be greater than zero",
+            ));
+        }
+
+        let max_liquidation_max_spread = Decimal::from_str(MAX_LIQUIDATION_MAX_SPREAD)?;
+        if self.liquidation_max_spread > max_liquidation_max_spread {
+            return Err(StdError::generic_err(format!(
+                "invalid liquidation max spread: {}; must be <= {}",
+                self.liquidation_max_spread, MAX_LIQUIDATION_MAX_SPREAD
+            )));
+        }
+
+        let max_max_allowed_spread = Decimal::from_str(MAX_MAX_ALLOWED_SPREAD)?;
+        if self.max_allowed_spread > max_max_allowed_spread {
+            return Err(StdError::generic_err(format!(
+                "invalid max allowed spread: {}; must be <= {}",
+                self.max_allowed_spread, MAX_MAX_ALLOWED_SPREAD
             )));
         }
 
+        for (i, (ltv, bonus_rate)) in self.bonus_schedule.iter().enumerate() {
+            if i > 0 && *ltv <= self.bonus_schedule[i - 1].0 {
+                return Err(StdError::generic_err(
+                    "invalid bonus schedule: breakpoints must be in ascending order",
+                ));
+            }
+            if *bonus_rate > limits.max_bonus_rate {
+                return Err(StdError::generic_err(format!(
+                    "invalid bonus schedule: bonus rate {} at ltv {}; must be <= {}",
+                    bonus_rate, ltv, limits.max_bonus_rate
+                )));
+            }
+        }
+
+        // percentages of a position's collateral that may be taken during liquidation must sum to
+        // less than 100%, otherwise the refund math underflows: the highest bonus rate that can
+        // apply, and the spread the collateral may be sold at. there is no separate protocol or
+        // insurance fee on liquidations; any such fee introduced later must be included here
+        let max_bonus_rate = self
+            .bonus_schedule
+            .iter()
+            .map(|(_, bonus_rate)| *bonus_rate)
+            .fold(self.bonus_rate, cmp::max);
+        assert_rates_below_one(
+            "liquidation",
+            &[
+                ("bonus rate", max_bonus_rate),
+                ("liquidation max spread", self.liquidation_max_spread),
+            ],
+        )?;
+
+        // likewise for percentages deducted from the assets withdrawn from a position before they
+        // are refunded to the user: the withdrawal fee charged upon unbonding, and the bonus
+        // refunded to the liquidator first if the position is liquidated
+        let withdrawal_fee_rate = match self.fee_mode {
+            FeeMode::Withdrawal => self.fee_rate,
+            _ => Decimal::zero(),
+        };
+        assert_rates_below_one(
+            "refund",
+            &[("withdrawal fee rate", withdrawal_fee_rate), ("bonus rate", max_bonus_rate)],
+        )?;
+
+        if let Some(incentive) = &self.liquidation_incentive {
+            if incentive.amount.is_zero() {
+                return Err(StdError::generic_err(
+                    "invalid liquidation incentive: amount must be greater than zero",
+                ));
+            }
+        }
+
+        for (i, (info, _)) in self.reward_swap_thresholds.iter().enumerate() {
+            if self.reward_swap_thresholds[..i].iter().any(|(other, _)| other == info) {
+                return Err(StdError::generic_err(format!(
+                    "invalid reward swap thresholds: duplicate asset {}",
+                    info
+                )));
+            }
+        }
+
+        for (i, (info, _)) in self.reward_pairs.iter().enumerate() {
+            if self.reward_pairs[..i].iter().any(|(other, _)| other == info) {
+                return Err(StdError::generic_err(format!(
+                    "invalid reward pairs: duplicate asset {}",
+                    info
+                )));
+            }
+            if info == &self.primary_asset_info
+                || info == &self.secondary_asset_info
+                || info == &self.astro_token_info
+            {
+                return Err(StdError::generic_err(format!(
+                    "invalid reward pairs: {} is swapped using a dedicated pair",
+                    info
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Return the slippage tolerance to be used for providing liquidity: the one specified by the
+    /// caller if any, otherwise the configured default if it is non-zero
+    pub fn slippage_tolerance_or_default(
+        &self,
+        slippage_tolerance: Option<Decimal>,
+    ) -> Option<Decimal> {
+        slippage_tolerance.or_else(|| {
+            if self.default_slippage_tolerance.is_zero() {
+                None
+            } else {
+                Some(self.default_slippage_tolerance)
+            }
+        })
+    }
+
+    /// Assert that a spread or slippage tolerance specified by the caller, if any, does not exceed
+    /// `max_allowed_spread`
+    pub fn assert_allowed_spread(&self, name: &str, spread: Option<Decimal>) -> StdResult<()> {
+        match spread {
+            Some(spread) if spread > self.max_allowed_spread => {
+                Err(StdError::generic_err(format!(
+                    "{} too large: {}; must be <= {}",
+                    name, spread, self.max_allowed_spread
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Return the minimum amount of the reward token to be swapped during harvest; zero if no
+    /// threshold is configured for it
+    pub fn reward_swap_threshold(&self, asset_info: &AssetInfo) -> Uint128 {
+        self.reward_swap_thresholds
+            .iter()
+            .find(|(info, _)| info == asset_info)
+            .map(|(_, threshold)| *threshold)
+            .unwrap_or_default()
+    }
+
+    /// Return the bonus rate for liquidating a position at the given LTV
+    pub fn bonus_rate_at(&self, ltv: Decimal) -> Decimal {
+        self.bonus_schedule
+            .iter()
+            .rev()
+            .find(|(breakpoint, _)| *breakpoint <= ltv)
+            .map(|(_, bonus_rate)| *bonus_rate)
+            .unwrap_or(self.bonus_rate)
+    }
+
+    /// Return the asset borrowed from Red Bank, which is the secondary asset unless a different one
+    /// is configured
+    pub fn borrow_asset_info(&self) -> &AssetInfo {
+        self.borrow_asset_info.as_ref().unwrap_or(&self.secondary_asset_info)
+    }
+
+    /// Return the pair to be used for swapping between the secondary and the borrowed assets, or
+    /// `None` if the secondary asset itself is borrowed
+    pub fn borrow_pair(&self) -> Option<&Pair> {
+        if self.borrow_asset_info() == &self.secondary_asset_info {
+            None
+        } else {
+            self.borrow_pair.as_ref()
+        }
+    }
+
+    /// Return the pair to be used for swapping the reward token to the secondary asset, if any
+    pub fn reward_pair(&self, asset_info: &AssetInfo) -> Option<&Pair> {
+        self.reward_pairs.iter().find(|(info, _)| info == asset_info).map(|(_, pair)| pair)
+    }
+
+    /// Return the assets that may be returned from the strategy's swaps, i.e. the primary,
+    /// secondary and borrowed assets, ASTRO, and reward tokens that are sold to the secondary asset
+    pub fn swap_asset_infos(&self) -> Vec<AssetInfo> {
+        let mut asset_infos = vec![
+            self.primary_asset_info.clone(),
+            self.secondary_asset_info.clone(),
+            self.borrow_asset_info().clone(),
+            self.astro_token_info.clone(),
+        ];
+        asset_infos.extend(self.reward_pairs.iter().map(|(info, _)| info.clone()));
+        asset_infos
+    }
+
+    /// Assert that the configured type and liquidity token of every pair match the ones reported by
+    /// the pair contract
+    ///
+    /// Unlike `validate`, this requires querying other contracts, so it is run separately
+    pub fn assert_pairs(&self, querier: &QuerierWrapper) -> StdResult<()> {
+        self.primary_pair.assert_pair_info(querier)?;
+        self.astro_pair.assert_pair_info(querier)?;
+        if let Some(borrow_pair) = self.borrow_pair() {
+            borrow_pair.assert_pair_info(querier)?;
+        }
+        for pair in &self.astro_route {
+            pair.assert_pair_info(querier)?;
+        }
+        for (_, pair) in &self.reward_pairs {
+            pair.assert_pair_info(querier)?;
+        }
+        Ok(())
+    }
+
+    /// Query the decimals of the primary, secondary and borrowed assets, and cache them in the
+    /// config, so that amounts can be normalized without querying the tokens each time
+    ///
+    /// Like `assert_pairs`, this requires querying other contracts, so it is run separately
+    pub fn cache_decimals(&mut self, querier: &QuerierWrapper) -> StdResult<()> {
+        self.primary_asset_decimals = query_decimals(querier, &self.primary_asset_info)?;
+        self.secondary_asset_decimals = query_decimals(querier, &self.secondary_asset_info)?;
+        self.borrow_asset_decimals = query_decimals(querier, self.borrow_asset_info())?;
         Ok(())
     }
+
+    /// Return the cached decimals of an asset. Assets other than the primary, secondary and
+    /// borrowed assets are assumed to have `VALUE_DECIMALS` decimals
+    pub fn decimals_of(&self, info: &AssetInfo) -> u8 {
+        if *info == self.primary_asset_info {
+            self.primary_asset_decimals
+        } else if *info == self.secondary_asset_info {
+            self.secondary_asset_decimals
+        } else if info == self.borrow_asset_info() {
+            self.borrow_asset_decimals
+        } else {
+            VALUE_DECIMALS
+        }
+    }
+
+    /// Convert an amount of an asset to `VALUE_DECIMALS` decimals, so that it can be valued with
+    /// the oracle price and compared with the values of other assets
+    pub fn normalize_amount(&self, info: &AssetInfo, amount: Uint128) -> StdResult<Uint128> {
+        let decimals = u32::from(self.decimals_of(info));
+        let value_decimals = u32::from(VALUE_DECIMALS);
+        if decimals >= value_decimals {
+            Ok(amount / Uint128::new(10u128.pow(decimals - value_decimals)))
+        } else {
+            Ok(amount.checked_mul(Uint128::new(10u128.pow(value_decimals - decimals)))?)
+        }
+    }
+
+    /// The inverse of `normalize_amount`: convert an amount in `VALUE_DECIMALS` decimals back to
+    /// the decimals of the asset
+    pub fn denormalize_amount(&self, info: &AssetInfo, amount: Uint128) -> StdResult<Uint128> {
+        let decimals = u32::from(self.decimals_of(info));
+        let value_decimals = u32::from(VALUE_DECIMALS);
+        if decimals >= value_decimals {
+            Ok(amount.checked_mul(Uint128::new(10u128.pow(decimals - value_decimals)))?)
+        } else {
+            Ok(amount / Uint128::new(10u128.pow(value_decimals - decimals)))
+        }
+    }
+}
+
+/// Query the number of decimals of an asset: read from the token info of a CW20 token, and fixed
+/// for native coins
+pub fn query_decimals(querier: &QuerierWrapper, info: &AssetInfo) -> StdResult<u8> {
+    let decimals = match info {
+        AssetInfoBase::Native(_) => NATIVE_DECIMALS,
+        AssetInfoBase::Cw20(contract_addr) => {
+            let res: TokenInfoResponse =
+                querier.query_wasm_smart(contract_addr, &Cw20QueryMsg::TokenInfo {})?;
+            res.decimals
+        }
+    };
+    if decimals > MAX_DECIMALS {
+        return Err(StdError::generic_err(format!(
+            "unsupported decimals of {}: {}; must be <= {}",
+            info, decimals, MAX_DECIMALS
+        )));
+    }
+    Ok(decimals)
+}
+
+/// Assert that a group of percentages applied to the same collateral sum to less than 100%
+fn assert_rates_below_one(group: &str, rates: &[(&str, Decimal)]) -> StdResult<()> {
+    let sum = rates.iter().fold(Decimal::zero(), |sum, (_, rate)| sum + *rate);
+    if sum >= Decimal::one() {
+        let names = rates.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(" + ");
+        return Err(StdError::generic_err(format!(
+            "invalid {} rates: {} = {}; must be < 1",
+            group, names, sum
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeMode {
+    /// Charge `fee_rate` on all harvested rewards
+    #[default]
+    Flat,
+    /// Charge `fee_rate` only on the appreciation of the value per bond unit above its previous
+    /// high-water mark. No fee is charged while the value is below the high-water mark
+    ///
+    /// The value is measured in units of the specified benchmark asset. The oracle must be able to
+    /// provide prices for all reward tokens (including ASTRO) in this mode
+    HighWaterMark {
+        benchmark: FeeBenchmark,
+    },
+    /// Charge no fee on harvested rewards, which are reinvested in full. Instead, charge `fee_rate`
+    /// when a user unbonds, on the appreciation of the value per bond unit above the value at which
+    /// the user bonded, so that each gain is only charged once
+    ///
+    /// Appreciation is only recognized up to the highest value per bond unit observed at harvest.
+    /// The fee is paid in liquidity tokens to the fee recipients
+    Withdrawal,
+}
+
+/// The asset against which appreciation of the strategy's value is measured
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeBenchmark {
+    Primary,
+    Secondary,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AstroHandling {
+    /// Swap ASTRO rewards to the secondary asset and reinvest them
+    #[default]
+    Reinvest,
+    /// Keep ASTRO rewards in the contract, claimable by governance, e.g. so that they can be
+    /// vote-locked
+    Accumulate,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReinvestMode {
+    /// Balance the net rewards, provide them to the primary pair, and bond the liquidity tokens
+    #[default]
+    Compound,
+    /// Repay the aggregate debt with the net rewards, without providing liquidity. Useful when the
+    /// primary pair is too thin to absorb the balancing swap. Rewards in the primary asset, as well
+    /// as any secondary asset in excess of the debt, are kept in the pending rewards
+    RepayDebt,
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -165,13 +881,20 @@ pub enum Action {
     /// If the asset is a CW20 token, the contract will attempt to draw it from the sender's
     /// wallet. NOTE: sender must have approved spending first
     Deposit(AssetUnchecked),
-    /// Borrow secondary asset of specified amount from Red Bank
+    /// Borrow secondary asset of specified amount from Red Bank. If a different asset is borrowed,
+    /// the amount borrowed is then swapped to the secondary asset using the borrow pair
     Borrow {
         amount: Uint128,
     },
-    /// Repay secondary asset of specified amount to Red Bank
+    /// Repay borrowed asset, which is the secondary asset by default, of specified amount to Red
+    /// Bank
+    ///
+    /// If `user` is provided, repay that user's debt instead of the position's own, using the
+    /// position's unlocked asset. Default to the position's owner if not provided
     Repay {
         amount: Uint128,
+        #[serde(default)]
+        user: Option<String>,
     },
     /// Provide all unlocked primary and secondary asset to Astroport pair, and bond the
     /// received liquidity tokens to the staking pool
@@ -184,21 +907,185 @@ pub enum Action {
     },
     /// Burn a specified amount bond units, unbond liquidity tokens of corresponding amount from
     /// the staking pool and withdraw liquidity
+    ///
+    /// If `swap_to` is provided, which must be either the primary or the secondary asset, all of
+    /// the user's unlocked units of the other asset are then swapped to it, so that the user is
+    /// refunded a single asset
     Unbond {
         bond_units_to_reduce: Uint128,
+        swap_to: Option<AssetInfoUnchecked>,
     },
     /// Swap a specified amount of unlocked primary asset to the secondary asset
+    ///
+    /// If `min_secondary_from_swap` is provided, the whole transaction reverts if the swap returns
+    /// less secondary asset than this amount, e.g. because the pool was skewed by a front-runner
     Swap {
         offer_amount: Uint128,
         max_spread: Option<Decimal>,
+        min_secondary_from_swap: Option<Uint128>,
+    },
+    /// Swap unlocked primary and secondary assets such that the two are in the pool's ratio after
+    /// the swap, then provide and bond them the same way as `Bond`. This allows a user who only
+    /// has one of the two assets to bond in a single transaction
+    Zap {
+        slippage_tolerance: Option<Decimal>,
     },
+    /// Exit the position entirely: burn all of the user's bond units, withdraw liquidity, sell
+    /// just enough primary asset to cover the debt, repay all of the debt, and refund the rest
+    ///
+    /// This is the same pipeline as a full liquidation, except the user can invoke it at any LTV
+    /// and no bonus is paid. The primary asset is sold with the config's `liquidation_max_spread`.
+    /// NOTE: the bond units burned are those the user has when the message is executed, so bond
+    /// units added by earlier actions in the same list are not included
+    Close {},
+}
+
+/// The kind of an action, without its parameters. Used to specify which actions a keeper is
+/// authorized to perform on a user's behalf
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionKind {
+    Deposit,
+    Borrow,
+    Repay,
+    Bond,
+    Unbond,
+    Swap,
+    Zap,
+    Close,
+}
+
+impl fmt::Display for ActionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ActionKind::Deposit => "deposit",
+            ActionKind::Borrow => "borrow",
+            ActionKind::Repay => "repay",
+            ActionKind::Bond => "bond",
+            ActionKind::Unbond => "unbond",
+            ActionKind::Swap => "swap",
+            ActionKind::Zap => "zap",
+            ActionKind::Close => "close",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Action {
+    pub fn kind(&self) -> ActionKind {
+        match self {
+            Action::Deposit(_) => ActionKind::Deposit,
+            Action::Borrow { .. } => ActionKind::Borrow,
+            Action::Repay { .. } => ActionKind::Repay,
+            Action::Bond { .. } => ActionKind::Bond,
+            Action::Unbond { .. } => ActionKind::Unbond,
+            Action::Swap { .. } => ActionKind::Swap,
+            Action::Zap { .. } => ActionKind::Zap,
+            Action::Close {} => ActionKind::Close,
+        }
+    }
+}
+
+/// Actions a keeper is authorized to perform on a user's position
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Default)]
+pub struct Authorization {
+    pub actions: Vec<ActionKind>,
+}
+
+/// A record of a user's position at the end of an `UpdatePosition`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Snapshot {
+    pub height: u64,
+    pub time: u64,
+    pub position: PositionResponse,
+}
+
+/// A past config, recorded when it is replaced by `UpdateConfig`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ConfigHistoryEntry {
+    /// Sequence number of the entry, increasing with each config update
+    pub id: u64,
+    /// The config that was replaced
+    pub config: ConfigUnchecked,
+    /// Block height at which the config was replaced
+    pub changed_at_height: u64,
+    /// Block time at which the config was replaced
+    pub changed_at_time: u64,
+    /// Account who replaced the config
+    pub changed_by: String,
+}
+
+/// Bounds of the config's risk parameters, which are enforced whenever the config is validated.
+/// Set once at instantiation, so that a strategy on a more conservative collateral can tighten them
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Limits {
+    /// Lower bound of `max_ltv`
+    pub min_max_ltv: Decimal,
+    /// Upper bound of `max_ltv`
+    pub max_max_ltv: Decimal,
+    /// Upper bound of `fee_rate`
+    pub max_fee_rate: Decimal,
+    /// Upper bound of `bonus_rate`, as well as of the bonus rates of `bonus_schedule`
+    pub max_bonus_rate: Decimal,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        // the constants are valid decimals, so parsing them never fails
+        Self {
+            min_max_ltv: Decimal::from_str(MIN_MAX_LTV).unwrap(),
+            max_max_ltv: Decimal::from_str(MAX_MAX_LTV).unwrap(),
+            max_fee_rate: Decimal::from_str(MAX_FEE_RATE).unwrap(),
+            max_bonus_rate: Decimal::from_str(MAX_BONUS_RATE).unwrap(),
+        }
+    }
+}
+
+impl Limits {
+    pub fn validate(&self) -> StdResult<()> {
+        if self.min_max_ltv.is_zero() || self.min_max_ltv > self.max_max_ltv {
+            return Err(StdError::generic_err(format!(
+                "invalid limits: min max ltv {}; must be in (0, {}]",
+                self.min_max_ltv, self.max_max_ltv
+            )));
+        }
+
+        if self.max_max_ltv >= Decimal::one() {
+            return Err(StdError::generic_err(format!(
+                "invalid limits: max max ltv {}; must be < 1",
+                self.max_max_ltv
+            )));
+        }
+
+        if self.max_fee_rate > Decimal::one() {
+            return Err(StdError::generic_err(format!(
+                "invalid limits: max fee rate {}; must be <= 1",
+                self.max_fee_rate
+            )));
+        }
+
+        if self.max_bonus_rate >= Decimal::one() {
+            return Err(StdError::generic_err(format!(
+                "invalid limits: max bonus rate {}; must be < 1",
+                self.max_bonus_rate
+            )));
+        }
+
+        Ok(())
+    }
 }
 
 //--------------------------------------------------------------------------------------------------
 // Message types
 //--------------------------------------------------------------------------------------------------
 
-pub type InstantiateMsg = ConfigUnchecked;
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct InstantiateMsg {
+    pub config: ConfigUnchecked,
+    /// Bounds the config is validated against, at instantiation as well as on every config update.
+    /// Immutable once instantiated. If not provided, the default limits are used
+    pub limits: Option<Limits>,
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
@@ -211,28 +1098,131 @@ pub enum ExecuteMsg {
     /// 1. Refund all unlocked assets to the user.
     ///
     /// 2. Assert the position's LTV is below the liquidation threshold. If not, throw an error
-    /// and revert all previous actions
+    ///    and revert all previous actions. Skipped if the actions can only reduce the position's
+    ///    risk, i.e. they consist of deposits, repayments, and (if the position has no debt)
+    ///    unbonding
     ///
     /// 3. Delete cached data in storage
     UpdatePosition(Vec<Action>),
+    /// Update the position of a user who has authorized the sender as a keeper, by executing a list
+    /// of actions. Each action must be of a kind the user has authorized
+    ///
+    /// The same callbacks are executed afterwards as for `UpdatePosition`. Unlocked assets are
+    /// always refunded to the user, never to the keeper, and a keeper can only repay the user's own
+    /// debt
+    UpdatePositionFor {
+        user: String,
+        actions: Vec<Action>,
+    },
+    /// Authorize a keeper to perform the specified kinds of actions on the sender's position,
+    /// replacing any existing authorization of the same keeper
+    AuthorizeKeeper {
+        keeper: String,
+        actions: Vec<ActionKind>,
+    },
+    /// Revoke a keeper's authorization to manage the sender's position
+    RevokeKeeper {
+        keeper: String,
+    },
     /// Claim staking reward and reinvest
     ///
     /// `max_spread` is used for ASTRO >> secondary swap and balancing operations
     ///
     /// `slippage_tolerance` is used for providing primary + secondary liquidity
+    ///
+    /// `min_lp_out` is the minimum amount of liquidity tokens reinvesting must mint, otherwise the
+    /// harvest is reverted. This protects against sandwiching between the swaps and the provision
     Harvest {
         max_spread: Option<Decimal>,
         slippage_tolerance: Option<Decimal>,
+        min_lp_out: Option<Uint128>,
     },
     /// Force close an underfunded position, repay all debts, and return all remaining funds to
     /// the position's owner. The liquidator is awarded a portion of the remaining funds.
+    ///
+    /// If `max_bond_units` is provided, only liquidate enough of the position to bring its LTV back
+    /// to `max_ltv`, and no more than `max_bond_units`. The liquidated portion is used to repay the
+    /// debt, with the liquidator awarded a portion of it, and the position remains open. If
+    /// provided, `max_bond_units` must be greater than zero
+    ///
+    /// `max_spread` is used for selling the primary asset to repay the debt. It may be tighter
+    /// than, but not exceed, the config's `liquidation_max_spread`, which is used if not provided
+    ///
+    /// By default, the bonus is paid out of what remains after repayment in a full liquidation,
+    /// which is mostly the secondary asset, or out of the assets withdrawn in a partial one. If
+    /// `bonus_asset` is the primary asset, the bonus is instead paid in the primary asset, refunded
+    /// before the primary asset is sold, and worth the same as by default: the bonus rate of the
+    /// position's bond value less its debt value in a full liquidation, or of the liquidated
+    /// portion's bond value in a partial one
     Liquidate {
         user: String,
+        max_bond_units: Option<Uint128>,
+        max_spread: Option<Decimal>,
+        bonus_asset: Option<AssetInfoUnchecked>,
     },
     /// Update data stored in config (only governance can call)
+    ///
+    /// The `governance` field of the new config is ignored; governance can only be transferred
+    /// with `ProposeNewGovernance` and `AcceptGovernance`
     UpdateConfig {
         new_config: ConfigUnchecked,
     },
+    /// Directly set the bond and debt units of an individual position, adjusting the global totals
+    /// accordingly (only governance can call)
+    ///
+    /// This is a break-glass tool, intended for correcting accounting that has come out of sync
+    /// with the Astro generator or Red Bank. It should not be used under normal operation
+    AdminAdjustPosition {
+        user: String,
+        bond_units: Uint128,
+        debt_units: Uint128,
+    },
+    /// Deposit the liquidation incentive asset into the reserve from which liquidators are paid
+    /// (only governance can call)
+    ///
+    /// For native assets, the amount must be sent along with the message; for CW20 tokens, the
+    /// contract must have been granted sufficient allowance
+    FundIncentiveReserve {
+        amount: Uint128,
+    },
+    /// Transfer all accumulated ASTRO rewards to the recipient (only governance can call)
+    WithdrawAccumulatedAstro {
+        recipient: String,
+    },
+    /// Transfer an asset sent to the contract by mistake to the recipient (only governance can
+    /// call)
+    ///
+    /// Assets held by the strategy on behalf of its users, i.e. the primary, secondary and borrowed
+    /// assets, ASTRO, the liquidity token, reward tokens and the liquidation incentive asset, can't
+    /// be swept
+    Sweep {
+        asset: AssetUnchecked,
+        recipient: String,
+    },
+    /// Pause or unpause the strategy (only governance can call)
+    ///
+    /// While paused, actions that increase exposure (`Deposit`, `Borrow`, `Bond` and `Swap`) are
+    /// rejected, whereas `Repay` and `Unbond` remain available so that users can de-risk.
+    /// Liquidations are not affected
+    SetPaused {
+        paused: bool,
+    },
+    /// Propose a new governance address, which takes effect once it calls `AcceptGovernance`
+    /// (only governance can call). Overwrites any pending proposal
+    ProposeNewGovernance {
+        new_governance: String,
+    },
+    /// Accept a pending governance transfer (only the proposed new governance can call)
+    AcceptGovernance {},
+    /// Add an account to the list of operators (only governance can call). If the account is
+    /// already an operator, nothing is changed
+    AddOperator {
+        operator: String,
+    },
+    /// Remove an account from the list of operators (only governance can call)
+    RemoveOperator {
+        operator: String,
+    },
     /// Callbacks; only callable by the strategy itself.
     Callback(CallbackMsg),
 }
@@ -242,12 +1232,22 @@ pub enum ExecuteMsg {
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum CallbackMsg {
+    /// Credit the user with the amounts of CW20 tokens actually received from deposits, that is,
+    /// the increase of the contract's balances since `balances_before`. For a token that charges
+    /// a fee on transfer, this is less than the amount specified in the `Deposit` action
+    ///
+    /// _Only used during the `UpdatePosition` function call_
+    CreditDeposits {
+        user_addr: Addr,
+        balances_before: AssetList,
+    },
     /// Provide unlocked primary & secondary assets to the AMM pool, receive share tokens;
     /// Reduce the user's unlocked primary & secondary asset amounts to zero;
     /// Increase the user's unlocked share token amount
     ProvideLiquidity {
         user_addr: Option<Addr>,
         slippage_tolerance: Option<Decimal>,
+        min_lp_out: Option<Uint128>,
     },
     /// Burn the user's unlocked share tokens, receive primary & secondary assets;
     /// Reduce the user's unlocked share token amount to zero;
@@ -268,49 +1268,73 @@ pub enum CallbackMsg {
         user_addr: Addr,
         bond_units_to_reduce: Uint128,
     },
-    /// Borrow specified amount of secondary asset from Red Bank;
+    /// Borrow specified amount of borrowed asset from Red Bank;
     /// Increase the user's debt units;
-    /// Increase the user's unlocked secondary asset amount
+    /// Increase the user's unlocked borrowed asset amount
     Borrow {
         user_addr: Addr,
         borrow_amount: Uint128,
     },
-    /// Repay specified amount of secondary asset to Red Bank;
+    /// Repay specified amount of borrowed asset to Red Bank;
     /// Reduce the user's debt units;
-    /// Reduce the user's unlocked secondary asset amount
+    /// Reduce the user's unlocked borrowed asset amount
     ///
-    /// If `repay_amount` is not provided, then use all available unlocked secondary asset
+    /// If `repay_amount` is not provided, then use all available unlocked borrowed asset
+    ///
+    /// If `user_addr` is not provided, repay the aggregate debt using the pending rewards, without
+    /// reducing any debt units; this is used during the `Harvest` function call in
+    /// `ReinvestMode::RepayDebt`
     Repay {
-        user_addr: Addr,
+        user_addr: Option<Addr>,
         repay_amount: Option<Uint128>,
     },
+    /// Repay specified amount of borrowed asset to Red Bank on behalf of another user;
+    /// Reduce the debtor's debt units;
+    /// Reduce the user's unlocked borrowed asset amount
+    ///
+    /// The amount repaid is capped at the debtor's debt
+    RepayFor {
+        user_addr: Addr,
+        debtor_addr: Addr,
+        repay_amount: Uint128,
+    },
     /// Swap a specified amount of primary asset to secondary asset;
     /// Reduce the user's unlocked primary asset amount;
     /// Increase the user's unlocked secondary asset amount;
     ///
     /// If `swap_amount` is not provided, then use all available unlocked asset
+    ///
+    /// If `min_return` is provided, throw an error if the swap returns less than this amount
     Swap {
         user_addr: Option<Addr>,
         offer_asset_info: AssetInfo,
         offer_amount: Option<Uint128>,
         max_spread: Option<Decimal>,
+        min_return: Option<Uint128>,
     },
-    /// Swap the primary and secondary assets currently held by the contract as pending rewards,
-    /// such that the two assets have the same value and can be reinvested
+    /// Swap the primary and secondary assets, such that after the swap the two assets are in the
+    /// pool's ratio and can be provided to the pool in full. The swap amount accounts for the
+    /// commission and the price impact of the swap itself
     ///
-    /// _Only used during the `Harvest` function call_
+    /// If `user_addr` is provided, swap the user's unlocked assets; this is used by the `Zap`
+    /// action. If not, swap the assets currently held by the contract as pending rewards; this is
+    /// used during the `Harvest` function call
     Balance {
+        user_addr: Option<Addr>,
         max_spread: Option<Decimal>,
     },
     /// Sell an appropriate amount of a user's unlocked primary asset, such that the user has
-    /// enough unlocked secondary asset to fully pay off debt
+    /// enough unlocked borrowed asset to fully pay off debt. If the borrowed asset is not the
+    /// secondary asset, secondary asset is sold for it, after selling primary asset for the
+    /// secondary asset if needed
     ///
     /// _Only used during the `Liquidate` function call_
     Cover {
         user_addr: Addr,
+        max_spread: Decimal,
     },
     /// Send a percentage of a user's unlocked primary & seoncdary asset to a recipient; default
-    /// to the user if unspecified
+    /// to the user if unspecified. The percentage must not exceed 100%
     ///
     /// Reduce the user's primary & secondary asset amounts
     Refund {
@@ -318,13 +1342,28 @@ pub enum CallbackMsg {
         recipient_addr: Addr,
         percentage: Decimal,
     },
+    /// Send up to the specified amount of an asset from a user's unlocked assets to a recipient;
+    /// if the user holds less, all of it is sent
+    ///
+    /// _Only used during `Liquidate`, to pay a bonus elected in the primary asset_
+    RefundAsset {
+        user_addr: Addr,
+        recipient_addr: Addr,
+        asset: Asset,
+    },
     /// Calculate a user's current LTV. If below the maximum LTV, emits a `position_updated`
     /// event; if above the maximum LTV, throw an error
     AssertHealth {
         user_addr: Addr,
     },
-    /// Check whether the user still has an outstanding debt. If no, do nothing. If yes, waive
-    /// the debt from the user's position, and emit a `bad_debt` event
+    /// Assert the total value of liquidity tokens bonded by the strategy does not exceed the
+    /// deposit cap; if it does, throw an error
+    ///
+    /// _Only used during the `UpdatePosition` function call, if a deposit cap is configured_
+    AssertDepositCap {},
+    /// Check whether the user still has an outstanding debt. If no, or if the position still has
+    /// liquidity tokens bonded, do nothing. If yes, waive the debt from the user's position, and
+    /// emit a `bad_debt` event
     ///  
     /// Effectively, the bad debt is shared by all other users. An altrustic person can monitor
     /// the event and repay the same amount of debt at Red Bank on behalf of the Fields contract,
@@ -332,11 +1371,22 @@ pub enum CallbackMsg {
     ClearBadDebt {
         user_addr: Addr,
     },
+    /// Record a snapshot of the user's position, and evict the oldest ones if the user has more
+    /// than `max_snapshots`. Skipped if the position's health can't be computed, e.g. on stale
+    /// prices
+    ///
+    /// _Only used during the `UpdatePosition` function call, if snapshots are enabled_
+    Snapshot {
+        user_addr: Addr,
+    },
     /// Remove the user's position from contract storage if it is empty. Invoked at the end of
     /// `update_position` and `liquidate` callback chains
     PurgeStorage {
         user_addr: Addr,
     },
+    /// Release the lock acquired by `update_position`, `harvest` or `liquidate`. Always dispatched
+    /// as the last message of these functions, so that the lock is held for the whole chain
+    ReleaseLock {},
 }
 
 impl CallbackMsg {
@@ -354,29 +1404,149 @@ impl CallbackMsg {
 pub enum QueryMsg {
     /// Return strategy configurations. Response: `ConfigUnchecked`
     Config {},
-    /// Return the global state of the strategy. Response: `PositionResponse`
+    /// Return the limits the strategy's config is validated against. Response: `Limits`
+    Limits {},
+    /// Return the global state of the strategy, i.e. the aggregate position of all users, with
+    /// live bond and debt amounts, their values and the strategy-wide LTV. Response:
+    /// `PositionResponse`
     State {},
     /// Return data on an individual user's position. Response: `PositionResponse`
     Position {
         user: String,
     },
+    /// Return the health of an individual user's position, or of the whole strategy if no user is
+    /// provided. Zero for a user without a position. Response: `HealthResponse`
+    Health {
+        user: Option<String>,
+    },
     /// Enumerate all user positions. Response: `Vec<PositionsResponseItem>`
+    ///
+    /// Positions are paginated in the order of user addresses. If `order_by` is provided, the
+    /// positions within the returned page are then sorted accordingly. Sorting all positions would
+    /// take too much gas, so only the page is sorted, and `limit` is capped at 30
     Positions {
         start_after: Option<String>,
         limit: Option<u32>,
+        order_by: Option<PositionOrder>,
+    },
+    /// Enumerate positions whose LTV is above the maximum, i.e. those that can be liquidated.
+    /// Response: `Vec<LiquidatablePositionsResponseItem>`
+    ///
+    /// Results are a snapshot of health at the time of query, and may change within the same block
+    /// as prices move or positions are updated
+    LiquidatablePositions {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// Return a point-in-time view of the whole strategy, consistent within the block.
+    /// Response: `StateSnapshotResponse`
+    StateSnapshot {},
+    /// Return all administrative state of the strategy in one read. Response: `AdminStatusResponse`
+    AdminStatus {},
+    /// Estimate how much a liquidator would gain by liquidating a position at current prices.
+    /// Response: `LiquidationProfitResponse`
+    LiquidationProfit {
+        user: String,
+    },
+    /// Return the parameters of the `Liquidate` call a liquidator should submit for a position, as
+    /// computed by the contract's own liquidation logic. Response: `LiquidationParamsResponse`
+    LiquidationParams {
+        user: String,
+    },
+    /// Preview the outcome of `UpdatePosition` with the given actions, for the given user or a new
+    /// one, without executing it. Response: `SimulateResponse`
+    SimulateUpdatePosition {
+        user: Option<String>,
+        actions: Vec<Action>,
+    },
+    /// Return rewards accumulated since the last harvest, and their values.
+    /// Response: `Vec<PendingRewardsResponseItem>`
+    PendingRewards {},
+    /// Return the actions a keeper is authorized to perform on a user's position.
+    /// Response: `Authorization`
+    Authorization {
+        user: String,
+        keeper: String,
+    },
+    /// Enumerate snapshots of a user's position, newest first. Response: `Vec<Snapshot>`
+    Snapshots {
+        user: String,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// Enumerate past configs replaced by `UpdateConfig`, newest first, paginated by entry id. Only
+    /// the most recent 50 are retained. Response: `Vec<ConfigHistoryEntry>`
+    ConfigHistory {
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// Return the largest amount of borrowed asset a user can borrow on top of their current debt
+    /// without exceeding the max LTV; zero if the position is unhealthy. Response: `Uint128`
+    MaxBorrow {
+        user: String,
     },
+    /// Compare the strategy's total debt as reported by Red Bank with the sum of the debt of all
+    /// positions, as derived from their debt units, so that keepers can alarm on a drift. This
+    /// iterates over all positions, so it may run out of gas if there are many.
+    /// Response: `ReconcileResponse`
+    Reconcile {},
+    /// Return the fees collected over the strategy's lifetime. Response: `FeeStatsResponse`
+    FeeStats {},
 }
 
 //--------------------------------------------------------------------------------------------------
 // Response types
 //--------------------------------------------------------------------------------------------------
 
+/// Order in which `QueryMsg::Positions` sorts the positions within a page
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PositionOrder {
+    /// Largest bond value first
+    BondValueDesc,
+    /// Highest LTV first. Positions without debt, whose LTV is undefined, come last
+    LtvDesc,
+    /// Largest debt value first
+    DebtValueDesc,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct PositionsResponseItem {
     pub user: String,
     pub position: PositionResponse,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct LiquidatablePositionsResponseItem {
+    pub user: String,
+    pub ltv: Decimal,
+    pub bond_value: Uint128,
+    pub debt_value: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct FeeStatsResponse {
+    /// Cumulative fees transferred to the fee recipients, including both the fees charged on
+    /// harvested rewards and the withdrawal fees
+    pub total_fees_collected: AssetListUnchecked,
+    /// Timestamp, in seconds, of the last harvest
+    pub last_harvest_time: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ReconcileResponse {
+    /// Amount of borrowed asset the strategy owes Red Bank, as reported by Red Bank
+    pub red_bank_debt: Uint128,
+    /// Sum of the debt units of all positions, converted to an amount of borrowed asset at the
+    /// strategy's total debt units. Equals `red_bank_debt` if the debt units are consistent
+    pub internal_debt: Uint128,
+    /// Absolute difference between `red_bank_debt` and `internal_debt`
+    pub delta: Uint128,
+    /// Whether the strategy has enabled the borrowed asset as collateral at Red Bank. The
+    /// strategy borrows against an uncollateralized credit line, so this is expected to be false
+    pub collateral_enabled: bool,
+}
+
 /// `PositionResponse` is used both to describe an individual position, as well as the overall state
 /// of the strategy
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -384,6 +1554,14 @@ pub struct PositionResponse {
     pub bond_units: Uint128,
     pub bond_amount: Uint128,
     pub bond_value: Uint128,
+    /// Amount of primary asset the bonded liquidity tokens are redeemable for at the pool's
+    /// current depths
+    #[serde(default)]
+    pub underlying_primary: Uint128,
+    /// Amount of secondary asset the bonded liquidity tokens are redeemable for at the pool's
+    /// current depths
+    #[serde(default)]
+    pub underlying_secondary: Uint128,
     pub debt_units: Uint128,
     pub debt_amount: Uint128,
     pub debt_value: Uint128,
@@ -391,5 +1569,138 @@ pub struct PositionResponse {
     pub unlocked_assets: AssetListUnchecked,
 }
 
-/// We currently don't need any input parameter for migration
-pub type MigrateMsg = Empty;
+/// Values of a position's bonded liquidity tokens and debt, measured in the oracle's base unit
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct HealthResponse {
+    pub bond_amount: Uint128,
+    pub bond_value: Uint128,
+    pub debt_amount: Uint128,
+    pub debt_value: Uint128,
+    /// `None` if the position has no bonded liquidity tokens
+    pub ltv: Option<Decimal>,
+}
+
+/// Comprehensive view of the strategy at a single block, for off-chain accounting
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct StateSnapshotResponse {
+    /// Version of the layout of this response; incremented whenever its fields are changed
+    pub snapshot_version: u32,
+    /// Block height at which the snapshot is taken
+    pub height: u64,
+    /// Block timestamp, in seconds, at which the snapshot is taken
+    pub time: u64,
+    pub config: ConfigUnchecked,
+    pub total_bond_units: Uint128,
+    pub total_debt_units: Uint128,
+    pub pending_rewards: AssetListUnchecked,
+    /// Cumulative amount of secondary asset debt waived as bad debt
+    pub total_bad_debt: Uint128,
+    /// Aggregate health of all positions; same as the response of `QueryMsg::State`
+    pub health: PositionResponse,
+}
+
+/// Administrative state of the strategy, consolidated for governance dashboards. Fields will be
+/// added here as more administrative features are introduced
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AdminStatusResponse {
+    /// Account who can update config
+    pub governance: String,
+    /// Accounts who can harvest
+    pub operators: Vec<String>,
+    /// Accounts to receive fee payments, and their weights
+    pub fee_recipients: Vec<(String, Decimal)>,
+    /// Whether actions that increase exposure are disabled
+    pub paused: bool,
+    /// Whether `Deposit` actions are accepted; they are rejected while paused
+    pub deposits_enabled: bool,
+    /// Maximum total value of liquidity tokens bonded by the strategy, denominated in the secondary
+    /// asset; `None` if there is no cap
+    pub deposit_cap: Option<Uint128>,
+    /// Account proposed as the new governance, who has not accepted yet
+    pub pending_governance: Option<String>,
+}
+
+/// Estimated gain of liquidating a position. All values are denominated in the oracle's base unit
+///
+/// The liquidator does not need to provide any asset, since the position's debt is repaid by
+/// selling its own primary asset. The cost of this swap reduces the collateral remaining after
+/// repayment, and therefore the bonus. If the position is not liquidatable, or is underwater so that nothing
+/// remains after repayment, all values are zero except the incentive (if applicable)
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct LiquidationProfitResponse {
+    /// Whether the position can be liquidated at current prices
+    pub liquidatable: bool,
+    /// Value of the collateral bonus paid to the liquidator
+    pub bonus_value: Uint128,
+    /// Value of the liquidation incentive paid out of the reserve, if any
+    pub incentive_value: Uint128,
+    /// Estimated value lost to slippage and commission when selling primary asset to repay debt
+    pub swap_cost_value: Uint128,
+    /// Total value gained by the liquidator, i.e. `bonus_value + incentive_value`
+    pub profit_value: Uint128,
+}
+
+/// Submitting `ExecuteMsg::Liquidate` with `max_bond_units` set to the returned value liquidates
+/// the minimum amount needed to bring the position's LTV back to `max_ltv`, or the whole position
+/// if that is what it takes. As with `LiquidationProfitResponse`, the liquidator does not need to
+/// attach any asset. If the position is not liquidatable, all values are zero
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct LiquidationParamsResponse {
+    /// Whether the position can be liquidated at current prices
+    pub liquidatable: bool,
+    /// Amount of bond units to liquidate, to be submitted as `max_bond_units`
+    pub max_bond_units: Uint128,
+    /// Whether this amount covers the whole position, in which case a full liquidation is done
+    pub full_liquidation: bool,
+    /// Expected value of the collateral bonus paid to the liquidator
+    pub bonus_value: Uint128,
+}
+
+/// Outcome of a simulated `UpdatePosition`, computed the same way as the callbacks do. Amounts
+/// returned by the pool are estimated from its current reserves, so they may differ from actual
+/// execution if the pool is traded against in the meantime. Values are denominated in the
+/// oracle's base unit
+///
+/// The simulation does not assert the position's health; it is up to the caller to compare `ltv`
+/// against `max_ltv`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SimulateResponse {
+    /// Amount of secondary asset borrowed from Red Bank, including tax
+    pub secondary_borrowed: Uint128,
+    /// Amount of secondary asset repaid to Red Bank
+    pub secondary_repaid: Uint128,
+    /// Amount of primary asset provided to the pool
+    pub primary_provided: Uint128,
+    /// Amount of secondary asset provided to the pool
+    pub secondary_provided: Uint128,
+    /// Amount of liquidity tokens expected to be minted and bonded
+    pub shares_bonded: Uint128,
+    /// Amount of liquidity tokens unbonded and burned
+    pub shares_unbonded: Uint128,
+    /// The position's bond units after the update
+    pub bond_units: Uint128,
+    /// The position's debt units after the update
+    pub debt_units: Uint128,
+    /// Value of the position's bonded liquidity tokens after the update
+    pub bond_value: Uint128,
+    /// Value of the position's debt after the update
+    pub debt_value: Uint128,
+    /// The position's LTV after the update; `None` if no liquidity token is bonded
+    pub ltv: Option<Decimal>,
+    /// Unlocked assets to be refunded to the user
+    pub refunded: Vec<AssetUnchecked>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingRewardsResponseItem {
+    pub asset: AssetUnchecked,
+    /// Value of the asset denominated in the oracle's base unit, or `None` if the oracle does not
+    /// have a price for it
+    pub value: Option<Uint128>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MigrateMsg {
+    /// If provided, overwrite the config with this one during migration
+    pub new_config: Option<ConfigUnchecked>,
+}
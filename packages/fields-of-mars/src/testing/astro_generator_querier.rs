@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+
+use cosmwasm_std::{to_binary, Addr, QuerierResult, SystemError, Uint128};
+
+use astroport::generator::{PendingTokenResponse, QueryMsg, RewardInfoResponse};
+
+#[derive(Default)]
+pub struct AstroGeneratorQuerier {
+    /// Maps (liquidity token, staker) to bonded amount
+    pub deposits: HashMap<(Addr, Addr), Uint128>,
+    /// Maps liquidity token to reward info
+    pub reward_infos: HashMap<Addr, RewardInfoResponse>,
+    /// Maps (liquidity token, staker) to pending rewards
+    pub pending_tokens: HashMap<(Addr, Addr), PendingTokenResponse>,
+}
+
+impl AstroGeneratorQuerier {
+    pub fn handle_query(&self, _contract_addr: &Addr, query: QueryMsg) -> QuerierResult {
+        match query {
+            QueryMsg::Deposit {
+                lp_token,
+                user,
+            } => {
+                let amount =
+                    self.deposits.get(&(lp_token, user)).cloned().unwrap_or_else(Uint128::zero);
+                Ok(to_binary(&amount).into()).into()
+            }
+
+            QueryMsg::RewardInfo {
+                lp_token,
+            } => match self.reward_infos.get(&lp_token) {
+                Some(reward_info) => Ok(to_binary(reward_info).into()).into(),
+                None => Err(SystemError::InvalidRequest {
+                    error: format!("[mock]: reward info not set for {}", lp_token),
+                    request: Default::default(),
+                })
+                .into(),
+            },
+
+            QueryMsg::PendingToken {
+                lp_token,
+                user,
+            } => {
+                let pending_token =
+                    self.pending_tokens.get(&(lp_token, user)).cloned().unwrap_or(
+                        PendingTokenResponse {
+                            pending: Uint128::zero(),
+                            pending_on_proxy: None,
+                        },
+                    );
+                Ok(to_binary(&pending_token).into()).into()
+            }
+
+            query => Err(SystemError::InvalidRequest {
+                error: format!("[mock]: unsupported generator query {:?}", query),
+                request: Default::default(),
+            })
+            .into(),
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+
+use std::convert::TryFrom;
+
+use cosmwasm_std::{to_binary, Addr, QuerierResult, SystemError, Uint128, Uint256};
+
+use astroport::asset::{Asset as AstroportAsset, PairInfo};
+use astroport::factory::PairType;
+use astroport::pair::{PoolResponse, QueryMsg, ReverseSimulationResponse, SimulationResponse};
+
+/// Astroport charges 0.3% commission on XYK pairs
+const COMMISSION_RATE_PERMILLE: u128 = 3;
+
+/// Astroport charges 0.05% commission on stableswap pairs
+const STABLE_COMMISSION_RATE_BPS: u128 = 5;
+
+/// Amplification coefficient of the mock stableswap pairs
+const AMP: u128 = 100;
+
+/// Maximum number of Newton iterations when solving the stableswap invariant
+const MAX_ITERATIONS: usize = 32;
+
+#[derive(Default)]
+pub struct AstroportPairQuerier {
+    /// Maps pair contract address to pool response
+    pub pools: HashMap<Addr, PoolResponse>,
+    /// Maps pair contract address to pair info. Pairs without an info are treated as XYK
+    pub pair_infos: HashMap<Addr, PairInfo>,
+}
+
+impl AstroportPairQuerier {
+    /// Whether the contract at the given address is a mock Astroport pair
+    pub fn is_pair(&self, contract_addr: &Addr) -> bool {
+        self.pools.contains_key(contract_addr) || self.pair_infos.contains_key(contract_addr)
+    }
+
+    pub fn handle_query(&self, contract_addr: &Addr, query: QueryMsg) -> QuerierResult {
+        if let QueryMsg::Pair {} = query {
+            return match self.pair_infos.get(contract_addr) {
+                Some(pair_info) => Ok(to_binary(pair_info).into()).into(),
+                None => Err(SystemError::InvalidRequest {
+                    error: format!("[mock]: pair info not set for {}", contract_addr),
+                    request: Default::default(),
+                })
+                .into(),
+            };
+        }
+
+        let is_stable = matches!(
+            self.pair_infos.get(contract_addr).map(|pair_info| &pair_info.pair_type),
+            Some(PairType::Stable {})
+        );
+
+        let pool = match self.pools.get(contract_addr) {
+            Some(pool) => pool,
+            None => {
+                return Err(SystemError::InvalidRequest {
+                    error: format!("[mock]: pool not set for {}", contract_addr),
+                    request: Default::default(),
+                })
+                .into()
+            }
+        };
+
+        match query {
+            QueryMsg::Pool {} => Ok(to_binary(pool).into()).into(),
+
+            QueryMsg::Simulation {
+                offer_asset,
+            } => {
+                let (offer_pool, ask_pool) = find_pools(pool, &offer_asset, true);
+                let (return_amount, spread_amount, commission_amount) = if is_stable {
+                    compute_stable_swap(offer_pool, ask_pool, offer_asset.amount)
+                } else {
+                    compute_swap(offer_pool, ask_pool, offer_asset.amount)
+                };
+                Ok(to_binary(&SimulationResponse {
+                    return_amount,
+                    spread_amount,
+                    commission_amount,
+                })
+                .into())
+                .into()
+            }
+
+            QueryMsg::ReverseSimulation {
+                ask_asset,
+            } => {
+                let (offer_pool, ask_pool) = find_pools(pool, &ask_asset, false);
+                let (offer_amount, spread_amount, commission_amount) = if is_stable {
+                    compute_stable_offer_amount(offer_pool, ask_pool, ask_asset.amount)
+                } else {
+                    compute_offer_amount(offer_pool, ask_pool, ask_asset.amount)
+                };
+                Ok(to_binary(&ReverseSimulationResponse {
+                    offer_amount,
+                    spread_amount,
+                    commission_amount,
+                })
+                .into())
+                .into()
+            }
+
+            query => Err(SystemError::InvalidRequest {
+                error: format!("[mock]: unsupported pair query {:?}", query),
+                request: Default::default(),
+            })
+            .into(),
+        }
+    }
+}
+
+/// Return a 2-tuple: (offer pool depth, ask pool depth)
+fn find_pools(pool: &PoolResponse, asset: &AstroportAsset, is_offer: bool) -> (Uint128, Uint128) {
+    let (this, other) = if pool.assets[0].info == asset.info {
+        (pool.assets[0].amount, pool.assets[1].amount)
+    } else {
+        (pool.assets[1].amount, pool.assets[0].amount)
+    };
+
+    if is_offer {
+        (this, other)
+    } else {
+        (other, this)
+    }
+}
+
+/// Mirrors `compute_swap` of the Astroport XYK pair contract
+///
+/// Returns a 3-tuple: (return amount, spread amount, commission amount)
+pub fn compute_swap(
+    offer_pool: Uint128,
+    ask_pool: Uint128,
+    offer_amount: Uint128,
+) -> (Uint128, Uint128, Uint128) {
+    // return_amount = ask_pool - cp / (offer_pool + offer_amount)
+    let return_amount = ask_pool - offer_pool.multiply_ratio(ask_pool, offer_pool + offer_amount);
+
+    // spread = offer_amount * ask_pool / offer_pool - return_amount
+    let spread_amount =
+        offer_amount.multiply_ratio(ask_pool, offer_pool).saturating_sub(return_amount);
+    let commission_amount = return_amount.multiply_ratio(COMMISSION_RATE_PERMILLE, 1000u128);
+
+    (return_amount - commission_amount, spread_amount, commission_amount)
+}
+
+/// Mirrors `compute_offer_amount` of the Astroport XYK pair contract
+///
+/// Returns a 3-tuple: (offer amount, spread amount, commission amount)
+pub fn compute_offer_amount(
+    offer_pool: Uint128,
+    ask_pool: Uint128,
+    ask_amount: Uint128,
+) -> (Uint128, Uint128, Uint128) {
+    // ask amount before commission deduction = ask_amount / (1 - commission_rate)
+    let before_commission_deduction =
+        ask_amount.multiply_ratio(1000u128, 1000u128 - COMMISSION_RATE_PERMILLE);
+
+    // offer_amount = cp / (ask_pool - before_commission_deduction) - offer_pool
+    let offer_amount = offer_pool.multiply_ratio(ask_pool, ask_pool - before_commission_deduction)
+        - offer_pool;
+
+    let spread_amount = offer_amount
+        .multiply_ratio(ask_pool, offer_pool)
+        .saturating_sub(before_commission_deduction);
+    let commission_amount =
+        before_commission_deduction.multiply_ratio(COMMISSION_RATE_PERMILLE, 1000u128);
+
+    (offer_amount, spread_amount, commission_amount)
+}
+
+/// Mirrors `compute_swap` of the Astroport stableswap pair contract, for two assets
+///
+/// Returns a 3-tuple: (return amount, spread amount, commission amount)
+pub fn compute_stable_swap(
+    offer_pool: Uint128,
+    ask_pool: Uint128,
+    offer_amount: Uint128,
+) -> (Uint128, Uint128, Uint128) {
+    let d = compute_d(offer_pool.into(), ask_pool.into());
+    let new_ask_pool = compute_new_balance((offer_pool + offer_amount).into(), d);
+    let return_amount = ask_pool - Uint128::try_from(new_ask_pool).unwrap();
+
+    // the assets are pegged 1:1, so the spread is the shortfall from the offer amount
+    let spread_amount = offer_amount.saturating_sub(return_amount);
+    let commission_amount = return_amount.multiply_ratio(STABLE_COMMISSION_RATE_BPS, 10000u128);
+
+    (return_amount - commission_amount, spread_amount, commission_amount)
+}
+
+/// Mirrors `compute_offer_amount` of the Astroport stableswap pair contract, for two assets
+///
+/// Returns a 3-tuple: (offer amount, spread amount, commission amount)
+pub fn compute_stable_offer_amount(
+    offer_pool: Uint128,
+    ask_pool: Uint128,
+    ask_amount: Uint128,
+) -> (Uint128, Uint128, Uint128) {
+    let before_commission_deduction =
+        ask_amount.multiply_ratio(10000u128, 10000u128 - STABLE_COMMISSION_RATE_BPS);
+
+    let d = compute_d(offer_pool.into(), ask_pool.into());
+    let new_offer_pool = compute_new_balance((ask_pool - before_commission_deduction).into(), d);
+    let offer_amount = Uint128::try_from(new_offer_pool).unwrap() - offer_pool;
+
+    let spread_amount = offer_amount.saturating_sub(before_commission_deduction);
+    let commission_amount =
+        before_commission_deduction.multiply_ratio(STABLE_COMMISSION_RATE_BPS, 10000u128);
+
+    (offer_amount, spread_amount, commission_amount)
+}
+
+/// Solve the stableswap invariant for `D`, given the depths of the two assets
+fn compute_d(x: Uint256, y: Uint256) -> Uint256 {
+    let one = Uint256::from(1u128);
+    let n = Uint256::from(2u128);
+    let leverage = Uint256::from(AMP * 2);
+
+    let sum = x + y;
+    if sum.is_zero() {
+        return sum;
+    }
+
+    let mut d = sum;
+    for _ in 0..MAX_ITERATIONS {
+        let d_product = d * d / (x * n) * d / (y * n);
+        let d_prev = d;
+        d = (leverage * sum + d_product * n) * d
+            / ((leverage - one) * d + (n + one) * d_product);
+        if abs_diff(d, d_prev) <= one {
+            break;
+        }
+    }
+    d
+}
+
+/// Given the new depth of one asset, find the depth of the other that preserves `D`
+fn compute_new_balance(new_pool: Uint256, d: Uint256) -> Uint256 {
+    let one = Uint256::from(1u128);
+    let n = Uint256::from(2u128);
+    let leverage = Uint256::from(AMP * 2);
+
+    let c = d * d / (new_pool * n) * d / (leverage * n);
+    let b = new_pool + d / leverage;
+
+    let mut y = d;
+    for _ in 0..MAX_ITERATIONS {
+        let y_prev = y;
+        y = (y * y + c) / (y * n + b - d);
+        if abs_diff(y, y_prev) <= one {
+            break;
+        }
+    }
+    y
+}
+
+fn abs_diff(a: Uint256, b: Uint256) -> Uint256 {
+    if a > b {
+        a - b
+    } else {
+        b - a
+    }
+}
@@ -0,0 +1,273 @@
+use cosmwasm_std::testing::MockQuerier;
+use cosmwasm_std::{
+    from_binary, from_slice, Addr, Coin, Decimal, Empty, Querier, QuerierResult, QueryRequest,
+    StdResult, SystemError, Uint128, WasmQuery,
+};
+use cw20::Cw20QueryMsg;
+
+use astroport::generator::{PendingTokenResponse, RewardInfoResponse};
+use astroport::asset::PairInfo;
+use astroport::factory::PairType as AstroportPairType;
+use astroport::pair::PoolResponse;
+
+use cw_asset::{Asset, AssetInfo};
+
+use mars_core::asset::AssetType as MarsAssetType;
+
+use crate::adapters::{
+    get_asset_reference, OracleBatchQueryMsg, OracleTimestampedQueryMsg, PairType,
+};
+
+use super::astro_generator_querier::AstroGeneratorQuerier;
+use super::astroport_pair_querier::AstroportPairQuerier;
+use super::cw20_querier::Cw20Querier;
+use super::oracle_querier::OracleQuerier;
+use super::red_bank_querier::RedBankQuerier;
+
+pub struct CustomMockQuerier {
+    base: MockQuerier<Empty>,
+    astro_generator_querier: AstroGeneratorQuerier,
+    astroport_pair_querier: AstroportPairQuerier,
+    cw20_querier: Cw20Querier,
+    oracle_querier: OracleQuerier,
+    red_bank_querier: RedBankQuerier,
+}
+
+impl Default for CustomMockQuerier {
+    fn default() -> Self {
+        CustomMockQuerier {
+            base: MockQuerier::<Empty>::new(&[]),
+            astro_generator_querier: AstroGeneratorQuerier::default(),
+            astroport_pair_querier: AstroportPairQuerier::default(),
+            cw20_querier: Cw20Querier::default(),
+            oracle_querier: OracleQuerier::default(),
+            red_bank_querier: RedBankQuerier::default(),
+        }
+    }
+}
+
+impl Querier for CustomMockQuerier {
+    fn raw_query(&self, bin_request: &[u8]) -> QuerierResult {
+        let request: QueryRequest<Empty> = match from_slice(bin_request) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(SystemError::InvalidRequest {
+                    error: format!("[mock]: failed to parse query request {}", e),
+                    request: bin_request.into(),
+                })
+                .into()
+            }
+        };
+        self.handle_query(&request)
+    }
+}
+
+impl CustomMockQuerier {
+    pub fn handle_query(&self, request: &QueryRequest<Empty>) -> QuerierResult {
+        match request {
+            QueryRequest::Wasm(WasmQuery::Smart {
+                contract_addr,
+                msg,
+            }) => {
+                let contract_addr = Addr::unchecked(contract_addr);
+
+                // Astroport pairs are identified by address, since their `Config {}` query would
+                // otherwise be ambiguous with those of the other contracts
+                if self.astroport_pair_querier.is_pair(&contract_addr) {
+                    if let Ok(pair_query) = from_binary(msg) {
+                        return self.astroport_pair_querier.handle_query(&contract_addr, pair_query);
+                    }
+                }
+
+                let parse_generator_query: StdResult<astroport::generator::QueryMsg> =
+                    from_binary(msg);
+                if let Ok(generator_query) = parse_generator_query {
+                    return self
+                        .astro_generator_querier
+                        .handle_query(&contract_addr, generator_query);
+                }
+
+                let parse_timestamped_oracle_query: StdResult<OracleTimestampedQueryMsg> =
+                    from_binary(msg);
+                if let Ok(oracle_query) = parse_timestamped_oracle_query {
+                    return self
+                        .oracle_querier
+                        .handle_timestamped_query(&contract_addr, oracle_query);
+                }
+
+                let parse_batch_oracle_query: StdResult<OracleBatchQueryMsg> = from_binary(msg);
+                if let Ok(oracle_query) = parse_batch_oracle_query {
+                    return self.oracle_querier.handle_batch_query(&contract_addr, oracle_query);
+                }
+
+                let parse_oracle_query: StdResult<mars_core::oracle::msg::QueryMsg> =
+                    from_binary(msg);
+                if let Ok(oracle_query) = parse_oracle_query {
+                    return self.oracle_querier.handle_query(&contract_addr, oracle_query);
+                }
+
+                let parse_red_bank_query: StdResult<mars_core::red_bank::msg::QueryMsg> =
+                    from_binary(msg);
+                if let Ok(red_bank_query) = parse_red_bank_query {
+                    return self.red_bank_querier.handle_query(&contract_addr, red_bank_query);
+                }
+
+                let parse_cw20_query: StdResult<Cw20QueryMsg> = from_binary(msg);
+                if let Ok(cw20_query) = parse_cw20_query {
+                    return self.cw20_querier.handle_query(&contract_addr, cw20_query);
+                }
+
+                panic!("[mock]: unsupported wasm query {:?}", msg);
+            }
+
+            _ => self.base.handle_query(request),
+        }
+    }
+
+    pub fn set_base_balances(&mut self, address: &str, balances: &[Coin]) {
+        self.base.update_balance(address, balances.to_vec());
+    }
+
+    pub fn set_cw20_balance(&mut self, token: &str, user: &str, balance: u128) {
+        self.cw20_querier
+            .balances
+            .entry(Addr::unchecked(token))
+            .or_default()
+            .insert(Addr::unchecked(user), Uint128::new(balance));
+    }
+
+    pub fn set_cw20_allowance(&mut self, token: &str, owner: &str, spender: &str, amount: u128) {
+        self.cw20_querier
+            .allowances
+            .entry(Addr::unchecked(token))
+            .or_default()
+            .insert((Addr::unchecked(owner), Addr::unchecked(spender)), Uint128::new(amount));
+    }
+
+    pub fn set_cw20_decimals(&mut self, token: &str, decimals: u8) {
+        self.cw20_querier.decimals.insert(Addr::unchecked(token), decimals);
+    }
+
+    /// Set the depths of an Astroport pool, as well as the total supply of its liquidity token
+    pub fn set_pool(&mut self, pair: &str, assets: &[Asset; 2], total_share: u128) {
+        self.astroport_pair_querier.pools.insert(
+            Addr::unchecked(pair),
+            PoolResponse {
+                assets: [assets[0].clone().into(), assets[1].clone().into()],
+                total_share: Uint128::new(total_share),
+            },
+        );
+    }
+
+    /// Set the info of an Astroport pair, which is returned by its `Pair {}` query and determines
+    /// the invariant used for simulating swaps
+    pub fn set_pair_info(
+        &mut self,
+        pair: &str,
+        asset_infos: &[AssetInfo; 2],
+        liquidity_token: &str,
+        pair_type: PairType,
+    ) {
+        self.astroport_pair_querier.pair_infos.insert(
+            Addr::unchecked(pair),
+            PairInfo {
+                asset_infos: [asset_infos[0].clone().into(), asset_infos[1].clone().into()],
+                contract_addr: Addr::unchecked(pair),
+                liquidity_token: Addr::unchecked(liquidity_token),
+                pair_type: match pair_type {
+                    PairType::Xyk => AstroportPairType::Xyk {},
+                    PairType::Stable => AstroportPairType::Stable {},
+                },
+            },
+        );
+    }
+
+    pub fn set_bonded_amount(&mut self, liquidity_token: &str, staker: &str, amount: u128) {
+        self.astro_generator_querier
+            .deposits
+            .insert((Addr::unchecked(liquidity_token), Addr::unchecked(staker)), amount.into());
+    }
+
+    pub fn set_reward_info(
+        &mut self,
+        liquidity_token: &str,
+        base_reward_token: &str,
+        proxy_reward_token: Option<&str>,
+    ) {
+        self.astro_generator_querier.reward_infos.insert(
+            Addr::unchecked(liquidity_token),
+            RewardInfoResponse {
+                base_reward_token: Addr::unchecked(base_reward_token),
+                proxy_reward_token: proxy_reward_token.map(Addr::unchecked),
+            },
+        );
+    }
+
+    pub fn set_pending_tokens(
+        &mut self,
+        liquidity_token: &str,
+        staker: &str,
+        pending: u128,
+        pending_on_proxy: Option<u128>,
+    ) {
+        self.astro_generator_querier.pending_tokens.insert(
+            (Addr::unchecked(liquidity_token), Addr::unchecked(staker)),
+            PendingTokenResponse {
+                pending: pending.into(),
+                pending_on_proxy: pending_on_proxy.map(Uint128::new),
+            },
+        );
+    }
+
+    pub fn set_oracle_price(&mut self, asset_info: &AssetInfo, price: Decimal) {
+        let asset_reference = get_asset_reference(asset_info);
+        self.oracle_querier.prices.insert(asset_reference, price);
+    }
+
+    /// Set the price of an asset as reported by the specified oracle only, overriding the price set
+    /// by `set_oracle_price`
+    pub fn set_oracle_price_at(&mut self, oracle: &str, asset_info: &AssetInfo, price: Decimal) {
+        let asset_reference = get_asset_reference(asset_info);
+        self.oracle_querier
+            .oracle_prices
+            .entry(Addr::unchecked(oracle))
+            .or_default()
+            .insert(asset_reference, price);
+    }
+
+    /// Let the specified oracle answer batch price queries
+    pub fn enable_oracle_batch_queries(&mut self, oracle: &str) {
+        self.oracle_querier.batch_oracles.insert(Addr::unchecked(oracle));
+    }
+
+    /// Set the timestamp at which the oracle price of an asset was last updated
+    pub fn set_oracle_price_updated_at(&mut self, asset_info: &AssetInfo, last_updated: u64) {
+        let asset_reference = get_asset_reference(asset_info);
+        self.oracle_querier.last_updated.insert(asset_reference, last_updated);
+    }
+
+    /// Set the debt amount of a user, and list the asset's market if not already listed
+    pub fn set_user_debt(&mut self, user: &str, asset_info: &AssetInfo, amount: u128) {
+        let asset_label = self.set_red_bank_market(asset_info);
+        self.red_bank_querier.debts.insert((user.to_string(), asset_label), amount.into());
+    }
+
+    /// Enable an asset as collateral for a user, and list the asset's market if not already listed
+    pub fn set_user_collateral(&mut self, user: &str, asset_info: &AssetInfo) {
+        let asset_label = self.set_red_bank_market(asset_info);
+        self.red_bank_querier.collaterals.entry(user.to_string()).or_default().push(asset_label);
+    }
+
+    /// List a market on Red Bank. Like Red Bank does, CW20 addresses are normalized to lower case.
+    /// Return the asset label under which the market is listed
+    pub fn set_red_bank_market(&mut self, asset_info: &AssetInfo) -> String {
+        let (asset_label, asset_type) = match asset_info {
+            AssetInfo::Cw20(contract_addr) => {
+                (contract_addr.to_string().to_lowercase(), MarsAssetType::Cw20)
+            }
+            AssetInfo::Native(denom) => (denom.clone(), MarsAssetType::Native),
+        };
+        self.red_bank_querier.markets.insert(asset_label.clone(), asset_type);
+        asset_label
+    }
+}
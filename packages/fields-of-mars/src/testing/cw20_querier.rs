@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+
+use cosmwasm_std::{to_binary, Addr, QuerierResult, SystemError, Uint128};
+use cw20::{AllowanceResponse, BalanceResponse, Cw20QueryMsg, Expiration, TokenInfoResponse};
+
+#[derive(Default)]
+pub struct Cw20Querier {
+    /// Maps token contract address to user balances
+    pub balances: HashMap<Addr, HashMap<Addr, Uint128>>,
+    /// Maps token contract address to allowances, keyed by (owner, spender)
+    pub allowances: HashMap<Addr, HashMap<(Addr, Addr), Uint128>>,
+    /// Maps token contract address to decimals. Default to 6 if not set
+    pub decimals: HashMap<Addr, u8>,
+}
+
+impl Cw20Querier {
+    pub fn handle_query(&self, contract_addr: &Addr, query: Cw20QueryMsg) -> QuerierResult {
+        match query {
+            Cw20QueryMsg::Balance {
+                address,
+            } => {
+                let balance = self
+                    .balances
+                    .get(contract_addr)
+                    .and_then(|balances| balances.get(&Addr::unchecked(&address)))
+                    .cloned()
+                    .unwrap_or_else(Uint128::zero);
+
+                Ok(to_binary(&BalanceResponse {
+                    balance,
+                })
+                .into())
+                .into()
+            }
+
+            Cw20QueryMsg::Allowance {
+                owner,
+                spender,
+            } => {
+                let allowance = self
+                    .allowances
+                    .get(contract_addr)
+                    .and_then(|allowances| {
+                        allowances.get(&(Addr::unchecked(&owner), Addr::unchecked(&spender)))
+                    })
+                    .cloned()
+                    .unwrap_or_else(Uint128::zero);
+
+                Ok(to_binary(&AllowanceResponse {
+                    allowance,
+                    expires: Expiration::Never {},
+                })
+                .into())
+                .into()
+            }
+
+            Cw20QueryMsg::TokenInfo {} => {
+                let decimals = self.decimals.get(contract_addr).cloned().unwrap_or(6);
+
+                Ok(to_binary(&TokenInfoResponse {
+                    name: contract_addr.to_string(),
+                    symbol: "MOCK".to_string(),
+                    decimals,
+                    total_supply: Uint128::zero(),
+                })
+                .into())
+                .into()
+            }
+
+            query => Err(SystemError::InvalidRequest {
+                error: format!("[mock]: unsupported cw20 query {:?}", query),
+                request: Default::default(),
+            })
+            .into(),
+        }
+    }
+}
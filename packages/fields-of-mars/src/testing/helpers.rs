@@ -1,4 +1,17 @@
-use cosmwasm_std::{StdError, StdResult};
+use cosmwasm_std::testing::{MockApi, MockStorage};
+use cosmwasm_std::{OwnedDeps, StdError, StdResult};
+
+use super::custom_mock_querier::CustomMockQuerier;
+
+/// `mock_dependencies` replacement for `cosmwasm_std::testing::mock_dependencies`, which can mock
+/// responses from Astroport, Astro generator, Mars oracle, Red Bank, and CW20 token contracts
+pub fn mock_dependencies() -> OwnedDeps<MockStorage, MockApi, CustomMockQuerier> {
+    OwnedDeps {
+        storage: MockStorage::default(),
+        api: MockApi::default(),
+        querier: CustomMockQuerier::default(),
+    }
+}
 
 /// Assert elements in vecs one by one in order to get a more meaningful error when debugging tests
 pub fn assert_eq_vec<T: std::fmt::Debug + PartialEq>(expected: Vec<T>, actual: Vec<T>) {
@@ -1,3 +1,10 @@
+mod astro_generator_querier;
+mod astroport_pair_querier;
+mod custom_mock_querier;
+mod cw20_querier;
 mod helpers;
+mod oracle_querier;
+mod red_bank_querier;
 
+pub use self::custom_mock_querier::CustomMockQuerier;
 pub use self::helpers::*;
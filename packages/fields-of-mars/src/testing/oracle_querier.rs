@@ -0,0 +1,122 @@
+use std::collections::{HashMap, HashSet};
+
+use cosmwasm_std::{to_binary, Addr, Decimal, QuerierResult, SystemError};
+
+use mars_core::math::decimal::Decimal as MarsDecimal;
+use mars_core::oracle::msg::QueryMsg;
+
+use crate::adapters::{OracleBatchQueryMsg, OracleTimestampedQueryMsg, PriceWithTimestampResponse};
+
+#[derive(Default)]
+pub struct OracleQuerier {
+    /// Maps asset reference to price
+    pub prices: HashMap<Vec<u8>, Decimal>,
+    /// Maps asset reference to the timestamp at which the price was last updated
+    pub last_updated: HashMap<Vec<u8>, u64>,
+    /// Maps oracle address to prices reported by that oracle only, which override `prices`
+    pub oracle_prices: HashMap<Addr, HashMap<Vec<u8>, Decimal>>,
+    /// Oracles that support batch queries. Like Mars oracle v1, others don't
+    pub batch_oracles: HashSet<Addr>,
+}
+
+impl OracleQuerier {
+    fn get_price(&self, contract_addr: &Addr, asset_reference: &[u8]) -> Option<&Decimal> {
+        self.oracle_prices
+            .get(contract_addr)
+            .and_then(|prices| prices.get(asset_reference))
+            .or_else(|| self.prices.get(asset_reference))
+    }
+
+    pub fn handle_query(&self, contract_addr: &Addr, query: QueryMsg) -> QuerierResult {
+        match query {
+            QueryMsg::AssetPriceByReference {
+                asset_reference,
+            } => match self.get_price(contract_addr, &asset_reference) {
+                Some(price) => Ok(to_binary(&MarsDecimal::from(*price)).into()).into(),
+                None => Err(SystemError::InvalidRequest {
+                    error: format!(
+                        "[mock]: price not set for {}",
+                        String::from_utf8(asset_reference).unwrap()
+                    ),
+                    request: Default::default(),
+                })
+                .into(),
+            },
+
+            query => Err(SystemError::InvalidRequest {
+                error: format!("[mock]: unsupported oracle query {:?}", query),
+                request: Default::default(),
+            })
+            .into(),
+        }
+    }
+
+    pub fn handle_batch_query(
+        &self,
+        contract_addr: &Addr,
+        query: OracleBatchQueryMsg,
+    ) -> QuerierResult {
+        if !self.batch_oracles.contains(contract_addr) {
+            return Err(SystemError::InvalidRequest {
+                error: format!("[mock]: batch queries not supported by {}", contract_addr),
+                request: Default::default(),
+            })
+            .into();
+        }
+
+        match query {
+            OracleBatchQueryMsg::AssetPricesByReference {
+                asset_references,
+            } => {
+                let mut prices: Vec<MarsDecimal> = vec![];
+                for asset_reference in asset_references {
+                    match self.get_price(contract_addr, &asset_reference) {
+                        Some(price) => prices.push(MarsDecimal::from(*price)),
+                        None => {
+                            return Err(SystemError::InvalidRequest {
+                                error: format!(
+                                    "[mock]: price not set for {}",
+                                    String::from_utf8(asset_reference).unwrap()
+                                ),
+                                request: Default::default(),
+                            })
+                            .into()
+                        }
+                    }
+                }
+                Ok(to_binary(&prices).into()).into()
+            }
+        }
+    }
+
+    pub fn handle_timestamped_query(
+        &self,
+        contract_addr: &Addr,
+        query: OracleTimestampedQueryMsg,
+    ) -> QuerierResult {
+        match query {
+            OracleTimestampedQueryMsg::AssetPriceWithTimestampByReference {
+                asset_reference,
+            } => {
+                let price = self.get_price(contract_addr, &asset_reference);
+                let last_updated = self.last_updated.get(&asset_reference);
+                match (price, last_updated) {
+                    (Some(price), Some(last_updated)) => Ok(to_binary(&PriceWithTimestampResponse {
+                        price: MarsDecimal::from(*price),
+                        last_updated: *last_updated,
+                    })
+                    .into())
+                    .into(),
+                    _ => Err(SystemError::InvalidRequest {
+                        error: format!(
+                            "[mock]: price or timestamp not set for {}",
+                            String::from_utf8(asset_reference).unwrap()
+                        ),
+                        request: Default::default(),
+                    })
+                    .into(),
+                }
+            }
+        }
+    }
+}
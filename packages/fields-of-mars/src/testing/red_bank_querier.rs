@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+
+use cosmwasm_std::{to_binary, Addr, QuerierResult, SystemError, Uint128};
+
+use mars_core::asset::{Asset as MarsAsset, AssetType as MarsAssetType};
+use mars_core::red_bank::msg::QueryMsg;
+use mars_core::red_bank::{
+    MarketInfo, MarketsListResponse, UserAssetCollateralResponse, UserAssetDebtResponse,
+    UserCollateralResponse,
+};
+
+#[derive(Default)]
+pub struct RedBankQuerier {
+    /// Maps (user address, asset label) to debt amount
+    pub debts: HashMap<(String, String), Uint128>,
+    /// Maps asset label to asset type of each market Red Bank lists
+    pub markets: HashMap<String, MarsAssetType>,
+    /// Maps user address to the labels of assets the user has enabled as collateral
+    pub collaterals: HashMap<String, Vec<String>>,
+}
+
+impl RedBankQuerier {
+    pub fn handle_query(&self, _contract_addr: &Addr, query: QueryMsg) -> QuerierResult {
+        match query {
+            QueryMsg::UserAssetDebt {
+                user_address,
+                asset,
+            } => {
+                let (denom, asset_type) = match &asset {
+                    MarsAsset::Cw20 {
+                        contract_addr,
+                    } => (contract_addr.clone(), MarsAssetType::Cw20),
+                    MarsAsset::Native {
+                        denom,
+                    } => (denom.clone(), MarsAssetType::Native),
+                };
+
+                let amount = self
+                    .debts
+                    .get(&(user_address, denom.clone()))
+                    .cloned()
+                    .unwrap_or_else(Uint128::zero);
+
+                Ok(to_binary(&UserAssetDebtResponse {
+                    denom: denom.clone(),
+                    asset_label: denom,
+                    asset_reference: vec![],
+                    asset_type,
+                    amount_scaled: Uint128::zero(),
+                    amount,
+                })
+                .into())
+                .into()
+            }
+
+            QueryMsg::UserCollateral {
+                user_address,
+            } => {
+                let enabled = self.collaterals.get(&user_address).cloned().unwrap_or_default();
+                let mut collateral: Vec<UserAssetCollateralResponse> = self
+                    .markets
+                    .iter()
+                    .map(|(asset_label, asset_type)| UserAssetCollateralResponse {
+                        denom: asset_label.clone(),
+                        asset_label: asset_label.clone(),
+                        asset_reference: asset_label.as_bytes().to_vec(),
+                        asset_type: *asset_type,
+                        enabled: enabled.contains(asset_label),
+                    })
+                    .collect();
+                collateral.sort_by(|a, b| a.asset_label.cmp(&b.asset_label));
+
+                Ok(to_binary(&UserCollateralResponse {
+                    collateral,
+                })
+                .into())
+                .into()
+            }
+
+            QueryMsg::MarketsList {} => {
+                let mut markets_list: Vec<MarketInfo> = self
+                    .markets
+                    .iter()
+                    .map(|(asset_label, asset_type)| MarketInfo {
+                        denom: asset_label.clone(),
+                        asset_label: asset_label.clone(),
+                        asset_reference: asset_label.as_bytes().to_vec(),
+                        asset_type: *asset_type,
+                        ma_token_address: Addr::unchecked(format!("ma_{}", asset_label)),
+                    })
+                    .collect();
+                markets_list.sort_by(|a, b| a.asset_label.cmp(&b.asset_label));
+
+                Ok(to_binary(&MarketsListResponse {
+                    markets_list,
+                })
+                .into())
+                .into()
+            }
+
+            query => Err(SystemError::InvalidRequest {
+                error: format!("[mock]: unsupported red bank query {:?}", query),
+                request: Default::default(),
+            })
+            .into(),
+        }
+    }
+}
@@ -122,12 +122,13 @@ async function setupTest() {
     oracle: {
       contract_addr: oracle,
     },
-    treasury: treasury.key.accAddress,
+    fee_recipients: [[treasury.key.accAddress, "1"]],
     governance: deployer.key.accAddress,
     operators: [deployer.key.accAddress],
     max_ltv: "0.75", // 75%, i.e. for every 100 UST asset there must be no more than 75 UST debt
     fee_rate: "0.2", // 20%
     bonus_rate: "0.05", // 5%
+    liquidation_max_spread: "0.5", // 50%; the liquidation in this test sells into a shallow pool
   };
 
   ({ field } = await deployMartianField(deployer, config));
@@ -115,12 +115,13 @@ async function setupTest() {
     oracle: {
       contract_addr: oracle,
     },
-    treasury: treasury.key.accAddress,
+    fee_recipients: [[treasury.key.accAddress, "1"]],
     governance: deployer.key.accAddress,
     operators: [deployer.key.accAddress],
     max_ltv: "0.83",
     fee_rate: "0",
     bonus_rate: "0.05",
+    liquidation_max_spread: "0.5",
   };
   ({ field } = await deployMartianField(deployer, config));
 
@@ -118,7 +118,7 @@ async function setupTest() {
     oracle: {
       contract_addr: oracle,
     },
-    treasury: deployer.key.accAddress,
+    fee_recipients: [[deployer.key.accAddress, "1"]],
     governance: deployer.key.accAddress,
     operators: [deployer.key.accAddress],
     max_ltv: "0.65",
@@ -206,6 +206,22 @@ export async function deployRedBank(deployer: Wallet) {
 
   console.log(chalk.green("Done!"), `${chalk.blue("address")}=${bank}`);
 
+  process.stdout.write("Listing uusd market on mock Red Bank... ");
+
+  await sendTransaction(deployer, [
+    new MsgExecuteContract(deployer.key.accAddress, bank, {
+      init_asset: {
+        asset: {
+          native: {
+            denom: "uusd",
+          },
+        },
+      },
+    }),
+  ]);
+
+  console.log(chalk.green("Done!"));
+
   return { bankCodeId: codeId, bank };
 }
 
@@ -213,7 +229,7 @@ export async function deployRedBank(deployer: Wallet) {
 // Martian Field
 //--------------------------------------------------------------------------------------------------
 
-export async function deployMartianField(deployer: Wallet, instantiateMsg: object) {
+export async function deployMartianField(deployer: Wallet, config: object) {
   process.stdout.write("Uploading Martian Field code... ");
 
   const codeId = await storeCode(
@@ -225,7 +241,7 @@ export async function deployMartianField(deployer: Wallet, instantiateMsg: objec
 
   process.stdout.write("Instantiating Martian Field contract... ");
 
-  const result = await instantiateContract(deployer, codeId, instantiateMsg);
+  const result = await instantiateContract(deployer, codeId, { config });
 
   const field = result.logs[0].events[0].attributes[3].value;
 